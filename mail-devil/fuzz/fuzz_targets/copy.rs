@@ -0,0 +1,55 @@
+//! Runs messages through `copy::copy` with the reader and writer split at arbitrary points, checking the output
+//! against a straightforward byte-by-byte implementation of the same transformation.
+
+#![no_main]
+
+use arbitrary::Arbitrary;
+use libfuzzer_sys::fuzz_target;
+use mail_devil::pop3::copy;
+use mail_devil_fuzz::{block_on, ChunkedReader, ChunkedWriter};
+
+#[derive(Arbitrary, Debug)]
+struct Input<'a> {
+    buffer_size: u8,
+    read_sizes: &'a [u8],
+    write_sizes: &'a [u8],
+    message: &'a [u8],
+}
+
+fuzz_target!(|input: Input| {
+    let expected = reference_copy(input.message);
+
+    let (result, output) = block_on(async {
+        let mut reader = ChunkedReader::new(input.message, input.read_sizes);
+        let mut writer = ChunkedWriter::new(input.write_sizes);
+        let result = copy::copy(input.buffer_size.max(1) as usize, &mut reader, &mut writer).await;
+        (result, writer.output)
+    });
+
+    let written = result.expect("copy failed");
+    assert_eq!(output, expected);
+    assert_eq!(written, output.len() as u64);
+});
+
+/// Converts LFs not preceded by a CR to CRLF, stuffs a period at the start of every line beginning with one, and adds a
+/// CRLF at the end if the message is not empty and doesn't already end with a newline.
+fn reference_copy(message: &[u8]) -> Vec<u8> {
+    let mut output = Vec::with_capacity(message.len() * 2);
+    let mut last = b'\n';
+    for &byte in message {
+        if byte == b'.' && last == b'\n' {
+            output.push(b'.');
+        } else if byte == b'\n' && last != b'\r' {
+            output.push(b'\r');
+        }
+
+        output.push(byte);
+        last = byte;
+    }
+
+    if last != b'\n' {
+        output.extend_from_slice(b"\r\n");
+    }
+
+    output
+}
@@ -0,0 +1,68 @@
+//! Reads lines with `read_line` from a client that sends arbitrary bytes in arbitrarily sized pieces, and parses each
+//! of them with `parse_command`, the way the session loop does.
+
+#![no_main]
+
+use std::io::ErrorKind;
+
+use arbitrary::Arbitrary;
+use inlined::TinyVec;
+use libfuzzer_sys::fuzz_target;
+use mail_devil::pop3::parsers::{self, Pop3Command, MAX_COMMAND_LINE_LENGTH};
+use mail_devil_fuzz::{block_on, ChunkedReader};
+use tokio::io::BufReader;
+
+#[derive(Arbitrary, Debug)]
+struct Input<'a> {
+    /// The capacity of the `BufReader` in front of the client, as with the server's `buffer_size` setting.
+    buffer_size: u8,
+    chunk_sizes: &'a [u8],
+    data: &'a [u8],
+}
+
+fuzz_target!(|input: Input| {
+    block_on(async {
+        let reader = ChunkedReader::new(input.data, input.chunk_sizes);
+        let mut reader = BufReader::with_capacity(input.buffer_size.max(1) as usize, reader);
+        let mut buf: TinyVec<MAX_COMMAND_LINE_LENGTH, u8> = TinyVec::new();
+
+        loop {
+            match parsers::read_line(&mut reader, &mut buf).await {
+                Ok(()) => {}
+                Err(error) if matches!(error.kind(), ErrorKind::UnexpectedEof | ErrorKind::InvalidData) => break,
+                Err(error) => panic!("unexpected error from read_line: {error}"),
+            }
+
+            assert!(!buf.contains(&b'\n'), "line {:?} contains a LF", &buf[..]);
+            assert!(buf.len() <= MAX_COMMAND_LINE_LENGTH as _, "line is {} bytes long", buf.len());
+
+            if let Ok(command) = parsers::parse_command(&mut buf) {
+                check_command(&command);
+            }
+
+            buf.clear();
+        }
+    });
+});
+
+/// Checks that the strings a command was parsed into, which skip UTF-8 validation, are printable ASCII.
+fn check_command(command: &Pop3Command) {
+    let strings: &[&str] = match command {
+        Pop3Command::User(username) => &[username],
+        Pop3Command::Pass(password) => &[password],
+        Pop3Command::Extension(command) => &[&command.keyword, &command.arguments],
+        _ => &[],
+    };
+
+    for string in strings {
+        assert!(
+            string.bytes().all(|b| (b' '..=b'~').contains(&b)),
+            "{command:?} has non-printable characters"
+        );
+    }
+
+    assert!(command
+        .keyword()
+        .bytes()
+        .all(|b| b.is_ascii_alphanumeric() && !b.is_ascii_lowercase()));
+}
@@ -0,0 +1,91 @@
+//! Helpers shared by the fuzz targets, which feed the server's I/O functions with data split at arbitrary points.
+
+use std::{
+    future::Future,
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// Runs a future to completion on a single-threaded runtime.
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+    runtime.block_on(future)
+}
+
+/// Takes the length of the next chunk from `sizes`, cycling through them, never returning zero.
+fn next_chunk_size(sizes: &[u8], index: &mut usize) -> usize {
+    let size = match sizes.is_empty() {
+        true => usize::MAX,
+        false => sizes[*index % sizes.len()].max(1) as usize,
+    };
+
+    *index += 1;
+    size
+}
+
+/// A reader that returns its data in chunks of the given sizes, as if it arrived in separate packets.
+pub struct ChunkedReader<'a> {
+    data: &'a [u8],
+    chunk_sizes: &'a [u8],
+    chunk_index: usize,
+}
+
+impl<'a> ChunkedReader<'a> {
+    pub fn new(data: &'a [u8], chunk_sizes: &'a [u8]) -> Self {
+        Self {
+            data,
+            chunk_sizes,
+            chunk_index: 0,
+        }
+    }
+}
+
+impl AsyncRead for ChunkedReader<'_> {
+    fn poll_read(mut self: Pin<&mut Self>, _cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        let size = next_chunk_size(this.chunk_sizes, &mut this.chunk_index);
+        let count = size.min(buf.remaining()).min(this.data.len());
+        let (chunk, rest) = this.data.split_at(count);
+        buf.put_slice(chunk);
+        this.data = rest;
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// A writer that accepts at most the given amounts of bytes on each write, collecting them into a [`Vec`].
+pub struct ChunkedWriter<'a> {
+    pub output: Vec<u8>,
+    chunk_sizes: &'a [u8],
+    chunk_index: usize,
+}
+
+impl<'a> ChunkedWriter<'a> {
+    pub fn new(chunk_sizes: &'a [u8]) -> Self {
+        Self {
+            output: Vec::new(),
+            chunk_sizes,
+            chunk_index: 0,
+        }
+    }
+}
+
+impl AsyncWrite for ChunkedWriter<'_> {
+    fn poll_write(mut self: Pin<&mut Self>, _cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        let size = next_chunk_size(this.chunk_sizes, &mut this.chunk_index);
+        let count = size.min(buf.len());
+        this.output.extend_from_slice(&buf[..count]);
+        Poll::Ready(Ok(count))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
@@ -0,0 +1,352 @@
+//! An administrative interface for controlling the running server, served on its own listening sockets.
+//!
+//! The protocol is line based, much like POP3 itself: the client sends one command per line and every response starts
+//! with `+OK` or `-ERR`. Responses spanning multiple lines end with a line holding a single `.`. Clients must first
+//! authenticate with `AUTH <password>`, and are disconnected after too many wrong passwords or if they don't
+//! authenticate in time. The commands are:
+//!
+//! - `SESSIONS` lists the open sessions, one per line, as `id peer user state sent received seconds writes`, where
+//!   `user` is `-` for sessions that haven't logged in yet, `sent` and `received` are the bytes exchanged with the
+//!   client, and `writes` is how many writes it took to send them.
+//! - `KICK <id>` closes a session right away, without committing its deletions.
+//! - `ADDUSER <user:password>` creates a user, or changes the password of an existing one.
+//! - `DELUSER <user>` removes a user's password file, so they can no longer log in, and kicks their sessions. Their
+//!   maildir and messages are kept.
+//! - `QUOTA <user>` shows a user's usage as `bytes messages quota percent`, where `quota` is in Maildir++ format and
+//!   `quota` and `percent` are `-` if the user has no quota.
+//! - `VERBOSE [ON|OFF]` turns verbose logging on or off regardless of the configured log level, or shows whether it's
+//!   currently on.
+//! - `EVENTS` streams the server's events (see [`crate::events`]) one per line, until the client sends any line.
+//! - `SHUTDOWN` begins a graceful shutdown, just like SIGTERM.
+//! - `HELP` lists the commands, and `QUIT` closes the connection.
+//!
+//! The administrative interface always works with the server's current state, so after a configuration reload new
+//! users are created in the new maildirs directory.
+
+use std::{collections::HashMap, io, sync::Arc, time::Duration};
+
+use tokio::{
+    io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+    select,
+    sync::{broadcast::error::RecvError, watch},
+    time::Instant,
+};
+use tracing::{debug, error, info, info_span, warn, Instrument};
+
+use crate::{
+    args, logging, quota, server,
+    state::Pop3ServerState,
+    types::{Pop3Username, PASSWORD_FILE_NAME},
+    util::sockets::{AcceptFromAny, PrintSockaddrOrUnknown},
+};
+
+pub const DEFAULT_ADMIN_PORT: u16 = 9111;
+
+/// The maximum length of a command line, including the line ending.
+const MAX_LINE_LENGTH: usize = 512;
+
+/// How long a client has to authenticate after connecting.
+const AUTH_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How many wrong passwords a client may send before being disconnected.
+const MAX_AUTH_ATTEMPTS: u32 = 3;
+
+/// How long to wait before answering a wrong password, to slow down guessing.
+const FAILED_AUTH_DELAY: Duration = Duration::from_secs(1);
+
+const HELP: &str = concat!(
+    "+OK Commands:\r\n",
+    "AUTH <password>\r\n",
+    "SESSIONS\r\n",
+    "KICK <id>\r\n",
+    "ADDUSER <user:password>\r\n",
+    "DELUSER <user>\r\n",
+    "QUOTA <user>\r\n",
+    "VERBOSE [ON|OFF]\r\n",
+    "EVENTS\r\n",
+    "SHUTDOWN\r\n",
+    "HELP\r\n",
+    "QUIT\r\n",
+    ".\r\n",
+);
+
+pub async fn serve_admin(listeners: Vec<TcpListener>, password: String, state: watch::Receiver<Pop3ServerState>) {
+    let password: Arc<str> = password.into();
+    let mut listeners = listeners;
+    while !listeners.is_empty() {
+        match listeners.accept_from_any().await {
+            Ok((socket, address)) => {
+                let password = password.clone();
+                let state = state.clone();
+                let task = async move {
+                    info!("Admin client connected");
+                    match handle_client(socket, &password, &state).await {
+                        Ok(()) => info!("Admin client disconnected"),
+                        Err(error) => warn!("Admin client ended with error: {error}"),
+                    }
+                };
+
+                tokio::spawn(task.instrument(info_span!("admin", peer = %address)));
+            }
+            Err((listener_index, error)) => {
+                let listener = listeners.swap_remove(listener_index);
+                let listener_addr = PrintSockaddrOrUnknown(listener.local_addr().ok());
+                error!("Error while accepting admin connection from listener {listener_addr}: {error}");
+            }
+        }
+    }
+
+    warn!("No longer serving the admin interface, all admin listeners failed");
+}
+
+async fn handle_client(mut socket: TcpStream, password: &str, state: &watch::Receiver<Pop3ServerState>) -> io::Result<()> {
+    let (read_half, mut writer) = socket.split();
+    let mut reader = BufReader::new(read_half);
+    let mut line = Vec::new();
+
+    writer.write_all(b"+OK mail-devil admin interface ready\r\n").await?;
+
+    let auth_deadline = Instant::now() + AUTH_TIMEOUT;
+    let mut authenticated = false;
+    let mut failed_attempts = 0;
+
+    loop {
+        line.clear();
+        let read_result = match authenticated {
+            true => read_line(&mut reader, &mut line).await,
+            false => match tokio::time::timeout_at(auth_deadline, read_line(&mut reader, &mut line)).await {
+                Ok(result) => result,
+                Err(_) => {
+                    writer.write_all(b"-ERR Authentication timed out\r\n").await?;
+                    return Ok(());
+                }
+            },
+        };
+
+        if !read_result? {
+            return Ok(());
+        }
+
+        let line = String::from_utf8_lossy(&line);
+        let line = line.trim_end_matches(['\r', '\n']);
+        let (command, arg) = line.split_once(' ').unwrap_or((line, ""));
+        let command = command.to_ascii_uppercase();
+
+        match (command.as_str(), authenticated) {
+            ("QUIT", _) => {
+                writer.write_all(b"+OK Bye\r\n").await?;
+                return Ok(());
+            }
+            ("HELP", _) => writer.write_all(HELP.as_bytes()).await?,
+            ("AUTH", true) => writer.write_all(b"-ERR Already authenticated\r\n").await?,
+            ("AUTH", false) if constant_time_eq(arg.as_bytes(), password.as_bytes()) => {
+                info!("Admin client authenticated");
+                authenticated = true;
+                writer.write_all(b"+OK Authenticated\r\n").await?;
+            }
+            ("AUTH", false) => {
+                failed_attempts += 1;
+                warn!("Admin client sent a wrong password ({failed_attempts} of {MAX_AUTH_ATTEMPTS} attempts)");
+                tokio::time::sleep(FAILED_AUTH_DELAY).await;
+                if failed_attempts >= MAX_AUTH_ATTEMPTS {
+                    writer.write_all(b"-ERR Too many failed attempts\r\n").await?;
+                    return Ok(());
+                }
+
+                writer.write_all(b"-ERR Wrong password\r\n").await?;
+            }
+            (_, false) => writer.write_all(b"-ERR Authentication required\r\n").await?,
+            ("EVENTS", true) => {
+                let state = state.borrow().clone();
+                stream_events(&mut reader, &mut writer, &state).await?;
+            }
+            ("SHUTDOWN", true) => {
+                info!("Shutdown requested through the admin interface");
+                writer.write_all(b"+OK Shutting down\r\n").await?;
+                state.borrow().begin_shutdown();
+                return Ok(());
+            }
+            (command, true) => {
+                let state = state.borrow().clone();
+                let response = handle_command(&state, command, arg).await;
+                writer.write_all(response.as_bytes()).await?;
+            }
+        }
+    }
+}
+
+/// Handles one of the commands that only produce a response, returning that response.
+async fn handle_command(state: &Pop3ServerState, command: &str, arg: &str) -> String {
+    match command {
+        "SESSIONS" => {
+            let sessions = state.sessions().list();
+            let mut response = format!("+OK {} sessions\r\n", sessions.len());
+            for session in sessions {
+                let user = session.user.as_ref().map_or("-", |user| user.as_str());
+                response.push_str(&format!(
+                    "{} {} {user} {} {} {} {} {}\r\n",
+                    session.id,
+                    session.peer,
+                    session.state,
+                    session.bytes_sent,
+                    session.bytes_received,
+                    session.duration.as_secs(),
+                    session.writes,
+                ));
+            }
+
+            response.push_str(".\r\n");
+            response
+        }
+        "KICK" => match arg.trim().parse::<u64>() {
+            Ok(id) if state.sessions().kick(id) => {
+                info!("Kicked session {id} through the admin interface");
+                format!("+OK Session {id} kicked\r\n")
+            }
+            Ok(_) => "-ERR No such session\r\n".to_string(),
+            Err(_) => "-ERR Invalid session id\r\n".to_string(),
+        },
+        "ADDUSER" => add_user(state, arg).await,
+        "DELUSER" => delete_user(state, arg).await,
+        "QUOTA" => user_quota(state, arg).await,
+        "VERBOSE" => {
+            let verbose = match arg.trim() {
+                "" => logging::is_verbose(),
+                arg if arg.eq_ignore_ascii_case("on") => true,
+                arg if arg.eq_ignore_ascii_case("off") => false,
+                _ => return "-ERR Expected ON or OFF\r\n".to_string(),
+            };
+
+            if verbose != logging::is_verbose() {
+                logging::set_verbose(verbose);
+                info!(
+                    "Verbose logging turned {} through the admin interface",
+                    if verbose { "on" } else { "off" }
+                );
+            }
+
+            format!("+OK Verbose logging is {}\r\n", if verbose { "on" } else { "off" })
+        }
+        _ => "-ERR Unknown command\r\n".to_string(),
+    }
+}
+
+async fn add_user(state: &Pop3ServerState, arg: &str) -> String {
+    let mut users = HashMap::new();
+    if let Err(error) = args::parse_new_user_arg(&mut users, "ADDUSER".to_string(), Some(arg.to_string())) {
+        return format!("-ERR {error}\r\n");
+    }
+
+    let Some((username, password)) = users.into_iter().next() else {
+        return "-ERR Invalid user specification\r\n".to_string();
+    };
+
+    match server::create_user_maildir(state.maildirs_dir(), &username, &password).await {
+        Ok(()) => format!("+OK User {username} created or updated\r\n"),
+        Err(error) => {
+            error!("Could not create or update user {username} through the admin interface: {error}");
+            format!("-ERR Could not create or update user {username}\r\n")
+        }
+    }
+}
+
+async fn delete_user(state: &Pop3ServerState, arg: &str) -> String {
+    let username = match Pop3Username::try_from(arg.trim()) {
+        Ok(username) => username,
+        Err(_) => return "-ERR Invalid username\r\n".to_string(),
+    };
+
+    let path = state.maildirs_dir().join(username.as_str()).join(PASSWORD_FILE_NAME);
+    match tokio::fs::remove_file(&path).await {
+        Ok(()) => {}
+        Err(error) if error.kind() == io::ErrorKind::NotFound => return "-ERR No such user\r\n".to_string(),
+        Err(error) => {
+            error!("Could not remove user {username}'s password file at {}: {error}", path.display());
+            return format!("-ERR Could not remove user {username}\r\n");
+        }
+    }
+
+    let kicked = state.sessions().kick_user(&username);
+    info!("Removed user {username} through the admin interface, kicking {kicked} sessions");
+    format!("+OK User {username} removed, {kicked} sessions kicked\r\n")
+}
+
+async fn user_quota(state: &Pop3ServerState, arg: &str) -> String {
+    let username = match Pop3Username::try_from(arg.trim()) {
+        Ok(username) => username,
+        Err(_) => return "-ERR Invalid username\r\n".to_string(),
+    };
+
+    let maildir = state.maildirs_dir().join(username.as_str());
+    match tokio::fs::metadata(&maildir).await {
+        Ok(metadata) if metadata.is_dir() => {}
+        _ => return "-ERR No such user\r\n".to_string(),
+    }
+
+    match quota::read_usage(&maildir).await {
+        Ok(usage) => {
+            let quota = usage.quota.map_or("-".to_string(), |quota| quota.to_string());
+            let percent = usage.percent().map_or("-".to_string(), |percent| percent.to_string());
+            format!("+OK {} {} {quota} {percent}\r\n", usage.bytes, usage.messages)
+        }
+        Err(error) => {
+            error!("Could not read user {username}'s quota usage: {error}");
+            format!("-ERR Could not read user {username}'s quota usage\r\n")
+        }
+    }
+}
+
+/// Writes the server's events to the client as they happen, until the client sends a line.
+async fn stream_events<R, W>(reader: &mut R, writer: &mut W, state: &Pop3ServerState) -> io::Result<()>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut events = state.subscribe_events();
+    let mut line = Vec::new();
+    writer.write_all(b"+OK Streaming events, send any line to stop\r\n").await?;
+
+    loop {
+        select! {
+            result = events.recv() => match result {
+                Ok(event) => writer.write_all(format!("{event}\r\n").as_bytes()).await?,
+                Err(RecvError::Lagged(missed)) => {
+                    debug!("Admin client fell behind on events, missed {missed}");
+                    writer.write_all(format!("events_missed count={missed}\r\n").as_bytes()).await?;
+                }
+                Err(RecvError::Closed) => break,
+            },
+            // Reading a line is resumed where it left off if an event arrives first, since the bytes stay in `line`.
+            result = read_line(reader, &mut line) => {
+                if !result? {
+                    return Ok(());
+                }
+
+                break;
+            }
+        }
+    }
+
+    writer.write_all(b".\r\n").await
+}
+
+/// Reads a line into `buf`, appending to whatever it already holds. Returns `false` if the client closed the connection
+/// before sending a full line, or an error if the line is too long.
+async fn read_line<R: AsyncBufRead + Unpin>(reader: &mut R, buf: &mut Vec<u8>) -> io::Result<bool> {
+    let limit = MAX_LINE_LENGTH.saturating_sub(buf.len()) as u64;
+    (&mut *reader).take(limit).read_until(b'\n', buf).await?;
+
+    if buf.ends_with(b"\n") {
+        Ok(true)
+    } else if buf.len() >= MAX_LINE_LENGTH {
+        Err(io::Error::new(io::ErrorKind::InvalidData, "Line too long"))
+    } else {
+        Ok(false)
+    }
+}
+
+/// Compares two byte strings in an amount of time that only depends on their lengths, not on where they differ.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
@@ -24,10 +24,23 @@ use std::{
     collections::HashMap,
     fmt,
     io::ErrorKind,
-    net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6, ToSocketAddrs},
-    path::PathBuf,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6, ToSocketAddrs},
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
 };
 
+use tracing::level_filters::LevelFilter;
+
+use crate::admin::DEFAULT_ADMIN_PORT;
+use crate::audit::{AuditDestination, AuditMaxSize, DEFAULT_AUDIT_KEEP};
+use crate::config::{self, ConfigErrorType};
+use crate::connection_tracker::ConnectionLimits;
+use crate::deletion::DeletionPolicy;
+use crate::logging::LogFormat;
+use crate::metrics::DEFAULT_METRICS_PORT;
+use crate::quota::Quota;
+use crate::syslog::{SyslogFacility, SyslogTarget};
 use crate::types::{Pop3ArgString, MAX_COMMAND_ARG_LENGTH};
 use crate::{
     types::Pop3Username,
@@ -35,9 +48,16 @@ use crate::{
 };
 
 pub const DEFAULT_MAILDIRS_FILE: &str = "./maildirs";
+pub const DEFAULT_TRANSCRIPTS_DIR: &str = "./transcripts";
 pub const DEFAULT_POP3_PORT: u16 = 110;
 pub const DEFAULT_BUFFER_SIZE: u32 = 0x2000;
 
+/// The default and the minimum timeouts for idle clients, as required by RFC #1939.
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+pub const MIN_TRANSACTION_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+pub const DEFAULT_GRACE_PERIOD: Duration = Duration::from_secs(30);
+
 pub fn get_version_string() -> String {
     format!(
         concat!(env!("CARGO_PKG_NAME"), " ", env!("CARGO_PKG_VERSION"), " ({} {})"),
@@ -52,18 +72,76 @@ pub fn get_help_string() -> &'static str {
         "Options:\n",
         "  -h, --help                      Display this help menu and exit\n",
         "  -V, --version                   Display the version number and exit\n",
-        "  -v, --verbose                   Display additional information while running\n",
-        "  -s, --silent                    Do not print logs to stdout\n",
+        "  -f, --config <path>             Load settings from a TOML configuration file\n",
+        "  -v, --verbose                   Log additional information while running\n",
+        "  -s, --silent                    Only log warnings and errors\n",
+        "  -L, --log-level <level>         Sets the minimum level of log messages to output\n",
+        "  -F, --log-format <format>       Sets the format of log messages, either text or json\n",
+        "  -o, --log-file <path>           Appends log messages to a file instead of stdout and stderr\n",
+        "  -S, --syslog <socket>           Sends log messages to syslog or journald instead of stdout and stderr\n",
+        "  -Y, --syslog-facility <name>    Sets the facility log messages are sent to syslog with\n",
         "  -l, --listen <address>          Specify a socket address to listen for incoming POP3 clients\n",
+        "  -m, --metrics <address>         Specify a socket address to serve Prometheus metrics over HTTP on\n",
+        "  -A, --admin <address>           Specify a socket address to serve the administrative interface on\n",
+        "  -P, --admin-password <password> Sets the password clients of the administrative interface must send\n",
+        "  -W, --admin-password-file <path> Reads the administrative interface's password from a file\n",
         "  -d, --maildirs <path>           Specify the folder where to find the user's maildirs\n",
         "  -u, --user <user>               Adds a new user, or updates it if already present\n",
         "  -b, --buffer-size <size>        Sets the size of the buffer for client connections\n",
         "  -t, --transformer               Specifies a program to run for applying message transformations\n",
+        "  -n, --newest-first              Number messages from newest to oldest instead of oldest to newest\n",
+        "  -q, --quota <user:quota>        Sets a user's Maildir++ quota\n",
+        "  -r, --quota-reject <percent>    Rejects logins from users whose usage is over this percentage of their quota\n",
+        "  -p, --deletion-policy <policy>  Specifies what to do with messages once their deletion is committed\n",
+        "  -e, --encrypt <user>            Enables encryption for a user and encrypts their existing messages\n",
+        "  -I, --key-file <path>           Specify the file holding the server key that protects users' encryption keys\n",
+        "  -a, --auth-timeout <time>       Sets the inactivity timeout for clients in the AUTHORIZATION state\n",
+        "  -T, --transaction-timeout <time> Sets the inactivity timeout for clients in the TRANSACTION state\n",
+        "  -c, --max-connections <n>       Sets the maximum amount of concurrent client connections\n",
+        "  -C, --max-ip-connections <n>    Sets the maximum amount of concurrent client connections per remote address\n",
+        "  -U, --max-unauth-connections <n> Sets the maximum amount of concurrent unauthenticated client connections\n",
+        "  -g, --grace-period <time>       Sets how long to wait for sessions to finish when shutting down\n",
+        "  -w, --threads <n>               Sets the amount of worker threads to run the server on\n",
+        "  -R, --run-as-user <user>        Switches to this user after binding the listening sockets\n",
+        "  -G, --run-as-group <group>      Switches to this group after binding the listening sockets\n",
+        "  -j, --chroot <path>             Changes the root directory after binding the listening sockets\n",
+        "  -x, --trace-user <user>         Records protocol transcripts of sessions logging in as this user\n",
+        "  -X, --trace-address <address>   Records protocol transcripts of sessions from this IP address\n",
+        "  -D, --transcripts-dir <path>    Specify the folder where to write protocol transcripts\n",
+        "  -E, --event-command <program>   Runs a program for every event, passing the event in environment variables\n",
+        "  -J, --event-log <path>          Appends every event to a file as a line of JSON\n",
+        "  -k, --audit-log <path>          Records retrieved and deleted messages to a file, or to each user's maildir\n",
+        "  -K, --audit-max-size <size>     Rotates the audit log once it grows past this size\n",
+        "  -N, --audit-keep <n>            Sets how many rotated audit log files to keep\n",
+        "\n",
+        "The log level may be one of error, warn, info, debug or trace, or off to disable logging. It defaults to info, ",
+        "or debug with -v/--verbose or warn with -s/--silent. Messages logged while handling a client include the ",
+        "session's id, the client's address and, once logged in, the username. The log level is updated on SIGHUP, but ",
+        "the format, file and syslog socket are not.\n",
+        "\n",
+        "The syslog socket may be \"syslog\" to send RFC #5424 messages to /dev/log, \"journald\" to send messages to ",
+        "journald's native socket, or the path of another syslog socket. Log levels are mapped to the matching syslog ",
+        "severities. The facility may be any syslog facility name, such as \"daemon\" or \"local0\", and defaults to ",
+        "\"mail\". The syslog socket is connected to at startup, so it doesn't need to be reachable after -j/--chroot.\n",
         "\n",
         "Socket addresses may be specified as an IPv4 or IPv6 address, or a domainname, and may include a port number. ",
         "The -l/--listen argument may be specified multiple times to listen on many addresses. If no port is specified, ",
         "then the default port of 110 will be used. If no -l/--listen argument is specified, then [::]:110 and ",
-        "0.0.0.0:110 will be used.\n",
+        "0.0.0.0:110 will be used, unless listening sockets are passed in through systemd socket activation.\n",
+        "\n",
+        "Metrics are served at /metrics on the addresses specified with -m/--metrics, which may also be specified ",
+        "multiple times and default to port 9110. No metrics are served unless an address is specified. The metrics ",
+        "listeners are not changed on SIGHUP, and the metrics are kept across reloads.\n",
+        "\n",
+        "The administrative interface is a line-based protocol served on the addresses specified with -A/--admin, which ",
+        "may also be specified multiple times and default to port 9111. It can list and kick open sessions, add and ",
+        "remove users, turn verbose logging on or off, stream the server's events and shut the server down. Send HELP ",
+        "after connecting for a list of commands. Clients must first send AUTH with the password set by ",
+        "-W/--admin-password-file or -P/--admin-password, one of which is required when serving the interface. Prefer ",
+        "-W/--admin-password-file, since any local user may read a password given on the command line from the process ",
+        "list. Its first line is the password, and it's read at startup before dropping privileges, so it may be kept ",
+        "readable only by root. The admin listeners and password are not changed on SIGHUP. Since the interface is ",
+        "unencrypted, it should only be served on trusted addresses.\n",
         "\n",
         "The maildirs directory, specified with -d/--maildirs, is where the user's maildirs are located. If, for ",
         "example, maildirs is \"./maildirs\" and there's a user named \"pablo\", then their emails will be stored in the ",
@@ -81,50 +159,300 @@ pub fn get_help_string() -> &'static str {
         "Programs for message transformation simply receive the Internet Message (RFC #822) on standard input and print ",
         "the processed message on standard output. If no transformer is specified, no transformation is applied. Only one ",
         "transformer may be specified.\n",
+        "\n",
+        "Messages are numbered by delivery time, taken from the timestamp at the start of the maildir file name or, if ",
+        "there is none, from the file's modification time. Messages delivered at the same time are ordered by file name.\n",
+        "\n",
+        "Quotas are stored in each user's \"maildirsize\" file and are specified in Maildir++ format, for example ",
+        "\"-q pablo:10000000S,1000C\" limits pablo to 10000000 bytes and 1000 messages. Either limit may be omitted. A ",
+        "warning is logged whenever a user over their quota logs in, and if -r/--quota-reject is specified then users ",
+        "over that percentage of their quota may not log in at all, for example '-r 200' for twice their quota or '-r 90' ",
+        "to refuse maildrops that are nearly full. Messages moved to the \".Trash\" or \".Retained\" folder still count ",
+        "towards the quota until they're removed.\n",
+        "\n",
+        "The deletion policy may be \"unlink\" to remove deleted messages immediately, \"trash\" to move them to the ",
+        "maildir's \".Trash\" folder, or \"retain\" to move them to the maildir's \".Retained\" folder, hidden from ",
+        "clients. A number of days may be specified for retained messages to be purged after their deletion, such as ",
+        "\"retain:30\". Only messages in \".Retained\" are purged. The default policy is \"retain\", which never purges ",
+        "them.\n",
+        "\n",
+        "Encryption is enabled per user with -e/--encrypt, which creates an encryption key for the user if they don't ",
+        "have one yet, and then encrypts in place any plaintext messages in their maildir before the server starts. ",
+        "Messages delivered afterwards remain in plaintext until the server is restarted with this again. Users' keys ",
+        "are protected by the server key in the -I/--key-file file, which is created if it doesn't exist and is read ",
+        "before dropping privileges. It must be kept outside the maildirs folder, and is required for -e/--encrypt and ",
+        "for users with encryption enabled to log in. Encrypted messages are only decrypted for a client after it logs ",
+        "in as their owner.\n",
+        "\n",
+        "Clients that don't send any command for longer than the inactivity timeout of their session's state are ",
+        "disconnected without committing any deletions. Timeouts are specified in seconds, or with an 's', 'm' or 'h' ",
+        "suffix (e.g. '-a 30s' or '-T 15m'). Both default to 10 minutes, and as required by RFC #1939 the TRANSACTION ",
+        "timeout may not be shorter than that. The AUTHORIZATION timeout deliberately departs from the RFC and may be ",
+        "shorter, so clients that never log in don't hold on to connections for as long.\n",
+        "\n",
+        "By default there is no limit on the amount of connections. Connections over any of the limits are sent an error ",
+        "and closed. For the per-address limit, IPv4 addresses are counted individually and IPv6 addresses are grouped by ",
+        "their /64 prefix. Each user may only be logged in from one connection at a time regardless of these limits.\n",
+        "\n",
+        "On SIGINT or SIGTERM the server stops accepting connections, idle sessions are closed with an error without ",
+        "committing deletions, and sessions in the middle of a command are given until the grace period (30 seconds by ",
+        "default) to finish it. If any session is still open after that it is aborted and the server exits with an error.\n",
+        "\n",
+        "By default the server runs on a single thread. With -w/--threads, sessions are spread across that many worker ",
+        "threads, so a slow session can't hold up other clients. The amount of threads is not changed on SIGHUP.\n",
+        "\n",
+        "When started as root, -R/--run-as-user and -G/--run-as-group drop root privileges once the listening sockets are ",
+        "bound, and -j/--chroot confines the server to a directory. If only a user is specified, their primary and ",
+        "supplementary groups are used. Users, quotas and encryption are set up after this, and paths such as the ",
+        "maildirs directory are resolved inside the chroot. These settings are not changed on SIGHUP, so a reload can't ",
+        "open new listening sockets on privileged ports. On SIGHUP the configuration file is read again from the same ",
+        "place inside the chroot, so if it's outside the chroot reloading is refused.\n",
+        "\n",
+        "Every session runs as the same user. When the server keeps running as root, the files and folders it creates ",
+        "in a user's maildir, such as their \".Retained\" folder or \"maildirsize\" file, are given to the owner of ",
+        "the user's maildir folder, so they remain manageable by that user.\n",
+        "\n",
+        "Protocol transcripts record every line sent and received in a session, with timestamps, to a file per session ",
+        "in the transcripts directory (\"./transcripts\" by default). With -x/--trace-user, sessions in which the client ",
+        "sends a USER command for that user are recorded, including the last 32 lines sent before it. Passwords are ",
+        "redacted, and the contents of retrieved messages are summarized as a count of lines and bytes. Both options may ",
+        "be specified multiple times, and are updated on SIGHUP.\n",
+        "\n",
+        "Events are emitted when a session is opened, kicked or closed, when a login succeeds or fails, when a message ",
+        "is retrieved and when deletions are committed. An event command is run once per event, one event at a time, ",
+        "with the event's name in the MAIL_DEVIL_EVENT environment variable and each of its fields in a variable such as ",
+        "MAIL_DEVIL_SESSION_ID or MAIL_DEVIL_USER, and is killed if it runs for longer than 30 seconds. An event log ",
+        "holds a JSON object per line with a timestamp, the event's name and its fields. Both options may be specified ",
+        "multiple times, and are updated on SIGHUP.\n",
+        "\n",
+        "The audit log records every message retrieved with RETR and every message whose deletion is committed, as a ",
+        "JSON object per line with the time, session id, client address, username, action, the message's maildir unique ",
+        "name and its size as listed to clients, plus the bytes sent for retrievals. With '-k maildir' each user's ",
+        "records are written to an \"audit.log\" file in their maildir, and otherwise all records are written to the ",
+        "specified file. Every record is synced to disk. With -K/--audit-max-size, sizes being specified like buffer ",
+        "sizes, a file that grew past the size is renamed with a \".1\" suffix before writing to it, shifting older files ",
+        "up to the amount to keep (5 by default). The audit log may also be rotated externally, since it's opened again ",
+        "for every record.\n",
+        "\n",
+        "A configuration file may specify any of the options above that don't exit immediately, as a key with the ",
+        "option's long name, such as 'maildirs = \"./maildirs\"' or 'auth-timeout = \"30s\"'. Options without a value, ",
+        "like verbose, take a boolean, and options that may be specified multiple times, like listen, also take an ",
+        "array. Users may instead be specified with a table per user, such as:\n",
+        "\n",
+        "  [users.pablo]\n",
+        "  password = \"hunter2\"\n",
+        "  quota = \"10000000S,1000C\"\n",
+        "  encrypt = true\n",
+        "\n",
+        "Options specified on the command line take precedence over the configuration file, so for example -s/--silent ",
+        "overrides 'verbose = true', as the two turn each other off. On SIGHUP the configuration file is read again and ",
+        "its settings are applied to new connections, while open sessions keep their settings. Users and quotas that ",
+        "changed are written in the background, and -e/--encrypt is only applied at startup. Message transformations ",
+        "and TLS aren't implemented yet, so there is no transformer or certificate to reload.\n",
     )
 }
 
+/// Gets the addresses to listen on when none are specified.
+pub fn get_default_pop3_bind_sockets() -> Vec<SocketAddr> {
+    vec![
+        SocketAddr::V6(SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, DEFAULT_POP3_PORT, 0, 0)),
+        SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, DEFAULT_POP3_PORT)),
+    ]
+}
+
 #[derive(Debug, PartialEq)]
 pub enum ArgumentsRequest {
     Help,
     Version,
-    Run(StartupArguments),
+    Run(Box<StartupArguments>),
 }
 
 #[derive(Debug, PartialEq)]
 pub struct StartupArguments {
     pub pop3_bind_sockets: Vec<SocketAddr>,
+    pub metrics_bind_sockets: Vec<SocketAddr>,
+    pub admin_bind_sockets: Vec<SocketAddr>,
+    pub admin_password: Option<String>,
+    pub admin_password_file: Option<PathBuf>,
     pub verbose: bool,
     pub silent: bool,
+    pub log_level: Option<LevelFilter>,
+    pub log_format: LogFormat,
+    pub log_file: Option<PathBuf>,
+    pub syslog: Option<SyslogTarget>,
+    pub syslog_facility: SyslogFacility,
     pub maildirs_file: PathBuf,
     pub users: HashMap<Pop3Username, Pop3ArgString>,
     pub buffer_size: u32,
     pub transformer_file: Option<PathBuf>,
+    pub newest_first: bool,
+    pub quotas: HashMap<Pop3Username, Quota>,
+    pub quota_reject_percent: Option<u64>,
+    pub deletion_policy: DeletionPolicy,
+    pub encrypt_users: Vec<Pop3Username>,
+    pub key_file: Option<PathBuf>,
+    pub auth_timeout: Duration,
+    pub transaction_timeout: Duration,
+    pub connection_limits: ConnectionLimits,
+    pub grace_period: Duration,
+    pub threads: usize,
+    pub run_as_user: Option<String>,
+    pub run_as_group: Option<String>,
+    pub chroot_dir: Option<PathBuf>,
+    pub trace_users: Vec<Pop3Username>,
+    pub trace_addresses: Vec<IpAddr>,
+    pub transcripts_dir: PathBuf,
+    pub event_commands: Vec<PathBuf>,
+    pub event_logs: Vec<PathBuf>,
+    pub audit_log: Option<AuditDestination>,
+    pub audit_max_size: Option<AuditMaxSize>,
+    pub audit_keep: usize,
+    pub config_file: Option<PathBuf>,
 }
 
 #[derive(Debug, PartialEq, Eq)]
 pub enum ArgumentsError {
     UnknownArgument(String),
+    LogLevelError(ChoiceErrorType),
+    LogFormatError(ChoiceErrorType),
+    LogFileError(FileErrorType),
+    SyslogError(ChoiceErrorType),
+    SyslogFacilityError(ChoiceErrorType),
     Pop3ListenError(SocketErrorType),
+    MetricsListenError(SocketErrorType),
+    AdminListenError(SocketErrorType),
+    AdminPasswordError(ChoiceErrorType),
+    AdminPasswordFileError(FileErrorType),
+    AdminPasswordConflict,
+    AdminPasswordRequired,
     MaildirsFileError(FileErrorType),
     NewUserError(NewUserErrorType),
     BufferSizeError(BufferSizeErrorType),
     TransformerFileError(FileErrorType),
+    QuotaError(QuotaErrorType),
+    QuotaRejectError(QuotaRejectErrorType),
+    DeletionPolicyError(DeletionPolicyErrorType),
+    EncryptUserError(UsernameListErrorType),
+    KeyFileError(FileErrorType),
+    KeyFileRequired,
+    AuthTimeoutError(TimeoutErrorType),
+    TransactionTimeoutError(TimeoutErrorType),
+    MaxConnectionsError(CountErrorType),
+    MaxIpConnectionsError(CountErrorType),
+    MaxUnauthConnectionsError(CountErrorType),
+    GracePeriodError(TimeoutErrorType),
+    ThreadsError(CountErrorType),
+    RunAsUserError(NameErrorType),
+    RunAsGroupError(NameErrorType),
+    ChrootError(FileErrorType),
+    TraceUserError(UsernameListErrorType),
+    TraceAddressError(AddressErrorType),
+    TranscriptsDirError(FileErrorType),
+    EventCommandError(FileErrorType),
+    EventLogError(FileErrorType),
+    AuditLogError(ChoiceErrorType),
+    AuditMaxSizeError(ChoiceErrorType),
+    AuditKeepError(CountErrorType),
+    ConfigFileError(FileErrorType),
+    ConfigError(ConfigErrorType),
 }
 
 impl fmt::Display for ArgumentsError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Self::UnknownArgument(arg) => write!(f, "Unknown argument: {arg}"),
+            Self::LogLevelError(choice_error) => fmt_choice_error_type(choice_error, "log level", f),
+            Self::LogFormatError(choice_error) => fmt_choice_error_type(choice_error, "log format", f),
+            Self::LogFileError(log_file_error) => fmt_file_error_type(log_file_error, "log", f),
+            Self::SyslogError(choice_error) => fmt_choice_error_type(choice_error, "syslog socket", f),
+            Self::SyslogFacilityError(choice_error) => fmt_choice_error_type(choice_error, "syslog facility", f),
             Self::Pop3ListenError(listen_error) => listen_error.fmt(f),
+            Self::MetricsListenError(listen_error) => listen_error.fmt(f),
+            Self::AdminListenError(listen_error) => listen_error.fmt(f),
+            Self::AdminPasswordError(choice_error) => fmt_choice_error_type(choice_error, "admin password", f),
+            Self::AdminPasswordFileError(file_error) => fmt_file_error_type(file_error, "admin password", f),
+            Self::AdminPasswordConflict => write!(f, "Only one of an admin password and an admin password file may be specified"),
+            Self::AdminPasswordRequired => write!(f, "An admin password must be specified to serve the admin interface"),
             Self::MaildirsFileError(users_file_error) => fmt_file_error_type(users_file_error, "users", f),
             Self::NewUserError(new_user_error) => new_user_error.fmt(f),
             Self::BufferSizeError(buffer_size_error) => buffer_size_error.fmt(f),
             Self::TransformerFileError(users_file_error) => fmt_file_error_type(users_file_error, "transformer", f),
+            Self::QuotaError(quota_error) => quota_error.fmt(f),
+            Self::QuotaRejectError(quota_reject_error) => quota_reject_error.fmt(f),
+            Self::DeletionPolicyError(deletion_policy_error) => deletion_policy_error.fmt(f),
+            Self::EncryptUserError(encrypt_user_error) => encrypt_user_error.fmt(f),
+            Self::KeyFileError(key_file_error) => fmt_file_error_type(key_file_error, "key", f),
+            Self::KeyFileRequired => write!(f, "A key file must be specified to enable encryption"),
+            Self::AuthTimeoutError(timeout_error) => fmt_timeout_error_type(timeout_error, "AUTHORIZATION timeout", f),
+            Self::TransactionTimeoutError(timeout_error) => fmt_timeout_error_type(timeout_error, "TRANSACTION timeout", f),
+            Self::MaxConnectionsError(count_error) => fmt_count_error_type(count_error, "connection limit", f),
+            Self::MaxIpConnectionsError(count_error) => fmt_count_error_type(count_error, "per-address connection limit", f),
+            Self::MaxUnauthConnectionsError(count_error) => fmt_count_error_type(count_error, "unauthenticated connection limit", f),
+            Self::GracePeriodError(timeout_error) => fmt_timeout_error_type(timeout_error, "grace period", f),
+            Self::ThreadsError(count_error) => fmt_count_error_type(count_error, "thread count", f),
+            Self::RunAsUserError(name_error) => fmt_name_error_type(name_error, "user", f),
+            Self::RunAsGroupError(name_error) => fmt_name_error_type(name_error, "group", f),
+            Self::ChrootError(chroot_error) => fmt_chroot_error_type(chroot_error, f),
+            Self::TraceUserError(trace_user_error) => trace_user_error.fmt(f),
+            Self::TraceAddressError(trace_address_error) => trace_address_error.fmt(f),
+            Self::TranscriptsDirError(transcripts_dir_error) => fmt_file_error_type(transcripts_dir_error, "transcripts", f),
+            Self::EventCommandError(event_command_error) => fmt_file_error_type(event_command_error, "event command", f),
+            Self::EventLogError(event_log_error) => fmt_file_error_type(event_log_error, "event log", f),
+            Self::AuditLogError(choice_error) => fmt_choice_error_type(choice_error, "audit log", f),
+            Self::AuditMaxSizeError(choice_error) => fmt_choice_error_type(choice_error, "audit log size", f),
+            Self::AuditKeepError(count_error) => fmt_count_error_type(count_error, "amount of audit logs to keep", f),
+            Self::ConfigFileError(config_file_error) => fmt_file_error_type(config_file_error, "config", f),
+            Self::ConfigError(config_error) => config_error.fmt(f),
+        }
+    }
+}
+
+impl StartupArguments {
+    /// The minimum level of log messages to output, either as specified or according to -v/--verbose or -s/--silent.
+    pub fn get_log_level(&self) -> LevelFilter {
+        match self.log_level {
+            Some(level) => level,
+            None if self.verbose => LevelFilter::DEBUG,
+            None if self.silent => LevelFilter::WARN,
+            None => LevelFilter::INFO,
         }
     }
 }
 
+#[derive(Debug, PartialEq, Eq)]
+pub enum ChoiceErrorType {
+    UnexpectedEnd(String),
+    AlreadySpecified(String),
+    InvalidValue(String, String),
+}
+
+fn fmt_choice_error_type(this: &ChoiceErrorType, s: &str, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match this {
+        ChoiceErrorType::UnexpectedEnd(arg) => write!(f, "Expected {s} after {arg}"),
+        ChoiceErrorType::AlreadySpecified(arg) => write!(f, "Only one {s} may be specified at {arg}"),
+        ChoiceErrorType::InvalidValue(arg, arg2) => write!(f, "Invalid {s} at {arg} {arg2}"),
+    }
+}
+
+/// Parses an argument whose value is one of a fixed set of choices, such as an enum implementing [`FromStr`].
+fn parse_choice_arg<T: FromStr>(choice: &mut Option<T>, arg: String, maybe_arg2: Option<String>) -> Result<(), ChoiceErrorType> {
+    let arg2 = match maybe_arg2 {
+        Some(arg2) => arg2,
+        None => return Err(ChoiceErrorType::UnexpectedEnd(arg)),
+    };
+
+    if choice.is_some() {
+        return Err(ChoiceErrorType::AlreadySpecified(arg));
+    }
+
+    match arg2.trim().parse::<T>() {
+        Ok(value) => *choice = Some(value),
+        Err(_) => return Err(ChoiceErrorType::InvalidValue(arg, arg2)),
+    }
+
+    Ok(())
+}
+
 #[derive(Debug, PartialEq, Eq)]
 pub enum FileErrorType {
     UnexpectedEnd(String),
@@ -156,6 +484,65 @@ fn parse_file_arg(file: &mut Option<PathBuf>, arg: String, maybe_arg2: Option<St
     Ok(())
 }
 
+/// Parses an argument naming a file that may be specified multiple times, adding it to `files`.
+fn parse_file_list_arg(files: &mut Vec<PathBuf>, arg: String, maybe_arg2: Option<String>) -> Result<(), FileErrorType> {
+    let arg2 = match maybe_arg2 {
+        Some(arg2) => arg2,
+        None => return Err(FileErrorType::UnexpectedEnd(arg)),
+    };
+
+    if arg2.is_empty() {
+        return Err(FileErrorType::EmptyPath(arg));
+    }
+
+    let path = PathBuf::from(arg2);
+    if !files.contains(&path) {
+        files.push(path);
+    }
+
+    Ok(())
+}
+
+fn fmt_chroot_error_type(this: &FileErrorType, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match this {
+        FileErrorType::UnexpectedEnd(arg) => write!(f, "Expected path to chroot directory after {arg}"),
+        FileErrorType::AlreadySpecified(_) => write!(f, "Only one chroot directory may be specified"),
+        FileErrorType::EmptyPath(arg) => write!(f, "Empty directory name after {arg}"),
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum NameErrorType {
+    UnexpectedEnd(String),
+    AlreadySpecified(String),
+    Empty(String),
+}
+
+fn fmt_name_error_type(this: &NameErrorType, s: &str, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match this {
+        NameErrorType::UnexpectedEnd(arg) => write!(f, "Expected {s} name or id after {arg}"),
+        NameErrorType::AlreadySpecified(arg) => write!(f, "Only one {s} to run as may be specified at {arg}"),
+        NameErrorType::Empty(arg) => write!(f, "Empty {s} name after {arg}"),
+    }
+}
+
+fn parse_name_arg(name: &mut Option<String>, arg: String, maybe_arg2: Option<String>) -> Result<(), NameErrorType> {
+    let arg2 = match maybe_arg2 {
+        Some(arg2) => arg2,
+        None => return Err(NameErrorType::UnexpectedEnd(arg)),
+    };
+
+    let trimmed = arg2.trim();
+    if trimmed.is_empty() {
+        return Err(NameErrorType::Empty(arg));
+    } else if name.is_some() {
+        return Err(NameErrorType::AlreadySpecified(arg));
+    }
+
+    *name = Some(trimmed.to_string());
+    Ok(())
+}
+
 #[derive(Debug, PartialEq, Eq)]
 pub enum SocketErrorType {
     UnexpectedEnd(String),
@@ -200,6 +587,40 @@ fn parse_socket_arg(
     Ok(())
 }
 
+#[derive(Debug, PartialEq, Eq)]
+pub enum AddressErrorType {
+    UnexpectedEnd(String),
+    InvalidAddress(String, String),
+}
+
+impl fmt::Display for AddressErrorType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnexpectedEnd(arg) => write!(f, "Expected IP address after {arg}"),
+            Self::InvalidAddress(arg, addr) => write!(f, "Invalid IP address after {arg}: {addr}"),
+        }
+    }
+}
+
+fn parse_address_arg(addresses: &mut Vec<IpAddr>, arg: String, maybe_arg2: Option<String>) -> Result<(), AddressErrorType> {
+    let arg2 = match maybe_arg2 {
+        Some(value) => value,
+        None => return Err(AddressErrorType::UnexpectedEnd(arg)),
+    };
+
+    // IPv4 addresses are matched regardless of whether clients connect over IPv4 or to an IPv6 dual-stack socket.
+    let address = match arg2.trim().parse::<IpAddr>() {
+        Ok(address) => address.to_canonical(),
+        Err(_) => return Err(AddressErrorType::InvalidAddress(arg, arg2)),
+    };
+
+    if !addresses.contains(&address) {
+        addresses.push(address);
+    }
+
+    Ok(())
+}
+
 #[derive(Debug, PartialEq, Eq)]
 pub enum NewUserErrorType {
     UnexpectedEnd(String),
@@ -229,7 +650,7 @@ impl From<NewUserErrorType> for ArgumentsError {
     }
 }
 
-fn parse_new_user_arg(
+pub fn parse_new_user_arg(
     users: &mut HashMap<Pop3Username, Pop3ArgString>,
     arg: String,
     maybe_arg2: Option<String>,
@@ -332,17 +753,295 @@ fn parse_buffer_size_arg(buffer_size: &mut u32, arg: String, maybe_arg2: Option<
     Ok(())
 }
 
-pub fn parse_arguments<T>(mut args: T) -> Result<ArgumentsRequest, ArgumentsError>
+#[derive(Debug, PartialEq, Eq)]
+pub enum QuotaErrorType {
+    UnexpectedEnd(String),
+    DuplicateUsername(String, String),
+    InvalidUsername(String, String),
+    InvalidQuota(String, String),
+    InvalidQuotaSpecification(String, String),
+}
+
+impl fmt::Display for QuotaErrorType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnexpectedEnd(arg) => write!(f, "Expected quota specification after {arg}"),
+            Self::DuplicateUsername(arg, arg2) => write!(f, "Duplicate username at {arg} {arg2}"),
+            Self::InvalidUsername(arg, arg2) => write!(f, "Invalid username {arg} {arg2}"),
+            Self::InvalidQuota(arg, arg2) => write!(f, "Invalid Maildir++ quota at {arg} {arg2}"),
+            Self::InvalidQuotaSpecification(arg, arg2) => write!(f, "Invalid quota specification at {arg} {arg2}"),
+        }
+    }
+}
+
+impl From<QuotaErrorType> for ArgumentsError {
+    fn from(value: QuotaErrorType) -> Self {
+        Self::QuotaError(value)
+    }
+}
+
+fn parse_quota_arg(quotas: &mut HashMap<Pop3Username, Quota>, arg: String, maybe_arg2: Option<String>) -> Result<(), QuotaErrorType> {
+    let arg2 = match maybe_arg2 {
+        Some(arg2) => arg2,
+        None => return Err(QuotaErrorType::UnexpectedEnd(arg)),
+    };
+
+    let (username_str, quota_str) = match arg2.trim().split_once(':') {
+        Some(split) => split,
+        None => return Err(QuotaErrorType::InvalidQuotaSpecification(arg, arg2)),
+    };
+
+    let username = match Pop3Username::try_from(username_str) {
+        Ok(u) => u,
+        Err(_) => return Err(QuotaErrorType::InvalidUsername(arg, arg2)),
+    };
+
+    let quota = match quota_str.parse::<Quota>() {
+        Ok(q) => q,
+        Err(_) => return Err(QuotaErrorType::InvalidQuota(arg, arg2)),
+    };
+
+    let vacant_entry = match quotas.entry(username) {
+        std::collections::hash_map::Entry::Occupied(_) => return Err(QuotaErrorType::DuplicateUsername(arg, arg2)),
+        std::collections::hash_map::Entry::Vacant(vac) => vac,
+    };
+
+    vacant_entry.insert(quota);
+    Ok(())
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum QuotaRejectErrorType {
+    UnexpectedEnd(String),
+    AlreadySpecified(String),
+    InvalidValue(String, String),
+}
+
+impl fmt::Display for QuotaRejectErrorType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnexpectedEnd(arg) => write!(f, "Expected percentage after {arg}"),
+            Self::AlreadySpecified(arg) => write!(f, "Quota rejection percentage already specified at {arg}"),
+            Self::InvalidValue(arg, arg2) => write!(f, "Invalid percentage at {arg} {arg2}"),
+        }
+    }
+}
+
+impl From<QuotaRejectErrorType> for ArgumentsError {
+    fn from(value: QuotaRejectErrorType) -> Self {
+        Self::QuotaRejectError(value)
+    }
+}
+
+fn parse_quota_reject_arg(percent: &mut Option<u64>, arg: String, maybe_arg2: Option<String>) -> Result<(), QuotaRejectErrorType> {
+    let arg2 = match maybe_arg2 {
+        Some(arg2) => arg2,
+        None => return Err(QuotaRejectErrorType::UnexpectedEnd(arg)),
+    };
+
+    if percent.is_some() {
+        return Err(QuotaRejectErrorType::AlreadySpecified(arg));
+    }
+
+    let trimmed = arg2.trim();
+    match trimmed.strip_suffix('%').unwrap_or(trimmed).parse::<u64>() {
+        Ok(p) if p != 0 => *percent = Some(p),
+        _ => return Err(QuotaRejectErrorType::InvalidValue(arg, arg2)),
+    }
+
+    Ok(())
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum DeletionPolicyErrorType {
+    UnexpectedEnd(String),
+    AlreadySpecified(String),
+    InvalidPolicy(String, String),
+}
+
+impl fmt::Display for DeletionPolicyErrorType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnexpectedEnd(arg) => write!(f, "Expected deletion policy after {arg}"),
+            Self::AlreadySpecified(arg) => write!(f, "Deletion policy already specified at {arg}"),
+            Self::InvalidPolicy(arg, arg2) => write!(f, "Invalid deletion policy at {arg} {arg2}"),
+        }
+    }
+}
+
+impl From<DeletionPolicyErrorType> for ArgumentsError {
+    fn from(value: DeletionPolicyErrorType) -> Self {
+        Self::DeletionPolicyError(value)
+    }
+}
+
+fn parse_deletion_policy_arg(
+    deletion_policy: &mut Option<DeletionPolicy>,
+    arg: String,
+    maybe_arg2: Option<String>,
+) -> Result<(), DeletionPolicyErrorType> {
+    let arg2 = match maybe_arg2 {
+        Some(arg2) => arg2,
+        None => return Err(DeletionPolicyErrorType::UnexpectedEnd(arg)),
+    };
+
+    if deletion_policy.is_some() {
+        return Err(DeletionPolicyErrorType::AlreadySpecified(arg));
+    }
+
+    match arg2.parse::<DeletionPolicy>() {
+        Ok(policy) => *deletion_policy = Some(policy),
+        Err(_) => return Err(DeletionPolicyErrorType::InvalidPolicy(arg, arg2)),
+    }
+
+    Ok(())
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum UsernameListErrorType {
+    UnexpectedEnd(String),
+    InvalidUsername(String, String),
+}
+
+impl fmt::Display for UsernameListErrorType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnexpectedEnd(arg) => write!(f, "Expected username after {arg}"),
+            Self::InvalidUsername(arg, arg2) => write!(f, "Invalid username {arg} {arg2}"),
+        }
+    }
+}
+
+fn parse_username_list_arg(users: &mut Vec<Pop3Username>, arg: String, maybe_arg2: Option<String>) -> Result<(), UsernameListErrorType> {
+    let arg2 = match maybe_arg2 {
+        Some(arg2) => arg2,
+        None => return Err(UsernameListErrorType::UnexpectedEnd(arg)),
+    };
+
+    let username = match Pop3Username::try_from(arg2.trim()) {
+        Ok(u) => u,
+        Err(_) => return Err(UsernameListErrorType::InvalidUsername(arg, arg2)),
+    };
+
+    if !users.contains(&username) {
+        users.push(username);
+    }
+
+    Ok(())
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum TimeoutErrorType {
+    UnexpectedEnd(String),
+    AlreadySpecified(String),
+    InvalidValue(String, String),
+    /// The timeout is shorter than the minimum, which is included.
+    TooShort(String, String, Duration),
+}
+
+fn fmt_timeout_error_type(this: &TimeoutErrorType, s: &str, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match this {
+        TimeoutErrorType::UnexpectedEnd(arg) => write!(f, "Expected {s} after {arg}"),
+        TimeoutErrorType::AlreadySpecified(arg) => write!(f, "Only one {s} may be specified at {arg}"),
+        TimeoutErrorType::InvalidValue(arg, arg2) => write!(f, "Invalid {s} at {arg} {arg2}"),
+        TimeoutErrorType::TooShort(arg, arg2, min) => write!(f, "The {s} must be at least {} at {arg} {arg2}", fmt_duration(*min)),
+    }
+}
+
+/// Formats a duration in whole minutes if possible, or otherwise in seconds.
+fn fmt_duration(duration: Duration) -> String {
+    match duration.as_secs() {
+        seconds if seconds != 0 && seconds % 60 == 0 => format!("{} minutes", seconds / 60),
+        seconds => format!("{seconds} seconds"),
+    }
+}
+
+fn parse_timeout_arg(
+    timeout: &mut Option<Duration>,
+    min_timeout: Duration,
+    arg: String,
+    maybe_arg2: Option<String>,
+) -> Result<(), TimeoutErrorType> {
+    let arg2 = match maybe_arg2 {
+        Some(arg2) => arg2,
+        None => return Err(TimeoutErrorType::UnexpectedEnd(arg)),
+    };
+
+    if timeout.is_some() {
+        return Err(TimeoutErrorType::AlreadySpecified(arg));
+    }
+
+    let trimmed = arg2.trim();
+    let (number_str, multiplier) = match trimmed.chars().next_back().map(|c| c.to_ascii_lowercase()) {
+        Some('s') => (&trimmed[..(trimmed.len() - 1)], 1),
+        Some('m') => (&trimmed[..(trimmed.len() - 1)], 60),
+        Some('h') => (&trimmed[..(trimmed.len() - 1)], 60 * 60),
+        _ => (trimmed, 1),
+    };
+
+    let seconds = match number_str.parse::<u64>().ok().and_then(|n| n.checked_mul(multiplier)) {
+        Some(s) if s != 0 => s,
+        _ => return Err(TimeoutErrorType::InvalidValue(arg, arg2)),
+    };
+
+    let duration = Duration::from_secs(seconds);
+    if duration < min_timeout {
+        return Err(TimeoutErrorType::TooShort(arg, arg2, min_timeout));
+    }
+
+    *timeout = Some(duration);
+    Ok(())
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum CountErrorType {
+    UnexpectedEnd(String),
+    AlreadySpecified(String),
+    InvalidValue(String, String),
+}
+
+fn fmt_count_error_type(this: &CountErrorType, s: &str, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match this {
+        CountErrorType::UnexpectedEnd(arg) => write!(f, "Expected {s} after {arg}"),
+        CountErrorType::AlreadySpecified(arg) => write!(f, "Only one {s} may be specified at {arg}"),
+        CountErrorType::InvalidValue(arg, arg2) => write!(f, "Invalid {s} at {arg} {arg2}"),
+    }
+}
+
+fn parse_count_arg(count: &mut Option<usize>, arg: String, maybe_arg2: Option<String>) -> Result<(), CountErrorType> {
+    let arg2 = match maybe_arg2 {
+        Some(arg2) => arg2,
+        None => return Err(CountErrorType::UnexpectedEnd(arg)),
+    };
+
+    if count.is_some() {
+        return Err(CountErrorType::AlreadySpecified(arg));
+    }
+
+    match arg2.trim().parse::<usize>() {
+        Ok(c) if c != 0 => *count = Some(c),
+        _ => return Err(CountErrorType::InvalidValue(arg, arg2)),
+    }
+
+    Ok(())
+}
+
+pub fn parse_arguments<T>(args: T) -> Result<ArgumentsRequest, ArgumentsError>
 where
     T: Iterator<Item = String>,
 {
-    let mut pop3_bind_sockets = Vec::new();
-    let mut verbose = false;
-    let mut silent = false;
-    let mut maildirs_file = None;
-    let mut users = HashMap::new();
-    let mut buffer_size = 0;
-    let mut transformer_file = None;
+    parse_arguments_with_config(args, None)
+}
+
+/// Parses the arguments like [`parse_arguments`], but reads the configuration file from `config_file` instead of the
+/// path given in the arguments, if one was given there. Used to re-read the configuration file after changing the root
+/// directory, from where the path in the arguments may no longer lead to it.
+pub fn parse_arguments_with_config<T>(mut args: T, config_file_override: Option<&Path>) -> Result<ArgumentsRequest, ArgumentsError>
+where
+    T: Iterator<Item = String>,
+{
+    let mut arguments = PartialArguments::default();
+    let mut config_file = None;
 
     // Ignore the first argument, as it's by convention the name of the program
     args.next();
@@ -354,45 +1053,342 @@ where
             return Ok(ArgumentsRequest::Help);
         } else if arg.eq("-V") || arg.eq_ignore_ascii_case("--version") {
             return Ok(ArgumentsRequest::Version);
-        } else if arg.eq("-v") || arg.eq_ignore_ascii_case("--verbose") {
-            verbose = true;
+        } else if arg.eq("-f") || arg.eq_ignore_ascii_case("--config") {
+            parse_file_arg(&mut config_file, arg, args.next()).map_err(ArgumentsError::ConfigFileError)?;
+        } else {
+            arguments.parse_argument(arg, &mut args)?;
+        }
+    }
+
+    // Settings from the configuration file are only used for whatever wasn't specified on the command line.
+    if config_file.is_some() {
+        config_file = config_file_override.map(Path::to_path_buf).or(config_file);
+    }
+
+    if let Some(config_file) = &config_file {
+        arguments.merge(config::load_config_file(config_file)?);
+    }
+
+    if arguments.admin_password.is_some() && arguments.admin_password_file.is_some() {
+        return Err(ArgumentsError::AdminPasswordConflict);
+    }
+
+    // The password file is only read at startup, where it's also rejected if it holds an empty password.
+    let has_admin_password = arguments.admin_password.as_ref().is_some_and(|password| !password.is_empty());
+    if !arguments.admin_bind_sockets.is_empty() && !has_admin_password && arguments.admin_password_file.is_none() {
+        return Err(ArgumentsError::AdminPasswordRequired);
+    }
+
+    if !arguments.encrypt_users.is_empty() && arguments.key_file.is_none() {
+        return Err(ArgumentsError::KeyFileRequired);
+    }
+
+    let mut startup_args = arguments.finish();
+    startup_args.config_file = config_file;
+    Ok(ArgumentsRequest::Run(Box::new(startup_args)))
+}
+
+/// The settings parsed from the command line or a configuration file, before defaults are filled in.
+#[derive(Default)]
+pub struct PartialArguments {
+    pop3_bind_sockets: Vec<SocketAddr>,
+    metrics_bind_sockets: Vec<SocketAddr>,
+    admin_bind_sockets: Vec<SocketAddr>,
+    admin_password: Option<String>,
+    admin_password_file: Option<PathBuf>,
+    verbose: Option<bool>,
+    silent: Option<bool>,
+    log_level: Option<LevelFilter>,
+    log_format: Option<LogFormat>,
+    log_file: Option<PathBuf>,
+    syslog: Option<SyslogTarget>,
+    syslog_facility: Option<SyslogFacility>,
+    maildirs_file: Option<PathBuf>,
+    users: HashMap<Pop3Username, Pop3ArgString>,
+    buffer_size: u32,
+    transformer_file: Option<PathBuf>,
+    newest_first: Option<bool>,
+    quotas: HashMap<Pop3Username, Quota>,
+    quota_reject_percent: Option<u64>,
+    deletion_policy: Option<DeletionPolicy>,
+    encrypt_users: Vec<Pop3Username>,
+    key_file: Option<PathBuf>,
+    auth_timeout: Option<Duration>,
+    transaction_timeout: Option<Duration>,
+    connection_limits: ConnectionLimits,
+    grace_period: Option<Duration>,
+    threads: Option<usize>,
+    run_as_user: Option<String>,
+    run_as_group: Option<String>,
+    chroot_dir: Option<PathBuf>,
+    trace_users: Vec<Pop3Username>,
+    trace_addresses: Vec<IpAddr>,
+    transcripts_dir: Option<PathBuf>,
+    event_commands: Vec<PathBuf>,
+    event_logs: Vec<PathBuf>,
+    audit_log: Option<AuditDestination>,
+    audit_max_size: Option<AuditMaxSize>,
+    audit_keep: Option<usize>,
+}
+
+impl PartialArguments {
+    /// Explicitly turns off an option that doesn't take a value, so that it stays off even if another source of settings
+    /// merged afterwards turns it on. Other options are left unchanged.
+    pub fn disable_flag(&mut self, arg: &str) {
+        if arg.eq("-v") || arg.eq_ignore_ascii_case("--verbose") {
+            self.verbose = Some(false);
+        } else if arg.eq("-s") || arg.eq_ignore_ascii_case("--silent") {
+            self.silent = Some(false);
+        } else if arg.eq("-n") || arg.eq_ignore_ascii_case("--newest-first") {
+            self.newest_first = Some(false);
+        }
+    }
+
+    /// Parses a single argument, taking its value (if it has one) from `args`.
+    pub fn parse_argument<T>(&mut self, arg: String, args: &mut T) -> Result<(), ArgumentsError>
+    where
+        T: Iterator<Item = String>,
+    {
+        if arg.eq("-v") || arg.eq_ignore_ascii_case("--verbose") {
+            self.verbose = Some(true);
+            self.silent = Some(false);
         } else if arg.eq("-s") || arg.eq_ignore_ascii_case("--silent") {
-            silent = true;
+            self.silent = Some(true);
+            self.verbose = Some(false);
+        } else if arg.eq("-L") || arg.eq_ignore_ascii_case("--log-level") {
+            parse_choice_arg(&mut self.log_level, arg, args.next()).map_err(ArgumentsError::LogLevelError)?;
+        } else if arg.eq("-F") || arg.eq_ignore_ascii_case("--log-format") {
+            parse_choice_arg(&mut self.log_format, arg, args.next()).map_err(ArgumentsError::LogFormatError)?;
+        } else if arg.eq("-o") || arg.eq_ignore_ascii_case("--log-file") {
+            parse_file_arg(&mut self.log_file, arg, args.next()).map_err(ArgumentsError::LogFileError)?;
+        } else if arg.eq("-S") || arg.eq_ignore_ascii_case("--syslog") {
+            parse_choice_arg(&mut self.syslog, arg, args.next()).map_err(ArgumentsError::SyslogError)?;
+        } else if arg.eq("-Y") || arg.eq_ignore_ascii_case("--syslog-facility") {
+            parse_choice_arg(&mut self.syslog_facility, arg, args.next()).map_err(ArgumentsError::SyslogFacilityError)?;
         } else if arg.eq("-l") || arg.eq_ignore_ascii_case("--listen") {
-            parse_socket_arg(&mut pop3_bind_sockets, arg, args.next(), DEFAULT_POP3_PORT).map_err(ArgumentsError::Pop3ListenError)?;
+            parse_socket_arg(&mut self.pop3_bind_sockets, arg, args.next(), DEFAULT_POP3_PORT).map_err(ArgumentsError::Pop3ListenError)?;
+        } else if arg.eq("-m") || arg.eq_ignore_ascii_case("--metrics") {
+            parse_socket_arg(&mut self.metrics_bind_sockets, arg, args.next(), DEFAULT_METRICS_PORT)
+                .map_err(ArgumentsError::MetricsListenError)?;
+        } else if arg.eq("-A") || arg.eq_ignore_ascii_case("--admin") {
+            parse_socket_arg(&mut self.admin_bind_sockets, arg, args.next(), DEFAULT_ADMIN_PORT)
+                .map_err(ArgumentsError::AdminListenError)?;
+        } else if arg.eq("-P") || arg.eq_ignore_ascii_case("--admin-password") {
+            parse_choice_arg(&mut self.admin_password, arg, args.next()).map_err(ArgumentsError::AdminPasswordError)?;
+        } else if arg.eq("-W") || arg.eq_ignore_ascii_case("--admin-password-file") {
+            parse_file_arg(&mut self.admin_password_file, arg, args.next()).map_err(ArgumentsError::AdminPasswordFileError)?;
         } else if arg.eq("-d") || arg.eq_ignore_ascii_case("--maildirs") {
-            parse_file_arg(&mut maildirs_file, arg, args.next()).map_err(ArgumentsError::MaildirsFileError)?;
+            parse_file_arg(&mut self.maildirs_file, arg, args.next()).map_err(ArgumentsError::MaildirsFileError)?;
         } else if arg.eq("-u") || arg.eq_ignore_ascii_case("--user") {
-            parse_new_user_arg(&mut users, arg, args.next())?;
+            parse_new_user_arg(&mut self.users, arg, args.next())?;
         } else if arg.eq("-b") || arg.eq_ignore_ascii_case("--buffer-size") {
-            parse_buffer_size_arg(&mut buffer_size, arg, args.next())?;
+            parse_buffer_size_arg(&mut self.buffer_size, arg, args.next())?;
         } else if arg.eq("-t") || arg.eq_ignore_ascii_case("--transformer") {
-            parse_file_arg(&mut transformer_file, arg, args.next()).map_err(ArgumentsError::TransformerFileError)?;
+            parse_file_arg(&mut self.transformer_file, arg, args.next()).map_err(ArgumentsError::TransformerFileError)?;
+        } else if arg.eq("-n") || arg.eq_ignore_ascii_case("--newest-first") {
+            self.newest_first = Some(true);
+        } else if arg.eq("-q") || arg.eq_ignore_ascii_case("--quota") {
+            parse_quota_arg(&mut self.quotas, arg, args.next())?;
+        } else if arg.eq("-r") || arg.eq_ignore_ascii_case("--quota-reject") {
+            parse_quota_reject_arg(&mut self.quota_reject_percent, arg, args.next())?;
+        } else if arg.eq("-p") || arg.eq_ignore_ascii_case("--deletion-policy") {
+            parse_deletion_policy_arg(&mut self.deletion_policy, arg, args.next())?;
+        } else if arg.eq("-e") || arg.eq_ignore_ascii_case("--encrypt") {
+            parse_username_list_arg(&mut self.encrypt_users, arg, args.next()).map_err(ArgumentsError::EncryptUserError)?;
+        } else if arg.eq("-I") || arg.eq_ignore_ascii_case("--key-file") {
+            parse_file_arg(&mut self.key_file, arg, args.next()).map_err(ArgumentsError::KeyFileError)?;
+        } else if arg.eq("-a") || arg.eq_ignore_ascii_case("--auth-timeout") {
+            parse_timeout_arg(&mut self.auth_timeout, Duration::ZERO, arg, args.next()).map_err(ArgumentsError::AuthTimeoutError)?;
+        } else if arg.eq("-T") || arg.eq_ignore_ascii_case("--transaction-timeout") {
+            parse_timeout_arg(&mut self.transaction_timeout, MIN_TRANSACTION_TIMEOUT, arg, args.next())
+                .map_err(ArgumentsError::TransactionTimeoutError)?;
+        } else if arg.eq("-c") || arg.eq_ignore_ascii_case("--max-connections") {
+            parse_count_arg(&mut self.connection_limits.max_total, arg, args.next()).map_err(ArgumentsError::MaxConnectionsError)?;
+        } else if arg.eq("-C") || arg.eq_ignore_ascii_case("--max-ip-connections") {
+            parse_count_arg(&mut self.connection_limits.max_per_ip, arg, args.next()).map_err(ArgumentsError::MaxIpConnectionsError)?;
+        } else if arg.eq("-U") || arg.eq_ignore_ascii_case("--max-unauth-connections") {
+            parse_count_arg(&mut self.connection_limits.max_unauthenticated, arg, args.next())
+                .map_err(ArgumentsError::MaxUnauthConnectionsError)?;
+        } else if arg.eq("-g") || arg.eq_ignore_ascii_case("--grace-period") {
+            parse_timeout_arg(&mut self.grace_period, Duration::ZERO, arg, args.next()).map_err(ArgumentsError::GracePeriodError)?;
+        } else if arg.eq("-w") || arg.eq_ignore_ascii_case("--threads") {
+            parse_count_arg(&mut self.threads, arg, args.next()).map_err(ArgumentsError::ThreadsError)?;
+        } else if arg.eq("-R") || arg.eq_ignore_ascii_case("--run-as-user") {
+            parse_name_arg(&mut self.run_as_user, arg, args.next()).map_err(ArgumentsError::RunAsUserError)?;
+        } else if arg.eq("-G") || arg.eq_ignore_ascii_case("--run-as-group") {
+            parse_name_arg(&mut self.run_as_group, arg, args.next()).map_err(ArgumentsError::RunAsGroupError)?;
+        } else if arg.eq("-j") || arg.eq_ignore_ascii_case("--chroot") {
+            parse_file_arg(&mut self.chroot_dir, arg, args.next()).map_err(ArgumentsError::ChrootError)?;
+        } else if arg.eq("-x") || arg.eq_ignore_ascii_case("--trace-user") {
+            parse_username_list_arg(&mut self.trace_users, arg, args.next()).map_err(ArgumentsError::TraceUserError)?;
+        } else if arg.eq("-X") || arg.eq_ignore_ascii_case("--trace-address") {
+            parse_address_arg(&mut self.trace_addresses, arg, args.next()).map_err(ArgumentsError::TraceAddressError)?;
+        } else if arg.eq("-D") || arg.eq_ignore_ascii_case("--transcripts-dir") {
+            parse_file_arg(&mut self.transcripts_dir, arg, args.next()).map_err(ArgumentsError::TranscriptsDirError)?;
+        } else if arg.eq("-E") || arg.eq_ignore_ascii_case("--event-command") {
+            parse_file_list_arg(&mut self.event_commands, arg, args.next()).map_err(ArgumentsError::EventCommandError)?;
+        } else if arg.eq("-J") || arg.eq_ignore_ascii_case("--event-log") {
+            parse_file_list_arg(&mut self.event_logs, arg, args.next()).map_err(ArgumentsError::EventLogError)?;
+        } else if arg.eq("-k") || arg.eq_ignore_ascii_case("--audit-log") {
+            parse_choice_arg(&mut self.audit_log, arg, args.next()).map_err(ArgumentsError::AuditLogError)?;
+        } else if arg.eq("-K") || arg.eq_ignore_ascii_case("--audit-max-size") {
+            parse_choice_arg(&mut self.audit_max_size, arg, args.next()).map_err(ArgumentsError::AuditMaxSizeError)?;
+        } else if arg.eq("-N") || arg.eq_ignore_ascii_case("--audit-keep") {
+            parse_count_arg(&mut self.audit_keep, arg, args.next()).map_err(ArgumentsError::AuditKeepError)?;
         } else {
             return Err(ArgumentsError::UnknownArgument(arg));
         }
-    }
 
-    if pop3_bind_sockets.is_empty() {
-        pop3_bind_sockets.push(SocketAddr::V6(SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, DEFAULT_POP3_PORT, 0, 0)));
-        pop3_bind_sockets.push(SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, DEFAULT_POP3_PORT)));
+        Ok(())
     }
 
-    let maildirs_file = maildirs_file.unwrap_or_else(|| DEFAULT_MAILDIRS_FILE.into());
+    /// Fills in any setting not present in `self` with its value from `other`. Users, quotas and encrypted users are
+    /// combined, with `self` taking precedence for users present in both.
+    pub fn merge(&mut self, other: PartialArguments) {
+        if self.pop3_bind_sockets.is_empty() {
+            self.pop3_bind_sockets = other.pop3_bind_sockets;
+        }
 
-    if buffer_size == 0 {
-        buffer_size = DEFAULT_BUFFER_SIZE;
+        if self.metrics_bind_sockets.is_empty() {
+            self.metrics_bind_sockets = other.metrics_bind_sockets;
+        }
+
+        if self.admin_bind_sockets.is_empty() {
+            self.admin_bind_sockets = other.admin_bind_sockets;
+        }
+
+        // A password on the command line replaces a password file in the configuration file, and vice versa.
+        if self.admin_password.is_none() && self.admin_password_file.is_none() {
+            self.admin_password = other.admin_password;
+            self.admin_password_file = other.admin_password_file;
+        }
+
+        self.verbose = self.verbose.or(other.verbose);
+        self.silent = self.silent.or(other.silent);
+        self.log_level = self.log_level.or(other.log_level);
+        self.log_format = self.log_format.or(other.log_format);
+        self.log_file = self.log_file.take().or(other.log_file);
+        self.syslog = self.syslog.take().or(other.syslog);
+        self.syslog_facility = self.syslog_facility.or(other.syslog_facility);
+        self.maildirs_file = self.maildirs_file.take().or(other.maildirs_file);
+
+        for (username, password) in other.users {
+            self.users.entry(username).or_insert(password);
+        }
+
+        if self.buffer_size == 0 {
+            self.buffer_size = other.buffer_size;
+        }
+
+        self.transformer_file = self.transformer_file.take().or(other.transformer_file);
+        self.newest_first = self.newest_first.or(other.newest_first);
+
+        for (username, quota) in other.quotas {
+            self.quotas.entry(username).or_insert(quota);
+        }
+
+        self.quota_reject_percent = self.quota_reject_percent.or(other.quota_reject_percent);
+        self.deletion_policy = self.deletion_policy.or(other.deletion_policy);
+
+        for username in other.encrypt_users {
+            if !self.encrypt_users.contains(&username) {
+                self.encrypt_users.push(username);
+            }
+        }
+
+        self.auth_timeout = self.auth_timeout.or(other.auth_timeout);
+        self.transaction_timeout = self.transaction_timeout.or(other.transaction_timeout);
+
+        let limits = &mut self.connection_limits;
+        limits.max_total = limits.max_total.or(other.connection_limits.max_total);
+        limits.max_per_ip = limits.max_per_ip.or(other.connection_limits.max_per_ip);
+        limits.max_unauthenticated = limits.max_unauthenticated.or(other.connection_limits.max_unauthenticated);
+
+        self.grace_period = self.grace_period.or(other.grace_period);
+        self.threads = self.threads.or(other.threads);
+        self.run_as_user = self.run_as_user.take().or(other.run_as_user);
+        self.run_as_group = self.run_as_group.take().or(other.run_as_group);
+        self.chroot_dir = self.chroot_dir.take().or(other.chroot_dir);
+        self.key_file = self.key_file.take().or(other.key_file);
+
+        for username in other.trace_users {
+            if !self.trace_users.contains(&username) {
+                self.trace_users.push(username);
+            }
+        }
+
+        for address in other.trace_addresses {
+            if !self.trace_addresses.contains(&address) {
+                self.trace_addresses.push(address);
+            }
+        }
+
+        self.transcripts_dir = self.transcripts_dir.take().or(other.transcripts_dir);
+
+        for path in other.event_commands {
+            if !self.event_commands.contains(&path) {
+                self.event_commands.push(path);
+            }
+        }
+
+        for path in other.event_logs {
+            if !self.event_logs.contains(&path) {
+                self.event_logs.push(path);
+            }
+        }
+
+        self.audit_log = self.audit_log.take().or(other.audit_log);
+        self.audit_max_size = self.audit_max_size.or(other.audit_max_size);
+        self.audit_keep = self.audit_keep.or(other.audit_keep);
     }
 
-    let result = StartupArguments {
-        pop3_bind_sockets,
-        verbose,
-        silent,
-        maildirs_file,
-        users,
-        buffer_size,
-        transformer_file,
-    };
+    /// Fills in the default value of any setting that wasn't specified. The listening addresses are left empty if none
+    /// were specified, since the server may have been passed listening sockets by systemd instead.
+    fn finish(mut self) -> StartupArguments {
+        if self.buffer_size == 0 {
+            self.buffer_size = DEFAULT_BUFFER_SIZE;
+        }
 
-    Ok(ArgumentsRequest::Run(result))
+        StartupArguments {
+            pop3_bind_sockets: self.pop3_bind_sockets,
+            metrics_bind_sockets: self.metrics_bind_sockets,
+            admin_bind_sockets: self.admin_bind_sockets,
+            admin_password: self.admin_password,
+            admin_password_file: self.admin_password_file,
+            verbose: self.verbose.unwrap_or(false),
+            silent: self.silent.unwrap_or(false),
+            log_level: self.log_level,
+            log_format: self.log_format.unwrap_or_default(),
+            log_file: self.log_file,
+            syslog: self.syslog,
+            syslog_facility: self.syslog_facility.unwrap_or_default(),
+            maildirs_file: self.maildirs_file.unwrap_or_else(|| DEFAULT_MAILDIRS_FILE.into()),
+            users: self.users,
+            buffer_size: self.buffer_size,
+            transformer_file: self.transformer_file,
+            newest_first: self.newest_first.unwrap_or(false),
+            quotas: self.quotas,
+            quota_reject_percent: self.quota_reject_percent,
+            deletion_policy: self.deletion_policy.unwrap_or_default(),
+            encrypt_users: self.encrypt_users,
+            key_file: self.key_file,
+            auth_timeout: self.auth_timeout.unwrap_or(DEFAULT_IDLE_TIMEOUT),
+            transaction_timeout: self.transaction_timeout.unwrap_or(DEFAULT_IDLE_TIMEOUT),
+            connection_limits: self.connection_limits,
+            grace_period: self.grace_period.unwrap_or(DEFAULT_GRACE_PERIOD),
+            threads: self.threads.unwrap_or(1),
+            run_as_user: self.run_as_user,
+            run_as_group: self.run_as_group,
+            chroot_dir: self.chroot_dir,
+            trace_users: self.trace_users,
+            trace_addresses: self.trace_addresses,
+            transcripts_dir: self.transcripts_dir.unwrap_or_else(|| DEFAULT_TRANSCRIPTS_DIR.into()),
+            event_commands: self.event_commands,
+            event_logs: self.event_logs,
+            audit_log: self.audit_log,
+            audit_max_size: self.audit_max_size,
+            audit_keep: self.audit_keep.unwrap_or(DEFAULT_AUDIT_KEEP),
+            config_file: None,
+        }
+    }
 }
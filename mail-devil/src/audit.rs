@@ -0,0 +1,225 @@
+//! An audit log recording which messages were retrieved or deleted, by whom, from where and when.
+//!
+//! A record is appended for every message sent to a client with `RETR`, and for every message whose deletion is
+//! committed once a session enters the `UPDATE` state. Each record is a JSON object on its own line, holding the time,
+//! the session's id, the client's address, the username, the action, the message's maildir unique name and its size as
+//! listed to the client, which is that of the decrypted and decompressed message with CRLF line endings. Retrievals also
+//! record the amount of bytes sent to the client, which may be larger than the size as it includes the periods added by
+//! byte-stuffing and the line ending added after an unterminated last line.
+//!
+//! Records are written either to an `audit.log` file in each user's maildir or to a single central file, and every
+//! record is synced to disk before moving on. The file is opened again for every record, so it may be rotated by an
+//! external tool, but the server can also rotate it by itself once it grows past a size, keeping a number of older
+//! files with a numeric suffix (`audit.log.1` being the most recent).
+
+use std::{
+    collections::BTreeMap,
+    fmt,
+    io::{self, ErrorKind},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+};
+
+use serde_json::{json, Value};
+use tokio::{fs::OpenOptions, io::AsyncWriteExt, sync::Mutex};
+use tracing::error;
+use tracing_subscriber::fmt::{format::Writer, time::FormatTime};
+
+use crate::{
+    auth::MaildirOwner,
+    types::{Pop3Username, AUDIT_LOG_FILE_NAME},
+    util::{buffer_size::parse_pretty_buffer_size, ownership},
+};
+
+/// How many rotated audit log files are kept by default.
+pub const DEFAULT_AUDIT_KEEP: usize = 5;
+
+/// The locks serializing writes to each audit log, so concurrent sessions don't interleave records or rotate a file at
+/// once, while sessions writing to different files don't wait on each other. A file's lock is removed once unused.
+static WRITE_LOCKS: std::sync::Mutex<BTreeMap<PathBuf, Arc<Mutex<()>>>> = std::sync::Mutex::new(BTreeMap::new());
+
+/// Where audit records are written to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuditDestination {
+    /// Each user's records are written to a file in their maildir.
+    Maildir,
+    /// All records are written to the file at this path.
+    File(PathBuf),
+}
+
+impl FromStr for AuditDestination {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "" => Err(()),
+            s if s.eq_ignore_ascii_case("maildir") => Ok(Self::Maildir),
+            s => Ok(Self::File(PathBuf::from(s))),
+        }
+    }
+}
+
+impl fmt::Display for AuditDestination {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Maildir => write!(f, "maildir"),
+            Self::File(path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+/// The size past which an audit log file is rotated, parsed like buffer sizes (such as `10M`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AuditMaxSize(pub u64);
+
+impl FromStr for AuditMaxSize {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match parse_pretty_buffer_size(s) {
+            Ok(size) if size != 0 => Ok(Self(size as u64)),
+            _ => Err(()),
+        }
+    }
+}
+
+/// The settings for where to write audit records and when to rotate the files.
+#[derive(Debug, Default)]
+pub struct AuditSettings {
+    /// Where to write audit records, or [`None`] if auditing is disabled.
+    pub destination: Option<AuditDestination>,
+    /// The size past which a file is rotated, or [`None`] to never rotate.
+    pub max_size: Option<u64>,
+    /// How many rotated files to keep.
+    pub keep: usize,
+}
+
+/// What happened to the audited message.
+#[derive(Debug, Clone, Copy)]
+pub enum AuditAction {
+    Retrieved { bytes_sent: u64 },
+    Deleted,
+}
+
+/// A record of something done to a message in a session.
+pub struct AuditRecord<'a> {
+    pub session_id: u64,
+    pub peer: SocketAddr,
+    pub username: &'a Pop3Username,
+    pub action: AuditAction,
+    pub message_path: &'a Path,
+    /// The size of the message as listed to the client, or [`None`] if it couldn't be calculated.
+    pub size: Option<u64>,
+}
+
+impl AuditRecord<'_> {
+    fn to_json(&self) -> Value {
+        let mut timestamp = String::new();
+        let _ = tracing_subscriber::fmt::time::SystemTime.format_time(&mut Writer::new(&mut timestamp));
+
+        let mut record = json!({
+            "timestamp": timestamp,
+            "session_id": self.session_id,
+            "peer": self.peer.to_string(),
+            "user": self.username.as_str(),
+            "action": match self.action {
+                AuditAction::Retrieved { .. } => "retrieve",
+                AuditAction::Deleted => "delete",
+            },
+            "uid": maildir_unique_name(self.message_path),
+            "size": self.size,
+        });
+
+        if let AuditAction::Retrieved { bytes_sent } = self.action {
+            record["bytes_sent"] = bytes_sent.into();
+        }
+
+        record
+    }
+}
+
+/// Gets a message's maildir unique name, which is its file name without the info that follows a ':' character.
+fn maildir_unique_name(path: &Path) -> String {
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    match file_name.split_once(':') {
+        Some((unique_name, _)) => unique_name.to_string(),
+        None => file_name.into_owned(),
+    }
+}
+
+/// Appends a record to the audit log, if auditing is enabled. `maildir` is the user's maildir, used when records are
+/// written there, in which case a newly created log is given to the maildir's `owner`. Errors are logged, but not
+/// returned, since they shouldn't interrupt the session.
+pub async fn append_record(settings: &AuditSettings, maildir: &Path, owner: Option<MaildirOwner>, record: &AuditRecord<'_>) {
+    let (path, owner) = match &settings.destination {
+        None => return,
+        Some(AuditDestination::Maildir) => (maildir.join(AUDIT_LOG_FILE_NAME), owner),
+        Some(AuditDestination::File(path)) => (path.clone(), None),
+    };
+
+    let mut line = record.to_json().to_string();
+    line.push('\n');
+
+    let lock = WRITE_LOCKS.lock().unwrap().entry(path.clone()).or_default().clone();
+    {
+        let _guard = lock.lock().await;
+        if let Some(max_size) = settings.max_size {
+            if let Err(error) = rotate_if_needed(&path, max_size, settings.keep).await {
+                error!("Could not rotate audit log {}: {error}", path.display());
+            }
+        }
+
+        if let Err(error) = write_line(&path, &line, owner).await {
+            error!("Could not write to audit log {}: {error}", path.display());
+        }
+    }
+
+    // The lock is only cloned while holding the map, so if nothing but the map and this call hold it, nobody waits on it.
+    let mut locks = WRITE_LOCKS.lock().unwrap();
+    if Arc::strong_count(&lock) == 2 {
+        locks.remove(&path);
+    }
+}
+
+async fn write_line(path: &Path, line: &str, owner: Option<MaildirOwner>) -> io::Result<()> {
+    let mut file = OpenOptions::new().create(true).append(true).open(path).await?;
+    if file.metadata().await?.len() == 0 {
+        ownership::set_owner(path, owner).await?;
+    }
+
+    file.write_all(line.as_bytes()).await?;
+    file.sync_data().await
+}
+
+/// Rotates the file at the given path if it's at least `max_size` bytes long, shifting the older files' suffixes and
+/// removing those past the amount to keep.
+async fn rotate_if_needed(path: &Path, max_size: u64, keep: usize) -> io::Result<()> {
+    match tokio::fs::metadata(path).await {
+        Ok(metadata) if metadata.len() >= max_size => {}
+        Ok(_) => return Ok(()),
+        Err(error) if error.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(error) => return Err(error),
+    }
+
+    let rotated_path = |index: usize| {
+        let mut rotated = path.as_os_str().to_owned();
+        rotated.push(format!(".{index}"));
+        PathBuf::from(rotated)
+    };
+
+    if keep == 0 {
+        return tokio::fs::remove_file(path).await;
+    }
+
+    // Renaming over the oldest kept file removes it.
+    for index in (1..keep).rev() {
+        match tokio::fs::rename(rotated_path(index), rotated_path(index + 1)).await {
+            Err(error) if error.kind() != ErrorKind::NotFound => return Err(error),
+            _ => {}
+        }
+    }
+
+    tokio::fs::rename(path, rotated_path(1)).await
+}
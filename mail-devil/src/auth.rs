@@ -0,0 +1,99 @@
+//! Checking the passwords users log in with.
+//!
+//! By default a user's password is the content of the `password` file in their maildir, which is what
+//! [`PasswordFileAuthenticator`] checks. Programs embedding the server may instead provide their own [`Authenticator`]
+//! through [`crate::ServerBuilder::authenticator`], for example to check passwords against a database. Either way the
+//! user's maildir must exist, and if it has encryption enabled the user's key is still unlocked with the server key.
+//!
+//! An authenticator also tells which system user and group own each user's mailbox, through [`Authenticator::owner`].
+//! When the server runs as root, the files and folders it creates in a user's maildir during their sessions are given
+//! to that owner. By default the owner is whoever owns the user's maildir folder.
+
+use std::{future::Future, path::Path, pin::Pin};
+
+use tokio::io::AsyncReadExt;
+use tracing::{info, warn};
+
+use crate::types::{Pop3ArgString, Pop3Username, MAX_COMMAND_ARG_LENGTH, PASSWORD_FILE_NAME};
+use crate::util::ownership;
+
+/// The future returned by [`Authenticator::authenticate`].
+pub type AuthenticateFuture<'a> = Pin<Box<dyn Future<Output = bool> + Send + 'a>>;
+
+/// The future returned by [`Authenticator::owner`].
+pub type OwnerFuture<'a> = Pin<Box<dyn Future<Output = Option<MaildirOwner>> + Send + 'a>>;
+
+/// The system user and group that own a user's mailbox.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MaildirOwner {
+    pub uid: u32,
+    pub gid: u32,
+}
+
+/// Decides whether a user may log in with a password.
+pub trait Authenticator: Send + Sync {
+    /// Checks the password a user is logging in with, returning whether it's correct. `maildirs_dir` is the directory
+    /// holding the users' maildirs. Errors should be logged and treated as a wrong password, since the client is only
+    /// ever told that the username or password is wrong.
+    fn authenticate<'a>(
+        &'a self,
+        maildirs_dir: &'a Path,
+        username: &'a Pop3Username,
+        password: &'a Pop3ArgString,
+    ) -> AuthenticateFuture<'a>;
+
+    /// Gets the system user and group that own a user's mailbox, or [`None`] to leave the files created for them owned by
+    /// the server's user. Only called once the user has been authenticated. By default this is the owner of the user's
+    /// maildir folder.
+    fn owner<'a>(&'a self, maildirs_dir: &'a Path, username: &'a Pop3Username) -> OwnerFuture<'a> {
+        Box::pin(async move { ownership::maildir_owner(&maildirs_dir.join(username.as_str())).await })
+    }
+}
+
+/// The default [`Authenticator`], which compares passwords against the `password` file in each user's maildir.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct PasswordFileAuthenticator;
+
+impl Authenticator for PasswordFileAuthenticator {
+    fn authenticate<'a>(
+        &'a self,
+        maildirs_dir: &'a Path,
+        username: &'a Pop3Username,
+        password: &'a Pop3ArgString,
+    ) -> AuthenticateFuture<'a> {
+        Box::pin(check_password_file(maildirs_dir, username, password))
+    }
+}
+
+async fn check_password_file(maildirs_dir: &Path, username: &Pop3Username, password: &Pop3ArgString) -> bool {
+    // Read the password file for the user into a `buf` buffer.
+    let path = maildirs_dir.join(username.as_str()).join(PASSWORD_FILE_NAME);
+    let mut file = match tokio::fs::File::open(&path).await {
+        Ok(f) => f,
+        Err(error) => {
+            info!("Failed to login user {username}, could not open password file: {error}");
+            return false;
+        }
+    };
+
+    let mut buf = [0u8; MAX_COMMAND_ARG_LENGTH];
+    let mut buf_len = 0;
+
+    while buf_len < buf.len() {
+        let bytes_read = match file.read(&mut buf[buf_len..]).await {
+            Ok(b) => b,
+            Err(error) => {
+                warn!("Failed to login user {username}, error while reading password file: {error}");
+                return false;
+            }
+        };
+
+        if bytes_read == 0 {
+            break;
+        }
+
+        buf_len += bytes_read;
+    }
+
+    password.as_bytes().eq(&buf[..buf_len])
+}
@@ -0,0 +1,476 @@
+//! Runs the server as configured by the command line's arguments, taking care of the parts specific to running as a
+//! standalone daemon: systemd's socket activation, dropping privileges, signals, and reloading the configuration on
+//! `SIGHUP`.
+
+use std::collections::HashMap;
+use std::env;
+use std::io::{self, ErrorKind};
+use std::net::SocketAddr;
+use std::path::{self, Path, PathBuf};
+use std::sync::Arc;
+
+use mail_devil::args::{self, ArgumentsError, ArgumentsRequest, StartupArguments};
+use mail_devil::audit::AuditSettings;
+use mail_devil::auth::PasswordFileAuthenticator;
+use mail_devil::deletion::{self, DeletionPolicy};
+use mail_devil::hooks::HookSettings;
+use mail_devil::quota::{self, Quota};
+use mail_devil::server::{self, ServerBuilder, ServerHandle, ServerReload};
+use mail_devil::state::Pop3ServerSettings;
+use mail_devil::transcript::TranscriptSettings;
+use mail_devil::types::{Pop3ArgString, Pop3Username};
+use mail_devil::{logging, systemd, ServerKey};
+use tokio::net::TcpListener;
+use tokio::select;
+use tokio::task::JoinHandle;
+use tracing::{error, info, warn};
+
+use crate::privileges;
+
+pub async fn run_server(mut startup_args: StartupArguments) -> io::Result<()> {
+    let mut builder = ServerBuilder::new();
+    let mut inherited_addrs = Vec::new();
+    for (name, result) in systemd::take_inherited_listeners() {
+        let name = if name.is_empty() { "unknown" } else { name.as_str() };
+        match result.and_then(|listener| Ok((listener.local_addr()?, listener))) {
+            Ok((address, listener)) => {
+                info!("Using listening socket {name} at {address} passed in by systemd");
+                inherited_addrs.push(address);
+                builder = builder.listener(listener);
+            }
+            Err(error) => error!("Could not use listening socket {name} passed in by systemd: {error}"),
+        }
+    }
+
+    for address in get_bind_sockets(&startup_args, &inherited_addrs) {
+        builder = builder.bind(address);
+    }
+
+    for &sockaddr in &startup_args.metrics_bind_sockets {
+        match TcpListener::bind(sockaddr).await {
+            Ok(listener) => {
+                info!("Serving metrics on {sockaddr}");
+                builder = builder.metrics_listener(listener);
+            }
+            Err(err) => error!("Failed to bind metrics listening socket at {sockaddr}: {err}"),
+        }
+    }
+
+    for &sockaddr in &startup_args.admin_bind_sockets {
+        match TcpListener::bind(sockaddr).await {
+            Ok(listener) => {
+                info!("Serving the admin interface on {sockaddr}");
+                builder = builder.admin_listener(listener);
+            }
+            Err(err) => error!("Failed to bind admin listening socket at {sockaddr}: {err}"),
+        }
+    }
+
+    // The admin password file is read before dropping privileges, so it may be kept readable only by root.
+    if let Some(admin_password) = load_admin_password(&startup_args).await? {
+        builder = builder.admin_password(admin_password);
+    }
+
+    // The server key is read before dropping privileges, so its file may be kept readable only by root and outside the
+    // chroot. It isn't re-read on reload.
+    let server_key = load_server_key(&startup_args).await?;
+
+    let server = builder
+        .settings(get_server_settings(&startup_args, &server_key))
+        .grace_period(startup_args.grace_period)
+        .hooks(get_hook_settings(&startup_args))
+        .build()
+        .await?;
+
+    // The configuration file is re-read on reload, when the path given in the arguments may no longer lead to it.
+    let reload_config_file = get_reload_config_file(&startup_args);
+
+    // Now that the listening sockets are bound, root privileges are no longer needed. The notification socket is
+    // connected beforehand, since its path may not be reachable from inside a chroot.
+    systemd::connect_notify_socket();
+    let run_as_user = startup_args.run_as_user.as_deref();
+    let run_as_group = startup_args.run_as_group.as_deref();
+    let chroot_dir = startup_args.chroot_dir.as_deref();
+    if let Err(error) = privileges::drop_privileges(run_as_user, run_as_group, chroot_dir) {
+        return Err(io::Error::new(
+            error.kind(),
+            format!("Failed to drop privileges, aborting server: {error}"),
+        ));
+    }
+
+    if run_as_user.is_some() || run_as_group.is_some() || chroot_dir.is_some() {
+        let chroot_dir = chroot_dir.map(|path| path.display().to_string());
+        info!(
+            "Dropped privileges to user {}, group {}, root directory {}",
+            run_as_user.unwrap_or("(unchanged)"),
+            run_as_group.or(run_as_user.map(|_| "(user's groups)")).unwrap_or("(unchanged)"),
+            chroot_dir.as_deref().unwrap_or("(unchanged)"),
+        );
+    }
+
+    update_users(&startup_args.maildirs_file, &startup_args.users, &startup_args.quotas).await;
+    encrypt_maildirs(&startup_args, &server_key).await;
+
+    let mut maildirs_task = None;
+    let mut purge_task = spawn_purge_task(&startup_args);
+    let handle = server.handle();
+    let server_task = server.run();
+    tokio::pin!(server_task);
+    let shutdown_signal = wait_for_shutdown_signal();
+    tokio::pin!(shutdown_signal);
+    let mut shutting_down = false;
+    let mut reload_signal = listen_for_reload_signal();
+
+    let result = loop {
+        select! {
+            result = &mut server_task => break result,
+            signal_name = &mut shutdown_signal, if !shutting_down => {
+                info!("Received {signal_name}, shutting down");
+                handle.shutdown();
+                shutting_down = true;
+            }
+            _ = wait_for_reload_signal(&mut reload_signal), if !shutting_down => {
+                info!("Received SIGHUP, reloading configuration");
+                let tasks = (&mut purge_task, &mut maildirs_task);
+                let config_file = reload_config_file.as_ref().map(Option::as_deref).map_err(String::as_str);
+                reload_configuration(&handle, &inherited_addrs, &server_key, config_file, &mut startup_args, tasks);
+            }
+        }
+    };
+
+    if let Some(purge_task) = purge_task {
+        purge_task.abort();
+    }
+
+    if let Some(maildirs_task) = maildirs_task {
+        let _ = maildirs_task.await;
+    }
+
+    result
+}
+
+/// Parses the program's arguments again and applies them to the running server, which also re-reads the configuration
+/// file if one was specified. If the arguments are no longer valid, the current configuration is kept.
+///
+/// Only the users and quotas that changed since `current_args` are written, and that happens in a background task, so
+/// the server keeps accepting clients meanwhile. Encryption is only ever enabled at startup, since encrypting messages
+/// in place would race with the sessions of their owners.
+///
+/// The transformer setting is carried over like any other, but has no effect since message transformations aren't
+/// implemented yet, and there are no TLS certificates to reload since the server doesn't support TLS.
+fn reload_configuration(
+    handle: &ServerHandle,
+    inherited_addrs: &[SocketAddr],
+    server_key: &Option<ServerKey>,
+    config_file: Result<Option<&Path>, &str>,
+    current_args: &mut StartupArguments,
+    (purge_task, maildirs_task): (&mut Option<JoinHandle<()>>, &mut Option<JoinHandle<()>>),
+) {
+    let config_file = match config_file {
+        Ok(config_file) => config_file,
+        Err(error) => {
+            error!("Could not reload configuration, keeping the current one: {error}");
+            return;
+        }
+    };
+
+    let startup_args = match reload_startup_arguments(config_file) {
+        Ok(startup_args) => startup_args,
+        Err(error) => {
+            error!("Could not reload configuration, keeping the current one: {error}");
+            return;
+        }
+    };
+
+    logging::set_level(startup_args.get_log_level());
+
+    // If the maildirs folder changed, every user is written to the new one.
+    let same_maildirs = startup_args.maildirs_file == current_args.maildirs_file;
+    let users = changed_entries(&startup_args.users, &current_args.users, same_maildirs);
+    let quotas = changed_entries(&startup_args.quotas, &current_args.quotas, same_maildirs);
+    if startup_args
+        .encrypt_users
+        .iter()
+        .any(|username| !current_args.encrypt_users.contains(username))
+    {
+        warn!("Encryption can only be enabled at startup, restart the server to encrypt the newly listed users");
+    }
+
+    if !users.is_empty() || !quotas.is_empty() {
+        let previous_task = maildirs_task.take();
+        let maildirs_dir = startup_args.maildirs_file.clone();
+        *maildirs_task = Some(tokio::spawn(async move {
+            // Updates from an earlier reload that are still running are finished first, so they're applied in order.
+            if let Some(previous_task) = previous_task {
+                let _ = previous_task.await;
+            }
+
+            update_users(&maildirs_dir, &users, &quotas).await;
+        }));
+    }
+
+    if let Some(purge_task) = purge_task.take() {
+        purge_task.abort();
+    }
+    *purge_task = spawn_purge_task(&startup_args);
+
+    handle.reload(ServerReload {
+        bind_addresses: get_bind_sockets(&startup_args, inherited_addrs),
+        settings: get_server_settings(&startup_args, server_key),
+        grace_period: startup_args.grace_period,
+        hooks: get_hook_settings(&startup_args),
+    });
+
+    *current_args = startup_args;
+}
+
+/// Gets the entries of `new` that aren't in `old` with the same value, or all of them if `only_changed` is false.
+fn changed_entries<V: Clone + PartialEq>(
+    new: &HashMap<Pop3Username, V>,
+    old: &HashMap<Pop3Username, V>,
+    only_changed: bool,
+) -> HashMap<Pop3Username, V> {
+    new.iter()
+        .filter(|&(username, value)| !only_changed || old.get(username) != Some(value))
+        .map(|(username, value)| (username.clone(), value.clone()))
+        .collect()
+}
+
+/// Gets the addresses to listen on, including those of any listening sockets passed in by systemd. If no addresses were
+/// specified and systemd didn't pass in any sockets, the default addresses are used.
+fn get_bind_sockets(startup_args: &StartupArguments, inherited_addrs: &[SocketAddr]) -> Vec<SocketAddr> {
+    let mut bind_sockets = match startup_args.pop3_bind_sockets.is_empty() && inherited_addrs.is_empty() {
+        true => args::get_default_pop3_bind_sockets(),
+        false => startup_args.pop3_bind_sockets.clone(),
+    };
+
+    bind_sockets.extend_from_slice(inherited_addrs);
+    bind_sockets
+}
+
+/// Loads the server key from the file specified in the startup arguments, creating it if it doesn't exist. The file
+/// may not be within the maildirs folder, since anyone able to read it could read every encrypted message.
+async fn load_server_key(startup_args: &StartupArguments) -> io::Result<Option<ServerKey>> {
+    let key_file = match &startup_args.key_file {
+        Some(key_file) => key_file,
+        None => return Ok(None),
+    };
+
+    let maildirs_dir = resolve_path(&startup_args.maildirs_file)?;
+    let key_dir = resolve_path(
+        key_file
+            .parent()
+            .filter(|parent| !parent.as_os_str().is_empty())
+            .unwrap_or(Path::new(".")),
+    )?;
+    if key_dir.starts_with(&maildirs_dir) {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            "The key file may not be within the maildirs folder",
+        ));
+    }
+
+    match ServerKey::load_or_create(key_file).await {
+        Ok(server_key) => Ok(Some(server_key)),
+        Err(error) => Err(io::Error::new(
+            error.kind(),
+            format!("Could not load key file {}: {error}", key_file.display()),
+        )),
+    }
+}
+
+/// Gets the admin password given on the command line, or else reads it from the first line of the admin password file.
+async fn load_admin_password(startup_args: &StartupArguments) -> io::Result<Option<String>> {
+    let path = match (&startup_args.admin_password, &startup_args.admin_password_file) {
+        (Some(admin_password), _) => return Ok(Some(admin_password.clone())),
+        (None, Some(path)) => path,
+        (None, None) => return Ok(None),
+    };
+
+    match tokio::fs::read_to_string(path).await {
+        Ok(contents) => Ok(Some(contents.lines().next().unwrap_or_default().to_string())),
+        Err(error) => Err(io::Error::new(
+            error.kind(),
+            format!("Could not read admin password file {}: {error}", path.display()),
+        )),
+    }
+}
+
+/// Resolves a path's symlinks if it exists, or otherwise just makes it absolute.
+fn resolve_path(path: &Path) -> io::Result<PathBuf> {
+    path.canonicalize().or_else(|_| path::absolute(path))
+}
+
+fn get_server_settings(startup_args: &StartupArguments, server_key: &Option<ServerKey>) -> Pop3ServerSettings {
+    Pop3ServerSettings {
+        buffer_size: startup_args.buffer_size,
+        maildirs_dir: startup_args.maildirs_file.clone(),
+        transformer_file: startup_args.transformer_file.clone(),
+        newest_first: startup_args.newest_first,
+        quota_reject_percent: startup_args.quota_reject_percent,
+        authenticator: Arc::new(PasswordFileAuthenticator),
+        commands: Arc::default(),
+        deletion_policy: startup_args.deletion_policy,
+        auth_timeout: startup_args.auth_timeout,
+        transaction_timeout: startup_args.transaction_timeout,
+        connection_limits: startup_args.connection_limits,
+        transcripts: TranscriptSettings {
+            users: startup_args.trace_users.clone(),
+            addresses: startup_args.trace_addresses.clone(),
+            dir: startup_args.transcripts_dir.clone(),
+        },
+        audit: AuditSettings {
+            destination: startup_args.audit_log.clone(),
+            max_size: startup_args.audit_max_size.map(|max_size| max_size.0),
+            keep: startup_args.audit_keep,
+        },
+        server_key: server_key.clone(),
+    }
+}
+
+fn get_hook_settings(startup_args: &StartupArguments) -> HookSettings {
+    HookSettings {
+        commands: startup_args.event_commands.clone(),
+        logs: startup_args.event_logs.clone(),
+    }
+}
+
+/// Creates or updates the given users and quotas.
+async fn update_users(maildirs_dir: &Path, users: &HashMap<Pop3Username, Pop3ArgString>, quotas: &HashMap<Pop3Username, Quota>) {
+    for (username, password) in users {
+        if let Err(error) = server::create_user_maildir(maildirs_dir, username, password).await {
+            error!("Could not create or update user {username} as requested via parameter: {error}");
+        }
+    }
+
+    for (username, quota) in quotas {
+        let maildir = maildirs_dir.join(username.as_str());
+        match quota::set_quota(&maildir, *quota).await {
+            Ok(usage) => info!("Set quota for user {username}, maildrop holds {usage}"),
+            Err(error) => error!("Could not set quota for user {username} as requested via parameter: {error}"),
+        }
+    }
+}
+
+/// Enables encryption for the users requested in the startup arguments, encrypting their existing messages.
+async fn encrypt_maildirs(startup_args: &StartupArguments, server_key: &Option<ServerKey>) {
+    // The arguments can't request encryption without a key file, which is always loaded by now.
+    let server_key = match server_key {
+        Some(server_key) => server_key,
+        None => return,
+    };
+
+    for username in &startup_args.encrypt_users {
+        let maildir = startup_args.maildirs_file.join(username.as_str());
+        match server::encrypt_user_maildir(&maildir, server_key).await {
+            Ok(count) => info!("Enabled encryption for user {username}, encrypted {count} messages"),
+            Err(error) => error!("Could not encrypt user {username}'s maildir as requested via parameter: {error}"),
+        }
+    }
+}
+
+fn spawn_purge_task(startup_args: &StartupArguments) -> Option<JoinHandle<()>> {
+    match startup_args.deletion_policy {
+        DeletionPolicy::Retain(Some(retention_days)) => {
+            let maildirs_dir = startup_args.maildirs_file.clone();
+            let task = deletion::purge_retained_messages_task(maildirs_dir, retention_days);
+            Some(tokio::spawn(task))
+        }
+        _ => None,
+    }
+}
+
+/// Gets the path from which to re-read the configuration file on reload, if one was specified. Relative paths are made
+/// absolute, and if the root directory is to be changed, the path is made relative to the new root. Returns an error
+/// message if the file isn't inside the new root, since it couldn't be read again.
+fn get_reload_config_file(startup_args: &StartupArguments) -> Result<Option<PathBuf>, String> {
+    let config_file = match &startup_args.config_file {
+        Some(config_file) => resolve_path(config_file).map_err(|error| format!("Could not resolve config file path: {error}"))?,
+        None => return Ok(None),
+    };
+
+    let chroot_dir = match &startup_args.chroot_dir {
+        Some(chroot_dir) => resolve_path(chroot_dir).map_err(|error| format!("Could not resolve chroot path: {error}"))?,
+        None => return Ok(Some(config_file)),
+    };
+
+    match config_file.strip_prefix(&chroot_dir) {
+        Ok(relative_path) => Ok(Some(Path::new("/").join(relative_path))),
+        Err(_) => Err(format!(
+            "The config file {} is outside the chroot, so it can't be read again",
+            config_file.display()
+        )),
+    }
+}
+
+/// Parses the program's arguments again, which also re-reads the configuration file if one was specified.
+fn reload_startup_arguments(config_file: Option<&Path>) -> Result<StartupArguments, ArgumentsError> {
+    match args::parse_arguments_with_config(env::args(), config_file)? {
+        ArgumentsRequest::Run(startup_args) => Ok(*startup_args),
+        // The server was started with these same arguments, so they can't be a help or version request.
+        ArgumentsRequest::Help | ArgumentsRequest::Version => unreachable!(),
+    }
+}
+
+#[cfg(unix)]
+fn listen_for_reload_signal() -> Option<tokio::signal::unix::Signal> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    match signal(SignalKind::hangup()) {
+        Ok(sighup) => Some(sighup),
+        Err(error) => {
+            error!("Could not listen for SIGHUP, configuration reloading is disabled: {error}");
+            None
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn listen_for_reload_signal() -> Option<()> {
+    None
+}
+
+/// Waits until the process receives a signal requesting it to reload its configuration.
+#[cfg(unix)]
+async fn wait_for_reload_signal(sighup: &mut Option<tokio::signal::unix::Signal>) {
+    if let Some(sighup) = sighup {
+        if sighup.recv().await.is_some() {
+            return;
+        }
+    }
+
+    std::future::pending().await
+}
+
+#[cfg(not(unix))]
+async fn wait_for_reload_signal(_: &mut Option<()>) {
+    std::future::pending().await
+}
+
+/// Waits until the process receives a signal requesting it to shut down, then returns the signal's name.
+async fn wait_for_shutdown_signal() -> &'static str {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut sigterm = match signal(SignalKind::terminate()) {
+            Ok(sigterm) => sigterm,
+            Err(error) => {
+                error!("Could not listen for SIGTERM: {error}");
+                let _ = tokio::signal::ctrl_c().await;
+                return "SIGINT";
+            }
+        };
+
+        select! {
+            _ = tokio::signal::ctrl_c() => "SIGINT",
+            _ = sigterm.recv() => "SIGTERM",
+        }
+    }
+
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+        "Ctrl+C"
+    }
+}
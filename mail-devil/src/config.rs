@@ -0,0 +1,228 @@
+//! Loads settings from a TOML configuration file, specified with the `--config` argument.
+//!
+//! The configuration file is a TOML table whose keys are the long names of the command-line options, so for example
+//! `maildirs = "./maildirs"` is equivalent to `--maildirs ./maildirs`. Each value is handed to the same parser used
+//! for the command line, so both accept exactly the same values. Options without a value take a boolean, and any
+//! option may take an array, which is equivalent to specifying the option once per element. Additionally, users may be
+//! specified with a `users` table containing a table per user, with the `password`, `quota` and `encrypt` keys.
+//!
+//! Errors are reported with the file's path and the line and column at which the problem was found.
+
+use std::{
+    fmt,
+    ops::Range,
+    path::{Path, PathBuf},
+};
+
+use toml::{
+    de::{DeTable, DeValue},
+    Spanned,
+};
+
+use crate::args::{ArgumentsError, PartialArguments};
+
+/// A position within a configuration file, used for error reporting.
+#[derive(Debug, PartialEq, Eq)]
+pub struct ConfigLocation {
+    pub path: PathBuf,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl ConfigLocation {
+    fn new(path: &Path, source: &str, offset: usize) -> Self {
+        let before = &source[..offset.min(source.len())];
+        let line_start = before.rfind('\n').map(|i| i + 1).unwrap_or(0);
+
+        Self {
+            path: path.to_path_buf(),
+            line: before.matches('\n').count() + 1,
+            column: before[line_start..].chars().count() + 1,
+        }
+    }
+}
+
+impl fmt::Display for ConfigLocation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.path.display(), self.line, self.column)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ConfigErrorType {
+    ReadError(PathBuf, String),
+    SyntaxError(ConfigLocation, String),
+    UnknownSetting(ConfigLocation, String),
+    InvalidType(ConfigLocation, String, &'static str),
+    InvalidUsername(ConfigLocation, String),
+    InvalidValue(ConfigLocation, Box<ArgumentsError>),
+}
+
+impl fmt::Display for ConfigErrorType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::ReadError(path, error) => write!(f, "Could not read config file {}: {error}", path.display()),
+            Self::SyntaxError(location, message) => write!(f, "{location}: {message}"),
+            Self::UnknownSetting(location, key) => write!(f, "{location}: Unknown setting {key}"),
+            Self::InvalidType(location, key, expected) => write!(f, "{location}: Expected {expected} for {key}"),
+            Self::InvalidUsername(location, username) => write!(f, "{location}: Invalid username {username}"),
+            Self::InvalidValue(location, error) => write!(f, "{location}: {error}"),
+        }
+    }
+}
+
+impl From<ConfigErrorType> for ArgumentsError {
+    fn from(value: ConfigErrorType) -> Self {
+        Self::ConfigError(value)
+    }
+}
+
+/// Reads and parses the configuration file at the given path.
+pub fn load_config_file(path: &Path) -> Result<PartialArguments, ConfigErrorType> {
+    let source = match std::fs::read_to_string(path) {
+        Ok(s) => s,
+        Err(error) => return Err(ConfigErrorType::ReadError(path.to_path_buf(), error.to_string())),
+    };
+
+    let parser = ConfigParser { path, source: &source };
+
+    let table = match DeTable::parse(&source) {
+        Ok(table) => table,
+        Err(error) => {
+            let location = parser.locate(error.span().unwrap_or(0..0));
+            return Err(ConfigErrorType::SyntaxError(location, error.message().trim().to_string()));
+        }
+    };
+
+    let mut arguments = PartialArguments::default();
+    for (key, value) in table.get_ref() {
+        match (key.get_ref().as_ref(), value.get_ref()) {
+            ("users", DeValue::Table(users)) => parser.parse_users_table(&mut arguments, users)?,
+            ("users", _) => return Err(parser.invalid_type(value, "users", "a table")),
+            (key_str, _) => parser.parse_setting(&mut arguments, key, key_str, value)?,
+        }
+    }
+
+    Ok(arguments)
+}
+
+struct ConfigParser<'a> {
+    path: &'a Path,
+    source: &'a str,
+}
+
+impl ConfigParser<'_> {
+    fn locate(&self, span: Range<usize>) -> ConfigLocation {
+        ConfigLocation::new(self.path, self.source, span.start)
+    }
+
+    fn invalid_type<T>(&self, value: &Spanned<T>, key: &str, expected: &'static str) -> ConfigErrorType {
+        ConfigErrorType::InvalidType(self.locate(value.span()), key.to_string(), expected)
+    }
+
+    /// Parses a top-level `key = value` setting by passing it to the command-line parser as `--key value`.
+    fn parse_setting(
+        &self,
+        arguments: &mut PartialArguments,
+        key: &Spanned<impl AsRef<str>>,
+        key_str: &str,
+        value: &Spanned<DeValue>,
+    ) -> Result<(), ConfigErrorType> {
+        let arg = format!("--{key_str}");
+        let result = match value.get_ref() {
+            DeValue::Boolean(false) => {
+                arguments.disable_flag(&arg);
+                return Ok(());
+            }
+            DeValue::Boolean(true) => self.apply(arguments, &arg, None, key_str, value),
+            DeValue::Array(array) => array.iter().try_for_each(|element| {
+                let element_str = self.value_to_string(element, key_str)?;
+                self.apply(arguments, &arg, Some(element_str), key_str, element)
+            }),
+            _ => {
+                let value_str = self.value_to_string(value, key_str)?;
+                self.apply(arguments, &arg, Some(value_str), key_str, value)
+            }
+        };
+
+        match result {
+            Err(ConfigErrorType::InvalidValue(_, error)) if matches!(*error, ArgumentsError::UnknownArgument(_)) => {
+                Err(ConfigErrorType::UnknownSetting(self.locate(key.span()), key_str.to_string()))
+            }
+            other => other,
+        }
+    }
+
+    /// Parses the `users` table, in which each user has its own table of settings.
+    fn parse_users_table(&self, arguments: &mut PartialArguments, users: &DeTable) -> Result<(), ConfigErrorType> {
+        for (username, settings) in users {
+            let username_str = username.get_ref().as_ref();
+            if username_str.is_empty() || username_str.contains(':') {
+                return Err(ConfigErrorType::InvalidUsername(
+                    self.locate(username.span()),
+                    username_str.to_string(),
+                ));
+            }
+
+            let settings = match settings.get_ref() {
+                DeValue::Table(settings) => settings,
+                _ => return Err(self.invalid_type(settings, &format!("users.{username_str}"), "a table")),
+            };
+
+            for (key, value) in settings {
+                let key_str = format!("users.{username_str}.{}", key.get_ref());
+                match key.get_ref().as_ref() {
+                    "password" => {
+                        let password = self.value_to_string(value, &key_str)?;
+                        self.apply(arguments, "--user", Some(format!("{username_str}:{password}")), &key_str, value)?;
+                    }
+                    "quota" => {
+                        let quota = self.value_to_string(value, &key_str)?;
+                        self.apply(arguments, "--quota", Some(format!("{username_str}:{quota}")), &key_str, value)?;
+                    }
+                    "encrypt" => match value.get_ref() {
+                        DeValue::Boolean(true) => self.apply(arguments, "--encrypt", Some(username_str.to_string()), &key_str, value)?,
+                        DeValue::Boolean(false) => {}
+                        _ => return Err(self.invalid_type(value, &key_str, "true or false")),
+                    },
+                    _ => return Err(ConfigErrorType::UnknownSetting(self.locate(key.span()), key_str)),
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Passes an argument and its value to the command-line parser, attaching the value's location to any error.
+    fn apply(
+        &self,
+        arguments: &mut PartialArguments,
+        arg: &str,
+        arg2: Option<String>,
+        key: &str,
+        value: &Spanned<DeValue>,
+    ) -> Result<(), ConfigErrorType> {
+        let mut values = arg2.into_iter();
+        if let Err(error) = arguments.parse_argument(arg.to_string(), &mut values) {
+            return Err(ConfigErrorType::InvalidValue(self.locate(value.span()), Box::new(error)));
+        }
+
+        // If the value wasn't taken, then this is an option that doesn't take one, and should've been a boolean.
+        match values.next() {
+            Some(_) => Err(self.invalid_type(value, key, "true or false")),
+            None => Ok(()),
+        }
+    }
+
+    /// Converts a string or integer value to the string that would be used for it on the command line.
+    fn value_to_string(&self, value: &Spanned<DeValue>, key: &str) -> Result<String, ConfigErrorType> {
+        match value.get_ref() {
+            DeValue::String(s) => Ok(s.to_string()),
+            DeValue::Integer(i) => match i64::from_str_radix(i.as_str(), i.radix()) {
+                Ok(n) => Ok(n.to_string()),
+                Err(_) => Err(self.invalid_type(value, key, "a smaller number")),
+            },
+            _ => Err(self.invalid_type(value, key, "a string or integer")),
+        }
+    }
+}
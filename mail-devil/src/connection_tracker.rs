@@ -0,0 +1,185 @@
+//! A connection tracker counts the server's currently open client connections and enforces limits on them.
+//!
+//! Connections are counted in total, per remote address and while unauthenticated. Remote addresses are grouped by
+//! prefix: IPv4 addresses are counted individually, while IPv6 addresses are grouped by their /64 prefix, since a
+//! single IPv6 host usually has a whole /64 at its disposal.
+//!
+//! Just like [`crate::user_tracker`], the tracker hands out handles that automatically release the connection from
+//! the counts when dropped, so no code path can forget to do so.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    net::{IpAddr, Ipv6Addr},
+    sync::{Arc, Mutex},
+};
+
+/// The amount of leading bits of an IPv6 address by which connections are grouped.
+const IPV6_PREFIX_BITS: u32 = 64;
+
+/// The limits enforced by a [`ConnectionTracker`]. [`None`] means no limit.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ConnectionLimits {
+    pub max_total: Option<usize>,
+    pub max_per_ip: Option<usize>,
+    pub max_unauthenticated: Option<usize>,
+}
+
+/// A snapshot of a [`ConnectionTracker`]'s counters.
+#[derive(Debug, Clone, Copy)]
+pub struct ConnectionCounts {
+    pub total: usize,
+    pub unauthenticated: usize,
+    pub distinct_ips: usize,
+}
+
+impl fmt::Display for ConnectionCounts {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} active, {} unauthenticated, from {} addresses",
+            self.total, self.unauthenticated, self.distinct_ips
+        )
+    }
+}
+
+/// The limit that a [`ConnectionTracker`] rejected a new connection for exceeding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionLimitExceeded {
+    Total,
+    PerIp,
+    Unauthenticated,
+}
+
+impl ConnectionLimitExceeded {
+    pub const fn get_reason_str(self) -> &'static str {
+        match self {
+            Self::Total => "Too many connections, try again later",
+            Self::PerIp => "Too many connections from your address, try again later",
+            Self::Unauthenticated => "Too many unauthenticated connections, try again later",
+        }
+    }
+}
+
+/// A connection tracker. Read the [`crate::connection_tracker`] module's documentation for more information.
+///
+/// This is a reference type which may be cloned to create multiple references to the same state, and may be shared
+/// across threads.
+#[derive(Clone)]
+pub struct ConnectionTracker {
+    inner: Arc<Mutex<InnerTracker>>,
+}
+
+struct InnerTracker {
+    limits: ConnectionLimits,
+    total: usize,
+    unauthenticated: usize,
+    per_ip: HashMap<IpAddr, usize>,
+}
+
+impl ConnectionTracker {
+    /// Creates a new [`ConnectionTracker`] with no connections that enforces the given limits.
+    pub fn new(limits: ConnectionLimits) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(InnerTracker {
+                limits,
+                total: 0,
+                unauthenticated: 0,
+                per_ip: HashMap::new(),
+            })),
+        }
+    }
+
+    /// Attempts to register a new unauthenticated connection from the given address. Returns [`Ok`] with the
+    /// connection's handle on success, or [`Err`] with the reason why the connection was rejected if it would go over
+    /// any of the limits.
+    pub fn try_register(&self, address: IpAddr) -> Result<ConnectionHandle, ConnectionLimitExceeded> {
+        let ip_prefix = get_ip_prefix(address);
+        let mut guard = self.inner.lock().unwrap();
+        let limits = guard.limits;
+
+        if limits.max_total.is_some_and(|max| guard.total >= max) {
+            return Err(ConnectionLimitExceeded::Total);
+        }
+
+        if limits.max_unauthenticated.is_some_and(|max| guard.unauthenticated >= max) {
+            return Err(ConnectionLimitExceeded::Unauthenticated);
+        }
+
+        let ip_count = guard.per_ip.get(&ip_prefix).copied().unwrap_or(0);
+        if limits.max_per_ip.is_some_and(|max| ip_count >= max) {
+            return Err(ConnectionLimitExceeded::PerIp);
+        }
+
+        guard.total += 1;
+        guard.unauthenticated += 1;
+        guard.per_ip.insert(ip_prefix, ip_count + 1);
+
+        Ok(ConnectionHandle {
+            tracker: self.clone(),
+            ip_prefix,
+            authenticated: false,
+        })
+    }
+
+    /// Replaces the limits enforced on new connections. Connections that are already open are not affected.
+    pub fn set_limits(&self, limits: ConnectionLimits) {
+        self.inner.lock().unwrap().limits = limits;
+    }
+
+    /// Gets a snapshot of this tracker's counters.
+    pub fn counts(&self) -> ConnectionCounts {
+        let guard = self.inner.lock().unwrap();
+        ConnectionCounts {
+            total: guard.total,
+            unauthenticated: guard.unauthenticated,
+            distinct_ips: guard.per_ip.len(),
+        }
+    }
+}
+
+/// Gets the prefix by which connections from the given address are grouped.
+fn get_ip_prefix(address: IpAddr) -> IpAddr {
+    match address.to_canonical() {
+        IpAddr::V4(v4) => IpAddr::V4(v4),
+        IpAddr::V6(v6) => {
+            let mask = u128::MAX << (128 - IPV6_PREFIX_BITS);
+            IpAddr::V6(Ipv6Addr::from_bits(v6.to_bits() & mask))
+        }
+    }
+}
+
+/// Represents an open connection in a [`ConnectionTracker`]. The connection is automatically removed from the tracker
+/// once this handle is dropped.
+pub struct ConnectionHandle {
+    tracker: ConnectionTracker,
+    ip_prefix: IpAddr,
+    authenticated: bool,
+}
+
+impl ConnectionHandle {
+    /// Marks this connection as authenticated, so it no longer counts towards the unauthenticated connections limit.
+    pub fn set_authenticated(&mut self) {
+        if !self.authenticated {
+            self.authenticated = true;
+            self.tracker.inner.lock().unwrap().unauthenticated -= 1;
+        }
+    }
+}
+
+impl Drop for ConnectionHandle {
+    fn drop(&mut self) {
+        let mut guard = self.tracker.inner.lock().unwrap();
+        guard.total -= 1;
+        if !self.authenticated {
+            guard.unauthenticated -= 1;
+        }
+
+        if let Some(count) = guard.per_ip.get_mut(&self.ip_prefix) {
+            *count -= 1;
+            if *count == 0 {
+                guard.per_ip.remove(&self.ip_prefix);
+            }
+        }
+    }
+}
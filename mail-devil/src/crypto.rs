@@ -0,0 +1,327 @@
+//! Encryption at rest for message files.
+//!
+//! Each user with encryption enabled has a random 256-bit message key, which is stored in an `encryption-key` file in
+//! their maildir wrapped (encrypted) with the server's [`ServerKey`]. The server key is kept in a file outside the
+//! maildirs, so access to a maildir alone isn't enough to read its messages. A user's message key is only unwrapped
+//! once they have logged in, and is kept in memory only for as long as their session lasts.
+//!
+//! An encrypted message file starts with [`ENCRYPTED_MESSAGE_MAGIC`] followed by a 7-byte nonce prefix, and then the
+//! message encrypted with ChaCha20-Poly1305 using the STREAM construction, split in chunks of
+//! [`PLAINTEXT_CHUNK_SIZE`] bytes, each followed by its 16-byte authentication tag. Encrypted and plaintext message
+//! files may coexist within the same maildir; [`crate::storage::open_message`] tells them apart by their magic bytes.
+
+use std::{
+    io::{self, ErrorKind},
+    path::Path,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+
+use chacha20poly1305::{
+    aead::{
+        rand_core::RngCore,
+        stream::{DecryptorBE32, EncryptorBE32},
+        Aead, AeadCore, KeyInit, OsRng,
+    },
+    ChaCha20Poly1305, Key, Nonce,
+};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWriteExt, BufReader, ReadBuf};
+
+use crate::types::{ENCRYPTION_KEY_FILE_NAME, MAILDIR_NEW_FOLDER, MAILDIR_OLD_FOLDER, MAILDIR_RETAINED_FOLDER, MAILDIR_TMP_FOLDER};
+use crate::util::ownership;
+
+/// The magic bytes at the start of an encrypted message file.
+pub const ENCRYPTED_MESSAGE_MAGIC: &[u8] = b"MDEVENC1";
+
+/// The magic bytes at the start of an `encryption-key` file.
+const KEY_FILE_MAGIC: &[u8] = b"MDEVKEY2";
+
+/// The magic bytes at the start of a server key file.
+const SERVER_KEY_FILE_MAGIC: &[u8] = b"MDEVSRV1";
+
+const KEY_LENGTH: usize = 32;
+const NONCE_LENGTH: usize = 12;
+const TAG_LENGTH: usize = 16;
+const KEY_FILE_LENGTH: usize = KEY_FILE_MAGIC.len() + NONCE_LENGTH + KEY_LENGTH + TAG_LENGTH;
+const SERVER_KEY_FILE_LENGTH: usize = SERVER_KEY_FILE_MAGIC.len() + KEY_LENGTH;
+
+/// The STREAM construction uses 5 bytes of the nonce for its counter and last-chunk flag.
+const STREAM_NONCE_PREFIX_LENGTH: usize = NONCE_LENGTH - 5;
+
+/// The size of each plaintext chunk in an encrypted message. The last chunk may be shorter.
+pub const PLAINTEXT_CHUNK_SIZE: usize = 0x10000;
+const CIPHERTEXT_CHUNK_SIZE: usize = PLAINTEXT_CHUNK_SIZE + TAG_LENGTH;
+
+/// A user's message key, used for encrypting and decrypting their message files.
+#[derive(Clone)]
+pub struct MessageKey(Key);
+
+/// The server's key, which wraps the message keys of all the users with encryption enabled.
+///
+/// It should be kept outside the maildirs, readable only by the server, since anyone holding it can read the messages
+/// of every user.
+#[derive(Clone)]
+pub struct ServerKey(Key);
+
+impl ServerKey {
+    /// Creates a server key from its raw bytes.
+    pub fn from_bytes(bytes: [u8; KEY_LENGTH]) -> Self {
+        Self(bytes.into())
+    }
+
+    /// Loads the server key from the given file, first creating the file with a new random key if it doesn't exist.
+    pub async fn load_or_create(path: &Path) -> io::Result<Self> {
+        match tokio::fs::read(path).await {
+            Ok(contents) if contents.len() == SERVER_KEY_FILE_LENGTH && contents.starts_with(SERVER_KEY_FILE_MAGIC) => {
+                Ok(Self(*Key::from_slice(&contents[SERVER_KEY_FILE_MAGIC.len()..])))
+            }
+            Ok(_) => Err(invalid_data("Malformed server key file")),
+            Err(error) if error.kind() == ErrorKind::NotFound => {
+                let key = ChaCha20Poly1305::generate_key(&mut OsRng);
+                let mut contents = Vec::with_capacity(SERVER_KEY_FILE_LENGTH);
+                contents.extend_from_slice(SERVER_KEY_FILE_MAGIC);
+                contents.extend_from_slice(key.as_slice());
+
+                // The file is created only readable by its owner, and never overwritten if it appeared in the meantime.
+                let mut options = tokio::fs::OpenOptions::new();
+                options.write(true).create_new(true);
+                #[cfg(unix)]
+                options.mode(0o600);
+                let mut file = options.open(path).await?;
+                file.write_all(&contents).await?;
+                file.sync_all().await?;
+                Ok(Self(key))
+            }
+            Err(error) => Err(error),
+        }
+    }
+}
+
+fn invalid_data(message: &'static str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, message)
+}
+
+/// Loads the message key of the user whose maildir is at the given path, unwrapping it with the server's key.
+///
+/// Returns [`Ok`] with [`None`] if the user doesn't have encryption enabled, or an error if they do but no server key
+/// was given.
+pub async fn load_user_key(maildir: &Path, server_key: Option<&ServerKey>) -> io::Result<Option<MessageKey>> {
+    let contents = match tokio::fs::read(maildir.join(ENCRYPTION_KEY_FILE_NAME)).await {
+        Ok(c) => c,
+        Err(error) if error.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(error) => return Err(error),
+    };
+
+    if contents.len() != KEY_FILE_LENGTH || !contents.starts_with(KEY_FILE_MAGIC) {
+        return Err(invalid_data("Malformed encryption key file"));
+    }
+
+    let server_key = server_key.ok_or_else(|| io::Error::other("User has encryption enabled but there is no server key"))?;
+    let (nonce, wrapped_key) = contents[KEY_FILE_MAGIC.len()..].split_at(NONCE_LENGTH);
+    let key = ChaCha20Poly1305::new(&server_key.0)
+        .decrypt(Nonce::from_slice(nonce), wrapped_key)
+        .map_err(|_| invalid_data("Could not unwrap encryption key, the server key may have changed"))?;
+
+    Ok(Some(MessageKey(*Key::from_slice(&key))))
+}
+
+/// Writes a user's `encryption-key` file, wrapping the message key with the server's key.
+async fn write_user_key(maildir: &Path, key: &MessageKey, server_key: &ServerKey) -> io::Result<()> {
+    let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let wrapped_key = ChaCha20Poly1305::new(&server_key.0)
+        .encrypt(&nonce, key.0.as_slice())
+        .map_err(|_| io::Error::other("Could not wrap encryption key"))?;
+
+    let mut contents = Vec::with_capacity(KEY_FILE_LENGTH);
+    contents.extend_from_slice(KEY_FILE_MAGIC);
+    contents.extend_from_slice(&nonce);
+    contents.extend_from_slice(&wrapped_key);
+
+    // Write to a temporary file first and then rename it, so a crash can't leave the key lost or half-written.
+    let path = maildir.join(ENCRYPTION_KEY_FILE_NAME);
+    let tmp_path = maildir.join(format!("{ENCRYPTION_KEY_FILE_NAME}.tmp"));
+    let mut file = tokio::fs::File::create(&tmp_path).await?;
+    file.write_all(&contents).await?;
+    file.sync_all().await?;
+    drop(file);
+    ownership::set_owner(&tmp_path, ownership::maildir_owner(maildir).await).await?;
+    tokio::fs::rename(&tmp_path, &path).await
+}
+
+/// Encrypts all the plaintext messages in a user's maildir in place, first enabling encryption for the user if it
+/// isn't already enabled.
+///
+/// Returns [`Ok`] with the amount of messages that were encrypted.
+pub async fn encrypt_maildir(maildir: &Path, server_key: &ServerKey) -> io::Result<u64> {
+    let key = match load_user_key(maildir, Some(server_key)).await? {
+        Some(key) => key,
+        None => {
+            let key = MessageKey(ChaCha20Poly1305::generate_key(&mut OsRng));
+            write_user_key(maildir, &key, server_key).await?;
+            key
+        }
+    };
+
+    let tmp_dir = maildir.join(MAILDIR_TMP_FOLDER);
+    ownership::create_dir(&tmp_dir, ownership::maildir_owner(maildir).await).await?;
+
+    let mut count = 0;
+    let retained_dir = Path::new(MAILDIR_RETAINED_FOLDER).join(MAILDIR_OLD_FOLDER);
+    for folder in [Path::new(MAILDIR_NEW_FOLDER), Path::new(MAILDIR_OLD_FOLDER), &retained_dir] {
+        let mut directory_reader = match tokio::fs::read_dir(maildir.join(folder)).await {
+            Ok(d) => d,
+            Err(error) if error.kind() == ErrorKind::NotFound => continue,
+            Err(error) => return Err(error),
+        };
+
+        while let Some(dir_entry) = directory_reader.next_entry().await? {
+            if dir_entry.file_type().await?.is_file() && encrypt_message_file(&dir_entry.path(), &tmp_dir, &key).await? {
+                count += 1;
+            }
+        }
+    }
+
+    Ok(count)
+}
+
+/// Encrypts a message file in place, by writing its encrypted version to `tmp_dir` and then renaming it over the
+/// original.
+///
+/// Returns [`Ok`] with `false` if the file was already encrypted.
+async fn encrypt_message_file(path: &Path, tmp_dir: &Path, key: &MessageKey) -> io::Result<bool> {
+    let file = tokio::fs::File::open(path).await?;
+    let metadata = file.metadata().await?;
+    let mut reader = BufReader::with_capacity(PLAINTEXT_CHUNK_SIZE, file);
+    if reader.fill_buf().await?.starts_with(ENCRYPTED_MESSAGE_MAGIC) {
+        return Ok(false);
+    }
+
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| io::Error::new(ErrorKind::InvalidInput, "Path has no file name"))?;
+    let tmp_path = tmp_dir.join(file_name);
+    let mut writer = tokio::fs::File::create(&tmp_path).await?;
+
+    let mut nonce_prefix = [0u8; STREAM_NONCE_PREFIX_LENGTH];
+    OsRng.fill_bytes(&mut nonce_prefix);
+    writer.write_all(ENCRYPTED_MESSAGE_MAGIC).await?;
+    writer.write_all(&nonce_prefix).await?;
+
+    let mut encryptor = EncryptorBE32::from_aead(ChaCha20Poly1305::new(&key.0), nonce_prefix.as_slice().into());
+    let mut chunk = vec![0u8; PLAINTEXT_CHUNK_SIZE];
+    loop {
+        let mut chunk_len = 0;
+        while chunk_len < chunk.len() {
+            match reader.read(&mut chunk[chunk_len..]).await? {
+                0 => break,
+                n => chunk_len += n,
+            }
+        }
+
+        // A chunk is the last one if there's nothing left to read after it.
+        if reader.fill_buf().await?.is_empty() {
+            let ciphertext = encryptor
+                .encrypt_last(&chunk[..chunk_len])
+                .map_err(|_| io::Error::other("Could not encrypt message"))?;
+            writer.write_all(&ciphertext).await?;
+            break;
+        }
+
+        let ciphertext = encryptor
+            .encrypt_next(chunk.as_slice())
+            .map_err(|_| io::Error::other("Could not encrypt message"))?;
+        writer.write_all(&ciphertext).await?;
+    }
+
+    writer.sync_all().await?;
+    drop(writer);
+
+    // The encrypted file replaces the original, so it keeps the original's permissions and owner.
+    tokio::fs::set_permissions(&tmp_path, metadata.permissions()).await?;
+    ownership::set_owner(&tmp_path, ownership::owner_of(&metadata)).await?;
+    tokio::fs::rename(&tmp_path, path).await?;
+    Ok(true)
+}
+
+/// An [`AsyncRead`] that decrypts an encrypted message as it's read from an inner reader.
+pub struct DecryptingReader<R> {
+    inner: R,
+    inner_ended: bool,
+    decryptor: Option<DecryptorBE32<ChaCha20Poly1305>>,
+    ciphertext: Box<[u8]>,
+    ciphertext_len: usize,
+    plaintext: Vec<u8>,
+    plaintext_pos: usize,
+}
+
+impl<R: AsyncRead + Unpin> DecryptingReader<R> {
+    /// Creates a [`DecryptingReader`] by reading an encrypted message's header from the given reader.
+    pub async fn new(mut inner: R, key: &MessageKey) -> io::Result<Self> {
+        let mut header = [0u8; ENCRYPTED_MESSAGE_MAGIC.len() + STREAM_NONCE_PREFIX_LENGTH];
+        inner.read_exact(&mut header).await?;
+        if !header.starts_with(ENCRYPTED_MESSAGE_MAGIC) {
+            return Err(invalid_data("Message file is not encrypted"));
+        }
+
+        let nonce_prefix = &header[ENCRYPTED_MESSAGE_MAGIC.len()..];
+        let decryptor = DecryptorBE32::from_aead(ChaCha20Poly1305::new(&key.0), nonce_prefix.into());
+
+        Ok(Self {
+            inner,
+            inner_ended: false,
+            decryptor: Some(decryptor),
+            // One extra byte is needed to tell whether a full-sized chunk is the last one.
+            ciphertext: vec![0u8; CIPHERTEXT_CHUNK_SIZE + 1].into_boxed_slice(),
+            ciphertext_len: 0,
+            plaintext: Vec::new(),
+            plaintext_pos: 0,
+        })
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for DecryptingReader<R> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        loop {
+            if this.plaintext_pos < this.plaintext.len() {
+                let available = &this.plaintext[this.plaintext_pos..];
+                let count = available.len().min(buf.remaining());
+                buf.put_slice(&available[..count]);
+                this.plaintext_pos += count;
+                return Poll::Ready(Ok(()));
+            }
+
+            if this.decryptor.is_none() {
+                return Poll::Ready(Ok(()));
+            }
+
+            while !this.inner_ended && this.ciphertext_len < this.ciphertext.len() {
+                let mut read_buf = ReadBuf::new(&mut this.ciphertext[this.ciphertext_len..]);
+                ready!(Pin::new(&mut this.inner).poll_read(cx, &mut read_buf))?;
+                match read_buf.filled().len() {
+                    0 => this.inner_ended = true,
+                    n => this.ciphertext_len += n,
+                }
+            }
+
+            let decryption_result = if this.ciphertext_len > CIPHERTEXT_CHUNK_SIZE {
+                let result = this
+                    .decryptor
+                    .as_mut()
+                    .unwrap()
+                    .decrypt_next(&this.ciphertext[..CIPHERTEXT_CHUNK_SIZE]);
+                this.ciphertext.copy_within(CIPHERTEXT_CHUNK_SIZE..this.ciphertext_len, 0);
+                this.ciphertext_len -= CIPHERTEXT_CHUNK_SIZE;
+                result
+            } else {
+                let result = this.decryptor.take().unwrap().decrypt_last(&this.ciphertext[..this.ciphertext_len]);
+                this.ciphertext_len = 0;
+                result
+            };
+
+            this.plaintext = decryption_result.map_err(|_| invalid_data("Message file failed decryption"))?;
+            this.plaintext_pos = 0;
+        }
+    }
+}
@@ -0,0 +1,215 @@
+//! Policies for what to do with the messages whose deletion is committed when a client QUITs, as well as the background
+//! task that purges messages once they have been retained for long enough.
+
+use std::{
+    fmt,
+    fs::FileTimes,
+    io::{self, ErrorKind},
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::{Duration, SystemTime},
+};
+
+use tracing::{error, info};
+
+use crate::auth::MaildirOwner;
+use crate::quota;
+use crate::types::{MAILDIR_NEW_FOLDER, MAILDIR_OLD_FOLDER, MAILDIR_RETAINED_FOLDER, MAILDIR_TMP_FOLDER, MAILDIR_TRASH_FOLDER};
+use crate::util::ownership;
+
+/// How often the retained messages purge task runs.
+const PURGE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// Specifies what happens to a message once its deletion is committed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeletionPolicy {
+    /// The message's file is removed immediately.
+    Unlink,
+
+    /// The message is moved to the maildir's Maildir++ `.Trash` folder.
+    Trash,
+
+    /// The message is moved to the maildir's Maildir++ `.Retained` folder, where it's hidden from clients, and if a
+    /// number of days is specified then it is purged once that many days have passed since its deletion.
+    Retain(Option<u32>),
+}
+
+impl Default for DeletionPolicy {
+    fn default() -> Self {
+        Self::Retain(None)
+    }
+}
+
+pub struct InvalidDeletionPolicyError;
+
+impl FromStr for DeletionPolicy {
+    type Err = InvalidDeletionPolicyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let (name, days) = match s.split_once(':') {
+            Some((name, days)) => (name, Some(days.trim().parse::<u32>().map_err(|_| InvalidDeletionPolicyError)?)),
+            None => (s, None),
+        };
+
+        match (name, days) {
+            (n, None) if n.eq_ignore_ascii_case("unlink") => Ok(Self::Unlink),
+            (n, None) if n.eq_ignore_ascii_case("trash") => Ok(Self::Trash),
+            (n, days) if n.eq_ignore_ascii_case("retain") => Ok(Self::Retain(days)),
+            _ => Err(InvalidDeletionPolicyError),
+        }
+    }
+}
+
+impl fmt::Display for DeletionPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Unlink => write!(f, "unlink"),
+            Self::Trash => write!(f, "trash"),
+            Self::Retain(None) => write!(f, "retain"),
+            Self::Retain(Some(days)) => write!(f, "retain:{days}"),
+        }
+    }
+}
+
+impl DeletionPolicy {
+    /// Ensures the folder into which this policy moves deleted messages exists within the given maildir, giving any
+    /// folders it creates to the maildir's owner.
+    ///
+    /// Returns [`Ok`] with the folder's path, or [`None`] if this policy doesn't move messages.
+    pub async fn prepare_destination(self, maildrop_dir: &Path, owner: Option<MaildirOwner>) -> io::Result<Option<PathBuf>> {
+        let folder_dir = match self {
+            Self::Unlink => return Ok(None),
+            Self::Trash => maildrop_dir.join(MAILDIR_TRASH_FOLDER),
+            Self::Retain(_) => maildrop_dir.join(MAILDIR_RETAINED_FOLDER),
+        };
+
+        ownership::create_dir(&folder_dir, owner).await?;
+        ownership::create_dir(&folder_dir.join(MAILDIR_NEW_FOLDER), owner).await?;
+        ownership::create_dir(&folder_dir.join(MAILDIR_TMP_FOLDER), owner).await?;
+        let destination = folder_dir.join(MAILDIR_OLD_FOLDER);
+        ownership::create_dir(&destination, owner).await?;
+        Ok(Some(destination))
+    }
+
+    /// Removes a message according to this policy. `destination` must be the value returned by
+    /// [`DeletionPolicy::prepare_destination`].
+    pub async fn remove_message(self, message_path: &Path, destination: Option<&Path>) -> io::Result<()> {
+        let destination = match destination {
+            Some(d) => d,
+            None => return tokio::fs::remove_file(message_path).await,
+        };
+
+        let file_name = message_path
+            .file_name()
+            .ok_or_else(|| io::Error::new(ErrorKind::InvalidInput, "Message path has no file name"))?;
+
+        // Retained messages are purged based on their modification time, so it's set to when they were deleted. This is
+        // done before moving the message, so that it's never retained with an older modification time and purged early.
+        if let Self::Retain(Some(_)) = self {
+            let path = message_path.to_path_buf();
+            tokio::task::spawn_blocking(move || {
+                let file = std::fs::File::options().write(true).open(path)?;
+                file.set_times(FileTimes::new().set_modified(SystemTime::now()))
+            })
+            .await
+            .map_err(io::Error::other)??;
+        }
+
+        tokio::fs::rename(message_path, destination.join(file_name)).await
+    }
+}
+
+/// Runs forever, periodically purging the messages in every maildir's `.Retained` folder that were retained over
+/// `retention_days` days ago. Only that folder is looked at, so messages elsewhere in the maildirs are never purged.
+pub async fn purge_retained_messages_task(maildirs_dir: PathBuf, retention_days: u32) {
+    let max_age = Duration::from_secs(retention_days as u64 * SECONDS_PER_DAY);
+    let mut interval = tokio::time::interval(PURGE_INTERVAL);
+
+    loop {
+        interval.tick().await;
+        match purge_retained_messages(&maildirs_dir, max_age).await {
+            Ok(0) => {}
+            Ok(count) => info!("Purged {count} retained messages older than {retention_days} days"),
+            Err(error) => error!("Error while purging retained messages in {}: {error}", maildirs_dir.display()),
+        }
+    }
+}
+
+/// Purges the messages in every maildir's `.Retained` folder that were retained over `max_age` ago, returning how many
+/// were purged. The purged messages are recorded as removed in each maildir's `maildirsize` file.
+pub async fn purge_retained_messages(maildirs_dir: &Path, max_age: Duration) -> io::Result<u64> {
+    let now = SystemTime::now();
+    let mut count = 0;
+
+    // Errors on a single maildir or message are logged and skipped, so they don't stop the purge of the rest.
+    let mut maildirs_reader = tokio::fs::read_dir(maildirs_dir).await?;
+    loop {
+        let maildir_entry = match maildirs_reader.next_entry().await {
+            Ok(Some(entry)) => entry,
+            Ok(None) => break,
+            Err(error) => {
+                error!("Could not list maildirs in {}: {error}", maildirs_dir.display());
+                break;
+            }
+        };
+
+        let maildir = maildir_entry.path();
+        let retained_dir = maildir.join(MAILDIR_RETAINED_FOLDER).join(MAILDIR_OLD_FOLDER);
+        let mut retained_reader = match tokio::fs::read_dir(&retained_dir).await {
+            Ok(r) => r,
+            Err(error) if matches!(error.kind(), ErrorKind::NotFound | ErrorKind::NotADirectory) => continue,
+            Err(error) => {
+                error!("Could not read retained messages folder {}: {error}", retained_dir.display());
+                continue;
+            }
+        };
+
+        let mut purged_bytes = 0;
+        let mut purged_messages = 0;
+        loop {
+            let message_entry = match retained_reader.next_entry().await {
+                Ok(Some(entry)) => entry,
+                Ok(None) => break,
+                Err(error) => {
+                    error!("Could not list retained messages folder {}: {error}", retained_dir.display());
+                    break;
+                }
+            };
+
+            let metadata = match message_entry.metadata().await {
+                Ok(metadata) => metadata,
+                Err(error) => {
+                    error!(
+                        "Could not get metadata of retained message {}: {error}",
+                        message_entry.path().display()
+                    );
+                    continue;
+                }
+            };
+
+            let age = metadata.modified().ok().and_then(|t| now.duration_since(t).ok());
+            if !metadata.is_file() || age.is_none_or(|age| age < max_age) {
+                continue;
+            }
+
+            let path = message_entry.path();
+            match tokio::fs::remove_file(&path).await {
+                Ok(()) => {
+                    purged_bytes += metadata.len();
+                    purged_messages += 1;
+                }
+                Err(error) => error!("Could not purge retained message {}: {error}", path.display()),
+            }
+        }
+
+        count += purged_messages;
+        if let Err(error) = quota::record_removal(&maildir, purged_bytes, purged_messages).await {
+            error!("Could not update quota usage file in {}: {error}", maildir.display());
+        }
+    }
+
+    Ok(count)
+}
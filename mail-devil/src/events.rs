@@ -0,0 +1,143 @@
+//! Events describing what happens in the server's sessions, which are broadcast to anyone subscribed to them through
+//! [`crate::state::Pop3ServerState::subscribe_events`].
+//!
+//! Events are delivered through a bounded broadcast channel, so emitting one never waits on subscribers. A subscriber
+//! that falls too far behind misses the oldest events, and is told how many it missed. Besides the administrative
+//! interface, events are delivered to the built-in subscribers in [`crate::hooks`].
+//!
+//! Each event has a name and a list of named fields, which subscribers use to present it in whichever format they need.
+
+use std::{fmt, net::SocketAddr, time::Duration};
+
+use crate::{
+    state::LoginUserError,
+    types::{MessageNumber, MessageNumberCount, Pop3Username},
+};
+
+/// How many events are buffered for subscribers that haven't received them yet.
+pub const EVENT_CHANNEL_CAPACITY: usize = 1024;
+
+#[derive(Debug, Clone)]
+pub enum ServerEvent {
+    SessionOpened {
+        session_id: u64,
+        peer: SocketAddr,
+    },
+    LoginSucceeded {
+        session_id: u64,
+        username: Pop3Username,
+    },
+    LoginFailed {
+        session_id: u64,
+        username: Pop3Username,
+        reason: LoginUserError,
+    },
+    MessageRetrieved {
+        session_id: u64,
+        username: Pop3Username,
+        message_number: MessageNumber,
+        bytes: u64,
+    },
+    DeletionsCommitted {
+        session_id: u64,
+        username: Pop3Username,
+        count: MessageNumberCount,
+    },
+    SessionKicked {
+        session_id: u64,
+    },
+    SessionClosed {
+        session_id: u64,
+        duration: Duration,
+    },
+}
+
+impl ServerEvent {
+    /// The event's name, in snake case.
+    pub const fn name(&self) -> &'static str {
+        match self {
+            Self::SessionOpened { .. } => "session_opened",
+            Self::LoginSucceeded { .. } => "login_succeeded",
+            Self::LoginFailed { .. } => "login_failed",
+            Self::MessageRetrieved { .. } => "message_retrieved",
+            Self::DeletionsCommitted { .. } => "deletions_committed",
+            Self::SessionKicked { .. } => "session_kicked",
+            Self::SessionClosed { .. } => "session_closed",
+        }
+    }
+
+    /// The event's fields, each with its name in snake case.
+    pub fn fields(&self) -> Vec<(&'static str, EventField)> {
+        use EventField::{Decimal, Number, Text};
+
+        match self {
+            Self::SessionOpened { session_id, peer } => vec![("session_id", Number(*session_id)), ("peer", Text(peer.to_string()))],
+            Self::LoginSucceeded { session_id, username } => {
+                vec![("session_id", Number(*session_id)), ("user", Text(username.to_string()))]
+            }
+            Self::LoginFailed {
+                session_id,
+                username,
+                reason,
+            } => vec![
+                ("session_id", Number(*session_id)),
+                ("user", Text(username.to_string())),
+                ("reason", Text(reason.get_label().to_string())),
+            ],
+            Self::MessageRetrieved {
+                session_id,
+                username,
+                message_number,
+                bytes,
+            } => vec![
+                ("session_id", Number(*session_id)),
+                ("user", Text(username.to_string())),
+                ("message_number", Number(message_number.get() as u64)),
+                ("bytes", Number(*bytes)),
+            ],
+            Self::DeletionsCommitted {
+                session_id,
+                username,
+                count,
+            } => vec![
+                ("session_id", Number(*session_id)),
+                ("user", Text(username.to_string())),
+                ("count", Number(*count as u64)),
+            ],
+            Self::SessionKicked { session_id } => vec![("session_id", Number(*session_id))],
+            Self::SessionClosed { session_id, duration } => {
+                vec![("session_id", Number(*session_id)), ("duration", Decimal(duration.as_secs_f64()))]
+            }
+        }
+    }
+}
+
+/// Formats the event as its name followed by its fields, such as `session_opened session_id=1 peer=127.0.0.1:50000`.
+impl fmt::Display for ServerEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())?;
+        for (name, value) in self.fields() {
+            write!(f, " {name}={value}")?;
+        }
+
+        Ok(())
+    }
+}
+
+/// The value of one of an event's fields.
+#[derive(Debug, Clone, PartialEq)]
+pub enum EventField {
+    Number(u64),
+    Decimal(f64),
+    Text(String),
+}
+
+impl fmt::Display for EventField {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Number(number) => write!(f, "{number}"),
+            Self::Decimal(decimal) => write!(f, "{decimal:.3}"),
+            Self::Text(text) => write!(f, "{text}"),
+        }
+    }
+}
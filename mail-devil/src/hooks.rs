@@ -0,0 +1,178 @@
+//! Built-in subscribers to the server's events (see [`crate::events`]), which let administrators run their own code
+//! whenever something happens in a session.
+//!
+//! An event command is an external program that's run once for every event, with the event's name in the
+//! `MAIL_DEVIL_EVENT` environment variable and each of its fields in a `MAIL_DEVIL_<FIELD>` variable, such as
+//! `MAIL_DEVIL_SESSION_ID` or `MAIL_DEVIL_USER`. Events are handled one at a time and in order, so a slow command
+//! delays the ones after it, and if it falls too far behind the oldest events are skipped. Commands that take longer
+//! than [`COMMAND_TIMEOUT`] are killed.
+//!
+//! An event log is a file to which every event is appended as a JSON object on its own line, holding a `timestamp`,
+//! the event's name as `event`, and its fields. The file is written to asynchronously and flushed whenever there are no
+//! more events waiting, so a slow disk delays the log but never the sessions.
+
+use std::{
+    io,
+    path::{Path, PathBuf},
+    process::Stdio,
+    time::Duration,
+};
+
+use serde_json::{Map, Number, Value};
+use tokio::{
+    fs::OpenOptions,
+    io::{AsyncWriteExt, BufWriter},
+    process::Command,
+    sync::broadcast::{self, error::RecvError},
+    task::{AbortHandle, JoinHandle},
+};
+use tracing::{error, warn};
+use tracing_subscriber::fmt::{format::Writer, time::FormatTime};
+
+use crate::{
+    events::{EventField, ServerEvent},
+    state::Pop3ServerState,
+};
+
+/// How long an event command may run before it's killed.
+pub const COMMAND_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long to wait on shutdown for the hooks to handle the events that are left.
+const FINISH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The prefix of the environment variables passed to event commands.
+const ENV_PREFIX: &str = "MAIL_DEVIL_";
+
+/// The programs to run and files to append to for every event.
+#[derive(Debug, Default)]
+pub struct HookSettings {
+    pub commands: Vec<PathBuf>,
+    pub logs: Vec<PathBuf>,
+}
+
+/// Subscribes the hooks in the given settings to the server's events, returning the tasks running them.
+pub fn spawn_hooks(settings: &HookSettings, state: &Pop3ServerState) -> Vec<JoinHandle<()>> {
+    let mut tasks = Vec::new();
+
+    for program in &settings.commands {
+        tasks.push(tokio::spawn(run_event_command(program.clone(), state.subscribe_events())));
+    }
+
+    for path in &settings.logs {
+        tasks.push(tokio::spawn(write_event_log(path.clone(), state.subscribe_events())));
+    }
+
+    tasks
+}
+
+/// Waits for the hooks to handle the events left once the server's event channel is closed, aborting them if they take
+/// too long.
+pub async fn finish_hooks(tasks: Vec<JoinHandle<()>>) {
+    let abort_handles: Vec<_> = tasks.iter().map(JoinHandle::abort_handle).collect();
+    let result = tokio::time::timeout(FINISH_TIMEOUT, async {
+        for task in tasks {
+            let _ = task.await;
+        }
+    })
+    .await;
+
+    if result.is_err() {
+        warn!("Event hooks took too long to handle the remaining events, aborting them");
+        abort_handles.iter().for_each(AbortHandle::abort);
+    }
+}
+
+/// Waits for the next event, logging how many were missed if the subscriber fell behind. Returns [`None`] once no more
+/// events can be sent.
+async fn next_event(events: &mut broadcast::Receiver<ServerEvent>, hook: &str, target: &Path) -> Option<ServerEvent> {
+    loop {
+        match events.recv().await {
+            Ok(event) => return Some(event),
+            Err(RecvError::Lagged(missed)) => warn!("Event {hook} {} fell behind, skipped {missed} events", target.display()),
+            Err(RecvError::Closed) => return None,
+        }
+    }
+}
+
+async fn run_event_command(program: PathBuf, mut events: broadcast::Receiver<ServerEvent>) {
+    while let Some(event) = next_event(&mut events, "command", &program).await {
+        let mut command = Command::new(&program);
+        command
+            .env(format!("{ENV_PREFIX}EVENT"), event.name())
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .kill_on_drop(true);
+
+        for (name, value) in event.fields() {
+            command.env(format!("{ENV_PREFIX}{}", name.to_ascii_uppercase()), value.to_string());
+        }
+
+        let mut child = match command.spawn() {
+            Ok(child) => child,
+            Err(error) => {
+                error!("Could not run event command {} for {}: {error}", program.display(), event.name());
+                continue;
+            }
+        };
+
+        match tokio::time::timeout(COMMAND_TIMEOUT, child.wait()).await {
+            Ok(Ok(status)) if status.success() => {}
+            Ok(Ok(status)) => warn!("Event command {} for {} exited with {status}", program.display(), event.name()),
+            Ok(Err(error)) => error!("Error while waiting for event command {}: {error}", program.display()),
+            Err(_) => warn!("Event command {} for {} timed out and was killed", program.display(), event.name()),
+        }
+    }
+}
+
+async fn write_event_log(path: PathBuf, mut events: broadcast::Receiver<ServerEvent>) {
+    let mut file = match OpenOptions::new().create(true).append(true).open(&path).await {
+        Ok(file) => BufWriter::new(file),
+        Err(error) => {
+            error!("Could not open event log {}: {error}", path.display());
+            return;
+        }
+    };
+
+    while let Some(event) = next_event(&mut events, "log", &path).await {
+        let mut line = event_to_json(&event).to_string();
+        line.push('\n');
+        if let Err(error) = write_event_line(&mut file, &line, events.is_empty()).await {
+            error!("Could not write to event log {}, no longer writing to it: {error}", path.display());
+            return;
+        }
+    }
+
+    if let Err(error) = file.flush().await {
+        error!("Could not write to event log {}: {error}", path.display());
+    }
+}
+
+/// Writes a line to an event log, flushing it if there are no more events waiting to be written.
+async fn write_event_line(file: &mut BufWriter<tokio::fs::File>, line: &str, flush: bool) -> io::Result<()> {
+    file.write_all(line.as_bytes()).await?;
+    match flush {
+        true => file.flush().await,
+        false => Ok(()),
+    }
+}
+
+/// Converts an event into a JSON object holding the current time, the event's name and its fields.
+fn event_to_json(event: &ServerEvent) -> Value {
+    let mut timestamp = String::new();
+    let _ = tracing_subscriber::fmt::time::SystemTime.format_time(&mut Writer::new(&mut timestamp));
+
+    let mut object = Map::new();
+    object.insert("timestamp".to_string(), Value::String(timestamp));
+    object.insert("event".to_string(), Value::String(event.name().to_string()));
+    for (name, value) in event.fields() {
+        let value = match value {
+            EventField::Number(number) => Value::Number(number.into()),
+            EventField::Decimal(decimal) => Number::from_f64(decimal).map_or(Value::Null, Value::Number),
+            EventField::Text(text) => Value::String(text),
+        };
+
+        object.insert(name.to_string(), value);
+    }
+
+    Value::Object(object)
+}
@@ -0,0 +1,55 @@
+//! A POP3 server serving users' maildirs, which may be run on its own with the `mail-devil` binary or embedded in
+//! another program.
+//!
+//! A server is configured with a [`ServerBuilder`] and run with [`Server::run`], which accepts and serves clients until
+//! it's told to shut down through a [`ServerHandle`]:
+//!
+//! ```no_run
+//! use mail_devil::ServerBuilder;
+//!
+//! # async fn example() -> std::io::Result<()> {
+//! let server = ServerBuilder::new()
+//!     .bind("127.0.0.1:1110".parse().unwrap())
+//!     .maildirs_dir("./maildirs")
+//!     .build()
+//!     .await?;
+//!
+//! let handle = server.handle();
+//! tokio::spawn(async move {
+//!     let _ = tokio::signal::ctrl_c().await;
+//!     handle.shutdown();
+//! });
+//!
+//! server.run().await
+//! # }
+//! ```
+//!
+//! The parser for the POP3 protocol's commands is also available in [`pop3::parsers`].
+
+mod admin;
+pub mod args;
+pub mod audit;
+pub mod auth;
+mod config;
+pub mod connection_tracker;
+mod crypto;
+pub mod deletion;
+pub mod events;
+pub mod hooks;
+pub mod logging;
+mod metrics;
+pub mod pop3;
+pub mod quota;
+pub mod server;
+mod session_tracker;
+pub mod state;
+mod storage;
+pub mod syslog;
+pub mod systemd;
+pub mod transcript;
+pub mod types;
+mod user_tracker;
+mod util;
+
+pub use crypto::ServerKey;
+pub use server::{Server, ServerBuilder, ServerHandle, ServerReload};
@@ -0,0 +1,169 @@
+//! Sets up the server's logging, which is built on the `tracing` crate.
+//!
+//! Log messages have a level, and those below the configured level are discarded. Each client connection runs inside
+//! a `session` span holding the session's id, the client's address and, once logged in, the username, so every
+//! message logged while handling a client carries that context.
+//!
+//! Messages are written either as human-readable text or as one JSON object per line. By default warnings and errors
+//! are written to stderr and everything else to stdout, but they may instead all be appended to a log file, sent to
+//! syslog or journald (see the [`crate::syslog`] module), or both.
+
+use std::{
+    fmt,
+    fs::OpenOptions,
+    io::{self, IsTerminal},
+    path::Path,
+    str::FromStr,
+    sync::{Mutex, OnceLock},
+};
+
+use tracing::{level_filters::LevelFilter, Level};
+use tracing_subscriber::{
+    fmt::{writer::MakeWriterExt, MakeWriter},
+    layer::{Layered, SubscriberExt},
+    reload,
+    util::SubscriberInitExt,
+    Layer, Registry,
+};
+
+use crate::syslog::{SyslogFacility, SyslogTarget};
+
+/// The format in which log messages are written.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            s if s.eq_ignore_ascii_case("text") => Ok(Self::Text),
+            s if s.eq_ignore_ascii_case("json") => Ok(Self::Json),
+            _ => Err(()),
+        }
+    }
+}
+
+impl fmt::Display for LogFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Text => write!(f, "text"),
+            Self::Json => write!(f, "json"),
+        }
+    }
+}
+
+/// The handle for changing the level filter after logging was set up.
+static LEVEL_HANDLE: OnceLock<reload::Handle<LevelFilter, Registry>> = OnceLock::new();
+
+/// The configured log level, and whether verbose logging was turned on regardless of it.
+static LEVEL_SETTINGS: Mutex<(LevelFilter, bool)> = Mutex::new((LevelFilter::INFO, false));
+
+/// The subscriber that output layers are added onto, which has already filtered out messages below the log level.
+type FilteredRegistry = Layered<reload::Layer<LevelFilter, Registry>, Registry>;
+
+type BoxedLayer = Box<dyn Layer<FilteredRegistry> + Send + Sync>;
+
+/// Sets up logging with the given level and format, writing to the given file and sending to the given syslog target,
+/// or if neither is specified to stdout and stderr. This may only be called once.
+pub fn init(
+    level: LevelFilter,
+    format: LogFormat,
+    file: Option<&Path>,
+    syslog: Option<&SyslogTarget>,
+    facility: SyslogFacility,
+) -> io::Result<()> {
+    let (level_layer, level_handle) = reload::Layer::new(level);
+
+    let mut layers: Vec<BoxedLayer> = Vec::new();
+    if let Some(path) = file {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        layers.push(output_layer(format, Mutex::new(file), false));
+    }
+
+    if let Some(target) = syslog {
+        layers.push(syslog_layer(target, facility)?);
+    }
+
+    if layers.is_empty() {
+        let writer = io::stderr.with_max_level(Level::WARN).or_else(io::stdout);
+        layers.push(output_layer(format, writer, io::stdout().is_terminal()));
+    }
+
+    tracing_subscriber::registry()
+        .with(level_layer)
+        .with(layers)
+        .try_init()
+        .map_err(io::Error::other)?;
+
+    let _ = LEVEL_HANDLE.set(level_handle);
+    LEVEL_SETTINGS.lock().unwrap().0 = level;
+    Ok(())
+}
+
+/// Creates a layer that formats log messages in the given format and writes them with the given writer.
+fn output_layer<W>(format: LogFormat, writer: W, ansi: bool) -> BoxedLayer
+where
+    W: for<'writer> MakeWriter<'writer> + Send + Sync + 'static,
+{
+    let layer = tracing_subscriber::fmt::layer()
+        .with_writer(writer)
+        .with_ansi(ansi)
+        .with_target(false);
+    match format {
+        LogFormat::Text => layer.boxed(),
+        LogFormat::Json => layer.json().with_current_span(true).with_span_list(false).boxed(),
+    }
+}
+
+#[cfg(unix)]
+fn syslog_layer(target: &SyslogTarget, facility: SyslogFacility) -> io::Result<BoxedLayer> {
+    let layer = crate::syslog::SyslogLayer::new(target, facility)
+        .map_err(|error| io::Error::new(error.kind(), format!("Could not connect to {target}: {error}")))?;
+    Ok(layer.boxed())
+}
+
+#[cfg(not(unix))]
+fn syslog_layer(_target: &SyslogTarget, _facility: SyslogFacility) -> io::Result<BoxedLayer> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "Logging to syslog is only supported on unix",
+    ))
+}
+
+/// Changes the level below which log messages are discarded. While verbose logging is turned on, debug messages are
+/// still logged even if the new level is higher.
+pub fn set_level(level: LevelFilter) {
+    let mut settings = LEVEL_SETTINGS.lock().unwrap();
+    settings.0 = level;
+    apply_level(*settings);
+}
+
+/// Turns verbose logging on or off, which logs debug messages regardless of the configured log level.
+pub fn set_verbose(verbose: bool) {
+    let mut settings = LEVEL_SETTINGS.lock().unwrap();
+    settings.1 = verbose;
+    apply_level(*settings);
+}
+
+/// Whether verbose logging is currently turned on with [`set_verbose`].
+pub fn is_verbose() -> bool {
+    LEVEL_SETTINGS.lock().unwrap().1
+}
+
+fn apply_level((level, verbose): (LevelFilter, bool)) {
+    let level = match verbose {
+        true => level.max(LevelFilter::DEBUG),
+        false => level,
+    };
+
+    if let Some(handle) = LEVEL_HANDLE.get() {
+        if let Err(error) = handle.reload(level) {
+            tracing::error!("Could not change the log level: {error}");
+        }
+    }
+}
@@ -1,15 +1,11 @@
 use std::{env, process::exit};
 
-use args::ArgumentsRequest;
-use tokio::task::LocalSet;
+use mail_devil::args::{self, ArgumentsRequest};
+use mail_devil::logging;
+use tracing::{debug, error};
 
-mod args;
-mod pop3;
-mod server;
-mod state;
-mod types;
-mod user_tracker;
-mod util;
+mod cli;
+mod privileges;
 
 fn main() {
     let arguments = match args::parse_arguments(env::args()) {
@@ -30,7 +26,7 @@ fn main() {
             println!("{}", args::get_help_string());
             return;
         }
-        ArgumentsRequest::Run(startup_args) => startup_args,
+        ArgumentsRequest::Run(startup_args) => *startup_args,
     };
 
     if startup_args.silent && startup_args.verbose {
@@ -38,26 +34,39 @@ fn main() {
         startup_args.verbose = false;
     }
 
-    printlnif!(startup_args.verbose, "Starting up tokio runtime");
-    let start_result = tokio::runtime::Builder::new_current_thread().enable_all().build();
+    let log_file = startup_args.log_file.as_deref();
+    let syslog = startup_args.syslog.as_ref();
+    let log_result = logging::init(
+        startup_args.get_log_level(),
+        startup_args.log_format,
+        log_file,
+        syslog,
+        startup_args.syslog_facility,
+    );
+    if let Err(err) = log_result {
+        eprintln!("Failed to set up logging: {err}");
+        exit(1);
+    }
+
+    debug!("Starting up tokio runtime with {} threads", startup_args.threads);
+    let start_result = match startup_args.threads {
+        1 => tokio::runtime::Builder::new_current_thread().enable_all().build(),
+        threads => tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(threads)
+            .enable_all()
+            .build(),
+    };
     let runtime = match start_result {
         Ok(rt) => rt,
         Err(err) => {
-            eprintln!("Failed to start tokio runtime: {err}");
+            error!("Failed to start tokio runtime: {err}");
             exit(1);
         }
     };
 
-    // Run the server's entrypoint on a `LocalSet`, then wait for any remaining tasks to wrap up.
-    let localset = LocalSet::new();
-    let result = localset.block_on(&runtime, server::run_server(startup_args));
-    if let Err(err) = &result {
-        eprintln!("{err}");
-    }
-
-    runtime.block_on(localset);
-
-    if result.is_err() {
+    // Run the server's entrypoint. By the time it returns, all the client tasks have either finished or been aborted.
+    if let Err(err) = runtime.block_on(cli::run_server(startup_args)) {
+        error!("{err}");
         exit(1);
     }
 }
@@ -0,0 +1,366 @@
+//! Counters and histograms describing the server's activity, and a minimal HTTP server exposing them in Prometheus'
+//! text exposition format at `/metrics`.
+//!
+//! Like [`crate::connection_tracker`], [`Metrics`] is a reference type shared by all sessions, and is kept across
+//! configuration reloads so counters never go backwards while the server is running. The amount of active connections
+//! isn't counted here, but taken from the connection tracker whenever the metrics are requested.
+//!
+//! The HTTP server only understands `GET` and `HEAD` requests, and closes each connection after responding.
+//!
+//! There are no metrics for the transformer, as message transformations aren't implemented yet. Its invocations,
+//! failures and latencies should be counted here once they are.
+
+use std::{
+    collections::BTreeMap,
+    fmt::{self, Write},
+    io,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+use tracing::{debug, error, warn};
+
+use crate::{
+    connection_tracker::ConnectionTracker,
+    state::LoginUserError,
+    util::sockets::{AcceptFromAny, PrintSockaddrOrUnknown},
+};
+
+/// The default port the metrics server listens on when an address without a port is specified.
+pub const DEFAULT_METRICS_PORT: u16 = 9110;
+
+/// The maximum size of an HTTP request's head. Anything larger is rejected.
+const MAX_REQUEST_LENGTH: usize = 4096;
+
+/// How long to wait for a client to send its HTTP request before closing the connection.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The upper bounds of the session duration histogram's buckets, in seconds.
+const SESSION_DURATION_BUCKETS: [f64; 11] = [0.1, 0.5, 1.0, 5.0, 10.0, 30.0, 60.0, 300.0, 600.0, 1800.0, 3600.0];
+
+/// The upper bounds of the retrieved message size histogram's buckets, in bytes.
+const RETR_SIZE_BUCKETS: [f64; 7] = [1e3, 1e4, 1e5, 1e6, 1e7, 1e8, 1e9];
+
+/// The outcomes of a login attempt, as used for the `outcome` label.
+const LOGIN_OUTCOMES: [&str; 5] = [
+    "success",
+    LoginUserError::AlreadyLoggedIn.get_label(),
+    LoginUserError::WrongUserOrPass.get_label(),
+    LoginUserError::FarOverQuota.get_label(),
+    LoginUserError::EncryptionKeyError.get_label(),
+];
+
+/// The server's metrics. Read the [`crate::metrics`] module's documentation for more information.
+#[derive(Clone)]
+pub struct Metrics {
+    inner: Arc<InnerMetrics>,
+}
+
+struct InnerMetrics {
+    connections: AtomicU64,
+    rejected_connections: AtomicU64,
+    logins: [AtomicU64; LOGIN_OUTCOMES.len()],
+    commands: Mutex<BTreeMap<String, u64>>,
+    invalid_commands: AtomicU64,
+    retr_bytes: AtomicU64,
+    deletions: AtomicU64,
+    session_duration: Histogram<{ SESSION_DURATION_BUCKETS.len() }>,
+    retr_size: Histogram<{ RETR_SIZE_BUCKETS.len() }>,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self {
+            inner: Arc::new(InnerMetrics {
+                connections: AtomicU64::new(0),
+                rejected_connections: AtomicU64::new(0),
+                logins: Default::default(),
+                commands: Mutex::new(BTreeMap::new()),
+                invalid_commands: AtomicU64::new(0),
+                retr_bytes: AtomicU64::new(0),
+                deletions: AtomicU64::new(0),
+                session_duration: Histogram::new(SESSION_DURATION_BUCKETS),
+                retr_size: Histogram::new(RETR_SIZE_BUCKETS),
+            }),
+        }
+    }
+
+    /// Records a connection that was accepted, or rejected due to connection limits.
+    pub fn record_connection(&self, accepted: bool) {
+        match accepted {
+            true => self.inner.connections.fetch_add(1, Ordering::Relaxed),
+            false => self.inner.rejected_connections.fetch_add(1, Ordering::Relaxed),
+        };
+    }
+
+    /// Records the outcome of a login attempt.
+    pub fn record_login(&self, result: Result<(), LoginUserError>) {
+        let index = match result {
+            Ok(()) => 0,
+            Err(LoginUserError::AlreadyLoggedIn) => 1,
+            Err(LoginUserError::WrongUserOrPass) => 2,
+            Err(LoginUserError::FarOverQuota) => 3,
+            Err(LoginUserError::EncryptionKeyError) => 4,
+        };
+
+        self.inner.logins[index].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records a command received from a client, by its keyword, or [`None`] if the command couldn't be parsed.
+    pub fn record_command(&self, keyword: Option<&str>) {
+        let keyword = match keyword {
+            Some(k) => k,
+            None => {
+                self.inner.invalid_commands.fetch_add(1, Ordering::Relaxed);
+                return;
+            }
+        };
+
+        let mut commands = self.inner.commands.lock().unwrap();
+        match commands.get_mut(keyword) {
+            Some(count) => *count += 1,
+            None => {
+                commands.insert(keyword.to_string(), 1);
+            }
+        }
+    }
+
+    /// Records a message sent to a client in full, with the amount of bytes sent.
+    pub fn record_retr(&self, bytes: u64) {
+        self.inner.retr_bytes.fetch_add(bytes, Ordering::Relaxed);
+        self.inner.retr_size.observe(bytes as f64);
+    }
+
+    /// Records messages whose deletion was committed when a client QUIT.
+    pub fn record_deletions(&self, count: u64) {
+        self.inner.deletions.fetch_add(count, Ordering::Relaxed);
+    }
+
+    /// Records a session that ended after the given duration.
+    pub fn record_session(&self, duration: Duration) {
+        self.inner.session_duration.observe(duration.as_secs_f64());
+    }
+
+    /// Writes all the metrics in Prometheus' text exposition format.
+    pub fn render(&self, connections: &ConnectionTracker) -> String {
+        let inner = &self.inner;
+        let counts = connections.counts();
+        let mut out = String::new();
+
+        write_header(
+            &mut out,
+            "mail_devil_connections_active",
+            "gauge",
+            "Client connections currently open",
+        );
+        let _ = writeln!(out, "mail_devil_connections_active {}", counts.total);
+
+        write_header(
+            &mut out,
+            "mail_devil_connections_unauthenticated",
+            "gauge",
+            "Open client connections not logged in",
+        );
+        let _ = writeln!(out, "mail_devil_connections_unauthenticated {}", counts.unauthenticated);
+
+        write_header(&mut out, "mail_devil_connections_total", "counter", "Client connections accepted");
+        let _ = writeln!(out, "mail_devil_connections_total {}", load(&inner.connections));
+
+        write_header(
+            &mut out,
+            "mail_devil_connections_rejected_total",
+            "counter",
+            "Client connections rejected by limits",
+        );
+        let _ = writeln!(out, "mail_devil_connections_rejected_total {}", load(&inner.rejected_connections));
+
+        write_header(&mut out, "mail_devil_logins_total", "counter", "Login attempts by outcome");
+        for (outcome, count) in LOGIN_OUTCOMES.iter().zip(&inner.logins) {
+            let _ = writeln!(out, "mail_devil_logins_total{{outcome=\"{outcome}\"}} {}", load(count));
+        }
+
+        write_header(&mut out, "mail_devil_commands_total", "counter", "Commands received by command");
+        for (command, count) in inner.commands.lock().unwrap().iter() {
+            let _ = writeln!(out, "mail_devil_commands_total{{command=\"{}\"}} {count}", EscapeLabel(command));
+        }
+
+        write_header(
+            &mut out,
+            "mail_devil_invalid_commands_total",
+            "counter",
+            "Command lines that couldn't be parsed",
+        );
+        let _ = writeln!(out, "mail_devil_invalid_commands_total {}", load(&inner.invalid_commands));
+
+        write_header(
+            &mut out,
+            "mail_devil_retr_bytes_total",
+            "counter",
+            "Bytes of message contents sent by RETR",
+        );
+        let _ = writeln!(out, "mail_devil_retr_bytes_total {}", load(&inner.retr_bytes));
+
+        write_header(&mut out, "mail_devil_deletions_total", "counter", "Message deletions committed");
+        let _ = writeln!(out, "mail_devil_deletions_total {}", load(&inner.deletions));
+
+        let name = "mail_devil_session_duration_seconds";
+        write_header(&mut out, name, "histogram", "Duration of client sessions");
+        inner.session_duration.render(&mut out, name);
+
+        let name = "mail_devil_retr_size_bytes";
+        write_header(&mut out, name, "histogram", "Size of messages sent by RETR");
+        inner.retr_size.render(&mut out, name);
+
+        out
+    }
+}
+
+fn load(counter: &AtomicU64) -> u64 {
+    counter.load(Ordering::Relaxed)
+}
+
+fn write_header(out: &mut String, name: &str, metric_type: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {metric_type}");
+}
+
+/// Escapes a label value as required by the exposition format.
+struct EscapeLabel<'a>(&'a str);
+
+impl fmt::Display for EscapeLabel<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for c in self.0.chars() {
+            match c {
+                '\\' => f.write_str("\\\\")?,
+                '"' => f.write_str("\\\"")?,
+                '\n' => f.write_str("\\n")?,
+                c => f.write_char(c)?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// A histogram with `N` buckets of fixed upper bounds, plus the implicit `+Inf` bucket.
+struct Histogram<const N: usize> {
+    bounds: [f64; N],
+    counts: [AtomicU64; N],
+    count: AtomicU64,
+    /// The sum of all observed values, stored as the bits of an [`f64`].
+    sum: AtomicU64,
+}
+
+impl<const N: usize> Histogram<N> {
+    fn new(bounds: [f64; N]) -> Self {
+        Self {
+            bounds,
+            counts: std::array::from_fn(|_| AtomicU64::new(0)),
+            count: AtomicU64::new(0),
+            sum: AtomicU64::new(0f64.to_bits()),
+        }
+    }
+
+    fn observe(&self, value: f64) {
+        if let Some(i) = self.bounds.iter().position(|bound| value <= *bound) {
+            self.counts[i].fetch_add(1, Ordering::Relaxed);
+        }
+
+        self.count.fetch_add(1, Ordering::Relaxed);
+        let _ = self.sum.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |bits| {
+            Some((f64::from_bits(bits) + value).to_bits())
+        });
+    }
+
+    /// Writes the histogram's series. Buckets are stored individually but exposed cumulatively, as the format requires.
+    fn render(&self, out: &mut String, name: &str) {
+        let mut cumulative = 0;
+        for (bound, count) in self.bounds.iter().zip(&self.counts) {
+            cumulative += load(count);
+            let _ = writeln!(out, "{name}_bucket{{le=\"{bound}\"}} {cumulative}");
+        }
+
+        let count = load(&self.count);
+        let _ = writeln!(out, "{name}_bucket{{le=\"+Inf\"}} {count}");
+        let _ = writeln!(out, "{name}_sum {}", f64::from_bits(load(&self.sum)));
+        let _ = writeln!(out, "{name}_count {count}");
+    }
+}
+
+/// Serves the metrics over HTTP on the given listeners until the task is aborted.
+pub async fn serve_metrics(listeners: Vec<TcpListener>, metrics: Metrics, connections: ConnectionTracker) {
+    let mut listeners = listeners;
+    while !listeners.is_empty() {
+        match listeners.accept_from_any().await {
+            Ok((socket, address)) => {
+                let metrics = metrics.clone();
+                let connections = connections.clone();
+                tokio::spawn(async move {
+                    let result = tokio::time::timeout(REQUEST_TIMEOUT, handle_request(socket, &metrics, &connections)).await;
+                    match result {
+                        Ok(Ok(())) => {}
+                        Ok(Err(error)) => debug!("Error while serving metrics to {address}: {error}"),
+                        Err(_) => debug!("Metrics client {address} timed out"),
+                    }
+                });
+            }
+            Err((listener_index, error)) => {
+                let listener = listeners.swap_remove(listener_index);
+                let listener_addr = PrintSockaddrOrUnknown(listener.local_addr().ok());
+                error!("Error while accepting metrics connection from listener {listener_addr}: {error}");
+            }
+        }
+    }
+
+    warn!("No longer serving metrics, all metrics listeners failed");
+}
+
+async fn handle_request(mut socket: TcpStream, metrics: &Metrics, connections: &ConnectionTracker) -> io::Result<()> {
+    let mut buf = Vec::with_capacity(1024);
+    while !buf.windows(4).any(|w| w == b"\r\n\r\n") && !buf.windows(2).any(|w| w == b"\n\n") {
+        if buf.len() >= MAX_REQUEST_LENGTH {
+            return write_response(&mut socket, "431 Request Header Fields Too Large", "", false).await;
+        }
+
+        if socket.read_buf(&mut buf).await? == 0 {
+            return Ok(());
+        }
+    }
+
+    let request_line = buf.split(|b| *b == b'\n').next().unwrap_or_default();
+    let mut parts = request_line.trim_ascii().split(|b| *b == b' ');
+    let method = parts.next().unwrap_or_default();
+    let path = parts.next().unwrap_or_default();
+    let path = path.split(|b| *b == b'?').next().unwrap_or_default();
+
+    let is_head = method == b"HEAD";
+    if method != b"GET" && !is_head {
+        return write_response(&mut socket, "405 Method Not Allowed", "", false).await;
+    }
+
+    match path {
+        b"/metrics" => write_response(&mut socket, "200 OK", &metrics.render(connections), is_head).await,
+        _ => write_response(&mut socket, "404 Not Found", "Not found, try /metrics\n", is_head).await,
+    }
+}
+
+async fn write_response(socket: &mut TcpStream, status: &str, body: &str, is_head: bool) -> io::Result<()> {
+    let head = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: text/plain; version=0.0.4; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        body.len()
+    );
+
+    socket.write_all(head.as_bytes()).await?;
+    if !is_head {
+        socket.write_all(body.as_bytes()).await?;
+    }
+
+    socket.shutdown().await
+}
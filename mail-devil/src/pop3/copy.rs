@@ -1,3 +1,6 @@
+//! The transformation applied to messages as they are sent to clients with `RETR`: LF newlines are converted to CRLF
+//! and lines beginning with a period are byte-stuffed.
+
 use std::io::{self, ErrorKind};
 
 use tokio::{
@@ -5,6 +8,7 @@ use tokio::{
     select,
 };
 
+#[derive(Debug)]
 pub enum CopyError {
     ReaderError(io::Error),
     WriterError(io::Error),
@@ -16,7 +20,9 @@ pub enum CopyError {
 /// Does not prepend an `+OK` status indicator nor a `CRLF.CRLF` at the end of the sequence.
 ///
 /// If the reader does not end in a newline, a newline is appended at its end.
-pub async fn copy<R, W>(buffer_size: usize, reader: &mut R, writer: &mut W) -> Result<(), CopyError>
+///
+/// Returns the amount of bytes written, including any inserted characters.
+pub async fn copy<R, W>(buffer_size: usize, reader: &mut R, writer: &mut W) -> Result<u64, CopyError>
 where
     R: AsyncRead + Unpin + ?Sized,
     W: AsyncWrite + Unpin + ?Sized,
@@ -27,8 +33,11 @@ where
 
     let mut reader_ended = false;
 
-    let mut last_char = 0;
+    // The message starts at the beginning of a line, so a period as its first character must be byte-stuffed too, and an
+    // empty message needs no newline appended.
+    let mut last_char = b'\n';
     let mut insert_char = None;
+    let mut total_written = 0;
 
     loop {
         let (buf_contents, buf_empty_space) = buf[buf_start..].split_at_mut(buf_end - buf_start);
@@ -41,7 +50,7 @@ where
 
             }
             result = do_write(writer, buf_contents, &mut insert_char, &mut buf_start, &mut last_char) => {
-                result?;
+                total_written += result? as u64;
             }
         }
 
@@ -57,9 +66,10 @@ where
 
     if last_char != b'\n' {
         writer.write_all(b"\r\n").await.map_err(CopyError::WriterError)?;
+        total_written += 2;
     }
 
-    Ok(())
+    Ok(total_written)
 }
 
 async fn do_write<W>(
@@ -68,15 +78,15 @@ async fn do_write<W>(
     insert_char: &mut Option<u8>,
     buf_start: &mut usize,
     last_char: &mut u8,
-) -> Result<(), CopyError>
+) -> Result<usize, CopyError>
 where
     W: AsyncWrite + Unpin + ?Sized,
 {
     if let Some(c) = insert_char {
-        let result = writer.write_u8(*c).await.map_err(CopyError::ReaderError);
+        let result = writer.write_u8(*c).await.map_err(CopyError::WriterError);
         *last_char = *c;
         *insert_char = None;
-        return result;
+        return result.map(|_| 1);
     }
 
     if buf_contents.is_empty() {
@@ -103,7 +113,7 @@ where
         *insert_char = new_insert_char;
     }
 
-    Ok(())
+    Ok(bytes_written_count)
 }
 
 fn find_buffer_split_point(buf_contents: &[u8], mut new_last_char: u8) -> (Option<u8>, usize) {
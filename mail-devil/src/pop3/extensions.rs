@@ -0,0 +1,181 @@
+//! Site-specific commands, which programs embedding the server can add without changing its parser or dispatch.
+//!
+//! Each command is registered in a [`CommandRegistry`] under a keyword such as `XSTATS` or `XTND`, along with the
+//! session states it's allowed in, the line it adds to the `CAPA` response (if any) and a [`CommandHandler`] that
+//! produces its response. The registry is then handed to the server with [`crate::ServerBuilder::commands`]. Commands
+//! sent in a state they weren't registered for are answered with an error without calling their handler. Commands can't
+//! be registered for the `UPDATE` state, since no commands are read once a session enters it.
+//!
+//! ```no_run
+//! use mail_devil::pop3::extensions::{CommandFuture, CommandHandler, CommandRegistry, CommandRequest, CommandResponse};
+//! use mail_devil::pop3::extensions::SessionState;
+//!
+//! struct Stats;
+//!
+//! impl CommandHandler for Stats {
+//!     fn handle<'a>(&'a self, request: CommandRequest<'a>) -> CommandFuture<'a> {
+//!         Box::pin(async move {
+//!             let lines = vec![format!("session {}", request.session_id), format!("peer {}", request.peer)];
+//!             CommandResponse::MultiLine(Some("Statistics follow".to_string()), lines)
+//!         })
+//!     }
+//! }
+//!
+//! let mut commands = CommandRegistry::new();
+//! commands.register("XSTATS", &[SessionState::Transaction], Some("XSTATS"), Stats).unwrap();
+//! let builder = mail_devil::ServerBuilder::new().commands(commands);
+//! ```
+
+use std::{fmt, future::Future, net::SocketAddr, pin::Pin, sync::Arc};
+
+use super::parsers;
+use crate::types::Pop3Username;
+
+pub use crate::session_tracker::SessionState;
+
+/// The maximum allowed length (in bytes) for the keyword of a registered command.
+pub const MAX_KEYWORD_LENGTH: usize = 16;
+
+/// The maximum allowed length (in bytes) for a registered command's `CAPA` line.
+pub const MAX_CAPABILITY_LENGTH: usize = 80;
+
+/// The future returned by [`CommandHandler::handle`].
+pub type CommandFuture<'a> = Pin<Box<dyn Future<Output = CommandResponse> + Send + 'a>>;
+
+/// Handles a registered command.
+pub trait CommandHandler: Send + Sync {
+    /// Handles the command described by `request`, returning the response to send to the client.
+    fn handle<'a>(&'a self, request: CommandRequest<'a>) -> CommandFuture<'a>;
+}
+
+/// A registered command sent by a client, along with what's known about the session it was sent in.
+#[derive(Debug, Clone, Copy)]
+pub struct CommandRequest<'a> {
+    /// The command's keyword, in uppercase.
+    pub keyword: &'a str,
+    /// Everything after the keyword and the space following it, or an empty string.
+    pub arguments: &'a str,
+    pub session_id: u64,
+    pub peer: SocketAddr,
+    pub state: SessionState,
+    /// The logged in user, or [`None`] if the session is still in the `AUTHORIZATION` state.
+    pub username: Option<&'a Pop3Username>,
+}
+
+/// The response to a registered command. Line breaks within a message are replaced with spaces. Each of a multi-line
+/// response's lines is sent as one line, even if it's empty, unless line breaks within it split it into more lines.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CommandResponse {
+    /// A single-line `+OK` response with an optional message.
+    Ok(Option<String>),
+    /// A single-line `-ERR` response with an optional message.
+    Err(Option<String>),
+    /// A `+OK` response with an optional message, followed by the given lines and a line with a single period. Lines
+    /// that begin with a period are byte-stuffed when sent.
+    MultiLine(Option<String>, Vec<String>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegisterCommandError {
+    InvalidKeyword,
+    BuiltInCommand,
+    AlreadyRegistered,
+    NoStates,
+    UpdateState,
+    InvalidCapability,
+}
+
+impl fmt::Display for RegisterCommandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidKeyword => write!(f, "Keywords must be 1 to {MAX_KEYWORD_LENGTH} ASCII letters or digits"),
+            Self::BuiltInCommand => write!(f, "Built-in commands can't be replaced"),
+            Self::AlreadyRegistered => write!(f, "A command with that keyword is already registered"),
+            Self::NoStates => write!(f, "Commands must be allowed in at least one session state"),
+            Self::UpdateState => write!(f, "Commands can't be allowed in the UPDATE state, as no commands are read in it"),
+            Self::InvalidCapability => write!(f, "CAPA lines must be 1 to {MAX_CAPABILITY_LENGTH} printable ASCII characters"),
+        }
+    }
+}
+
+impl std::error::Error for RegisterCommandError {}
+
+/// A command in a [`CommandRegistry`].
+pub(crate) struct RegisteredCommand {
+    pub keyword: String,
+    pub states: Vec<SessionState>,
+    pub capability: Option<String>,
+    pub handler: Arc<dyn CommandHandler>,
+}
+
+/// The site-specific commands a server accepts in addition to the built-in ones.
+#[derive(Default)]
+pub struct CommandRegistry {
+    commands: Vec<RegisteredCommand>,
+}
+
+impl CommandRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers a command under the given keyword, which is case-insensitive. The command is only accepted in the given
+    /// session states, and if `capability` is [`Some`], that line is listed in the response to `CAPA`.
+    pub fn register(
+        &mut self,
+        keyword: &str,
+        states: &[SessionState],
+        capability: Option<&str>,
+        handler: impl CommandHandler + 'static,
+    ) -> Result<(), RegisterCommandError> {
+        if keyword.is_empty() || keyword.len() > MAX_KEYWORD_LENGTH || !keyword.bytes().all(|b| b.is_ascii_alphanumeric()) {
+            return Err(RegisterCommandError::InvalidKeyword);
+        }
+
+        let keyword = keyword.to_ascii_uppercase();
+        if parsers::is_built_in_keyword(&keyword) {
+            return Err(RegisterCommandError::BuiltInCommand);
+        }
+
+        if self.get(&keyword).is_some() {
+            return Err(RegisterCommandError::AlreadyRegistered);
+        }
+
+        if states.is_empty() {
+            return Err(RegisterCommandError::NoStates);
+        } else if states.contains(&SessionState::Update) {
+            return Err(RegisterCommandError::UpdateState);
+        }
+
+        if let Some(capability) = capability {
+            let is_printable = capability.bytes().all(|b| (b' '..=b'~').contains(&b));
+            if capability.trim().is_empty() || capability.len() > MAX_CAPABILITY_LENGTH || !is_printable {
+                return Err(RegisterCommandError::InvalidCapability);
+            }
+        }
+
+        self.commands.push(RegisteredCommand {
+            keyword,
+            states: states.to_vec(),
+            capability: capability.map(str::to_string),
+            handler: Arc::new(handler),
+        });
+
+        Ok(())
+    }
+
+    /// Whether no commands are registered.
+    pub fn is_empty(&self) -> bool {
+        self.commands.is_empty()
+    }
+
+    /// Finds the command registered under the given uppercase keyword.
+    pub(crate) fn get(&self, keyword: &str) -> Option<&RegisteredCommand> {
+        self.commands.iter().find(|command| command.keyword == keyword)
+    }
+
+    /// The `CAPA` lines of the registered commands, in the order they were registered.
+    pub(crate) fn capabilities(&self) -> impl Iterator<Item = &str> {
+        self.commands.iter().filter_map(|command| command.capability.as_deref())
+    }
+}
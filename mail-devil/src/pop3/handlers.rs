@@ -2,11 +2,19 @@ use std::{fmt::Write, io};
 
 use inlined::TinyString;
 use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tracing::error;
 
-use crate::types::{MessageNumber, Pop3ArgString, Pop3Username};
+use crate::{
+    audit::{AuditAction, AuditRecord},
+    events::ServerEvent,
+    storage,
+    types::{MessageNumber, Pop3ArgString, Pop3Username},
+};
 
 use super::{
     copy::{self, CopyError},
+    extensions::{CommandRequest, CommandResponse},
+    parsers::{ExtensionCommand, Pop3CommandError},
     responses::Pop3Response,
     session::{GetMessageError, Pop3Session, Pop3SessionState},
 };
@@ -17,6 +25,9 @@ const NO_SUCH_MESSAGE: &str = "No such message";
 const MESSAGE_IS_DELETED: &str = "Message is deleted";
 const ERROR_ACCESSING_FILE: &str = "Error accessing file";
 
+/// The capabilities of the server itself, listed in the response to `CAPA` before those of any extension commands.
+const CAPABILITIES: [&str; 4] = ["USER", "PIPELINING", "RESP-CODES", "AUTH-RESP-CODE"];
+
 pub async fn handle_user_command<W>(writer: &mut W, session: &mut Pop3Session, username: Pop3Username) -> io::Result<()>
 where
     W: AsyncWrite + Unpin + ?Sized,
@@ -40,11 +51,22 @@ where
         Pop3SessionState::Authorization(authorization_state) => match &authorization_state.username {
             None => Pop3Response::err("Must specify a user before a password"),
             Some(username) => match session.server.try_login_user(username, &password).await {
-                Ok((user_handle, maildrop_path)) => match session.enter_transaction_state(user_handle, maildrop_path).await {
-                    Some(_) => Pop3Response::ok_empty(),
-                    None => Pop3Response::err("An unexpected error occurred while opening your maildrop"),
-                },
-                Err(reason) => Pop3Response::err(reason.get_reason_str()),
+                Ok(logged_in_user) => {
+                    session.server.metrics().record_login(Ok(()));
+                    match session.enter_transaction_state(logged_in_user).await {
+                        Some(_) => Pop3Response::ok_empty(),
+                        None => Pop3Response::err("An unexpected error occurred while opening your maildrop"),
+                    }
+                }
+                Err(reason) => {
+                    session.server.metrics().record_login(Err(reason));
+                    session.server.emit_event(ServerEvent::LoginFailed {
+                        session_id: session.registration.id(),
+                        username: username.clone(),
+                        reason,
+                    });
+                    Pop3Response::err(reason.get_reason_str())
+                }
             },
         },
         _ => Pop3Response::err(ONLY_ALLOWED_IN_AUTHORIZATION_STATE),
@@ -57,7 +79,11 @@ pub async fn handle_quit_command<W>(writer: &mut W, session: &mut Pop3Session) -
 where
     W: AsyncWrite + Unpin + ?Sized,
 {
-    let response = match session.quit_session().await {
+    let result = session.quit_session().await;
+    let (Ok(count) | Err(count)) = result;
+    session.server.metrics().record_deletions(count as u64);
+
+    let response = match result {
         Ok(count) => Pop3Response::ok_deleted(count),
         Err(count) => Pop3Response::err_deleted(count),
     };
@@ -86,10 +112,10 @@ where
 {
     let error_message = match &mut session.state {
         Pop3SessionState::Transaction(transaction_state) => match message_number {
-            Some(msgnum) => match transaction_state.get_message_mut(msgnum) {
+            Some(msgnum) => match transaction_state.calculate_message_size(msgnum).await {
                 Err(GetMessageError::NotExists) => NO_SUCH_MESSAGE,
                 Err(GetMessageError::Deleted) => MESSAGE_IS_DELETED,
-                Ok(message) => match message.calculate_size().await {
+                Ok(size_result) => match size_result {
                     Ok(s) => return Pop3Response::ok_list_one(msgnum, s).write_to(writer).await,
                     Err(_) => ERROR_ACCESSING_FILE,
                 },
@@ -118,16 +144,44 @@ pub async fn handle_retr_command<W>(writer: &mut W, session: &mut Pop3Session, m
 where
     W: AsyncWrite + Unpin + ?Sized,
 {
+    // The audit log records the message's size as listed to the client, so it's calculated beforehand if it wasn't yet.
+    if let Pop3SessionState::Transaction(transaction_state) = &mut session.state {
+        if session.server.is_auditing() {
+            let _ = transaction_state.calculate_message_size(message_number).await;
+        }
+    }
+
     let error = match &session.state {
         Pop3SessionState::Transaction(transaction_state) => match transaction_state.get_message(message_number) {
-            Ok(message) => match tokio::fs::File::open(message.path()).await {
+            Ok(message) => match storage::open_message(message.path(), transaction_state.encryption_key()).await {
                 Ok(mut file) => {
                     Pop3Response::ok_empty().write_to(writer).await?;
                     match copy::copy(session.server.buffer_size(), &mut file, writer).await {
-                        Ok(()) => {}
+                        Ok(bytes) => {
+                            session.server.metrics().record_retr(bytes);
+                            session.server.emit_event(ServerEvent::MessageRetrieved {
+                                session_id: session.registration.id(),
+                                username: transaction_state.username().clone(),
+                                message_number,
+                                bytes,
+                            });
+
+                            let record = AuditRecord {
+                                session_id: session.registration.id(),
+                                peer: session.registration.peer(),
+                                username: transaction_state.username(),
+                                action: AuditAction::Retrieved { bytes_sent: bytes },
+                                message_path: message.path(),
+                                size: message.size(),
+                            };
+                            session
+                                .server
+                                .record_audit(transaction_state.maildrop_dir(), transaction_state.owner(), &record)
+                                .await;
+                        }
                         Err(CopyError::WriterError(error)) => return Err(error),
                         Err(CopyError::ReaderError(error)) => {
-                            eprintln!("Error while reading from file during copy: {error}");
+                            error!("Error while reading from file during copy: {error}");
                             return Err(error);
                         }
                     };
@@ -135,7 +189,7 @@ where
                     return Ok(());
                 }
                 Err(error) => {
-                    eprintln!("Could not open message file {} {error}", message.path().display());
+                    error!("Could not open message file {} {error}", message.path().display());
                     "Error opening message file"
                 }
             },
@@ -190,3 +244,85 @@ where
 
     response.write_to(writer).await
 }
+
+pub async fn handle_capa_command<W>(writer: &mut W, session: &mut Pop3Session) -> io::Result<()>
+where
+    W: AsyncWrite + Unpin + ?Sized,
+{
+    Pop3Response::ok("Capability list follows").write_to(writer).await?;
+    for capability in CAPABILITIES.into_iter().chain(session.server.commands().capabilities()) {
+        writer.write_all(capability.as_bytes()).await?;
+        writer.write_all(b"\r\n").await?;
+    }
+
+    writer.write_all(b".\r\n").await
+}
+
+pub async fn handle_extension_command<W>(writer: &mut W, session: &mut Pop3Session, command: ExtensionCommand) -> io::Result<()>
+where
+    W: AsyncWrite + Unpin + ?Sized,
+{
+    let registered = match session.server.commands().get(&command.keyword) {
+        Some(registered) => registered,
+        None => return Pop3Response::err(Pop3CommandError::UnknownCommand).write_to(writer).await,
+    };
+
+    let state = session.state.kind();
+    if !registered.states.contains(&state) {
+        return Pop3Response::err(format!("Command not allowed in the {state} state"))
+            .write_to(writer)
+            .await;
+    }
+
+    let request = CommandRequest {
+        keyword: &command.keyword,
+        arguments: &command.arguments,
+        session_id: session.registration.id(),
+        peer: session.registration.peer(),
+        state,
+        username: match &session.state {
+            Pop3SessionState::Transaction(transaction_state) => Some(transaction_state.username()),
+            _ => None,
+        },
+    };
+
+    match registered.handler.handle(request).await {
+        CommandResponse::Ok(message) => {
+            Pop3Response::<_, &str>::Ok(message.as_deref().map(single_line))
+                .write_to(writer)
+                .await
+        }
+        CommandResponse::Err(message) => {
+            Pop3Response::<&str, _>::Err(message.as_deref().map(single_line))
+                .write_to(writer)
+                .await
+        }
+        CommandResponse::MultiLine(message, lines) => {
+            Pop3Response::<_, &str>::Ok(message.as_deref().map(single_line))
+                .write_to(writer)
+                .await?;
+            for line in lines.iter().flat_map(|element| element_lines(element)) {
+                if line.starts_with('.') {
+                    writer.write_all(b".").await?;
+                }
+
+                writer.write_all(line.as_bytes()).await?;
+                writer.write_all(b"\r\n").await?;
+            }
+
+            writer.write_all(b".\r\n").await
+        }
+    }
+}
+
+/// Gets the lines to send for an element of a multi-line response, which is a single line, even if empty, unless it holds
+/// line breaks that split it into more. A line break at the element's end doesn't start another line.
+fn element_lines(element: &str) -> impl Iterator<Item = &str> {
+    let element = element.strip_suffix('\n').unwrap_or(element);
+    element.split('\n').map(|line| line.strip_suffix('\r').unwrap_or(line))
+}
+
+/// Replaces the line breaks in a response's message with spaces, so it can't be mistaken for more than one line.
+fn single_line(message: &str) -> String {
+    message.replace(['\r', '\n'], " ")
+}
@@ -7,22 +7,60 @@ use tokio::{
     io::{AsyncWriteExt, BufReader, BufWriter},
     net::TcpStream,
     select,
+    sync::watch,
+    time::Instant,
 };
 
-use crate::{printlnif, state::Pop3ServerState};
+use tracing::info;
 
-mod copy;
+use crate::{
+    connection_tracker::ConnectionHandle,
+    events::ServerEvent,
+    session_tracker::SessionHandle,
+    state::Pop3ServerState,
+    transcript::{Transcript, TranscriptWriter},
+    util::counting::Counted,
+};
+
+pub mod copy;
+pub mod extensions;
 mod handlers;
-mod parsers;
+pub mod parsers;
 mod responses;
 mod session;
 
-pub async fn handle_client(mut socket: TcpStream, server_state: Pop3ServerState) -> io::Result<()> {
+/// Sends a client that was rejected due to connection limits a `-ERR [SYS/TEMP]` greeting and closes the connection.
+pub(crate) async fn reject_client(mut socket: TcpStream, reason: &str) -> io::Result<()> {
+    let message = format!("[SYS/TEMP] {reason}");
+    Pop3Response::err(message).write_to(&mut socket).await?;
+    socket.shutdown().await
+}
+
+/// Waits until the given receiver indicates that the server is shutting down.
+pub(crate) async fn wait_for_shutdown(receiver: &mut watch::Receiver<bool>) {
+    let _ = receiver.wait_for(|shutting_down| *shutting_down).await;
+}
+
+pub(crate) async fn handle_client(
+    mut socket: TcpStream,
+    server_state: Pop3ServerState,
+    connection: ConnectionHandle,
+    registration: SessionHandle,
+    transcript: Transcript,
+) -> io::Result<()> {
+    // The bytes exchanged with the client are counted below the buffers, so they match what went through the socket.
+    // The writes are counted there too, showing how well the responses to pipelined commands are batched.
     let (read_half, write_half) = socket.split();
+    let read_half = Counted::new(read_half, registration.bytes_received_counter());
+    let write_half = Counted::new(write_half, registration.bytes_sent_counter()).with_calls(registration.writes_counter());
     let mut reader = BufReader::with_capacity(server_state.buffer_size(), read_half);
-    let mut writer = BufWriter::with_capacity(server_state.buffer_size(), write_half);
+    let writer = BufWriter::with_capacity(server_state.buffer_size(), write_half);
 
-    let mut session = session::Pop3Session::new(server_state);
+    // Everything written to the client passes through the transcript, which only records it if tracing this session.
+    let mut writer = TranscriptWriter::new(writer, transcript);
+
+    let kick = registration.kick_notify();
+    let mut session = session::Pop3Session::new(server_state, connection, registration);
 
     let banner = "No swearing on my christian POP3 server";
     Pop3Response::ok(banner).write_to(&mut writer).await?;
@@ -31,56 +69,107 @@ pub async fn handle_client(mut socket: TcpStream, server_state: Pop3ServerState)
     let mut parse_buf: TinyVec<MAX_COMMAND_LINE_LENGTH, u8> = TinyVec::new();
     let mut reader_closed = false;
 
+    // The inactivity timer, which is restarted whenever a command is received. If it expires the connection is closed
+    // without entering the `UPDATE` state, as required by RFC #1939.
+    let idle_timer = tokio::time::sleep(session.idle_timeout());
+    tokio::pin!(idle_timer);
+
+    let mut shutdown_receiver = session.server.shutdown_receiver();
+
+    // Commands are handled one at a time, in the order they were received, and their responses are buffered in the
+    // writer. Reading a line takes precedence over flushing, so the responses to commands that a client pipelined
+    // (RFC #2449) are sent together once there are no complete lines left to read, instead of one write per command.
     loop {
-        if reader_closed && writer.buffer().is_empty() {
+        if reader_closed && writer.get_ref().buffer().is_empty() {
             break;
         }
 
         select! {
             biased;
+            // A command that's already being handled is allowed to finish, but after that the session is closed without
+            // entering the `UPDATE` state.
+            _ = wait_for_shutdown(&mut shutdown_receiver) => {
+                Pop3Response::err("[SYS/TEMP] Server is shutting down").write_to(&mut writer).await?;
+                break;
+            }
+            // A session kicked by an administrator is closed right away, also without entering the `UPDATE` state.
+            _ = kick.notified() => {
+                info!("Session kicked by an administrator");
+                session.server.emit_event(ServerEvent::SessionKicked { session_id: session.registration.id() });
+                Pop3Response::err("[SYS/TEMP] Session closed by an administrator").write_to(&mut writer).await?;
+                break;
+            }
             result = parsers::read_line(&mut reader, &mut parse_buf), if !reader_closed => {
                 match result {
                     Err(error) if error.kind() == ErrorKind::UnexpectedEof => {
                         reader_closed = true;
                         continue;
                     },
+                    // The responses to the commands before an overlong line are still sent, followed by the error.
+                    Err(error) if error.kind() == ErrorKind::InvalidData => {
+                        Pop3Response::err(&error).write_to(&mut writer).await?;
+                        writer.flush().await?;
+                        return Err(error);
+                    }
                     Err(error) => return Err(error),
                     _ => {}
                 }
 
+                writer.transcript_mut().record_command(&parse_buf);
                 let parse_result = parsers::parse_command(&mut parse_buf);
                 parse_buf.clear();
 
-                let command = match parse_result {
-                    Err(err) => {
-                        Pop3Response::err(err).write_to(&mut writer).await?;
-                        continue;
-                    }
-                    Ok(cmd) => cmd,
+                // Unregistered extension commands are counted as invalid, so clients can't make up new metric labels.
+                let keyword = match &parse_result {
+                    Ok(Pop3Command::Extension(command)) if session.server.commands().get(&command.keyword).is_none() => None,
+                    Ok(command) => Some(command.keyword()),
+                    Err(_) => None,
                 };
+                session.server.metrics().record_command(keyword);
+
+                if let Ok(Pop3Command::User(username)) = &parse_result {
+                    writer.transcript_mut().on_user_command(session.server.transcript_settings(), username);
+                }
 
-                match command {
-                    Pop3Command::User(user) => handlers::handle_user_command(&mut writer, &mut session, user).await?,
-                    Pop3Command::Pass(pass) => handlers::handle_pass_command(&mut writer, &mut session, pass).await?,
-                    Pop3Command::Stat => handlers::handle_stat_command(&mut writer, &mut session).await?,
-                    Pop3Command::List(arg) => handlers::handle_list_command(&mut writer, &mut session, arg).await?,
-                    Pop3Command::Retr(arg) => handlers::handle_retr_command(&mut writer, &mut session, arg).await?,
-                    Pop3Command::Dele(arg) => handlers::handle_dele_command(&mut writer, &mut session, arg).await?,
-                    Pop3Command::Noop => handlers::handle_noop_command(&mut writer, &mut session).await?,
-                    Pop3Command::Rset => handlers::handle_rset_command(&mut writer, &mut session).await?,
-                    Pop3Command::Quit => {
+                match parse_result {
+                    Err(err) => Pop3Response::err(err).write_to(&mut writer).await?,
+                    Ok(Pop3Command::User(user)) => handlers::handle_user_command(&mut writer, &mut session, user).await?,
+                    Ok(Pop3Command::Pass(pass)) => handlers::handle_pass_command(&mut writer, &mut session, pass).await?,
+                    Ok(Pop3Command::Stat) => handlers::handle_stat_command(&mut writer, &mut session).await?,
+                    Ok(Pop3Command::List(arg)) => handlers::handle_list_command(&mut writer, &mut session, arg).await?,
+                    Ok(Pop3Command::Retr(arg)) => handlers::handle_retr_command(&mut writer, &mut session, arg).await?,
+                    Ok(Pop3Command::Dele(arg)) => handlers::handle_dele_command(&mut writer, &mut session, arg).await?,
+                    Ok(Pop3Command::Noop) => handlers::handle_noop_command(&mut writer, &mut session).await?,
+                    Ok(Pop3Command::Rset) => handlers::handle_rset_command(&mut writer, &mut session).await?,
+                    Ok(Pop3Command::Capa) => handlers::handle_capa_command(&mut writer, &mut session).await?,
+                    Ok(Pop3Command::Extension(command)) => {
+                        handlers::handle_extension_command(&mut writer, &mut session, command).await?
+                    }
+                    Ok(Pop3Command::Quit) => {
                         handlers::handle_quit_command(&mut writer, &mut session).await?;
                         break;
                     }
                 }
+
+                if !matches!(session.state, session::Pop3SessionState::Authorization(_)) {
+                    writer.transcript_mut().on_authorization_end();
+                }
+
+                // Restart the inactivity timer now that the command was handled, using the timeout of the new state.
+                idle_timer.as_mut().reset(Instant::now() + session.idle_timeout());
             }
-            result = writer.flush(), if !writer.buffer().is_empty() => {
+            // This is only reached when reading the next line would have to wait for the client.
+            result = writer.flush(), if !writer.get_ref().buffer().is_empty() => {
                 result?;
             }
+            _ = &mut idle_timer => {
+                info!("Client timed out after {:?} of inactivity", session.idle_timeout());
+                return Ok(());
+            }
         }
     }
 
     writer.shutdown().await?;
-    printlnif!(!session.server.silent(), "Client disconnected");
+    info!("Client disconnected");
     Ok(())
 }
@@ -13,6 +13,7 @@ use std::{
 use inlined::TinyVec;
 use tokio::io::{AsyncBufRead, AsyncBufReadExt};
 
+use super::extensions::MAX_KEYWORD_LENGTH;
 use crate::{
     types::{MessageNumber, Pop3ArgString, Pop3Username},
     util::ascii,
@@ -22,15 +23,19 @@ use crate::{
 pub const MAX_COMMAND_LINE_LENGTH: usize = 255;
 
 // All command keywords are 4 bytes, so for easier comparison we represent them as little-endian int32s in uppercase.
-const USER_COMMAND_CODE: u32 = u32::from_le_bytes([b'U', b'S', b'E', b'R']);
-const PASS_COMMAND_CODE: u32 = u32::from_le_bytes([b'P', b'A', b'S', b'S']);
-const STAT_COMMAND_CODE: u32 = u32::from_le_bytes([b'S', b'T', b'A', b'T']);
-const LIST_COMMAND_CODE: u32 = u32::from_le_bytes([b'L', b'I', b'S', b'T']);
-const RETR_COMMAND_CODE: u32 = u32::from_le_bytes([b'R', b'E', b'T', b'R']);
-const DELE_COMMAND_CODE: u32 = u32::from_le_bytes([b'D', b'E', b'L', b'E']);
-const NOOP_COMMAND_CODE: u32 = u32::from_le_bytes([b'N', b'O', b'O', b'P']);
-const RSET_COMMAND_CODE: u32 = u32::from_le_bytes([b'R', b'S', b'E', b'T']);
-const QUIT_COMMAND_CODE: u32 = u32::from_le_bytes([b'Q', b'U', b'I', b'T']);
+const USER_COMMAND_CODE: u32 = u32::from_le_bytes(*b"USER");
+const PASS_COMMAND_CODE: u32 = u32::from_le_bytes(*b"PASS");
+const STAT_COMMAND_CODE: u32 = u32::from_le_bytes(*b"STAT");
+const LIST_COMMAND_CODE: u32 = u32::from_le_bytes(*b"LIST");
+const RETR_COMMAND_CODE: u32 = u32::from_le_bytes(*b"RETR");
+const DELE_COMMAND_CODE: u32 = u32::from_le_bytes(*b"DELE");
+const NOOP_COMMAND_CODE: u32 = u32::from_le_bytes(*b"NOOP");
+const RSET_COMMAND_CODE: u32 = u32::from_le_bytes(*b"RSET");
+const QUIT_COMMAND_CODE: u32 = u32::from_le_bytes(*b"QUIT");
+const CAPA_COMMAND_CODE: u32 = u32::from_le_bytes(*b"CAPA");
+
+/// The keywords of the commands implemented by the server itself, which can't be registered as extensions.
+const BUILT_IN_KEYWORDS: [&str; 10] = ["USER", "PASS", "QUIT", "STAT", "LIST", "RETR", "DELE", "NOOP", "RSET", "CAPA"];
 
 #[derive(Debug)]
 pub enum Pop3Command {
@@ -43,6 +48,43 @@ pub enum Pop3Command {
     Dele(MessageNumber),
     Noop,
     Rset,
+    Capa,
+    /// A command that isn't built into the server, which is handled if it was registered as an extension (see
+    /// [`super::extensions`]).
+    Extension(ExtensionCommand),
+}
+
+/// A command whose keyword isn't one of the built-in commands.
+#[derive(Debug)]
+pub struct ExtensionCommand {
+    /// The command's keyword, in uppercase.
+    pub keyword: String,
+    /// Everything after the keyword and the space following it, or an empty string.
+    pub arguments: String,
+}
+
+impl Pop3Command {
+    /// Gets the command's keyword, in uppercase.
+    pub fn keyword(&self) -> &str {
+        match self {
+            Self::User(_) => "USER",
+            Self::Pass(_) => "PASS",
+            Self::Quit => "QUIT",
+            Self::Stat => "STAT",
+            Self::List(_) => "LIST",
+            Self::Retr(_) => "RETR",
+            Self::Dele(_) => "DELE",
+            Self::Noop => "NOOP",
+            Self::Rset => "RSET",
+            Self::Capa => "CAPA",
+            Self::Extension(command) => &command.keyword,
+        }
+    }
+}
+
+/// Whether the given uppercase keyword belongs to one of the commands implemented by the server itself.
+pub fn is_built_in_keyword(keyword: &str) -> bool {
+    BUILT_IN_KEYWORDS.contains(&keyword)
 }
 
 #[derive(Debug)]
@@ -59,6 +101,7 @@ pub enum Pop3CommandError {
     Dele(NumericArgCommandError),
     Noop(NoArgCommandError),
     Rset(NoArgCommandError),
+    Capa(NoArgCommandError),
 }
 
 impl fmt::Display for Pop3CommandError {
@@ -76,6 +119,7 @@ impl fmt::Display for Pop3CommandError {
             Self::Dele(e) => e.fmt(f),
             Self::Noop(e) => e.fmt(f),
             Self::Rset(e) => e.fmt(f),
+            Self::Capa(e) => e.fmt(f),
         }
     }
 }
@@ -201,10 +245,13 @@ where
         let mut maybe_line_end_index = reader_buf.iter().position(|b| *b == b'\n');
         let consumed_bytes = maybe_line_end_index.map(|b| b + 1).unwrap_or(reader_buf.len());
 
-        // If a '\n' was found and it's not at the beginning of the line, remove the preceding '\r' if present.
+        // If a '\n' was found, remove the preceding '\r' if present. If the '\n' is the first of the newly read bytes, the
+        // '\r' may have been appended to `buf` by a previous read, when the CRLF was split across the reader's buffer.
         if let Some(line_end_index) = &mut maybe_line_end_index {
             if *line_end_index != 0 && reader_buf[*line_end_index - 1] == b'\r' {
                 *line_end_index -= 1;
+            } else if *line_end_index == 0 && buf.last() == Some(&b'\r') {
+                buf.pop();
             }
         }
 
@@ -245,22 +292,25 @@ pub fn parse_command(buf: &mut [u8]) -> Result<Pop3Command, Pop3CommandError> {
     // Check that the whole line consists only of printable ASCII characters and if not, return an appropriate error.
     let _ = ascii::printable_ascii_from_bytes(buf).map_err(Pop3CommandError::NonPrintableAsciiChar)?;
 
-    // All the arguments implemented in this server are exactly 4 chars long, let's ensure that here for easy parsing.
-    if buf.len() < 4 || (buf.len() > 4 && !buf[4].is_ascii_whitespace()) {
-        return Err(Pop3CommandError::UnknownCommand);
+    // The keyword extends up to the first whitespace. All the commands built into this server are exactly 4 chars long,
+    // while extension commands may have longer or shorter keywords.
+    let keyword_len = buf.iter().position(|b| b.is_ascii_whitespace()).unwrap_or(buf.len());
+    buf[..keyword_len].make_ascii_uppercase();
+
+    // Get the remaining arguments as a single string, stripping the space after the command, or an empty string.
+    let args = match buf.len() > keyword_len + 1 {
+        true => unsafe { std::str::from_utf8_unchecked(&buf[(keyword_len + 1)..]) },
+        false => "",
+    };
+
+    if keyword_len != 4 {
+        return parse_extension_command(&buf[..keyword_len], args);
     }
 
     // Calculate the command's "code", which is done by interpreting the uppercased chars as a little-endian u32.
-    buf[..4].make_ascii_uppercase();
     let command = <[u8; 4]>::try_from(&buf[..4]).unwrap();
     let command_code = u32::from_le_bytes(command);
 
-    // Get the remaining arguments as a single string, stripping the space after the command, or an empty string.
-    let args = match buf.len() >= 6 {
-        true => unsafe { std::str::from_utf8_unchecked(&buf[5..]) },
-        false => "",
-    };
-
     match command_code {
         USER_COMMAND_CODE => Ok(Pop3Command::User(parse_user_command(args)?)),
         PASS_COMMAND_CODE => Ok(Pop3Command::Pass(parse_pass_command(args)?)),
@@ -271,8 +321,22 @@ pub fn parse_command(buf: &mut [u8]) -> Result<Pop3Command, Pop3CommandError> {
         DELE_COMMAND_CODE => Ok(Pop3Command::Dele(parse_num_command(args).map_err(Pop3CommandError::Dele)?)),
         NOOP_COMMAND_CODE => parse_no_arg_command(args, Pop3Command::Noop).map_err(Pop3CommandError::Noop),
         RSET_COMMAND_CODE => parse_no_arg_command(args, Pop3Command::Rset).map_err(Pop3CommandError::Rset),
-        _ => Err(Pop3CommandError::UnknownCommand),
+        CAPA_COMMAND_CODE => parse_no_arg_command(args, Pop3Command::Capa).map_err(Pop3CommandError::Capa),
+        _ => parse_extension_command(&buf[..4], args),
+    }
+}
+
+fn parse_extension_command(keyword: &[u8], args: &str) -> Result<Pop3Command, Pop3CommandError> {
+    if keyword.is_empty() || keyword.len() > MAX_KEYWORD_LENGTH || !keyword.iter().all(u8::is_ascii_alphanumeric) {
+        return Err(Pop3CommandError::UnknownCommand);
     }
+
+    // The keyword was already checked to be ASCII.
+    let keyword = unsafe { std::str::from_utf8_unchecked(keyword) };
+    Ok(Pop3Command::Extension(ExtensionCommand {
+        keyword: keyword.to_string(),
+        arguments: args.to_string(),
+    }))
 }
 
 fn parse_user_command(args: &str) -> Result<Pop3Username, UserCommandError> {
@@ -3,14 +3,23 @@
 use std::{
     io,
     path::{Path, PathBuf},
+    time::{Duration, SystemTime},
 };
 
 use tokio::io::{AsyncBufReadExt, BufReader};
+use tracing::{debug, error, info, warn, Instrument, Span};
 
 use crate::{
-    printlnif,
-    state::Pop3ServerState,
-    types::{MessageNumber, MessageNumberCount, Pop3Username, MAILDIR_NEW_FOLDER, MAILDIR_OLD_FOLDER},
+    audit::{AuditAction, AuditRecord},
+    auth::MaildirOwner,
+    connection_tracker::ConnectionHandle,
+    crypto::MessageKey,
+    events::ServerEvent,
+    quota,
+    session_tracker::{SessionHandle, SessionState},
+    state::{LoggedInUser, Pop3ServerState},
+    storage,
+    types::{MessageNumber, MessageNumberCount, Pop3Username, MAILDIR_NEW_FOLDER},
     user_tracker::UserHandle,
 };
 
@@ -18,13 +27,29 @@ use crate::{
 pub struct Pop3Session {
     pub server: Pop3ServerState,
     pub state: Pop3SessionState,
+
+    /// The handle in the connection tracker for this session's connection.
+    connection: ConnectionHandle,
+
+    /// The handle in the session tracker for this session.
+    pub registration: SessionHandle,
 }
 
 impl Pop3Session {
-    pub const fn new(server: Pop3ServerState) -> Pop3Session {
+    pub const fn new(server: Pop3ServerState, connection: ConnectionHandle, registration: SessionHandle) -> Pop3Session {
         Self {
             server,
             state: Pop3SessionState::new(),
+            connection,
+            registration,
+        }
+    }
+
+    /// Gets how long this session may stay idle in its current state before the client is disconnected.
+    pub fn idle_timeout(&self) -> Duration {
+        match self.state {
+            Pop3SessionState::Transaction(_) => self.server.transaction_timeout(),
+            _ => self.server.auth_timeout(),
         }
     }
 
@@ -32,32 +57,28 @@ impl Pop3Session {
     /// session to the `TRANSACTION` state and returns [`Some`] with the amount of new messages.
     ///
     /// Returns [`None`] if a problem occurs while reading the user's maildrop.
-    pub async fn enter_transaction_state(&mut self, user_handle: UserHandle, mut maildrop_path: PathBuf) -> Option<MessageNumberCount> {
+    pub async fn enter_transaction_state(&mut self, logged_in_user: LoggedInUser) -> Option<MessageNumberCount> {
+        let user_handle = logged_in_user.handle;
+        let mut maildrop_path = logged_in_user.maildrop_path;
         maildrop_path.push(MAILDIR_NEW_FOLDER);
 
-        printlnif!(
-            !self.server.silent(),
-            "Opening user's {} maildrop at {}",
-            user_handle.username(),
-            maildrop_path.display()
-        );
+        info!("Opening user's {} maildrop at {}", user_handle.username(), maildrop_path.display());
 
         let username = user_handle.username();
 
         let mut directory_reader = tokio::fs::read_dir(&maildrop_path)
             .await
-            .inspect_err(|error| eprintln!("Unexpected error while reading user {}'s maildrop: {error}", user_handle.username()))
+            .inspect_err(|error| error!("Unexpected error while reading user {}'s maildrop: {error}", user_handle.username()))
             .ok()?;
 
         let mut messages = Vec::new();
 
-        // Just in case, we only load the first `MessageNumberCount::MAX` messages.
-        while messages.len() < MessageNumberCount::MAX as usize {
+        loop {
             let dir_entry = match directory_reader.next_entry().await {
                 Ok(Some(d)) => d,
                 Ok(None) => break,
                 Err(error) => {
-                    eprintln!("Unexpected directory error for user {username}'s maildrop: {error}");
+                    error!("Unexpected directory error for user {username}'s maildrop: {error}");
                     continue;
                 }
             };
@@ -66,26 +87,82 @@ impl Pop3Session {
             let file_type = match dir_entry.file_type().await {
                 Ok(t) => t,
                 Err(error) => {
-                    eprintln!("Unexpected error getting file type of {}: {error}", path.display());
+                    error!("Unexpected error getting file type of {}: {error}", path.display());
                     continue;
                 }
             };
 
-            if file_type.is_file() {
-                messages.push(Message::new(path));
+            if !file_type.is_file() {
+                continue;
             }
+
+            let delivery_time = match get_delivery_time(&path) {
+                Some(t) => t,
+                None => match dir_entry.metadata().await.and_then(|m| m.modified()) {
+                    Ok(t) => t,
+                    Err(error) => {
+                        error!("Unexpected error getting modification time of {}: {error}", path.display());
+                        SystemTime::UNIX_EPOCH
+                    }
+                },
+            };
+
+            messages.push((delivery_time, Message::new(path)));
         }
 
+        // Sort the messages by delivery time so message numbers don't depend on the order the filesystem lists them in.
+        // Ties are broken by file name, which in a maildir is unique.
+        let newest_first = self.server.newest_first();
+        messages.sort_unstable_by(|(t1, m1), (t2, m2)| {
+            let time_ordering = match newest_first {
+                true => t2.cmp(t1),
+                false => t1.cmp(t2),
+            };
+
+            time_ordering.then_with(|| m1.path.file_name().cmp(&m2.path.file_name()))
+        });
+
+        // Just in case, we only number the first `MessageNumberCount::MAX` messages. This is done after sorting, so an
+        // oversized maildrop still offers the oldest (or newest) messages rather than whichever were listed first.
+        if messages.len() > MessageNumberCount::MAX as usize {
+            warn!(
+                "User {username}'s maildrop holds {} messages, only the first {} are offered",
+                messages.len(),
+                MessageNumberCount::MAX
+            );
+            messages.truncate(MessageNumberCount::MAX as usize);
+        }
+
+        let messages: Vec<Message> = messages.into_iter().map(|(_, m)| m).collect();
         let messages_len = messages.len() as MessageNumberCount;
+        debug!(
+            "Loaded {messages_len} messages from user {username}'s maildrop, {} first",
+            if newest_first { "newest" } else { "oldest" }
+        );
+
         maildrop_path.pop();
-        self.state = Pop3SessionState::Transaction(TransactionState::new(maildrop_path, user_handle, messages));
+        self.connection.set_authenticated();
+        self.registration.set_user(username.clone());
+        self.registration.set_state(SessionState::Transaction);
+        self.server.emit_event(ServerEvent::LoginSucceeded {
+            session_id: self.registration.id(),
+            username: username.clone(),
+        });
+        Span::current().record("user", username.as_str());
+        self.state = Pop3SessionState::Transaction(TransactionState::new(
+            maildrop_path,
+            user_handle,
+            logged_in_user.encryption_key,
+            logged_in_user.owner,
+            messages,
+        ));
         Some(messages_len)
     }
 }
 
 impl Pop3Session {
-    /// Quits the current session and, if in the transaction state, deletes any messages marked for deletion by moving
-    /// them to the `cur` directory.
+    /// Quits the current session and, if in the transaction state, deletes any messages marked for deletion according
+    /// to the server's [`DeletionPolicy`].
     ///
     /// Returns [`Ok`] or [`Err`] depending on whether the operation succeeded, in both cases specifying the maount of
     /// deleted messages. In all cases, the state is set to the `END` state.
@@ -95,49 +172,94 @@ impl Pop3Session {
         let old_state = std::mem::replace(&mut self.state, Pop3SessionState::End);
 
         match old_state {
-            Pop3SessionState::Transaction(transaction_state) => handle_close_transaction(transaction_state).await,
+            Pop3SessionState::Transaction(transaction_state) => {
+                self.registration.set_state(SessionState::Update);
+                let username = transaction_state.username().clone();
+                let result = handle_close_transaction(transaction_state, &self.server, &self.registration).await;
+
+                let (Ok(count) | Err(count)) = result;
+                if count != 0 {
+                    self.server.emit_event(ServerEvent::DeletionsCommitted {
+                        session_id: self.registration.id(),
+                        username,
+                        count,
+                    });
+                }
+
+                result
+            }
             _ => Ok(0),
         }
     }
 }
 
-async fn handle_close_transaction(transaction_state: TransactionState) -> Result<MessageNumberCount, MessageNumberCount> {
+async fn handle_close_transaction(
+    transaction_state: TransactionState,
+    server: &Pop3ServerState,
+    registration: &SessionHandle,
+) -> Result<MessageNumberCount, MessageNumberCount> {
+    let deletion_policy = server.deletion_policy();
     if !transaction_state.messages.iter().any(|m| m.delete_requested) {
         return Ok(0);
     }
 
-    let mut pathbuf = transaction_state.maildrop_dir;
-    pathbuf.push(MAILDIR_OLD_FOLDER);
-    if let Err(error) = tokio::fs::create_dir_all(pathbuf.as_path()).await {
-        eprintln!("Could not ensure old messages folder exists: {error} on {}", pathbuf.display());
-        return Err(0);
-    }
+    let maildrop_dir = transaction_state.maildrop_dir;
+    let owner = transaction_state.owner;
+    let destination = match deletion_policy.prepare_destination(&maildrop_dir, owner).await {
+        Ok(d) => d,
+        Err(error) => {
+            error!(
+                "Could not ensure deleted messages folder exists in {}: {error}",
+                maildrop_dir.display()
+            );
+            return Err(0);
+        }
+    };
 
     let mut count = 0;
+    let mut removed_bytes = 0;
     let mut is_ok = true;
     for deleted_message in transaction_state.messages.iter().filter(|m| m.delete_requested) {
-        let deleted_message_file = match deleted_message.path.file_name() {
-            Some(f) => f,
-            None => {
-                eprintln!("Could not get file name from path {}", deleted_message.path.display());
-                is_ok = false;
-                continue;
-            }
+        let file_size = tokio::fs::metadata(&deleted_message.path).await.map(|m| m.len()).ok();
+
+        // The audit log records the message's size as listed to the client, which can only be calculated before removal.
+        let size = match (deleted_message.size, server.is_auditing()) {
+            (None, true) => calculate_message_size(&deleted_message.path, transaction_state.encryption_key.as_ref())
+                .await
+                .ok(),
+            (size, _) => size,
         };
 
-        pathbuf.push(deleted_message_file);
-        match tokio::fs::rename(&deleted_message.path.as_path(), pathbuf.as_path()).await {
-            Ok(()) => count += 1,
+        match deletion_policy.remove_message(&deleted_message.path, destination.as_deref()).await {
+            Ok(()) => {
+                count += 1;
+                removed_bytes += file_size.unwrap_or(0);
+
+                let record = AuditRecord {
+                    session_id: registration.id(),
+                    peer: registration.peer(),
+                    username: transaction_state.user_handle.username(),
+                    action: AuditAction::Deleted,
+                    message_path: &deleted_message.path,
+                    size,
+                };
+                server.record_audit(&maildrop_dir, owner, &record).await;
+            }
             Err(error) => {
                 is_ok = false;
-                eprintln!(
-                    "Error moving message file to old messages folder: {error} while moving {} to {}",
+                error!(
+                    "Error removing message file {} with deletion policy {deletion_policy}: {error}",
                     deleted_message.path.display(),
-                    pathbuf.display()
                 )
             }
         }
-        pathbuf.pop();
+    }
+
+    // Messages moved to another folder of the maildir still count towards its quota.
+    if destination.is_none() {
+        if let Err(error) = quota::record_removal(&maildrop_dir, removed_bytes, count as u64).await {
+            error!("Could not update quota usage file in {}: {error}", maildrop_dir.display());
+        }
     }
 
     match is_ok {
@@ -158,6 +280,15 @@ impl Pop3SessionState {
     pub const fn new() -> Self {
         Self::Authorization(AuthorizationState::new())
     }
+
+    /// Gets which of the states defined by RFC #1939 this is.
+    pub const fn kind(&self) -> SessionState {
+        match self {
+            Self::Authorization(_) => SessionState::Authorization,
+            Self::Transaction(_) => SessionState::Transaction,
+            Self::End => SessionState::Update,
+        }
+    }
 }
 
 /// Represents the state of a POP3 session in the `AUTHORIZATION` state.
@@ -177,9 +308,15 @@ pub struct TransactionState {
     /// The currently open maildrop's directory on the filesystem.
     maildrop_dir: PathBuf,
 
-    /// The handle in the user tracker for the logged in user. This is not accessed but must be present here so the
-    /// user's exclusive lock is automatically released when this handle is dropped.
-    _user_handle: UserHandle,
+    /// The handle in the user tracker for the logged in user. The user's exclusive lock is automatically released when
+    /// this handle is dropped.
+    user_handle: UserHandle,
+
+    /// The logged in user's message key, or [`None`] if the user doesn't have encryption enabled.
+    encryption_key: Option<MessageKey>,
+
+    /// The owner of the user's maildir, to whom files created in it are given.
+    owner: Option<MaildirOwner>,
 
     /// The list of messages on the user's maildrop at the time of opening it, alongisde information on each message.
     ///
@@ -193,14 +330,38 @@ pub enum GetMessageError {
 }
 
 impl TransactionState {
-    pub const fn new(maildrop_dir: PathBuf, user_handle: UserHandle, messages: Vec<Message>) -> Self {
+    pub const fn new(
+        maildrop_dir: PathBuf,
+        user_handle: UserHandle,
+        encryption_key: Option<MessageKey>,
+        owner: Option<MaildirOwner>,
+        messages: Vec<Message>,
+    ) -> Self {
         Self {
             maildrop_dir,
-            _user_handle: user_handle,
+            user_handle,
+            encryption_key,
+            owner,
             messages,
         }
     }
 
+    pub fn maildrop_dir(&self) -> &Path {
+        &self.maildrop_dir
+    }
+
+    pub fn owner(&self) -> Option<MaildirOwner> {
+        self.owner
+    }
+
+    pub const fn username(&self) -> &Pop3Username {
+        self.user_handle.username()
+    }
+
+    pub const fn encryption_key(&self) -> Option<&MessageKey> {
+        self.encryption_key.as_ref()
+    }
+
     pub const fn messages(&self) -> &Vec<Message> {
         &self.messages
     }
@@ -252,6 +413,27 @@ impl TransactionState {
         }
     }
 
+    /// Gets a message's size, calculating it if not already cached by traversing the message's file, converting LF line
+    /// endings to CRLF.
+    ///
+    /// The file is not modified; we simply count LF line endings as if they were CRLF. Encrypted and compressed
+    /// messages are decrypted and decompressed while traversing them, so the size is always that of the message as
+    /// sent to the client.
+    pub async fn calculate_message_size(&mut self, message_number: MessageNumber) -> Result<io::Result<u64>, GetMessageError> {
+        let encryption_key = self.encryption_key.clone();
+        let message = self.get_message_mut(message_number)?;
+        if let Some(size) = message.size {
+            return Ok(Ok(size));
+        }
+
+        let result = calculate_message_size(&message.path, encryption_key.as_ref()).await;
+        if let Ok(size) = result {
+            message.size = Some(size);
+        }
+
+        Ok(result)
+    }
+
     pub async fn ensure_all_sizes_loaded(&mut self) {
         if self.messages.iter().all(|m| m.size.is_some()) {
             return;
@@ -262,12 +444,16 @@ impl TransactionState {
         for message in &mut self.messages.iter().filter(|m| !m.delete_requested) {
             let maybe_size = message.size;
             let path = message.path.clone();
-            handles.push(tokio::task::spawn_local(async move {
-                match maybe_size {
-                    Some(size) => Ok(size),
-                    None => calculate_message_size(&path).await,
+            let encryption_key = self.encryption_key.clone();
+            handles.push(tokio::spawn(
+                async move {
+                    match maybe_size {
+                        Some(size) => Ok(size),
+                        None => calculate_message_size(&path, encryption_key.as_ref()).await,
+                    }
                 }
-            }));
+                .in_current_span(),
+            ));
         }
 
         for (handle, message) in handles.into_iter().zip(self.messages.iter_mut().filter(|m| !m.delete_requested)) {
@@ -303,20 +489,6 @@ impl Message {
         self.size
     }
 
-    /// Gets this message's size, calculating it if not already cached by traversing this message's file, converting LF
-    /// line endings to CRLF.
-    ///
-    /// The file is not modified; we simply count LF line endings as if they were CRLF.
-    pub async fn calculate_size(&mut self) -> io::Result<u64> {
-        if let Some(file_size) = self.size {
-            return Ok(file_size);
-        }
-
-        let file_size = calculate_message_size(&self.path).await?;
-        self.size = Some(file_size);
-        Ok(file_size)
-    }
-
     pub const fn delete_requested(&self) -> bool {
         self.delete_requested
     }
@@ -326,12 +498,30 @@ impl Message {
     }
 }
 
-async fn calculate_message_size(path: &Path) -> io::Result<u64> {
-    let file = tokio::fs::File::open(path)
+/// Gets a message's delivery time from its maildir file name, which by convention starts with the amount of seconds
+/// since the UNIX epoch at which the message was delivered, followed by a '.' character.
+///
+/// Returns [`None`] if the file name doesn't follow this format.
+fn get_delivery_time(path: &Path) -> Option<SystemTime> {
+    let file_name = path.file_name()?.to_str()?;
+    let seconds_str = file_name.split_once('.')?.0;
+    if seconds_str.is_empty() || !seconds_str.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+
+    let seconds = seconds_str.parse().ok()?;
+    SystemTime::UNIX_EPOCH.checked_add(Duration::from_secs(seconds))
+}
+
+/// Calculates the size of a message as listed to the client, which is its size with CRLF line endings. Like RFC #1939
+/// defines it, this doesn't include the periods added by byte-stuffing, nor a line ending added after an unterminated
+/// last line, so it can be smaller than the amount of bytes sent by `RETR`.
+async fn calculate_message_size(path: &Path, encryption_key: Option<&MessageKey>) -> io::Result<u64> {
+    let message_reader = storage::open_message(path, encryption_key)
         .await
-        .inspect_err(|error| eprintln!("Could not open file for reading {}: {error}", path.display()))?;
+        .inspect_err(|error| error!("Could not open file for reading {}: {error}", path.display()))?;
 
-    let mut reader = BufReader::new(file);
+    let mut reader = BufReader::new(message_reader);
     let mut file_size = 0;
     let mut was_last_char_cr = false;
 
@@ -340,7 +530,7 @@ async fn calculate_message_size(path: &Path) -> io::Result<u64> {
             Ok([]) => break,
             Ok(b) => b,
             Err(error) => {
-                eprintln!("Error while reading from file {}: {error}", path.display());
+                error!("Error while reading from file {}: {error}", path.display());
                 return Err(error);
             }
         };
@@ -0,0 +1,136 @@
+//! Dropping root privileges once the listening sockets are bound, optionally confining the server to a chroot.
+//!
+//! Binding the POP3 port usually requires starting as root, but nothing after that does. The user and group may be
+//! specified by name or by numeric id. If only a user is specified, their primary group and supplementary groups (as
+//! listed in the group database) are used.
+
+use std::io;
+use std::path::Path;
+
+/// Changes the root directory and the process's user and group, in that order.
+///
+/// Users and groups are looked up before changing the root directory, as the user and group databases usually aren't
+/// available inside the chroot. After changing the root directory the working directory is changed to the new root,
+/// so relative paths are resolved from there.
+#[cfg(unix)]
+pub fn drop_privileges(user: Option<&str>, group: Option<&str>, chroot_dir: Option<&Path>) -> io::Result<()> {
+    use std::{ffi::CString, os::unix::ffi::OsStrExt};
+
+    let user = user.map(lookup_user).transpose()?;
+    let gid = match group {
+        Some(group) => Some(lookup_group(group)?),
+        None => user.as_ref().map(|user| user.gid),
+    };
+
+    if let Some(chroot_dir) = chroot_dir {
+        let path = CString::new(chroot_dir.as_os_str().as_bytes())?;
+        check(unsafe { libc::chroot(path.as_ptr()) })?;
+        std::env::set_current_dir("/")?;
+    }
+
+    if let Some(gid) = gid {
+        match &user {
+            Some(user) => check(unsafe { libc::initgroups(user.name.as_ptr(), gid as _) })?,
+            None => check(unsafe { libc::setgroups(1, &gid) })?,
+        }
+
+        check(unsafe { libc::setgid(gid) })?;
+    }
+
+    if let Some(user) = &user {
+        check(unsafe { libc::setuid(user.uid) })?;
+
+        // Make sure the privileges can't be regained, which would mean they weren't properly dropped.
+        if user.uid != 0 && unsafe { libc::setuid(0) } == 0 {
+            return Err(io::Error::other("Privileges were not dropped, root can still be regained"));
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn drop_privileges(user: Option<&str>, group: Option<&str>, chroot_dir: Option<&Path>) -> io::Result<()> {
+    match (user, group, chroot_dir) {
+        (None, None, None) => Ok(()),
+        _ => Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "Dropping privileges is only supported on unix",
+        )),
+    }
+}
+
+#[cfg(unix)]
+fn check(result: libc::c_int) -> io::Result<()> {
+    match result {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+#[cfg(unix)]
+struct UserEntry {
+    name: std::ffi::CString,
+    uid: libc::uid_t,
+    gid: libc::gid_t,
+}
+
+/// Looks up a user by name or, if the name is numeric, by id.
+#[cfg(unix)]
+fn lookup_user(user: &str) -> io::Result<UserEntry> {
+    use std::ffi::{CStr, CString};
+
+    let c_user = CString::new(user)?;
+    let numeric_uid = user.parse::<libc::uid_t>().ok();
+
+    let mut entry: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut result = std::ptr::null_mut();
+    let mut buf = vec![0 as libc::c_char; 4096];
+
+    loop {
+        let error = unsafe {
+            match numeric_uid {
+                Some(uid) => libc::getpwuid_r(uid, &mut entry, buf.as_mut_ptr(), buf.len(), &mut result),
+                None => libc::getpwnam_r(c_user.as_ptr(), &mut entry, buf.as_mut_ptr(), buf.len(), &mut result),
+            }
+        };
+
+        match error {
+            0 if result.is_null() => return Err(io::Error::new(io::ErrorKind::NotFound, format!("User {user} not found"))),
+            0 => break,
+            libc::ERANGE => buf.resize(buf.len() * 2, 0),
+            error => return Err(io::Error::from_raw_os_error(error)),
+        }
+    }
+
+    Ok(UserEntry {
+        name: unsafe { CStr::from_ptr(entry.pw_name) }.to_owned(),
+        uid: entry.pw_uid,
+        gid: entry.pw_gid,
+    })
+}
+
+/// Looks up a group by name. Numeric group ids are used as-is.
+#[cfg(unix)]
+fn lookup_group(group: &str) -> io::Result<libc::gid_t> {
+    use std::ffi::CString;
+
+    if let Ok(gid) = group.parse::<libc::gid_t>() {
+        return Ok(gid);
+    }
+
+    let c_group = CString::new(group)?;
+    let mut entry: libc::group = unsafe { std::mem::zeroed() };
+    let mut result = std::ptr::null_mut();
+    let mut buf = vec![0 as libc::c_char; 4096];
+
+    loop {
+        let error = unsafe { libc::getgrnam_r(c_group.as_ptr(), &mut entry, buf.as_mut_ptr(), buf.len(), &mut result) };
+        match error {
+            0 if result.is_null() => return Err(io::Error::new(io::ErrorKind::NotFound, format!("Group {group} not found"))),
+            0 => return Ok(entry.gr_gid),
+            libc::ERANGE => buf.resize(buf.len() * 2, 0),
+            error => return Err(io::Error::from_raw_os_error(error)),
+        }
+    }
+}
@@ -0,0 +1,263 @@
+//! Support for Maildir++ quotas, which are stored in a `maildirsize` file at the root of each user's maildir.
+//!
+//! The first line of a `maildirsize` file is the quota definition, such as `10000000S,1000C` (at most 10MB and 1000
+//! messages). Each following line contains a size in bytes and a message count, separated by a space, which are added
+//! up to obtain the maildir's current usage. Whenever messages are removed a new line with negative values is appended,
+//! and when the file grows too large or can't be parsed it is recalculated from scratch by scanning the maildir.
+//!
+//! Like Maildir++ specifies, the messages in the `new` and `cur` folders of the maildir and of each of its folders are
+//! counted. Messages moved to the `.Trash` or `.Retained` folder thus still count, until they're actually removed.
+
+use std::{
+    fmt,
+    io::{self, ErrorKind},
+    path::Path,
+    str::FromStr,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use tokio::io::AsyncWriteExt;
+
+use crate::types::{MAILDIRSIZE_FILE_NAME, MAILDIR_NEW_FOLDER, MAILDIR_OLD_FOLDER};
+use crate::util::ownership;
+
+/// The size above which a `maildirsize` file is recalculated, as specified by Maildir++.
+const MAILDIRSIZE_RECALCULATE_THRESHOLD: u64 = 5120;
+
+/// Tells apart the temporary files written at once by this process when rewriting `maildirsize` files.
+static NEXT_TMP_FILE_ID: AtomicU64 = AtomicU64::new(0);
+
+/// A Maildir++ quota definition, specifying the maximum amount of bytes and messages a maildir may hold.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Quota {
+    pub max_bytes: Option<u64>,
+    pub max_messages: Option<u64>,
+}
+
+pub struct InvalidQuotaError;
+
+impl FromStr for Quota {
+    type Err = InvalidQuotaError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut quota = Quota::default();
+
+        for part in s.trim().split(',').filter(|p| !p.is_empty()) {
+            let (value, field) = if let Some(value) = part.strip_suffix('S') {
+                (value, &mut quota.max_bytes)
+            } else if let Some(value) = part.strip_suffix('C') {
+                (value, &mut quota.max_messages)
+            } else {
+                return Err(InvalidQuotaError);
+            };
+
+            let value = value.parse::<u64>().map_err(|_| InvalidQuotaError)?;
+            if field.replace(value).is_some() {
+                return Err(InvalidQuotaError);
+            }
+        }
+
+        match quota.max_bytes.is_some() || quota.max_messages.is_some() {
+            true => Ok(quota),
+            false => Err(InvalidQuotaError),
+        }
+    }
+}
+
+impl fmt::Display for Quota {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.max_bytes, self.max_messages) {
+            (Some(bytes), Some(messages)) => write!(f, "{bytes}S,{messages}C"),
+            (Some(bytes), None) => write!(f, "{bytes}S"),
+            (None, Some(messages)) => write!(f, "{messages}C"),
+            (None, None) => Ok(()),
+        }
+    }
+}
+
+/// The current usage of a maildir, alongside its quota if it has one.
+#[derive(Debug, Clone, Copy)]
+pub struct QuotaUsage {
+    pub quota: Option<Quota>,
+    pub bytes: u64,
+    pub messages: u64,
+}
+
+impl QuotaUsage {
+    /// Gets the usage as a percentage of the quota, taking whichever of the bytes or messages limits is closest to
+    /// being exceeded. Returns [`None`] if there is no quota.
+    pub fn percent(&self) -> Option<u64> {
+        let quota = self.quota?;
+        let bytes_percent = quota.max_bytes.map(|max| percent_of(self.bytes, max));
+        let messages_percent = quota.max_messages.map(|max| percent_of(self.messages, max));
+        bytes_percent.max(messages_percent)
+    }
+}
+
+impl fmt::Display for QuotaUsage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} bytes in {} messages", self.bytes, self.messages)?;
+        match (self.quota, self.percent()) {
+            (Some(quota), Some(percent)) => write!(f, " ({percent}% of quota {quota})"),
+            _ => write!(f, " (no quota)"),
+        }
+    }
+}
+
+fn percent_of(value: u64, max: u64) -> u64 {
+    match max {
+        0 => u64::MAX,
+        max => (value as u128 * 100 / max as u128).min(u64::MAX as u128) as u64,
+    }
+}
+
+/// Reads the usage and quota of the given maildir.
+///
+/// If the maildir has no `maildirsize` file, the usage is calculated by scanning the maildir and no quota is returned.
+/// If the file exists but is too large or malformed, it is recalculated and rewritten.
+pub async fn read_usage(maildir: &Path) -> io::Result<QuotaUsage> {
+    let path = maildir.join(MAILDIRSIZE_FILE_NAME);
+    let contents = match tokio::fs::read(&path).await {
+        Ok(c) => c,
+        Err(error) if error.kind() == ErrorKind::NotFound => {
+            let (bytes, messages) = calculate_usage(maildir).await?;
+            return Ok(QuotaUsage {
+                quota: None,
+                bytes,
+                messages,
+            });
+        }
+        Err(error) => return Err(error),
+    };
+
+    let contents = String::from_utf8_lossy(&contents);
+    let mut lines = contents.lines();
+    let quota = match lines.next().map(Quota::from_str) {
+        Some(Ok(q)) => q,
+        _ => {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                "maildirsize file has no valid quota definition",
+            ))
+        }
+    };
+
+    if contents.len() as u64 <= MAILDIRSIZE_RECALCULATE_THRESHOLD {
+        if let Some((bytes, messages)) = sum_maildirsize_lines(lines) {
+            return Ok(QuotaUsage {
+                quota: Some(quota),
+                bytes: bytes.max(0) as u64,
+                messages: messages.max(0) as u64,
+            });
+        }
+    }
+
+    set_quota(maildir, quota).await
+}
+
+fn sum_maildirsize_lines<'a>(lines: impl Iterator<Item = &'a str>) -> Option<(i64, i64)> {
+    let mut total_bytes = 0i64;
+    let mut total_messages = 0i64;
+
+    for line in lines.filter(|l| !l.trim().is_empty()) {
+        let mut split = line.split_ascii_whitespace();
+        let bytes = split.next()?.parse::<i64>().ok()?;
+        let messages = split.next()?.parse::<i64>().ok()?;
+        if split.next().is_some() {
+            return None;
+        }
+
+        total_bytes = total_bytes.saturating_add(bytes);
+        total_messages = total_messages.saturating_add(messages);
+    }
+
+    Some((total_bytes, total_messages))
+}
+
+/// Sets the quota for the given maildir, recalculating its usage and rewriting its `maildirsize` file. The new file is
+/// given to the owner of the maildir's folder.
+pub async fn set_quota(maildir: &Path, quota: Quota) -> io::Result<QuotaUsage> {
+    let (bytes, messages) = calculate_usage(maildir).await?;
+
+    // Write to a temporary file first and then rename it, so the `maildirsize` file is replaced atomically. The name is
+    // unique like those of Maildir's `tmp` files, so concurrent rewrites don't write to the same temporary file.
+    let path = maildir.join(MAILDIRSIZE_FILE_NAME);
+    let tmp_id = NEXT_TMP_FILE_ID.fetch_add(1, Ordering::Relaxed);
+    let tmp_path = maildir.join(format!("{MAILDIRSIZE_FILE_NAME}.{}_{tmp_id}.tmp", std::process::id()));
+    let mut file = tokio::fs::File::create(&tmp_path).await?;
+    file.write_all(format!("{quota}\n{bytes} {messages}\n").as_bytes()).await?;
+    file.flush().await?;
+    drop(file);
+    ownership::set_owner(&tmp_path, ownership::maildir_owner(maildir).await).await?;
+    tokio::fs::rename(&tmp_path, &path).await?;
+
+    Ok(QuotaUsage {
+        quota: Some(quota),
+        bytes,
+        messages,
+    })
+}
+
+/// Records in the given maildir's `maildirsize` file that messages totalling `bytes` bytes were removed. Messages that
+/// are only moved to another of the maildir's folders aren't removed, as they still count towards its usage.
+///
+/// Does nothing if no messages were removed, or if the maildir has no `maildirsize` file, as then it has no quota.
+pub async fn record_removal(maildir: &Path, bytes: u64, messages: u64) -> io::Result<()> {
+    if messages == 0 {
+        return Ok(());
+    }
+
+    let path = maildir.join(MAILDIRSIZE_FILE_NAME);
+    let mut file = match tokio::fs::OpenOptions::new().append(true).open(&path).await {
+        Ok(f) => f,
+        Err(error) if error.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(error) => return Err(error),
+    };
+
+    file.write_all(format!("-{bytes} -{messages}\n").as_bytes()).await?;
+    file.flush().await
+}
+
+/// Calculates the amount of bytes and messages in a maildir by scanning the `new` and `cur` folders of the maildir and of
+/// each of its Maildir++ folders, whose names start with a '.'.
+async fn calculate_usage(maildir: &Path) -> io::Result<(u64, u64)> {
+    let mut directory_reader = match tokio::fs::read_dir(maildir).await {
+        Ok(d) => d,
+        Err(error) if error.kind() == ErrorKind::NotFound => return Ok((0, 0)),
+        Err(error) => return Err(error),
+    };
+
+    let mut folders = vec![maildir.to_path_buf()];
+    while let Some(dir_entry) = directory_reader.next_entry().await? {
+        if dir_entry.file_name().as_encoded_bytes().starts_with(b".") && dir_entry.file_type().await?.is_dir() {
+            folders.push(dir_entry.path());
+        }
+    }
+
+    let mut usage = (0, 0);
+    for folder in folders {
+        add_folder_usage(&folder.join(MAILDIR_NEW_FOLDER), &mut usage).await?;
+        add_folder_usage(&folder.join(MAILDIR_OLD_FOLDER), &mut usage).await?;
+    }
+
+    Ok(usage)
+}
+
+/// Adds the amount of bytes and messages in a folder of messages to `usage`. A folder that doesn't exist is empty.
+async fn add_folder_usage(folder: &Path, (bytes, messages): &mut (u64, u64)) -> io::Result<()> {
+    let mut directory_reader = match tokio::fs::read_dir(folder).await {
+        Ok(d) => d,
+        Err(error) if error.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(error) => return Err(error),
+    };
+
+    while let Some(dir_entry) = directory_reader.next_entry().await? {
+        let metadata = dir_entry.metadata().await?;
+        if metadata.is_file() {
+            *bytes += metadata.len();
+            *messages += 1;
+        }
+    }
+
+    Ok(())
+}
@@ -1,85 +1,542 @@
+//! Building and running a POP3 server.
+//!
+//! A server is configured with a [`ServerBuilder`], which binds its listening sockets and returns a [`Server`]. Calling
+//! [`Server::run`] gives a future that accepts and serves clients until the server is told to shut down, either through
+//! a [`ServerHandle`] or the admin interface, and then waits for the open sessions to close.
+
 use std::io::{self, ErrorKind};
 use std::net::SocketAddr;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
 
-use crate::args::StartupArguments;
-use crate::state::Pop3ServerState;
+use crate::args::{DEFAULT_BUFFER_SIZE, DEFAULT_GRACE_PERIOD, DEFAULT_IDLE_TIMEOUT, DEFAULT_MAILDIRS_FILE};
+use crate::audit::AuditSettings;
+use crate::auth::{Authenticator, PasswordFileAuthenticator};
+use crate::connection_tracker::{ConnectionHandle, ConnectionLimits};
+use crate::crypto::ServerKey;
+use crate::deletion::DeletionPolicy;
+use crate::events::ServerEvent;
+use crate::hooks::HookSettings;
+use crate::pop3::extensions::CommandRegistry;
+use crate::session_tracker::SessionHandle;
+use crate::state::{Pop3ServerSettings, Pop3ServerState};
+use crate::transcript::{Transcript, TranscriptSettings};
 use crate::types::{MAILDIR_NEW_FOLDER, PASSWORD_FILE_NAME};
+use crate::util::ownership;
 use crate::util::sockets::{AcceptFromAny, PrintSockaddrOrUnknown};
-use crate::{pop3, printlnif};
+use crate::{admin, crypto, hooks, metrics, pop3, quota, systemd};
 use tokio::io::AsyncWriteExt;
 use tokio::net::{TcpListener, TcpStream};
+use tokio::select;
+use tokio::sync::{broadcast, mpsc, watch};
+use tokio::task::{JoinHandle, JoinSet};
+use tokio::time::Instant;
+use tracing::{debug, error, field, info, info_span, warn, Instrument};
+
+/// The id assigned to the next client session, used to tell sessions apart in the logs.
+static NEXT_SESSION_ID: AtomicU64 = AtomicU64::new(1);
 
-pub async fn run_server(startup_args: StartupArguments) -> io::Result<()> {
-    let verbose = startup_args.verbose;
-    let silent = startup_args.silent;
+/// Configures a POP3 server before binding its sockets.
+///
+/// Every setting starts out with the same default as the command line's. The server must be given at least one
+/// listening socket, either with [`ServerBuilder::listener`] or [`ServerBuilder::bind`].
+pub struct ServerBuilder {
+    listeners: Vec<TcpListener>,
+    bind_addresses: Vec<SocketAddr>,
+    settings: Pop3ServerSettings,
+    grace_period: Duration,
+    hooks: HookSettings,
+    metrics_listeners: Vec<TcpListener>,
+    admin_listeners: Vec<TcpListener>,
+    admin_password: Option<String>,
+}
 
-    for (username, password) in &startup_args.users {
-        if let Err(error) = create_user_maildir(silent, &startup_args.maildirs_file, username, password).await {
-            eprintln!("Could not create or update user {username} as requested via parameter: {error}");
+impl Default for ServerBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ServerBuilder {
+    pub fn new() -> Self {
+        Self {
+            listeners: Vec::new(),
+            bind_addresses: Vec::new(),
+            settings: Pop3ServerSettings {
+                buffer_size: DEFAULT_BUFFER_SIZE,
+                maildirs_dir: DEFAULT_MAILDIRS_FILE.into(),
+                transformer_file: None,
+                newest_first: false,
+                quota_reject_percent: None,
+                authenticator: Arc::new(PasswordFileAuthenticator),
+                commands: Arc::default(),
+                deletion_policy: DeletionPolicy::default(),
+                auth_timeout: DEFAULT_IDLE_TIMEOUT,
+                transaction_timeout: DEFAULT_IDLE_TIMEOUT,
+                connection_limits: ConnectionLimits::default(),
+                transcripts: TranscriptSettings::default(),
+                audit: AuditSettings::default(),
+                server_key: None,
+            },
+            grace_period: DEFAULT_GRACE_PERIOD,
+            hooks: HookSettings::default(),
+            metrics_listeners: Vec::new(),
+            admin_listeners: Vec::new(),
+            admin_password: None,
         }
     }
 
-    let mut listeners = Vec::with_capacity(startup_args.pop3_bind_sockets.len());
+    /// Accepts POP3 clients on an already bound listening socket.
+    pub fn listener(mut self, listener: TcpListener) -> Self {
+        self.listeners.push(listener);
+        self
+    }
+
+    /// Accepts POP3 clients on a socket bound to the given address when the server is built. Addresses that one of the
+    /// listeners passed to [`ServerBuilder::listener`] is already bound to are skipped.
+    pub fn bind(mut self, address: SocketAddr) -> Self {
+        self.bind_addresses.push(address);
+        self
+    }
 
-    for sockaddr in startup_args.pop3_bind_sockets {
-        match TcpListener::bind(sockaddr).await {
-            Ok(l) => listeners.push(l),
-            Err(err) => eprintln!("Failed to bind listening socket at {sockaddr}: {err}"),
+    /// Replaces all the settings the server's state is created with, such as those from the command line's arguments.
+    pub fn settings(mut self, settings: Pop3ServerSettings) -> Self {
+        self.settings = settings;
+        self
+    }
+
+    /// The directory holding the users' maildirs.
+    pub fn maildirs_dir(mut self, maildirs_dir: impl Into<PathBuf>) -> Self {
+        self.settings.maildirs_dir = maildirs_dir.into();
+        self
+    }
+
+    /// The size of each session's read and write buffers.
+    pub fn buffer_size(mut self, buffer_size: u32) -> Self {
+        self.settings.buffer_size = buffer_size;
+        self
+    }
+
+    /// Whether messages are numbered from newest to oldest, rather than from oldest to newest.
+    pub fn newest_first(mut self, newest_first: bool) -> Self {
+        self.settings.newest_first = newest_first;
+        self
+    }
+
+    /// The key that users' encryption keys are wrapped with. Users with encryption enabled can't log in without it.
+    pub fn server_key(mut self, server_key: ServerKey) -> Self {
+        self.settings.server_key = Some(server_key);
+        self
+    }
+
+    /// What happens to messages whose deletion is committed.
+    pub fn deletion_policy(mut self, deletion_policy: DeletionPolicy) -> Self {
+        self.settings.deletion_policy = deletion_policy;
+        self
+    }
+
+    /// Checks the passwords users log in with, instead of the `password` file in their maildirs.
+    pub fn authenticator(mut self, authenticator: impl Authenticator + 'static) -> Self {
+        self.settings.authenticator = Arc::new(authenticator);
+        self
+    }
+
+    /// Accepts the site-specific commands in the given registry in addition to the built-in ones.
+    pub fn commands(mut self, commands: CommandRegistry) -> Self {
+        self.settings.commands = Arc::new(commands);
+        self
+    }
+
+    /// How long a client in the `AUTHORIZATION` state may stay idle before being disconnected.
+    pub fn auth_timeout(mut self, timeout: Duration) -> Self {
+        self.settings.auth_timeout = timeout;
+        self
+    }
+
+    /// How long a client in the `TRANSACTION` state may stay idle before being disconnected.
+    pub fn transaction_timeout(mut self, timeout: Duration) -> Self {
+        self.settings.transaction_timeout = timeout;
+        self
+    }
+
+    /// Rejects logins from users whose quota usage is over this percentage, or [`None`] to never reject them.
+    pub fn quota_reject_percent(mut self, percent: Option<u64>) -> Self {
+        self.settings.quota_reject_percent = percent;
+        self
+    }
+
+    /// The limits on how many clients may be connected at once.
+    pub fn connection_limits(mut self, limits: ConnectionLimits) -> Self {
+        self.settings.connection_limits = limits;
+        self
+    }
+
+    /// How long to wait for open sessions to close once shutting down, before aborting them.
+    pub fn grace_period(mut self, grace_period: Duration) -> Self {
+        self.grace_period = grace_period;
+        self
+    }
+
+    /// The event commands and event logs to run for every event.
+    pub fn hooks(mut self, hooks: HookSettings) -> Self {
+        self.hooks = hooks;
+        self
+    }
+
+    /// Which sessions to record protocol transcripts of.
+    pub fn transcripts(mut self, transcripts: TranscriptSettings) -> Self {
+        self.settings.transcripts = transcripts;
+        self
+    }
+
+    /// Where to write the audit log of retrieved and deleted messages.
+    pub fn audit(mut self, audit: AuditSettings) -> Self {
+        self.settings.audit = audit;
+        self
+    }
+
+    /// Serves Prometheus metrics on an already bound listening socket.
+    pub fn metrics_listener(mut self, listener: TcpListener) -> Self {
+        self.metrics_listeners.push(listener);
+        self
+    }
+
+    /// Serves the admin interface on an already bound listening socket. An admin password must also be set.
+    pub fn admin_listener(mut self, listener: TcpListener) -> Self {
+        self.admin_listeners.push(listener);
+        self
+    }
+
+    /// The password clients of the admin interface must authenticate with, which can't be empty.
+    pub fn admin_password(mut self, password: impl Into<String>) -> Self {
+        self.admin_password = Some(password.into());
+        self
+    }
+
+    /// Binds the server's listening sockets and creates its state, without accepting any clients yet.
+    ///
+    /// Fails if no listening socket could be bound, or if the admin interface is enabled without a password or with an
+    /// empty one.
+    pub async fn build(self) -> io::Result<Server> {
+        let admin_password = match (
+            self.admin_listeners.is_empty(),
+            self.admin_password.filter(|password| !password.is_empty()),
+        ) {
+            (false, None) => return Err(io::Error::new(ErrorKind::InvalidInput, "The admin interface requires a password")),
+            (_, password) => password.unwrap_or_default(),
+        };
+
+        let mut listeners = self.listeners;
+        bind_missing_listeners(&mut listeners, &self.bind_addresses).await;
+        if listeners.is_empty() {
+            return Err(io::Error::other("Failed to bind any listening sockets, aborting server"));
+        }
+
+        let (control_sender, control_receiver) = mpsc::unbounded_channel();
+        Ok(Server {
+            listeners,
+            state: Pop3ServerState::new(self.settings),
+            grace_period: self.grace_period,
+            hooks: self.hooks,
+            metrics_listeners: self.metrics_listeners,
+            admin_listeners: self.admin_listeners,
+            admin_password,
+            control_sender,
+            control_receiver,
+        })
+    }
+}
+
+/// A POP3 server whose sockets are bound, as built by a [`ServerBuilder`].
+pub struct Server {
+    listeners: Vec<TcpListener>,
+    state: Pop3ServerState,
+    grace_period: Duration,
+    hooks: HookSettings,
+    metrics_listeners: Vec<TcpListener>,
+    admin_listeners: Vec<TcpListener>,
+    admin_password: String,
+    control_sender: mpsc::UnboundedSender<ServerControl>,
+    control_receiver: mpsc::UnboundedReceiver<ServerControl>,
+}
+
+/// Requests sent to a running server through a [`ServerHandle`].
+enum ServerControl {
+    Reload(Box<ServerReload>),
+    Shutdown,
+}
+
+/// The new configuration applied to a running server by [`ServerHandle::reload`].
+pub struct ServerReload {
+    /// The addresses to listen on. Listening sockets bound to any other address are closed, including those passed to
+    /// [`ServerBuilder::listener`].
+    pub bind_addresses: Vec<SocketAddr>,
+    /// The settings for new sessions. Sessions that are already open keep using the settings they started with.
+    pub settings: Pop3ServerSettings,
+    pub grace_period: Duration,
+    pub hooks: HookSettings,
+}
+
+/// Controls a running [`Server`]. This may be cloned, and outlive the server, in which case it has no effect.
+#[derive(Clone)]
+pub struct ServerHandle {
+    control: mpsc::UnboundedSender<ServerControl>,
+}
+
+impl ServerHandle {
+    /// Applies a new configuration to the server.
+    pub fn reload(&self, reload: ServerReload) {
+        let _ = self.control.send(ServerControl::Reload(Box::new(reload)));
+    }
+
+    /// Tells the server to stop accepting clients and wait for the open sessions to close.
+    pub fn shutdown(&self) {
+        let _ = self.control.send(ServerControl::Shutdown);
+    }
+}
+
+impl Server {
+    /// Gets a handle with which to control the server once it's running.
+    pub fn handle(&self) -> ServerHandle {
+        ServerHandle {
+            control: self.control_sender.clone(),
         }
     }
 
-    if listeners.is_empty() {
-        return Err(io::Error::new(
-            ErrorKind::Other,
-            "Failed to bind any listening sockets, aborting server",
-        ));
+    /// The addresses the server is listening on.
+    pub fn local_addrs(&self) -> Vec<SocketAddr> {
+        self.listeners.iter().filter_map(|listener| listener.local_addr().ok()).collect()
     }
 
-    let server_state = Pop3ServerState::new(
-        startup_args.verbose,
-        startup_args.silent,
-        startup_args.buffer_size,
-        startup_args.maildirs_file,
-        startup_args.transformer_file,
-    );
+    /// Subscribes to the server's events, receiving those emitted from now on.
+    pub fn subscribe_events(&self) -> broadcast::Receiver<ServerEvent> {
+        self.state.subscribe_events()
+    }
+
+    /// Accepts and serves clients until the server is told to shut down, then waits up to the grace period for the open
+    /// sessions to close. Returns [`Err`] if any sessions had to be aborted.
+    pub async fn run(self) -> io::Result<()> {
+        let Self {
+            mut listeners,
+            state: mut server_state,
+            mut grace_period,
+            hooks: hook_settings,
+            metrics_listeners,
+            admin_listeners,
+            admin_password,
+            control_sender: _control_sender,
+            mut control_receiver,
+        } = self;
 
-    loop {
-        match listeners.accept_from_any().await {
-            Ok((socket, address)) => {
-                printlnif!(startup_args.verbose, "Incoming connection from {address}");
-                tokio::task::spawn_local(handle_client_wrapper(socket, address, server_state.clone()));
+        let mut hook_tasks = hooks::spawn_hooks(&hook_settings, &server_state);
+        let metrics_task = match metrics_listeners.is_empty() {
+            true => None,
+            false => {
+                let connections = server_state.connections().clone();
+                let task = metrics::serve_metrics(metrics_listeners, server_state.metrics().clone(), connections);
+                Some(tokio::spawn(task))
             }
-            Err((listener_index, error)) => {
-                let listener = listeners.swap_remove(listener_index);
-                let listener_addr = PrintSockaddrOrUnknown(listener.local_addr().ok());
-                eprintln!("Error while accepting incoming connection from listener {listener_addr}: {error}");
-                drop(listener);
+        };
+
+        // The admin interface is handed the current state whenever the configuration is reloaded.
+        let (state_sender, state_receiver) = watch::channel(server_state.clone());
+        let admin_task =
+            (!admin_listeners.is_empty()).then(move || tokio::spawn(admin::serve_admin(admin_listeners, admin_password, state_receiver)));
+        let mut shutdown_receiver = server_state.shutdown_receiver();
+
+        // All client tasks are kept in a JoinSet, so on shutdown we can wait for them to finish.
+        let mut client_tasks = JoinSet::new();
+
+        systemd::notify(&format!("READY=1\nSTATUS=Listening on {} sockets", listeners.len()));
+
+        loop {
+            select! {
+                result = listeners.accept_from_any() => match result {
+                    Ok((socket, address)) => {
+                        let connections = server_state.connections();
+                        let register_result = connections.try_register(address.ip());
+                        server_state.metrics().record_connection(register_result.is_ok());
+                        match register_result {
+                            Ok(handle) => {
+                                let session_id = NEXT_SESSION_ID.fetch_add(1, Ordering::Relaxed);
+                                let span = info_span!("session", id = session_id, peer = %address, user = field::Empty);
+                                span.in_scope(|| debug!("Incoming connection ({})", connections.counts()));
+                                let registration = server_state.sessions().register(session_id, address);
+                                server_state.emit_event(ServerEvent::SessionOpened { session_id, peer: address });
+                                let task = handle_client_wrapper(socket, address, server_state.clone(), handle, registration);
+                                client_tasks.spawn(task.instrument(span));
+                            }
+                            Err(limit_exceeded) => {
+                                let reason = limit_exceeded.get_reason_str();
+                                warn!("Rejected connection from {address}: {reason} ({})", connections.counts());
+                                client_tasks.spawn(async move {
+                                    let _ = pop3::reject_client(socket, reason).await;
+                                });
+                            }
+                        }
+                    }
+                    Err((listener_index, error)) => {
+                        let listener = listeners.swap_remove(listener_index);
+                        let listener_addr = PrintSockaddrOrUnknown(listener.local_addr().ok());
+                        error!("Error while accepting incoming connection from listener {listener_addr}: {error}");
+                        drop(listener);
+                    }
+                },
+                Some(_) = client_tasks.join_next() => {}
+                Some(control) = control_receiver.recv() => match control {
+                    ServerControl::Reload(reload) => {
+                        let ServerReload { bind_addresses, settings, grace_period: new_grace_period, hooks: hook_settings } = *reload;
+                        update_listeners(&mut listeners, &bind_addresses).await;
+                        if listeners.is_empty() {
+                            warn!("Not listening on any sockets after reloading configuration");
+                        }
+
+                        grace_period = new_grace_period;
+
+                        // Sessions that are already open keep their reference to the old state, and thus its settings.
+                        server_state = server_state.reload(settings);
+                        state_sender.send_replace(server_state.clone());
+
+                        hook_tasks.iter().for_each(JoinHandle::abort);
+                        hook_tasks = hooks::spawn_hooks(&hook_settings, &server_state);
+                        info!("Configuration reloaded ({})", server_state.connections().counts());
+                        systemd::notify(&format!("STATUS=Listening on {} sockets", listeners.len()));
+                    }
+                    ServerControl::Shutdown => break,
+                },
+                _ = pop3::wait_for_shutdown(&mut shutdown_receiver) => {
+                    info!("Shutting down");
+                    break;
+                }
             }
         }
+
+        // Stop accepting connections and tell all sessions to close once they're done with their current command.
+        drop(listeners);
+        if let Some(metrics_task) = metrics_task {
+            metrics_task.abort();
+        }
+        if let Some(admin_task) = admin_task {
+            admin_task.abort();
+        }
+        server_state.begin_shutdown();
+
+        let remaining = client_tasks.len();
+        systemd::notify(&format!("STOPPING=1\nSTATUS=Waiting for {remaining} sessions to close"));
+        if remaining != 0 {
+            info!("Waiting up to {grace_period:?} for {remaining} sessions to close");
+        }
+
+        let drain_result = tokio::time::timeout(grace_period, async { while client_tasks.join_next().await.is_some() {} }).await;
+
+        let aborted = client_tasks.len();
+        client_tasks.shutdown().await;
+
+        // With every session gone the states can be dropped, which closes the event channel once the hooks have handled
+        // the events left in it.
+        drop(state_sender);
+        drop(server_state);
+        hooks::finish_hooks(hook_tasks).await;
+
+        if drain_result.is_err() {
+            return Err(io::Error::new(
+                ErrorKind::TimedOut,
+                format!("Grace period expired, aborted {aborted} sessions that were still open"),
+            ));
+        }
+
+        info!("All sessions closed, server stopped");
+        Ok(())
+    }
+}
+
+/// Closes any listeners not bound to one of the given addresses, and binds new listeners for any addresses that
+/// aren't being listened on yet.
+async fn update_listeners(listeners: &mut Vec<TcpListener>, bind_sockets: &[SocketAddr]) {
+    listeners.retain(|listener| {
+        let keep = listener.local_addr().is_ok_and(|addr| bind_sockets.contains(&addr));
+        if !keep {
+            let listener_addr = PrintSockaddrOrUnknown(listener.local_addr().ok());
+            info!("Closing listening socket at {listener_addr}");
+        }
+        keep
+    });
+
+    bind_missing_listeners(listeners, bind_sockets).await;
+}
+
+/// Binds new listeners for any of the given addresses that aren't being listened on yet.
+async fn bind_missing_listeners(listeners: &mut Vec<TcpListener>, bind_sockets: &[SocketAddr]) {
+    for &sockaddr in bind_sockets {
+        if listeners
+            .iter()
+            .any(|listener| listener.local_addr().is_ok_and(|addr| addr == sockaddr))
+        {
+            continue;
+        }
+
+        match TcpListener::bind(sockaddr).await {
+            Ok(l) => {
+                info!("Listening on {sockaddr}");
+                listeners.push(l);
+            }
+            Err(err) => error!("Failed to bind listening socket at {sockaddr}: {err}"),
+        }
     }
 }
 
-async fn create_user_maildir(silent: bool, maildirs_file: &Path, username: &str, password: &str) -> io::Result<()> {
-    // Create the user's maildrop directory if it doesn't exist.
+pub async fn create_user_maildir(maildirs_file: &Path, username: &str, password: &str) -> io::Result<()> {
+    // Create the user's maildrop directory if it doesn't exist. If it already existed, whatever is created within it is
+    // given to its owner.
     let mut path = maildirs_file.to_path_buf();
     path.push(username);
-    path.push(MAILDIR_NEW_FOLDER);
     tokio::fs::create_dir_all(&path).await?;
-    path.pop();
+    let owner = ownership::maildir_owner(&path).await;
+    ownership::create_dir(&path.join(MAILDIR_NEW_FOLDER), owner).await?;
 
     // Create a password file in the user's maildrop and write the password to that file.
     path.push(PASSWORD_FILE_NAME);
-    let mut file = tokio::fs::File::create(path).await?;
+    let mut file = tokio::fs::File::create(&path).await?;
     file.write_all(password.as_bytes()).await?;
     file.flush().await?;
+    ownership::set_owner(&path, owner).await?;
 
-    printlnif!(!silent, "Successfully created or updated user {username}");
+    info!("Successfully created or updated user {username}");
     Ok(())
 }
 
-async fn handle_client_wrapper(socket: TcpStream, address: SocketAddr, server_state: Pop3ServerState) {
-    if let Err(err) = pop3::handle_client(socket, server_state).await {
-        eprintln!("Client from {address} ended with error: {err}");
+/// Encrypts all the messages in a user's maildir, enabling encryption for the user if it wasn't already. Returns the
+/// amount of messages that were encrypted.
+pub async fn encrypt_user_maildir(maildir: &Path, server_key: &ServerKey) -> io::Result<u64> {
+    let count = crypto::encrypt_maildir(maildir, server_key).await?;
+
+    // Encrypted messages take up more space on disk, so the user's quota usage (if they have a quota) is recalculated.
+    if let Some(quota) = quota::read_usage(maildir).await?.quota {
+        quota::set_quota(maildir, quota).await?;
     }
+
+    Ok(count)
+}
+
+async fn handle_client_wrapper(
+    socket: TcpStream,
+    address: SocketAddr,
+    server_state: Pop3ServerState,
+    connection: ConnectionHandle,
+    registration: SessionHandle,
+) {
+    let start_time = Instant::now();
+    let session_id = registration.id();
+    let transcript = Transcript::new(server_state.transcript_settings(), session_id, address);
+    if let Err(err) = pop3::handle_client(socket, server_state.clone(), connection, registration, transcript).await {
+        warn!("Client ended with error: {err}");
+    }
+
+    let duration = start_time.elapsed();
+    server_state.metrics().record_session(duration);
+    server_state.emit_event(ServerEvent::SessionClosed { session_id, duration });
+
+    debug!("Connection closed ({})", server_state.connections().counts());
 }
@@ -0,0 +1,195 @@
+//! A session tracker keeps a live list of the server's open client sessions, so they can be inspected and kicked
+//! through the administrative interface.
+//!
+//! Just like [`crate::connection_tracker`], the tracker hands out handles that automatically remove the session from
+//! the list when dropped. Each session updates its entry as it goes, with its username, protocol state and the amount
+//! of bytes it has sent and received.
+
+use std::{
+    collections::BTreeMap,
+    fmt,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use tokio::sync::Notify;
+
+use crate::types::Pop3Username;
+
+/// The protocol state of a session, as defined by RFC #1939.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionState {
+    Authorization,
+    Transaction,
+    Update,
+}
+
+impl fmt::Display for SessionState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Authorization => write!(f, "AUTHORIZATION"),
+            Self::Transaction => write!(f, "TRANSACTION"),
+            Self::Update => write!(f, "UPDATE"),
+        }
+    }
+}
+
+/// A snapshot of a session's entry in a [`SessionTracker`].
+#[derive(Debug, Clone)]
+pub struct SessionInfo {
+    pub id: u64,
+    pub peer: SocketAddr,
+    pub user: Option<Pop3Username>,
+    pub state: SessionState,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub duration: Duration,
+    pub writes: u64,
+}
+
+/// A session tracker. Read the [`crate::session_tracker`] module's documentation for more information.
+///
+/// This is a reference type which may be cloned to create multiple references to the same state, and may be shared
+/// across threads.
+#[derive(Clone, Default)]
+pub struct SessionTracker {
+    inner: Arc<Mutex<BTreeMap<u64, Arc<SessionEntry>>>>,
+}
+
+struct SessionEntry {
+    peer: SocketAddr,
+    started: Instant,
+    details: Mutex<(Option<Pop3Username>, SessionState)>,
+    bytes_sent: Arc<AtomicU64>,
+    bytes_received: Arc<AtomicU64>,
+    writes: Arc<AtomicU64>,
+    kick: Arc<Notify>,
+}
+
+impl SessionTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a session to the tracker, returning the handle through which the session updates its entry.
+    pub fn register(&self, id: u64, peer: SocketAddr) -> SessionHandle {
+        let entry = Arc::new(SessionEntry {
+            peer,
+            started: Instant::now(),
+            details: Mutex::new((None, SessionState::Authorization)),
+            bytes_sent: Arc::new(AtomicU64::new(0)),
+            bytes_received: Arc::new(AtomicU64::new(0)),
+            writes: Arc::new(AtomicU64::new(0)),
+            kick: Arc::new(Notify::new()),
+        });
+
+        self.inner.lock().unwrap().insert(id, entry.clone());
+        SessionHandle {
+            tracker: self.clone(),
+            id,
+            entry,
+        }
+    }
+
+    /// Gets a snapshot of all the open sessions, ordered by id.
+    pub fn list(&self) -> Vec<SessionInfo> {
+        let sessions = self.inner.lock().unwrap();
+        sessions
+            .iter()
+            .map(|(id, entry)| {
+                let (user, state) = entry.details.lock().unwrap().clone();
+                SessionInfo {
+                    id: *id,
+                    peer: entry.peer,
+                    user,
+                    state,
+                    bytes_sent: entry.bytes_sent.load(Ordering::Relaxed),
+                    bytes_received: entry.bytes_received.load(Ordering::Relaxed),
+                    duration: entry.started.elapsed(),
+                    writes: entry.writes.load(Ordering::Relaxed),
+                }
+            })
+            .collect()
+    }
+
+    /// Asks the session with the given id to close. Returns `false` if there is no such session.
+    pub fn kick(&self, id: u64) -> bool {
+        match self.inner.lock().unwrap().get(&id) {
+            Some(entry) => {
+                entry.kick.notify_one();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Asks all the sessions of the given user to close, returning how many there were.
+    pub fn kick_user(&self, username: &Pop3Username) -> usize {
+        let sessions = self.inner.lock().unwrap();
+        let mut count = 0;
+        for entry in sessions.values() {
+            if entry.details.lock().unwrap().0.as_ref() == Some(username) {
+                entry.kick.notify_one();
+                count += 1;
+            }
+        }
+
+        count
+    }
+}
+
+/// Represents an open session in a [`SessionTracker`]. The session is automatically removed from the tracker once
+/// this handle is dropped.
+pub struct SessionHandle {
+    tracker: SessionTracker,
+    id: u64,
+    entry: Arc<SessionEntry>,
+}
+
+impl SessionHandle {
+    pub const fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn peer(&self) -> SocketAddr {
+        self.entry.peer
+    }
+
+    pub fn set_user(&self, user: Pop3Username) {
+        self.entry.details.lock().unwrap().0 = Some(user);
+    }
+
+    pub fn set_state(&self, state: SessionState) {
+        self.entry.details.lock().unwrap().1 = state;
+    }
+
+    /// The counter of bytes sent to the client, to be incremented as they are written.
+    pub fn bytes_sent_counter(&self) -> Arc<AtomicU64> {
+        self.entry.bytes_sent.clone()
+    }
+
+    /// The counter of bytes received from the client, to be incremented as they are read.
+    pub fn bytes_received_counter(&self) -> Arc<AtomicU64> {
+        self.entry.bytes_received.clone()
+    }
+
+    /// The counter of writes made to the client's socket, to be incremented as they are made.
+    pub fn writes_counter(&self) -> Arc<AtomicU64> {
+        self.entry.writes.clone()
+    }
+
+    /// The notification the session receives when it's kicked.
+    pub fn kick_notify(&self) -> Arc<Notify> {
+        self.entry.kick.clone()
+    }
+}
+
+impl Drop for SessionHandle {
+    fn drop(&mut self) {
+        self.tracker.inner.lock().unwrap().remove(&self.id);
+    }
+}
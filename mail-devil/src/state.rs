@@ -1,133 +1,302 @@
 //! This module contains types for managing the POP3 server's state, as well as logic for interacting with it.
 
-use std::{path::PathBuf, rc::Rc};
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
 
-use tokio::io::AsyncReadExt;
+use tokio::sync::{broadcast, watch};
+use tracing::{debug, error, info, warn};
 
 use crate::{
-    printlnif,
-    types::{Pop3ArgString, Pop3Username, MAX_COMMAND_ARG_LENGTH, PASSWORD_FILE_NAME},
+    audit::{self, AuditRecord, AuditSettings},
+    auth::{Authenticator, MaildirOwner},
+    connection_tracker::{ConnectionLimits, ConnectionTracker},
+    crypto::{self, MessageKey, ServerKey},
+    deletion::DeletionPolicy,
+    events::{ServerEvent, EVENT_CHANNEL_CAPACITY},
+    metrics::Metrics,
+    pop3::extensions::CommandRegistry,
+    quota,
+    session_tracker::SessionTracker,
+    transcript::TranscriptSettings,
+    types::{Pop3ArgString, Pop3Username},
     user_tracker::{UserHandle, UserTracker},
 };
 
 /// Stores the POP3 server's state.
 ///
-/// This is a reference type which may be cloned to create multiple references to the same state.
+/// This is a reference type which may be cloned to create multiple references to the same state, and may be shared
+/// across threads.
 #[derive(Clone)]
 pub struct Pop3ServerState {
-    rc: Rc<InnerState>,
+    rc: Arc<InnerState>,
 }
 
 impl Pop3ServerState {
-    pub fn new(verbose: bool, silent: bool, buffer_size: u32, maildirs_dir: PathBuf, transformer_file: Option<PathBuf>) -> Self {
+    pub fn new(settings: Pop3ServerSettings) -> Self {
+        Self {
+            rc: Arc::new(InnerState::new(settings)),
+        }
+    }
+
+    /// Creates a new state with the given settings which shares this state's user, connection and session trackers,
+    /// metrics, events and shutdown notifications. Sessions using this state keep using its settings, so this is used
+    /// to apply new settings only to new connections.
+    pub fn reload(&self, settings: Pop3ServerSettings) -> Self {
+        self.rc.connections.set_limits(settings.connection_limits);
+
         Self {
-            rc: Rc::new(InnerState::new(verbose, silent, buffer_size, maildirs_dir, transformer_file)),
+            rc: Arc::new(InnerState {
+                settings,
+                current_users: self.rc.current_users.clone(),
+                connections: self.rc.connections.clone(),
+                sessions: self.rc.sessions.clone(),
+                metrics: self.rc.metrics.clone(),
+                events: self.rc.events.clone(),
+                shutdown: self.rc.shutdown.clone(),
+            }),
         }
     }
 
-    pub fn verbose(&self) -> bool {
-        self.rc.verbose
+    pub fn transcript_settings(&self) -> &TranscriptSettings {
+        &self.rc.settings.transcripts
+    }
+
+    /// Whether auditing is enabled, so that records are written by [`Pop3ServerState::record_audit`].
+    pub fn is_auditing(&self) -> bool {
+        self.rc.settings.audit.destination.is_some()
     }
 
-    pub fn silent(&self) -> bool {
-        self.rc.silent
+    /// Appends a record to the audit log, if auditing is enabled. `maildir` is the user's maildir, and `owner` its owner.
+    pub async fn record_audit(&self, maildir: &Path, owner: Option<MaildirOwner>, record: &AuditRecord<'_>) {
+        audit::append_record(&self.rc.settings.audit, maildir, owner, record).await
+    }
+
+    /// The site-specific commands accepted in addition to the built-in ones.
+    pub fn commands(&self) -> &CommandRegistry {
+        &self.rc.settings.commands
     }
 
     pub fn buffer_size(&self) -> usize {
-        self.rc.buffer_size as usize
+        self.rc.settings.buffer_size as usize
+    }
+
+    /// Whether messages should be numbered from newest to oldest, rather than from oldest to newest.
+    pub fn newest_first(&self) -> bool {
+        self.rc.settings.newest_first
+    }
+
+    /// How long a client in the `AUTHORIZATION` state may stay idle before being disconnected.
+    pub fn auth_timeout(&self) -> Duration {
+        self.rc.settings.auth_timeout
+    }
+
+    /// How long a client in the `TRANSACTION` state may stay idle before being disconnected.
+    pub fn transaction_timeout(&self) -> Duration {
+        self.rc.settings.transaction_timeout
+    }
+
+    /// The policy to follow for messages whose deletion is committed.
+    pub fn deletion_policy(&self) -> DeletionPolicy {
+        self.rc.settings.deletion_policy
+    }
+
+    /// The tracker for this server's open client connections.
+    pub fn connections(&self) -> &ConnectionTracker {
+        &self.rc.connections
+    }
+
+    /// Notifies all sessions that the server is shutting down.
+    pub fn begin_shutdown(&self) {
+        self.rc.shutdown.send_replace(true);
+    }
+
+    /// The tracker for this server's open client sessions.
+    pub fn sessions(&self) -> &SessionTracker {
+        &self.rc.sessions
+    }
+
+    /// Sends an event to everyone subscribed to this server's events.
+    pub fn emit_event(&self, event: ServerEvent) {
+        // Sending only fails if there are no subscribers, in which case nobody needs the event.
+        let _ = self.rc.events.send(event);
+    }
+
+    /// Subscribes to this server's events, receiving those emitted from now on.
+    pub fn subscribe_events(&self) -> broadcast::Receiver<ServerEvent> {
+        self.rc.events.subscribe()
+    }
+
+    /// The path of the directory holding the users' maildirs.
+    pub fn maildirs_dir(&self) -> &Path {
+        &self.rc.settings.maildirs_dir
+    }
+
+    /// The server's metrics, which are shared by all states created with [`Pop3ServerState::reload`].
+    pub fn metrics(&self) -> &Metrics {
+        &self.rc.metrics
+    }
+
+    /// Gets a receiver whose value becomes `true` once the server begins shutting down.
+    pub fn shutdown_receiver(&self) -> watch::Receiver<bool> {
+        self.rc.shutdown.subscribe()
     }
 
     /// Attempts to log in as the given user with the given password.
     ///
-    /// On success, returns the user's handle on the user tracker and the path to the user's maildrop.
-    pub async fn try_login_user(&self, username: &Pop3Username, password: &Pop3ArgString) -> Result<(UserHandle, PathBuf), LoginUserError> {
-        // Read the password file for the user into a `buf` buffer.
-        let mut path = self.rc.maildirs_dir.to_path_buf();
-        path.push(username.as_str());
-        path.push(PASSWORD_FILE_NAME);
-
-        let mut file = match tokio::fs::File::open(&path).await {
-            Ok(f) => f,
+    /// On success, returns the user's handle on the user tracker, the path to the user's maildrop and, if the user has
+    /// encryption enabled, their message key.
+    pub async fn try_login_user(&self, username: &Pop3Username, password: &Pop3ArgString) -> Result<LoggedInUser, LoginUserError> {
+        let settings = &self.rc.settings;
+        if !settings
+            .authenticator
+            .authenticate(&settings.maildirs_dir, username, password)
+            .await
+        {
+            info!("Wrong login for user {username}");
+            return Err(LoginUserError::WrongUserOrPass);
+        }
+
+        let user_tracker = &self.rc.current_users;
+        let user_handle = user_tracker.try_register(username.clone()).ok_or(LoginUserError::AlreadyLoggedIn)?;
+
+        // The quota is only checked once the user is registered, as this may rewrite the maildir's `maildirsize` file,
+        // which a session already logged in as this user could otherwise be updating at the same time.
+        let path = settings.maildirs_dir.join(username.as_str());
+        self.check_quota(username, &path).await?;
+
+        let encryption_key = match crypto::load_user_key(&path, settings.server_key.as_ref()).await {
+            Ok(k) => k,
             Err(error) => {
-                printlnif!(
-                    !self.silent(),
-                    "Failed to login user {username}, could not open password file: {error}"
-                );
-                return Err(LoginUserError::WrongUserOrPass);
+                error!("Failed to login user {username}, could not load encryption key: {error}");
+                return Err(LoginUserError::EncryptionKeyError);
             }
         };
 
-        let mut buf = [0u8; MAX_COMMAND_ARG_LENGTH];
-        let mut buf_len = 0;
-
-        while buf_len < buf.len() {
-            let bytes_read = match file.read(&mut buf[buf_len..]).await {
-                Ok(b) => b,
-                Err(error) => {
-                    printlnif!(
-                        !self.silent(),
-                        "Failed to login user {username}, error while reading password file: {error}"
-                    );
-                    return Err(LoginUserError::WrongUserOrPass);
-                }
-            };
-
-            if bytes_read == 0 {
-                break;
+        let owner = settings.authenticator.owner(&settings.maildirs_dir, username).await;
+
+        info!("User {username} logged in successfully");
+        Ok(LoggedInUser {
+            handle: user_handle,
+            maildrop_path: path,
+            encryption_key,
+            owner,
+        })
+    }
+
+    /// Checks the quota usage of a user's maildrop, logging a warning if the user is over their quota, or returning
+    /// [`Err`] if the user is so far over their quota that they are not allowed to log in.
+    async fn check_quota(&self, username: &Pop3Username, maildrop_path: &Path) -> Result<(), LoginUserError> {
+        let usage = match quota::read_usage(maildrop_path).await {
+            Ok(u) => u,
+            Err(error) => {
+                warn!("Could not read quota usage for user {username}: {error}");
+                return Ok(());
             }
+        };
 
-            buf_len += bytes_read;
-        }
-        drop(file);
+        debug!("User {username}'s maildrop holds {usage}");
 
-        if !password.as_bytes().eq(&buf[..buf_len]) {
-            printlnif!(!self.silent(), "Wrong login for user {username}");
-            return Err(LoginUserError::WrongUserOrPass);
+        let percent = match usage.percent() {
+            Some(p) => p,
+            None => return Ok(()),
+        };
+
+        // The rejection threshold may be below 100%, to refuse logins to maildrops that are nearly full.
+        if self.rc.settings.quota_reject_percent.is_some_and(|max| percent > max) {
+            warn!("Rejecting login for user {username}, over the quota rejection threshold: {usage}");
+            return Err(LoginUserError::FarOverQuota);
         }
 
-        let user_tracker = &self.rc.current_users;
-        let user_handle = user_tracker.try_register(username.clone()).ok_or(LoginUserError::AlreadyLoggedIn)?;
+        if percent > 100 {
+            warn!("User {username} is over quota: {usage}");
+        }
 
-        printlnif!(!self.silent(), "User {username} logged in successfully");
-        path.pop();
-        Ok((user_handle, path))
+        Ok(())
     }
 }
 
+/// The settings a POP3 server's state is created with.
+pub struct Pop3ServerSettings {
+    pub buffer_size: u32,
+    pub maildirs_dir: PathBuf,
+    // Message transformations are not yet implemented, so this is only carried along, including across reloads.
+    #[allow(dead_code)]
+    pub transformer_file: Option<PathBuf>,
+    pub newest_first: bool,
+    pub quota_reject_percent: Option<u64>,
+    pub authenticator: Arc<dyn Authenticator>,
+    pub commands: Arc<CommandRegistry>,
+    pub deletion_policy: DeletionPolicy,
+    pub auth_timeout: Duration,
+    pub transaction_timeout: Duration,
+    pub connection_limits: ConnectionLimits,
+    pub transcripts: TranscriptSettings,
+    pub audit: AuditSettings,
+    pub server_key: Option<ServerKey>,
+}
+
 /// Stores the immutable variables of a POP3 server's state.
 struct InnerState {
-    verbose: bool,
-    silent: bool,
-    buffer_size: u32,
-    maildirs_dir: PathBuf,
-    transformer_file: Option<PathBuf>,
+    settings: Pop3ServerSettings,
     current_users: UserTracker,
+    connections: ConnectionTracker,
+    sessions: SessionTracker,
+    metrics: Metrics,
+    events: broadcast::Sender<ServerEvent>,
+    shutdown: watch::Sender<bool>,
 }
 
 impl InnerState {
-    pub fn new(verbose: bool, silent: bool, buffer_size: u32, maildirs_dir: PathBuf, transformer_file: Option<PathBuf>) -> Self {
+    pub fn new(settings: Pop3ServerSettings) -> Self {
+        let connections = ConnectionTracker::new(settings.connection_limits);
         Self {
-            verbose,
-            silent,
-            buffer_size,
-            maildirs_dir,
-            transformer_file,
+            settings,
             current_users: UserTracker::new(),
+            connections,
+            sessions: SessionTracker::new(),
+            metrics: Metrics::new(),
+            events: broadcast::Sender::new(EVENT_CHANNEL_CAPACITY),
+            shutdown: watch::Sender::new(false),
         }
     }
 }
 
-#[derive(Clone, Copy)]
+/// A user who successfully logged in, as returned by [`Pop3ServerState::try_login_user`].
+pub struct LoggedInUser {
+    pub handle: UserHandle,
+    pub maildrop_path: PathBuf,
+    pub encryption_key: Option<MessageKey>,
+    pub owner: Option<MaildirOwner>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum LoginUserError {
     AlreadyLoggedIn,
     WrongUserOrPass,
+    FarOverQuota,
+    EncryptionKeyError,
 }
 
 impl LoginUserError {
     pub const fn get_reason_str(self) -> &'static str {
         match self {
-            Self::AlreadyLoggedIn => "User is already logged in",
-            Self::WrongUserOrPass => "Wrong username or password",
+            Self::AlreadyLoggedIn => "[IN-USE] User is already logged in",
+            Self::WrongUserOrPass => "[AUTH] Wrong username or password",
+            Self::FarOverQuota => "[SYS/PERM] Mailbox is too full, contact your administrator",
+            Self::EncryptionKeyError => "[SYS/PERM] Could not unlock your maildrop, contact your administrator",
+        }
+    }
+
+    /// A short snake case label for the error, used in events and metrics.
+    pub const fn get_label(self) -> &'static str {
+        match self {
+            Self::AlreadyLoggedIn => "already_logged_in",
+            Self::WrongUserOrPass => "wrong_user_or_pass",
+            Self::FarOverQuota => "far_over_quota",
+            Self::EncryptionKeyError => "encryption_key_error",
         }
     }
 }
@@ -0,0 +1,92 @@
+//! Provides [`open_message`], used for opening the message files in a maildir.
+//!
+//! Message files may be stored encrypted (see [`crate::crypto`]) and/or compressed with gzip or zstd, in which case
+//! they are transparently decrypted and decompressed as they're read. Compressed files are recognized by their magic
+//! bytes, and may also be marked with the `Z` flag in their maildir file name (e.g. `1700000000.M1P2.host:2,Z`), in
+//! which case a file that isn't in a known compressed format is considered an error.
+
+use std::{
+    io::{self, ErrorKind},
+    path::Path,
+};
+
+use async_compression::tokio::bufread::{GzipDecoder, ZstdDecoder};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncRead, BufReader};
+
+use crate::crypto::{DecryptingReader, MessageKey, ENCRYPTED_MESSAGE_MAGIC};
+
+/// The magic bytes at the start of a gzip file.
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+
+/// The magic bytes at the start of a zstd frame.
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+
+/// The maildir flag that marks a message file as compressed.
+const COMPRESSED_FLAG: char = 'Z';
+
+/// A reader over a message's contents, as returned by [`open_message`].
+pub type MessageReader = Box<dyn AsyncRead + Send + Unpin>;
+
+/// The format in which a message file is stored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    /// Detects a compression format from the first bytes of a file.
+    pub fn from_magic_bytes(buf: &[u8]) -> Self {
+        if buf.starts_with(GZIP_MAGIC) {
+            Self::Gzip
+        } else if buf.starts_with(ZSTD_MAGIC) {
+            Self::Zstd
+        } else {
+            Self::None
+        }
+    }
+}
+
+/// Gets whether a message's maildir file name has the `Z` flag, marking it as compressed.
+pub fn has_compressed_flag(path: &Path) -> bool {
+    let flags = path.file_name().and_then(|n| n.to_str()).and_then(|n| n.rsplit_once(":2,"));
+    flags.is_some_and(|(_, flags)| flags.contains(COMPRESSED_FLAG))
+}
+
+/// Opens a message file for reading, returning a reader over the message's decrypted and decompressed contents.
+///
+/// `key` is the message key of the user who owns the message, or [`None`] if the user doesn't have encryption enabled,
+/// in which case opening an encrypted message fails.
+pub async fn open_message(path: &Path, key: Option<&MessageKey>) -> io::Result<MessageReader> {
+    let file = tokio::fs::File::open(path).await?;
+    let mut reader = BufReader::new(file);
+
+    if !reader.fill_buf().await?.starts_with(ENCRYPTED_MESSAGE_MAGIC) {
+        return decompress(reader, path).await;
+    }
+
+    let key = key.ok_or_else(|| io::Error::new(ErrorKind::PermissionDenied, "Message file is encrypted but no key is available"))?;
+    let decrypting_reader = DecryptingReader::new(reader, key).await?;
+    decompress(BufReader::new(decrypting_reader), path).await
+}
+
+async fn decompress<R>(mut reader: R, path: &Path) -> io::Result<MessageReader>
+where
+    R: AsyncBufRead + Send + Unpin + 'static,
+{
+    let compression = Compression::from_magic_bytes(reader.fill_buf().await?);
+    match compression {
+        Compression::Gzip => {
+            let mut decoder = GzipDecoder::new(reader);
+            decoder.multiple_members(true);
+            Ok(Box::new(decoder))
+        }
+        Compression::Zstd => Ok(Box::new(ZstdDecoder::new(reader))),
+        Compression::None if has_compressed_flag(path) => Err(io::Error::new(
+            ErrorKind::InvalidData,
+            "Message file is flagged as compressed but is not in a known compression format",
+        )),
+        Compression::None => Ok(Box::new(reader)),
+    }
+}
@@ -0,0 +1,414 @@
+//! A log output that sends messages to the local syslog daemon or to journald.
+//!
+//! Messages sent to syslog are formatted as in RFC #5424 and written to a unix datagram socket, `/dev/log` by default.
+//! Messages sent to journald use its native protocol instead, in which each message is a list of `KEY=value` fields,
+//! so the fields of the session a message was logged in (such as its id, address and username) are kept as separate
+//! fields, like `SESSION_USER`, rather than being added to the message's text.
+//!
+//! Each log level is mapped to a syslog severity, and all messages are sent with the same facility, which defaults to
+//! `mail`.
+//!
+//! Messages are sent without blocking, so a slow or stuck daemon can't hold up the server. If the socket's buffer is
+//! full the message is dropped, and messages too long to fit in a single datagram have their text truncated.
+
+use std::{
+    fmt::{self, Write},
+    path::PathBuf,
+    str::FromStr,
+};
+
+/// The path of the local syslog daemon's socket.
+const SYSLOG_SOCKET_PATH: &str = "/dev/log";
+
+/// The path of journald's native protocol socket.
+const JOURNALD_SOCKET_PATH: &str = "/run/systemd/journal/socket";
+
+/// The application name messages are sent with.
+const APP_NAME: &str = "mail-devil";
+
+/// Where to send syslog messages to, and in which format.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SyslogTarget {
+    /// Send RFC #5424 messages to the unix datagram socket at the given path.
+    Syslog(PathBuf),
+    /// Send messages to journald's native protocol socket at the given path.
+    Journald(PathBuf),
+}
+
+impl FromStr for SyslogTarget {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "" => Err(()),
+            s if s.eq_ignore_ascii_case("syslog") => Ok(Self::Syslog(PathBuf::from(SYSLOG_SOCKET_PATH))),
+            s if s.eq_ignore_ascii_case("journald") => Ok(Self::Journald(PathBuf::from(JOURNALD_SOCKET_PATH))),
+            s => Ok(Self::Syslog(PathBuf::from(s))),
+        }
+    }
+}
+
+impl fmt::Display for SyslogTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Syslog(path) => write!(f, "syslog at {}", path.display()),
+            Self::Journald(path) => write!(f, "journald at {}", path.display()),
+        }
+    }
+}
+
+/// The facility names defined by RFC #5424, in order of their numerical code.
+const FACILITY_NAMES: [&str; 24] = [
+    "kern",
+    "user",
+    "mail",
+    "daemon",
+    "auth",
+    "syslog",
+    "lpr",
+    "news",
+    "uucp",
+    "cron",
+    "authpriv",
+    "ftp",
+    "ntp",
+    "security",
+    "console",
+    "solaris-cron",
+    "local0",
+    "local1",
+    "local2",
+    "local3",
+    "local4",
+    "local5",
+    "local6",
+    "local7",
+];
+
+/// A syslog facility, which tells the syslog daemon what kind of program a message comes from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SyslogFacility(u8);
+
+impl SyslogFacility {
+    pub const MAIL: Self = Self(2);
+
+    pub const fn code(self) -> u8 {
+        self.0
+    }
+}
+
+impl Default for SyslogFacility {
+    fn default() -> Self {
+        Self::MAIL
+    }
+}
+
+impl FromStr for SyslogFacility {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        match FACILITY_NAMES.iter().position(|name| name.eq_ignore_ascii_case(s)) {
+            Some(code) => Ok(Self(code as u8)),
+            None => match s.parse::<u8>() {
+                Ok(code) if (code as usize) < FACILITY_NAMES.len() => Ok(Self(code)),
+                _ => Err(()),
+            },
+        }
+    }
+}
+
+impl fmt::Display for SyslogFacility {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", FACILITY_NAMES[self.0 as usize])
+    }
+}
+
+#[cfg(unix)]
+pub use layer::SyslogLayer;
+
+#[cfg(unix)]
+mod layer {
+    use std::{
+        io,
+        os::unix::net::UnixDatagram,
+        path::{Path, PathBuf},
+        sync::Mutex,
+    };
+
+    use tracing::{
+        field::{Field, Visit},
+        span, Event, Level, Subscriber,
+    };
+    use tracing_subscriber::{
+        fmt::{
+            format::Writer,
+            time::{FormatTime, SystemTime},
+        },
+        layer::Context,
+        registry::LookupSpan,
+        Layer,
+    };
+
+    use super::{SyslogFacility, SyslogTarget, Write, APP_NAME};
+
+    /// The largest datagram sent to the socket. Linux's default limit is around 200KiB, but journald and most syslog
+    /// daemons read messages into smaller buffers, so longer ones would be truncated or dropped anyway.
+    const MAX_DATAGRAM_SIZE: usize = 0x10000;
+
+    /// Appended to the text of messages that were truncated to fit in a datagram.
+    const TRUNCATION_MARKER: &str = " [truncated]";
+
+    /// A layer that sends each log message to the local syslog daemon or to journald.
+    pub struct SyslogLayer {
+        socket: Mutex<UnixDatagram>,
+        path: PathBuf,
+        journald: bool,
+        facility: SyslogFacility,
+        hostname: String,
+        pid: u32,
+    }
+
+    impl SyslogLayer {
+        /// Connects to the given target's socket. This must be done before changing the root directory, as the socket
+        /// may not be reachable afterwards.
+        pub fn new(target: &SyslogTarget, facility: SyslogFacility) -> io::Result<Self> {
+            let (path, journald) = match target {
+                SyslogTarget::Syslog(path) => (path.clone(), false),
+                SyslogTarget::Journald(path) => (path.clone(), true),
+            };
+
+            Ok(Self {
+                socket: Mutex::new(connect(&path)?),
+                path,
+                journald,
+                facility,
+                hostname: get_hostname(),
+                pid: std::process::id(),
+            })
+        }
+
+        /// Sends a message without blocking, dropping it if the socket's buffer is full. If sending fails otherwise the
+        /// socket is reconnected once, in case the daemon was restarted since connecting.
+        fn send(&self, message: &[u8]) {
+            let mut socket = self.socket.lock().unwrap();
+            match socket.send(message) {
+                Ok(_) => return,
+                Err(error) if error.kind() == io::ErrorKind::WouldBlock => return,
+                Err(_) => {}
+            }
+
+            if let Ok(new_socket) = connect(&self.path) {
+                *socket = new_socket;
+                let _ = socket.send(message);
+            }
+        }
+
+        /// Formats a message in the target's format.
+        fn format(&self, severity: u8, event: &EventFields, spans: &[SpanFields]) -> Vec<u8> {
+            match self.journald {
+                true => self.format_journald(severity, event, spans),
+                false => self.format_syslog(severity, event, spans).into_bytes(),
+            }
+        }
+
+        /// Formats a message as in RFC #5424, with the session's fields before the message's text.
+        fn format_syslog(&self, severity: u8, event: &EventFields, spans: &[SpanFields]) -> String {
+            let mut timestamp = String::new();
+            let _ = SystemTime.format_time(&mut Writer::new(&mut timestamp));
+
+            let priority = self.facility.code() as u32 * 8 + severity as u32;
+            let mut message = format!("<{priority}>1 {timestamp} {} {APP_NAME} {} - - ", self.hostname, self.pid);
+
+            for span in spans {
+                let _ = write!(message, "{}{{", span.name);
+                for (i, (name, value)) in span.fields.iter().enumerate() {
+                    let separator = if i == 0 { "" } else { " " };
+                    let _ = write!(message, "{separator}{name}={value}");
+                }
+                message.push_str("}: ");
+            }
+
+            message.push_str(&event.message);
+            for (name, value) in &event.fields {
+                let _ = write!(message, " {name}={value}");
+            }
+
+            message
+        }
+
+        /// Formats a message for journald's native protocol, with each of the session's fields as a separate field.
+        fn format_journald(&self, severity: u8, event: &EventFields, spans: &[SpanFields]) -> Vec<u8> {
+            let mut message = Vec::new();
+            append_journald_field(&mut message, "MESSAGE", &event.message);
+            append_journald_field(&mut message, "PRIORITY", &severity.to_string());
+            append_journald_field(&mut message, "SYSLOG_FACILITY", &self.facility.code().to_string());
+            append_journald_field(&mut message, "SYSLOG_IDENTIFIER", APP_NAME);
+
+            for span in spans {
+                for (name, value) in &span.fields {
+                    append_journald_field(&mut message, &format!("{}_{name}", span.name), value);
+                }
+            }
+
+            for (name, value) in &event.fields {
+                append_journald_field(&mut message, name, value);
+            }
+
+            message
+        }
+    }
+
+    impl<S> Layer<S> for SyslogLayer
+    where
+        S: Subscriber + for<'a> LookupSpan<'a>,
+    {
+        fn on_new_span(&self, attrs: &span::Attributes<'_>, id: &span::Id, ctx: Context<'_, S>) {
+            if let Some(span) = ctx.span(id) {
+                let mut fields = SpanFields {
+                    name: span.name(),
+                    fields: Vec::new(),
+                };
+                attrs.record(&mut fields);
+                span.extensions_mut().insert(fields);
+            }
+        }
+
+        fn on_record(&self, id: &span::Id, values: &span::Record<'_>, ctx: Context<'_, S>) {
+            if let Some(span) = ctx.span(id) {
+                if let Some(fields) = span.extensions_mut().get_mut::<SpanFields>() {
+                    values.record(fields);
+                }
+            }
+        }
+
+        fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+            let mut fields = EventFields::default();
+            event.record(&mut fields);
+
+            let spans: Vec<SpanFields> = ctx
+                .event_scope(event)
+                .into_iter()
+                .flat_map(|scope| scope.from_root())
+                .filter_map(|span| span.extensions().get::<SpanFields>().cloned())
+                .collect();
+
+            let severity = get_severity(event.metadata().level());
+            let mut message = self.format(severity, &fields, &spans);
+            if message.len() > MAX_DATAGRAM_SIZE {
+                truncate_text(&mut fields.message, message.len() - MAX_DATAGRAM_SIZE);
+                message = self.format(severity, &fields, &spans);
+            }
+
+            // The message's other fields may still be too long, but that's unusual enough to just drop it.
+            if message.len() <= MAX_DATAGRAM_SIZE {
+                self.send(&message);
+            }
+        }
+    }
+
+    fn connect(path: &Path) -> io::Result<UnixDatagram> {
+        let socket = UnixDatagram::unbound()?;
+        socket.connect(path)?;
+        socket.set_nonblocking(true)?;
+        Ok(socket)
+    }
+
+    /// Shortens a message's text by at least `excess` bytes, marking it as truncated.
+    fn truncate_text(text: &mut String, excess: usize) {
+        let mut len = text.len().saturating_sub(excess + TRUNCATION_MARKER.len());
+        while !text.is_char_boundary(len) {
+            len -= 1;
+        }
+
+        text.truncate(len);
+        text.push_str(TRUNCATION_MARKER);
+    }
+
+    /// Maps a log level to a syslog severity.
+    const fn get_severity(level: &Level) -> u8 {
+        match *level {
+            Level::ERROR => 3,
+            Level::WARN => 4,
+            Level::INFO => 6,
+            Level::DEBUG | Level::TRACE => 7,
+        }
+    }
+
+    fn get_hostname() -> String {
+        let mut buf = [0u8; 256];
+        match unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) } {
+            0 => {
+                let len = buf.iter().position(|b| *b == 0).unwrap_or(buf.len());
+                match std::str::from_utf8(&buf[..len]) {
+                    // RFC #5424 only allows printable ASCII characters in the hostname.
+                    Ok(hostname) if !hostname.is_empty() && hostname.bytes().all(|b| b.is_ascii_graphic()) => hostname.to_string(),
+                    _ => "-".to_string(),
+                }
+            }
+            _ => "-".to_string(),
+        }
+    }
+
+    /// Appends a field to a journald message. Field names may only contain uppercase letters, digits and underscores.
+    /// Values containing newlines are written with their length in front, as required by the protocol.
+    fn append_journald_field(message: &mut Vec<u8>, name: &str, value: &str) {
+        let name = name.trim_start_matches('_');
+        message.extend(name.bytes().map(|b| match b {
+            b'a'..=b'z' => b.to_ascii_uppercase(),
+            b'A'..=b'Z' | b'0'..=b'9' => b,
+            _ => b'_',
+        }));
+
+        match value.contains('\n') {
+            true => {
+                message.push(b'\n');
+                message.extend_from_slice(&(value.len() as u64).to_le_bytes());
+            }
+            false => message.push(b'='),
+        }
+
+        message.extend_from_slice(value.as_bytes());
+        message.push(b'\n');
+    }
+
+    /// The fields of a span, stored in the span's extensions so they're available when formatting events.
+    #[derive(Clone)]
+    struct SpanFields {
+        name: &'static str,
+        fields: Vec<(&'static str, String)>,
+    }
+
+    impl Visit for SpanFields {
+        fn record_str(&mut self, field: &Field, value: &str) {
+            match self.fields.iter_mut().find(|(name, _)| *name == field.name()) {
+                Some((_, existing)) => *existing = value.to_string(),
+                None => self.fields.push((field.name(), value.to_string())),
+            }
+        }
+
+        fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+            self.record_str(field, &format!("{value:?}"));
+        }
+    }
+
+    /// The message and any other fields of an event.
+    #[derive(Default)]
+    struct EventFields {
+        message: String,
+        fields: Vec<(&'static str, String)>,
+    }
+
+    impl Visit for EventFields {
+        fn record_str(&mut self, field: &Field, value: &str) {
+            match field.name() {
+                "message" => self.message = value.to_string(),
+                name => self.fields.push((name, value.to_string())),
+            }
+        }
+
+        fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+            self.record_str(field, &format!("{value:?}"));
+        }
+    }
+}
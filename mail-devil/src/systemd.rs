@@ -0,0 +1,134 @@
+//! Support for systemd's socket activation and service notification protocols.
+//!
+//! Neither needs libsystemd: inherited sockets are passed as file descriptors starting at 3 and described by the
+//! `LISTEN_PID`, `LISTEN_FDS` and `LISTEN_FDNAMES` environment variables, while notifications are datagrams sent to the
+//! unix socket named by `NOTIFY_SOCKET`. When the server isn't started by systemd these variables aren't set, so
+//! nothing here has any effect.
+
+use std::io;
+
+use tokio::net::TcpListener;
+use tracing::warn;
+
+/// The first file descriptor passed in by systemd, `SD_LISTEN_FDS_START`.
+#[cfg(unix)]
+const LISTEN_FDS_START: i32 = 3;
+
+/// Takes ownership of the listening sockets passed in by systemd, returning each one along with its name (as set by
+/// `FileDescriptorName=`, or an empty string).
+///
+/// The variables are removed from the environment, like `sd_listen_fds(1)` does, and the file descriptors are marked
+/// close-on-exec, so neither leaks into the programs run for events. Since this modifies the environment, it should be
+/// called at startup before anything else could be reading it.
+///
+/// This must only be called once, as each call would take ownership of the same file descriptors.
+#[cfg(unix)]
+pub fn take_inherited_listeners() -> Vec<(String, io::Result<TcpListener>)> {
+    use std::{env, os::fd::FromRawFd};
+
+    let listen_pid = env::var("LISTEN_PID");
+    let listen_fds = env::var("LISTEN_FDS");
+    let names = env::var("LISTEN_FDNAMES").unwrap_or_default();
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+
+    // The variables are meant for this process only, not for any child processes that may inherit our environment.
+    let for_this_process = listen_pid.is_ok_and(|pid| pid.trim() == std::process::id().to_string());
+    let fd_count = match listen_fds.ok().and_then(|count| count.trim().parse::<i32>().ok()) {
+        Some(count) if for_this_process && count > 0 => count,
+        _ => return Vec::new(),
+    };
+
+    let mut names = names.split(':');
+
+    (LISTEN_FDS_START..(LISTEN_FDS_START + fd_count))
+        .map(|fd| {
+            let name = names.next().unwrap_or_default().to_string();
+
+            // SAFETY: systemd passes us ownership of these file descriptors, and nothing else in the process uses them.
+            let listener = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+
+            // Getting the local address fails if the file descriptor isn't an IP socket.
+            let result = set_cloexec(fd)
+                .and_then(|_| listener.local_addr())
+                .and_then(|_| listener.set_nonblocking(true))
+                .and_then(|_| TcpListener::from_std(listener));
+
+            (name, result)
+        })
+        .collect()
+}
+
+/// Sets the close-on-exec flag on a file descriptor, which systemd passes in without it.
+#[cfg(unix)]
+fn set_cloexec(fd: i32) -> io::Result<()> {
+    // SAFETY: fcntl only reads and sets the descriptor's flags, and fails with EBADF if it isn't open.
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFD) };
+    if flags == -1 || unsafe { libc::fcntl(fd, libc::F_SETFD, flags | libc::FD_CLOEXEC) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn take_inherited_listeners() -> Vec<(String, io::Result<TcpListener>)> {
+    Vec::new()
+}
+
+/// The socket connected to `NOTIFY_SOCKET`, or [`None`] if the variable isn't set or connecting failed.
+#[cfg(unix)]
+static NOTIFY_SOCKET: std::sync::OnceLock<Option<std::os::unix::net::UnixDatagram>> = std::sync::OnceLock::new();
+
+/// Connects to systemd's notification socket, if there is one. This is otherwise done on the first notification, but
+/// must be done before changing the root directory, as the socket's path may not be reachable afterwards.
+pub fn connect_notify_socket() {
+    #[cfg(unix)]
+    NOTIFY_SOCKET.get_or_init(|| {
+        let path = std::env::var_os("NOTIFY_SOCKET")?;
+        match connect(&path) {
+            Ok(socket) => Some(socket),
+            Err(error) => {
+                warn!("Could not connect to systemd's notification socket: {error}");
+                None
+            }
+        }
+    });
+}
+
+/// Sends a notification to systemd, such as `READY=1`. Multiple notifications may be sent at once by separating them
+/// with newlines.
+pub fn notify(state: &str) {
+    #[cfg(unix)]
+    {
+        connect_notify_socket();
+        if let Some(Some(socket)) = NOTIFY_SOCKET.get() {
+            if let Err(error) = socket.send(state.as_bytes()) {
+                warn!("Could not send notification to systemd: {error}");
+            }
+        }
+    }
+
+    #[cfg(not(unix))]
+    let _ = state;
+}
+
+#[cfg(unix)]
+fn connect(path: &std::ffi::OsStr) -> io::Result<std::os::unix::net::UnixDatagram> {
+    use std::os::unix::{ffi::OsStrExt, net::UnixDatagram};
+
+    let socket = UnixDatagram::unbound()?;
+
+    // A path starting with '@' refers to a socket in Linux's abstract namespace.
+    #[cfg(target_os = "linux")]
+    if let Some(name) = path.as_bytes().strip_prefix(b"@") {
+        use std::os::{linux::net::SocketAddrExt, unix::net::SocketAddr};
+
+        socket.connect_addr(&SocketAddr::from_abstract_name(name)?)?;
+        return Ok(socket);
+    }
+
+    socket.connect(path)?;
+    Ok(socket)
+}
@@ -0,0 +1,380 @@
+//! Opt-in recording of POP3 protocol transcripts, for debugging problems with specific users or clients.
+//!
+//! A transcript is recorded for sessions from any of the traced addresses, as well as for sessions in which the client
+//! sends a `USER` command for any of the traced users. In the latter case the lines exchanged before the `USER` command
+//! are held in memory until then, and discarded if the session leaves the `AUTHORIZATION` state without having named a
+//! traced user.
+//!
+//! Each session's transcript is written to its own file in the transcripts directory, with every command line received
+//! and every response line sent preceded by a timestamp. Passwords and authentication payloads are redacted, and the
+//! contents of messages sent to the client are summarized as a count of lines and bytes.
+//!
+//! The file is written to by a separate task, so a slow disk never holds up the session. Should that task fall too far
+//! behind, or should too many lines be exchanged before a traced user is named, the oldest lines are dropped and a note
+//! of how many is left in the transcript.
+
+use std::{
+    collections::VecDeque,
+    io,
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    pin::Pin,
+    task::{Context, Poll},
+    time::SystemTime,
+};
+
+use tokio::{
+    fs::OpenOptions,
+    io::{AsyncWrite, AsyncWriteExt, BufWriter},
+    sync::mpsc::{self, error::TrySendError},
+};
+use tracing::{info, warn};
+use tracing_subscriber::fmt::{format::Writer, time::FormatTime};
+
+use crate::types::Pop3Username;
+
+/// The maximum amount of lines held in memory while waiting for a `USER` command naming a traced user.
+const MAX_PENDING_LINES: usize = 32;
+
+/// The maximum amount of lines queued for the task writing a transcript's file.
+const MAX_QUEUED_LINES: usize = 1024;
+
+/// The maximum amount of bytes of a single line recorded in a transcript. Longer lines are truncated.
+const MAX_RECORDED_LINE_LENGTH: usize = 512;
+
+/// The settings for which sessions to record transcripts of, and where to write them.
+#[derive(Debug, Default)]
+pub struct TranscriptSettings {
+    pub users: Vec<Pop3Username>,
+    pub addresses: Vec<IpAddr>,
+    pub dir: PathBuf,
+}
+
+enum TranscriptState {
+    /// No transcript is being recorded for this session.
+    Disabled,
+    /// Waiting for a `USER` command to know whether to record this session, holding the latest lines recorded so far.
+    Pending(VecDeque<String>),
+    /// A transcript is being recorded by the task receiving from this channel.
+    Active(mpsc::Sender<String>),
+}
+
+/// Tracks the state of a multi-line message sent to the client, which is summarized instead of recorded.
+#[derive(Default)]
+struct BodySummary {
+    lines: u64,
+    bytes: u64,
+    line_len: usize,
+    line_start: [u8; 2],
+}
+
+/// The protocol transcript of a single session.
+pub struct Transcript {
+    state: TranscriptState,
+    session_id: u64,
+    peer: SocketAddr,
+    dir: PathBuf,
+
+    /// The response line currently being written to the client.
+    response_line: Vec<u8>,
+
+    /// Whether the last command was one whose successful response is followed by a message's contents.
+    expecting_body: bool,
+
+    /// The message contents currently being sent to the client, if any.
+    body: Option<BodySummary>,
+
+    /// The amount of lines dropped since the last one that was kept.
+    dropped_lines: u64,
+}
+
+impl Transcript {
+    /// Creates the transcript for a new session, which starts recording right away if the client's address is traced.
+    pub fn new(settings: &TranscriptSettings, session_id: u64, peer: SocketAddr) -> Self {
+        let mut transcript = Self {
+            state: TranscriptState::Disabled,
+            session_id,
+            peer,
+            dir: settings.dir.clone(),
+            response_line: Vec::new(),
+            expecting_body: false,
+            body: None,
+            dropped_lines: 0,
+        };
+
+        if settings.addresses.contains(&peer.ip().to_canonical()) {
+            transcript.activate(VecDeque::new());
+        } else if !settings.users.is_empty() {
+            transcript.state = TranscriptState::Pending(VecDeque::new());
+        }
+
+        transcript
+    }
+
+    /// Starts recording to a new file in the transcripts directory, first writing any lines that were held in memory.
+    fn activate(&mut self, pending: VecDeque<String>) {
+        let started = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let path = self.dir.join(format!("{started}-{}.log", self.session_id));
+
+        info!("Recording protocol transcript to {}", path.display());
+        let (sender, receiver) = mpsc::channel(MAX_QUEUED_LINES);
+        tokio::spawn(write_transcript(self.dir.clone(), path, receiver));
+
+        let dropped_pending = std::mem::take(&mut self.dropped_lines);
+        self.state = TranscriptState::Active(sender);
+        self.write_line(&format!("-- Session {} from {}", self.session_id, self.peer));
+        if dropped_pending != 0 {
+            self.write_line(&format!("-- {dropped_pending} earlier lines not recorded"));
+        }
+
+        for line in pending {
+            self.write_line(&line);
+        }
+    }
+
+    /// Called when the client sends a `USER` command, to start recording if the user is traced.
+    pub fn on_user_command(&mut self, settings: &TranscriptSettings, username: &Pop3Username) {
+        if let TranscriptState::Pending(pending) = &mut self.state {
+            if settings.users.contains(username) {
+                let pending = std::mem::take(pending);
+                self.activate(pending);
+            }
+        }
+    }
+
+    /// Called when the session leaves the `AUTHORIZATION` state, after which a traced user can't be named anymore.
+    pub fn on_authorization_end(&mut self) {
+        if let TranscriptState::Pending(_) = self.state {
+            self.state = TranscriptState::Disabled;
+        }
+    }
+
+    /// Records a command line received from the client, redacting any credentials.
+    pub fn record_command(&mut self, line: &[u8]) {
+        if let TranscriptState::Disabled = self.state {
+            return;
+        }
+
+        let (keyword, _) = split_keyword(line);
+        self.expecting_body = keyword.eq_ignore_ascii_case(b"RETR") || keyword.eq_ignore_ascii_case(b"TOP");
+
+        let line = redact_command(line);
+        self.record(format!("C: {}", truncate(&line).escape_ascii()));
+    }
+
+    /// Records bytes written to the client, recording each full response line, but summarizing message contents.
+    pub fn record_output(&mut self, bytes: &[u8]) {
+        if let TranscriptState::Disabled = self.state {
+            return;
+        }
+
+        for &b in bytes {
+            match &mut self.body {
+                Some(body) => {
+                    if b != b'\n' {
+                        if body.line_len < body.line_start.len() {
+                            body.line_start[body.line_len] = b;
+                        }
+                        body.line_len += 1;
+                        continue;
+                    }
+
+                    // The contents end with a line holding a single '.'.
+                    let is_end = matches!((body.line_len, &body.line_start), (1, [b'.', _]) | (2, b".\r"));
+                    if !is_end {
+                        body.lines += 1;
+                        body.bytes += body.line_len as u64 + 1;
+                        body.line_len = 0;
+                        continue;
+                    }
+
+                    let summary = format!("S: [message contents, {} lines, {} bytes]", body.lines, body.bytes);
+                    self.body = None;
+                    self.record(summary);
+                    self.record("S: .".to_string());
+                }
+                None if b == b'\n' => {
+                    let mut line = std::mem::take(&mut self.response_line);
+                    if line.last() == Some(&b'\r') {
+                        line.pop();
+                    }
+
+                    if std::mem::take(&mut self.expecting_body) && line.starts_with(b"+OK") {
+                        self.body = Some(BodySummary::default());
+                    }
+
+                    self.record(format!("S: {}", line.escape_ascii()));
+                }
+                None => {
+                    if self.response_line.len() < MAX_RECORDED_LINE_LENGTH {
+                        self.response_line.push(b);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Records a line preceded by the current time.
+    fn record(&mut self, line: String) {
+        let mut timestamp = String::new();
+        let _ = tracing_subscriber::fmt::time::SystemTime.format_time(&mut Writer::new(&mut timestamp));
+        self.write_line(&format!("{timestamp} {line}"));
+    }
+
+    fn write_line(&mut self, line: &str) {
+        match &mut self.state {
+            TranscriptState::Disabled => {}
+            TranscriptState::Pending(pending) => {
+                if pending.len() >= MAX_PENDING_LINES {
+                    pending.pop_front();
+                    self.dropped_lines += 1;
+                }
+
+                pending.push_back(line.to_string());
+            }
+            TranscriptState::Active(sender) => {
+                if self.dropped_lines != 0 {
+                    match sender.try_send(format!("-- {} lines not recorded", self.dropped_lines)) {
+                        Ok(()) => self.dropped_lines = 0,
+                        Err(TrySendError::Full(_)) => {
+                            self.dropped_lines += 1;
+                            return;
+                        }
+                        Err(TrySendError::Closed(_)) => {
+                            self.state = TranscriptState::Disabled;
+                            return;
+                        }
+                    }
+                }
+
+                match sender.try_send(line.to_string()) {
+                    Ok(()) => {}
+                    Err(TrySendError::Full(_)) => self.dropped_lines += 1,
+                    // The writing task has stopped after an error, which it has already logged.
+                    Err(TrySendError::Closed(_)) => self.state = TranscriptState::Disabled,
+                }
+            }
+        }
+    }
+}
+
+/// Writes the lines received from a session's transcript to a new file, until the transcript is dropped.
+async fn write_transcript(dir: PathBuf, path: PathBuf, mut receiver: mpsc::Receiver<String>) {
+    let result = match tokio::fs::create_dir_all(&dir).await {
+        Ok(()) => OpenOptions::new().create(true).append(true).open(&path).await,
+        Err(error) => Err(error),
+    };
+
+    let mut file = match result {
+        Ok(file) => BufWriter::new(file),
+        Err(error) => {
+            warn!("Could not create protocol transcript at {}: {error}", path.display());
+            return;
+        }
+    };
+
+    while let Some(line) = receiver.recv().await {
+        let mut result = file.write_all(line.as_bytes()).await;
+        if result.is_ok() {
+            result = file.write_all(b"\n").await;
+        }
+
+        // Flush whenever caught up with the session, so the file is up to date while the session goes on.
+        if result.is_ok() && receiver.is_empty() {
+            result = file.flush().await;
+        }
+
+        if let Err(error) = result {
+            warn!(
+                "Could not write to protocol transcript {}, no longer recording: {error}",
+                path.display()
+            );
+            return;
+        }
+    }
+
+    if let Err(error) = file.flush().await {
+        warn!("Could not write to protocol transcript {}: {error}", path.display());
+    }
+}
+
+impl Drop for Transcript {
+    fn drop(&mut self) {
+        if let TranscriptState::Active(_) = self.state {
+            if let Some(body) = self.body.take() {
+                self.record(format!(
+                    "S: [message contents, {} lines, {} bytes, incomplete]",
+                    body.lines, body.bytes
+                ));
+            }
+
+            self.record("-- Session closed".to_string());
+        }
+    }
+}
+
+/// Splits a command line into its keyword and whatever follows it, which starts at the first whitespace character after
+/// the keyword. Whitespace before the keyword is skipped.
+fn split_keyword(line: &[u8]) -> (&[u8], &[u8]) {
+    let line = line.trim_ascii_start();
+    let keyword_len = line.iter().position(|b| b.is_ascii_whitespace()).unwrap_or(line.len());
+    line.split_at(keyword_len)
+}
+
+/// Replaces everything after the keyword of a `PASS`, `APOP` or `AUTH` command line with a placeholder, whatever the
+/// whitespace separating them, so credentials are redacted even from lines the command parser would reject.
+fn redact_command(line: &[u8]) -> Vec<u8> {
+    let (keyword, rest) = split_keyword(line);
+    match keyword.to_ascii_uppercase().as_slice() {
+        b"PASS" | b"APOP" | b"AUTH" if rest.is_empty() => keyword.to_vec(),
+        b"PASS" | b"APOP" | b"AUTH" => [keyword, b" [redacted]"].concat(),
+        _ => line.to_vec(),
+    }
+}
+
+fn truncate(line: &[u8]) -> &[u8] {
+    &line[..line.len().min(MAX_RECORDED_LINE_LENGTH)]
+}
+
+/// A writer that records everything written through it to a session's [`Transcript`].
+pub struct TranscriptWriter<W> {
+    inner: W,
+    transcript: Transcript,
+}
+
+impl<W> TranscriptWriter<W> {
+    pub const fn new(inner: W, transcript: Transcript) -> Self {
+        Self { inner, transcript }
+    }
+
+    pub const fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
+    pub fn transcript_mut(&mut self) -> &mut Transcript {
+        &mut self.transcript
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for TranscriptWriter<W> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let result = Pin::new(&mut this.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(written)) = result {
+            this.transcript.record_output(&buf[..written]);
+        }
+
+        result
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
@@ -7,11 +7,23 @@ use crate::util::ascii::IsValidUsername;
 /// The name of the file containing the plaintext password within each user's maildrop directory.
 pub const PASSWORD_FILE_NAME: &str = "password";
 
+/// The name of the file containing the wrapped message encryption key within each user's maildrop directory.
+pub const ENCRYPTION_KEY_FILE_NAME: &str = "encryption-key";
+
+/// The name of the Maildir++ quota file within each user's maildrop directory.
+pub const MAILDIRSIZE_FILE_NAME: &str = "maildirsize";
+
+/// The name of the audit log file within each user's maildrop directory, when audit records are written there.
+pub const AUDIT_LOG_FILE_NAME: &str = "audit.log";
+
 /// The maximum allowed length (in bytes) for a POP3 command argument (taken from RFC #1939).
 pub const MAX_COMMAND_ARG_LENGTH: usize = 40;
 
 pub const MAILDIR_NEW_FOLDER: &str = "new";
 pub const MAILDIR_OLD_FOLDER: &str = "cur";
+pub const MAILDIR_TMP_FOLDER: &str = "tmp";
+pub const MAILDIR_TRASH_FOLDER: &str = ".Trash";
+pub const MAILDIR_RETAINED_FOLDER: &str = ".Retained";
 
 pub type Pop3ArgString = TinyString<MAX_COMMAND_ARG_LENGTH>;
 pub type MessageNumberCount = u16;
@@ -5,30 +5,34 @@
 //! will automatically release the user in question. This definitively avoids the issue of "I forgot to remove the user
 //! from the set!", as well as handle any race conditions or access-between-await-bounds issues.
 
-use std::{cell::RefCell, collections::HashSet, rc::Rc};
+use std::{
+    collections::HashSet,
+    sync::{Arc, Mutex},
+};
 
 use crate::types::Pop3Username;
 
 /// A user tracker. Read the [`crate::user_tracker`] module's documentation for more information.
 ///
-/// This is a reference type which may be cloned to create multiple references to the same state.
+/// This is a reference type which may be cloned to create multiple references to the same state, and may be shared
+/// across threads.
 #[derive(Clone)]
 pub struct UserTracker {
-    user_set: Rc<RefCell<HashSet<Pop3Username>>>,
+    user_set: Arc<Mutex<HashSet<Pop3Username>>>,
 }
 
 impl UserTracker {
     /// Creates a new [`UserTracker`] with no users.
     pub fn new() -> Self {
         Self {
-            user_set: Rc::new(RefCell::new(HashSet::new())),
+            user_set: Arc::new(Mutex::new(HashSet::new())),
         }
     }
 
     /// Attempt to register a user into the [`UserTracker`]. Return [`Some`] with the registered user's handle on
     /// success, or [`None`] if the user is already registered.
     pub fn try_register(&self, username: Pop3Username) -> Option<UserHandle> {
-        let mut guard = self.user_set.borrow_mut();
+        let mut guard = self.user_set.lock().unwrap();
         match guard.insert(username.clone()) {
             true => Some(UserHandle::new(username, self.clone())),
             false => None,
@@ -55,7 +59,7 @@ impl UserHandle {
 
 impl Drop for UserHandle {
     fn drop(&mut self) {
-        let mut guard = self.tracker.user_set.borrow_mut();
+        let mut guard = self.tracker.user_set.lock().unwrap();
         guard.remove(&self.username);
     }
 }
@@ -0,0 +1,74 @@
+//! A wrapper around readers and writers that counts the bytes passing through them, and optionally the calls that moved
+//! them.
+
+use std::{
+    io,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// Wraps a reader or writer, adding the amount of bytes read or written to a shared counter.
+pub struct Counted<T> {
+    inner: T,
+    count: Arc<AtomicU64>,
+    calls: Option<Arc<AtomicU64>>,
+}
+
+impl<T> Counted<T> {
+    pub const fn new(inner: T, count: Arc<AtomicU64>) -> Self {
+        Self { inner, count, calls: None }
+    }
+
+    /// Also adds each read or write that moved any bytes to a shared counter. For a socket, that's how many system
+    /// calls it took.
+    pub fn with_calls(mut self, calls: Arc<AtomicU64>) -> Self {
+        self.calls = Some(calls);
+        self
+    }
+
+    fn add(&self, bytes: usize) {
+        self.count.fetch_add(bytes as u64, Ordering::Relaxed);
+        if let Some(calls) = self.calls.as_ref().filter(|_| bytes != 0) {
+            calls.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for Counted<T> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let filled_before = buf.filled().len();
+        let result = Pin::new(&mut this.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = result {
+            this.add(buf.filled().len() - filled_before);
+        }
+
+        result
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for Counted<T> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let result = Pin::new(&mut this.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(written)) = result {
+            this.add(written);
+        }
+
+        result
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
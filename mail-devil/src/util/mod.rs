@@ -1,4 +1,5 @@
 pub mod ascii;
 pub mod buffer_size;
-pub mod macros;
+pub mod counting;
+pub mod ownership;
 pub mod sockets;
@@ -0,0 +1,62 @@
+//! Giving the files and folders the server creates within a user's maildir to the maildir's owner.
+//!
+//! A server running as root would otherwise leave root-owned files in maildirs that belong to other users, which their
+//! mail delivery agents or the users themselves can't then modify. When the server isn't running as root it can't give
+//! files away, and they belong to the server's user as usual.
+
+use std::{fs::Metadata, io, path::Path};
+
+use crate::auth::MaildirOwner;
+
+/// Gets the owner of a maildir's folder, or [`None`] if it can't be determined.
+pub async fn maildir_owner(maildir: &Path) -> Option<MaildirOwner> {
+    owner_of(&tokio::fs::metadata(maildir).await.ok()?)
+}
+
+/// Gets the owner of a file or folder from its metadata, or [`None`] if the platform has no such concept.
+pub fn owner_of(metadata: &Metadata) -> Option<MaildirOwner> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+
+        Some(MaildirOwner {
+            uid: metadata.uid(),
+            gid: metadata.gid(),
+        })
+    }
+
+    #[cfg(not(unix))]
+    {
+        let _ = metadata;
+        None
+    }
+}
+
+/// Gives a file or folder the server has created to the given owner. Does nothing if there is no owner, or if the
+/// server isn't running as root.
+pub async fn set_owner(path: &Path, owner: Option<MaildirOwner>) -> io::Result<()> {
+    #[cfg(unix)]
+    if let Some(owner) = owner {
+        // SAFETY: geteuid has no preconditions and can't fail.
+        if unsafe { libc::geteuid() } == 0 {
+            let path = path.to_path_buf();
+            return tokio::task::spawn_blocking(move || std::os::unix::fs::chown(path, Some(owner.uid), Some(owner.gid)))
+                .await
+                .map_err(io::Error::other)?;
+        }
+    }
+
+    #[cfg(not(unix))]
+    let _ = (path, owner);
+
+    Ok(())
+}
+
+/// Creates a folder if it doesn't exist, giving it to the given owner. The folder's parent must already exist.
+pub async fn create_dir(path: &Path, owner: Option<MaildirOwner>) -> io::Result<()> {
+    match tokio::fs::create_dir(path).await {
+        Ok(()) => set_owner(path, owner).await,
+        Err(error) if error.kind() == io::ErrorKind::AlreadyExists => Ok(()),
+        Err(error) => Err(error),
+    }
+}
@@ -0,0 +1,42 @@
+//! The administrative interface: requiring a password, and the commands served once authenticated.
+
+mod common;
+
+use std::io::ErrorKind;
+
+use common::{TestClient, TestServer};
+use mail_devil::quota::{self, Quota};
+use mail_devil::ServerBuilder;
+use tokio::net::TcpListener;
+
+#[tokio::test]
+async fn admin_interface_requires_a_non_empty_password() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let builder = ServerBuilder::new().listener(TcpListener::bind("127.0.0.1:0").await.unwrap());
+    let result = builder.admin_listener(listener).admin_password("").build().await;
+    assert_eq!(result.err().map(|error| error.kind()), Some(ErrorKind::InvalidInput));
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let builder = ServerBuilder::new().listener(TcpListener::bind("127.0.0.1:0").await.unwrap());
+    let result = builder.admin_listener(listener).build().await;
+    assert_eq!(result.err().map(|error| error.kind()), Some(ErrorKind::InvalidInput));
+}
+
+#[tokio::test]
+async fn admin_quota_command() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let admin_address = listener.local_addr().unwrap();
+    let server = TestServer::start_with(|builder| builder.admin_listener(listener).admin_password("hunter2")).await;
+    server.add_user("alice", "secret", &[&[b'x'; 60]]).await;
+    let quota: Quota = "100S".parse().ok().unwrap();
+    quota::set_quota(&server.maildirs_dir.join("alice"), quota).await.unwrap();
+    server.add_user("bob", "secret", &[b"hello\n"]).await;
+
+    let mut admin = TestClient::connect(admin_address).await;
+    assert_eq!(admin.command("AUTH hunter2").await, "+OK Authenticated");
+    assert_eq!(admin.command("QUOTA alice").await, "+OK 60 1 100S 60");
+    assert_eq!(admin.command("QUOTA bob").await, "+OK 6 1 - -");
+    assert_eq!(admin.command("QUOTA nobody").await, "-ERR No such user");
+    assert_eq!(admin.command("QUOTA").await, "-ERR Invalid username");
+    server.stop().await;
+}
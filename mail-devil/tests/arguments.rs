@@ -0,0 +1,76 @@
+//! Parsing the command line's arguments and merging them with those of a configuration file.
+
+use mail_devil::args::{self, ArgumentsError, ArgumentsRequest, StartupArguments};
+use tracing::level_filters::LevelFilter;
+
+/// Writes a configuration file with the given contents and parses the given arguments followed by `--config <file>`.
+fn parse_with_config(name: &str, config: &str, arguments: &[&str]) -> StartupArguments {
+    let path = std::env::temp_dir().join(format!("mail-devil-test-{}-{name}.toml", std::process::id()));
+    std::fs::write(&path, config).unwrap();
+
+    let mut all_arguments = vec!["mail-devil".to_string()];
+    all_arguments.extend(arguments.iter().map(|arg| arg.to_string()));
+    all_arguments.extend(["--config".to_string(), path.display().to_string()]);
+    let result = args::parse_arguments(all_arguments.into_iter());
+    std::fs::remove_file(&path).unwrap();
+
+    match result {
+        Ok(ArgumentsRequest::Run(startup_args)) => *startup_args,
+        _ => panic!("arguments were not parsed as a request to run the server"),
+    }
+}
+
+#[test]
+fn command_line_flag_overrides_the_config_file() {
+    let startup_args = parse_with_config("silent-over-verbose", "verbose = true\n", &["-s"]);
+    assert!(!startup_args.verbose);
+    assert_eq!(startup_args.get_log_level(), LevelFilter::WARN);
+
+    let startup_args = parse_with_config("verbose-over-silent", "silent = true\n", &["--verbose"]);
+    assert!(!startup_args.silent);
+    assert_eq!(startup_args.get_log_level(), LevelFilter::DEBUG);
+}
+
+#[test]
+fn config_file_flags_apply_when_not_on_the_command_line() {
+    let startup_args = parse_with_config("config-flags", "verbose = true\nnewest-first = true\n", &[]);
+    assert!(startup_args.verbose);
+    assert!(startup_args.newest_first);
+
+    let startup_args = parse_with_config("config-flags-off", "newest-first = false\n", &["-n"]);
+    assert!(startup_args.newest_first);
+}
+
+#[test]
+fn too_short_transaction_timeout_names_the_minimum() {
+    let arguments = ["mail-devil", "-T", "5m"].into_iter().map(String::from);
+    match args::parse_arguments(arguments) {
+        Err(error) => assert_eq!(error.to_string(), "The TRANSACTION timeout must be at least 10 minutes at -T 5m"),
+        Ok(_) => panic!("a 5 minute TRANSACTION timeout was accepted"),
+    }
+}
+
+#[test]
+fn admin_password_file_serves_as_the_admin_password() {
+    let arguments = ["mail-devil", "-A", "127.0.0.1", "-W", "/etc/mail-devil/admin-password"]
+        .into_iter()
+        .map(String::from);
+    match args::parse_arguments(arguments) {
+        Ok(ArgumentsRequest::Run(startup_args)) => {
+            assert_eq!(
+                startup_args.admin_password_file.unwrap().to_str(),
+                Some("/etc/mail-devil/admin-password")
+            );
+            assert_eq!(startup_args.admin_password, None);
+        }
+        _ => panic!("arguments were not parsed as a request to run the server"),
+    }
+
+    let arguments = ["mail-devil", "-P", "hunter2", "--admin-password-file", "password"]
+        .into_iter()
+        .map(String::from);
+    assert!(matches!(
+        args::parse_arguments(arguments),
+        Err(ArgumentsError::AdminPasswordConflict)
+    ));
+}
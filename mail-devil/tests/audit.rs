@@ -0,0 +1,45 @@
+//! Recording retrieved and deleted messages in the audit log.
+
+mod common;
+
+use common::TestServer;
+use mail_devil::audit::{AuditDestination, AuditSettings, DEFAULT_AUDIT_KEEP};
+use serde_json::Value;
+
+#[tokio::test]
+async fn records_hold_the_listed_size() {
+    let settings = AuditSettings {
+        destination: Some(AuditDestination::Maildir),
+        max_size: None,
+        keep: DEFAULT_AUDIT_KEEP,
+    };
+
+    let server = TestServer::start_with(|builder| builder.audit(settings)).await;
+    // With bare LF line endings, the size listed to the client is larger than the file's, and the byte-stuffed period
+    // makes the bytes sent larger still.
+    server
+        .add_user("alice", "secret", &[b"Subject: a\n\nHi\n", b"Subject: b\n\n.Hi\n"])
+        .await;
+
+    // The sizes aren't listed before, so they're calculated for the records.
+    let mut client = server.login("alice", "secret").await;
+    client.multiline("RETR 2").await;
+    assert_eq!(client.command("DELE 1").await, "+OK");
+    assert_eq!(client.command("DELE 2").await, "+OK");
+    assert_eq!(client.command("QUIT").await, "+OK 2 messages deleted");
+    assert!(client.read_to_end().await.is_empty());
+
+    let log = tokio::fs::read_to_string(server.maildirs_dir.join("alice").join("audit.log"))
+        .await
+        .unwrap();
+    let records: Vec<Value> = log.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+    assert_eq!(records.len(), 3, "audit log was {log:?}");
+    assert_eq!(
+        (&records[0]["action"], &records[0]["size"], &records[0]["bytes_sent"]),
+        (&"retrieve".into(), &19.into(), &20.into())
+    );
+    assert_eq!((&records[1]["action"], &records[1]["size"]), (&"delete".into(), &18.into()));
+    assert_eq!((&records[2]["action"], &records[2]["size"]), (&"delete".into(), &19.into()));
+
+    server.stop().await;
+}
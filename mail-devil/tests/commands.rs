@@ -0,0 +1,275 @@
+//! Every command in every state of a session, as defined by RFC #1939, along with their argument errors.
+
+mod common;
+
+use common::TestServer;
+
+const MESSAGES: [&[u8]; 3] = [
+    b"Subject: one\r\n\r\nFirst\r\n",
+    b"Subject: two\n\nSecond\n",
+    b"Subject: three\r\n\r\nThird",
+];
+
+/// The sizes of [`MESSAGES`] once their line endings are converted to CRLF, as reported by STAT and LIST.
+const SIZES: [u64; 3] = [23, 24, 23];
+
+#[tokio::test]
+async fn authorization_state_rejects_transaction_commands() {
+    let server = TestServer::start().await;
+    server.add_user("alice", "secret", &MESSAGES).await;
+    let mut client = server.connect().await;
+
+    for command in ["STAT", "LIST", "LIST 1", "RETR 1", "DELE 1", "NOOP", "RSET"] {
+        let response = client.command(command).await;
+        assert_eq!(
+            response, "-ERR Command only allowed in the TRANSACTION state",
+            "response to {command}"
+        );
+    }
+
+    // The rejected commands didn't change the session's state, so logging in still works.
+    assert_eq!(client.command("USER alice").await, "+OK");
+    assert_eq!(client.command("PASS secret").await, "+OK");
+    assert_eq!(client.command("QUIT").await, "+OK 0 messages deleted");
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn user_and_pass() {
+    let server = TestServer::start().await;
+    server.add_user("alice", "secret", &MESSAGES).await;
+    let mut client = server.connect().await;
+
+    assert_eq!(client.command("PASS secret").await, "-ERR Must specify a user before a password");
+    assert_eq!(client.command("USER").await, "-ERR No username specified");
+    assert_eq!(client.command("USER alice bob").await, "-ERR Too many arguments");
+    assert_eq!(client.command("USER alice").await, "+OK");
+    assert_eq!(client.command("PASS").await, "-ERR No password specified");
+    assert_eq!(client.command("PASS wrong").await, "-ERR [AUTH] Wrong username or password");
+
+    // A user that doesn't exist fails just like a wrong password.
+    assert_eq!(client.command("USER nobody").await, "+OK");
+    assert_eq!(client.command("PASS secret").await, "-ERR [AUTH] Wrong username or password");
+
+    // The last USER command is the one PASS applies to, and keywords are case-insensitive.
+    assert_eq!(client.command("user alice").await, "+OK");
+    assert_eq!(client.command("pAsS secret").await, "+OK");
+    assert_eq!(client.command("QUIT").await, "+OK 0 messages deleted");
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn passwords_may_contain_spaces() {
+    let server = TestServer::start().await;
+    server.add_user("alice", "correct horse", &[]).await;
+    let mut client = server.connect().await;
+
+    assert_eq!(client.command("USER alice").await, "+OK");
+    assert_eq!(client.command("PASS correct horse").await, "+OK");
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn quit_in_authorization_state() {
+    let server = TestServer::start().await;
+    let mut client = server.connect().await;
+
+    assert_eq!(client.command("QUIT").await, "+OK 0 messages deleted");
+    assert!(client.read_to_end().await.is_empty());
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn transaction_state_rejects_authorization_commands() {
+    let server = TestServer::start().await;
+    server.add_user("alice", "secret", &MESSAGES).await;
+    let mut client = server.login("alice", "secret").await;
+
+    assert_eq!(
+        client.command("USER alice").await,
+        "-ERR Command only allowed in the AUTHORIZATION state"
+    );
+    assert_eq!(
+        client.command("PASS secret").await,
+        "-ERR Command only allowed in the AUTHORIZATION state"
+    );
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn stat() {
+    let server = TestServer::start().await;
+    server.add_user("alice", "secret", &MESSAGES).await;
+    let mut client = server.login("alice", "secret").await;
+
+    let total: u64 = SIZES.iter().sum();
+    assert_eq!(client.command("STAT").await, format!("+OK 3 {total}"));
+    assert_eq!(client.command("STAT 1").await, "-ERR This command takes no arguments");
+
+    // Messages marked as deleted aren't counted.
+    assert_eq!(client.command("DELE 2").await, "+OK");
+    assert_eq!(client.command("STAT").await, format!("+OK 2 {}", total - SIZES[1]));
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn list() {
+    let server = TestServer::start().await;
+    server.add_user("alice", "secret", &MESSAGES).await;
+    let mut client = server.login("alice", "secret").await;
+
+    let (status, lines) = client.multiline("LIST").await;
+    assert!(status.starts_with("+OK"));
+    assert_eq!(
+        lines,
+        [format!("1 {}", SIZES[0]), format!("2 {}", SIZES[1]), format!("3 {}", SIZES[2])]
+    );
+
+    assert_eq!(client.command("LIST 2").await, format!("+OK 2 {}", SIZES[1]));
+    assert_eq!(client.command("LIST 4").await, "-ERR No such message");
+    assert_eq!(client.command("LIST 0").await, "-ERR Argument is not a valid number");
+    assert_eq!(client.command("LIST x").await, "-ERR Argument is not a valid number");
+    assert_eq!(client.command("LIST 1 2").await, "-ERR This command takes at most one argument");
+
+    // Deleted messages are left out of the listing, but keep their numbers.
+    assert_eq!(client.command("DELE 2").await, "+OK");
+    assert_eq!(client.command("LIST 2").await, "-ERR Message is deleted");
+    let (_, lines) = client.multiline("LIST").await;
+    assert_eq!(lines, [format!("1 {}", SIZES[0]), format!("3 {}", SIZES[2])]);
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn list_empty_maildrop() {
+    let server = TestServer::start().await;
+    server.add_user("alice", "secret", &[]).await;
+    let mut client = server.login("alice", "secret").await;
+
+    assert_eq!(client.command("STAT").await, "+OK 0 0");
+    let (status, lines) = client.multiline("LIST").await;
+    assert!(status.starts_with("+OK"));
+    assert!(lines.is_empty());
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn retr() {
+    let server = TestServer::start().await;
+    server.add_user("alice", "secret", &MESSAGES).await;
+    let mut client = server.login("alice", "secret").await;
+
+    let (status, lines) = client.multiline("RETR 1").await;
+    assert!(status.starts_with("+OK"));
+    assert_eq!(lines, ["Subject: one", "", "First"]);
+
+    assert_eq!(client.command("RETR 4").await, "-ERR No such message");
+    assert_eq!(client.command("RETR").await, "-ERR This command takes exactly one argument");
+    assert_eq!(client.command("RETR one").await, "-ERR Argument is not a valid number");
+    assert_eq!(client.command("RETR 1 2").await, "-ERR Too many arguments");
+
+    assert_eq!(client.command("DELE 1").await, "+OK");
+    assert_eq!(client.command("RETR 1").await, "-ERR Message is deleted");
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn dele() {
+    let server = TestServer::start().await;
+    server.add_user("alice", "secret", &MESSAGES).await;
+    let mut client = server.login("alice", "secret").await;
+
+    assert_eq!(client.command("DELE 1").await, "+OK");
+    assert_eq!(client.command("DELE 1").await, "-ERR Message is deleted");
+    assert_eq!(client.command("DELE 4").await, "-ERR No such message");
+    assert_eq!(client.command("DELE").await, "-ERR This command takes exactly one argument");
+    assert_eq!(client.command("DELE -1").await, "-ERR Argument is not a valid number");
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn noop() {
+    let server = TestServer::start().await;
+    server.add_user("alice", "secret", &MESSAGES).await;
+    let mut client = server.login("alice", "secret").await;
+
+    assert_eq!(client.command("NOOP").await, "+OK");
+    assert_eq!(client.command("NOOP now").await, "-ERR This command takes no arguments");
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn rset_unmarks_deleted_messages() {
+    let server = TestServer::start().await;
+    server.add_user("alice", "secret", &MESSAGES).await;
+    let mut client = server.login("alice", "secret").await;
+
+    assert_eq!(client.command("DELE 1").await, "+OK");
+    assert_eq!(client.command("DELE 3").await, "+OK");
+    assert_eq!(client.command("RSET").await, "+OK");
+    assert_eq!(client.command("RSET 1").await, "-ERR This command takes no arguments");
+
+    let total: u64 = SIZES.iter().sum();
+    assert_eq!(client.command("STAT").await, format!("+OK 3 {total}"));
+    assert_eq!(client.command("QUIT").await, "+OK 0 messages deleted");
+    assert_eq!(common::list_files(&server.new_dir("alice")).await.len(), 3);
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn capa_in_both_states() {
+    let server = TestServer::start().await;
+    server.add_user("alice", "secret", &[]).await;
+    let mut client = server.connect().await;
+
+    let (status, capabilities) = client.multiline("CAPA").await;
+    assert!(status.starts_with("+OK"));
+    assert!(capabilities.iter().any(|line| line == "USER"));
+    assert!(capabilities.iter().any(|line| line == "PIPELINING"));
+    assert_eq!(client.command("CAPA USER").await, "-ERR This command takes no arguments");
+
+    assert_eq!(client.command("USER alice").await, "+OK");
+    assert_eq!(client.command("PASS secret").await, "+OK");
+    let (status, transaction_capabilities) = client.multiline("CAPA").await;
+    assert!(status.starts_with("+OK"));
+    assert_eq!(transaction_capabilities, capabilities);
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn failed_logins_carry_the_advertised_response_codes() {
+    let server = TestServer::start().await;
+    server.add_user("alice", "secret", &[]).await;
+    let mut first = server.login("alice", "secret").await;
+    let mut second = server.connect().await;
+
+    let (_, capabilities) = second.multiline("CAPA").await;
+    assert!(capabilities.iter().any(|line| line == "RESP-CODES"));
+    assert!(capabilities.iter().any(|line| line == "AUTH-RESP-CODE"));
+
+    assert_eq!(second.command("USER alice").await, "+OK");
+    assert!(second.command("PASS wrong").await.starts_with("-ERR [AUTH] "));
+    assert_eq!(second.command("USER alice").await, "+OK");
+    assert!(second.command("PASS secret").await.starts_with("-ERR [IN-USE] "));
+
+    assert_eq!(first.command("QUIT").await, "+OK 0 messages deleted");
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn invalid_commands() {
+    let server = TestServer::start().await;
+    let mut client = server.connect().await;
+
+    assert_eq!(client.command("HELO").await, "-ERR Unknown command");
+    assert_eq!(client.command("TOPS 1 2").await, "-ERR Unknown command");
+    assert_eq!(client.command("X").await, "-ERR Unknown command");
+    assert_eq!(client.command(" NOOP").await, "-ERR Unknown command");
+    assert!(client.command("").await.starts_with("-ERR"));
+    client.send_raw(b"NO\x01P\r\n").await;
+    assert_eq!(client.read_line().await, "-ERR Non ASCII character with code 0x1");
+
+    // Lines may also end with a bare LF.
+    client.send_raw(b"QUIT\n").await;
+    assert_eq!(client.read_line().await, "+OK 0 messages deleted");
+    server.stop().await;
+}
@@ -0,0 +1,220 @@
+//! A harness that runs the server in-process on an ephemeral port with a temporary maildirs directory, and drives it
+//! like a POP3 client would.
+
+// Each test crate only uses some of the helpers.
+#![allow(dead_code)]
+
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use mail_devil::{server, ServerBuilder, ServerHandle};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+
+/// How long to wait for the server to answer before failing a test.
+pub const RESPONSE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Tells apart the maildirs directories of the tests running at once in the same process.
+static NEXT_SERVER_ID: AtomicUsize = AtomicUsize::new(0);
+
+pub struct TestServer {
+    pub address: SocketAddr,
+    pub maildirs_dir: PathBuf,
+    handle: ServerHandle,
+    task: JoinHandle<io::Result<()>>,
+}
+
+impl TestServer {
+    /// Starts a server with the default settings.
+    pub async fn start() -> Self {
+        Self::start_with(|builder| builder).await
+    }
+
+    /// Starts a server with settings changed by `configure`. The listener and maildirs directory are set beforehand.
+    pub async fn start_with(configure: impl FnOnce(ServerBuilder) -> ServerBuilder) -> Self {
+        let id = NEXT_SERVER_ID.fetch_add(1, Ordering::Relaxed);
+        let maildirs_dir = std::env::temp_dir().join(format!("mail-devil-test-{}-{id}", std::process::id()));
+        let _ = tokio::fs::remove_dir_all(&maildirs_dir).await;
+        tokio::fs::create_dir_all(&maildirs_dir).await.unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let builder = ServerBuilder::new().listener(listener).maildirs_dir(&maildirs_dir);
+        let server = configure(builder).build().await.unwrap();
+        let handle = server.handle();
+        let task = tokio::spawn(server.run());
+
+        Self {
+            address,
+            maildirs_dir,
+            handle,
+            task,
+        }
+    }
+
+    /// Creates a user whose maildrop holds the given messages, numbered in the same order. Returns the paths to the
+    /// message files.
+    pub async fn add_user(&self, username: &str, password: &str, messages: &[&[u8]]) -> Vec<PathBuf> {
+        server::create_user_maildir(&self.maildirs_dir, username, password).await.unwrap();
+
+        let mut paths = Vec::new();
+        for (index, contents) in messages.iter().enumerate() {
+            // Maildir file names start with the delivery time, which the server numbers messages by.
+            let number = index + 1;
+            let path = self.new_dir(username).join(format!("{number}.message{number}.localhost"));
+            tokio::fs::write(&path, contents).await.unwrap();
+            paths.push(path);
+        }
+
+        paths
+    }
+
+    /// The folder holding a user's messages.
+    pub fn new_dir(&self, username: &str) -> PathBuf {
+        self.maildirs_dir.join(username).join("new")
+    }
+
+    /// The folder messages are moved to when their deletion is committed under the default deletion policy.
+    pub fn retained_dir(&self, username: &str) -> PathBuf {
+        self.maildirs_dir.join(username).join(".Retained").join("cur")
+    }
+
+    /// Connects a client to the server and reads the greeting.
+    pub async fn connect(&self) -> TestClient {
+        TestClient::connect(self.address).await
+    }
+
+    /// Connects a client to the server and logs in as the given user.
+    pub async fn login(&self, username: &str, password: &str) -> TestClient {
+        let mut client = self.connect().await;
+        assert_eq!(client.command(&format!("USER {username}")).await, "+OK");
+        assert_eq!(client.command(&format!("PASS {password}")).await, "+OK");
+        client
+    }
+
+    /// Shuts the server down, waits for it to stop and removes its maildirs directory.
+    pub async fn stop(self) {
+        self.handle.shutdown();
+        let result = tokio::time::timeout(RESPONSE_TIMEOUT, self.task).await;
+        result.expect("timed out waiting for the server to stop").unwrap().unwrap();
+        tokio::fs::remove_dir_all(&self.maildirs_dir).await.unwrap();
+    }
+}
+
+pub struct TestClient {
+    reader: BufReader<OwnedReadHalf>,
+    writer: OwnedWriteHalf,
+}
+
+impl TestClient {
+    /// Connects to the given address, which may also be one of the server's other interfaces, and reads the greeting.
+    pub async fn connect(address: SocketAddr) -> Self {
+        let stream = TcpStream::connect(address).await.unwrap();
+        let (reader, writer) = stream.into_split();
+        let mut client = Self {
+            reader: BufReader::new(reader),
+            writer,
+        };
+
+        let greeting = client.read_line().await;
+        assert!(greeting.starts_with("+OK"), "unexpected greeting {greeting:?}");
+        client
+    }
+
+    /// Sends a line, appending a CRLF.
+    pub async fn send(&mut self, line: &str) {
+        self.send_raw(format!("{line}\r\n").as_bytes()).await;
+    }
+
+    /// Sends the given bytes as they are, in a single write.
+    pub async fn send_raw(&mut self, bytes: &[u8]) {
+        self.writer.write_all(bytes).await.unwrap();
+    }
+
+    /// Reads a line, checking that it ends with a CRLF, and returns it without the CRLF.
+    pub async fn read_line(&mut self) -> String {
+        let mut line = Vec::new();
+        let result = tokio::time::timeout(RESPONSE_TIMEOUT, self.reader.read_until(b'\n', &mut line)).await;
+        result.expect("timed out waiting for a response").unwrap();
+
+        let line = String::from_utf8(line).expect("response is not UTF-8");
+        match line.strip_suffix("\r\n") {
+            Some(line) => line.to_string(),
+            None => panic!("line {line:?} does not end with a CRLF"),
+        }
+    }
+
+    /// Sends a command and reads its single-line response.
+    pub async fn command(&mut self, line: &str) -> String {
+        self.send(line).await;
+        self.read_line().await
+    }
+
+    /// Sends a command and reads its response. If the response is positive, the lines that follow are read up to the
+    /// terminating period, and returned as they were sent, without undoing any byte-stuffing.
+    pub async fn multiline(&mut self, line: &str) -> (String, Vec<String>) {
+        self.send(line).await;
+        let status = self.read_line().await;
+        let mut lines = Vec::new();
+        if status.starts_with("+OK") {
+            loop {
+                let line = self.read_line().await;
+                if line == "." {
+                    break;
+                }
+                lines.push(line);
+            }
+        }
+
+        (status, lines)
+    }
+
+    /// Sends a command and reads the raw bytes of its multi-line response, after the status line, up to and including
+    /// the terminating `CRLF.CRLF`.
+    pub async fn multiline_raw(&mut self, line: &str) -> (String, Vec<u8>) {
+        self.send(line).await;
+        let status = self.read_line().await;
+        let mut body = Vec::new();
+        if !status.starts_with("+OK") {
+            return (status, body);
+        }
+
+        while !body.ends_with(b"\r\n.\r\n") && body != b".\r\n" {
+            let result = tokio::time::timeout(RESPONSE_TIMEOUT, self.reader.read_until(b'\n', &mut body)).await;
+            let bytes_read = result.expect("timed out waiting for a response").unwrap();
+            assert_ne!(bytes_read, 0, "connection closed in the middle of a multi-line response");
+        }
+
+        (status, body)
+    }
+
+    /// Waits for the server to close the connection, returning everything it sent until then.
+    pub async fn read_to_end(&mut self) -> Vec<u8> {
+        let mut rest = Vec::new();
+        let result = tokio::time::timeout(RESPONSE_TIMEOUT, self.reader.read_to_end(&mut rest)).await;
+        result.expect("timed out waiting for the connection to close").unwrap();
+        rest
+    }
+}
+
+/// Lists the names of the files in a directory, sorted.
+pub async fn list_files(dir: &Path) -> Vec<String> {
+    let mut names = Vec::new();
+    let mut entries = match tokio::fs::read_dir(dir).await {
+        Ok(entries) => entries,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => return names,
+        Err(error) => panic!("could not list {}: {error}", dir.display()),
+    };
+
+    while let Some(entry) = entries.next_entry().await.unwrap() {
+        names.push(entry.file_name().to_string_lossy().into_owned());
+    }
+
+    names.sort();
+    names
+}
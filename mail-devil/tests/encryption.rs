@@ -0,0 +1,72 @@
+//! Encryption at rest: message keys wrapped with the server key rather than anything stored in the maildirs.
+
+mod common;
+
+use common::TestServer;
+use mail_devil::{server, ServerKey};
+
+const MESSAGE: &[u8] = b"Subject: secret\r\n\r\nThe launch codes.\r\n";
+
+fn test_key() -> ServerKey {
+    ServerKey::from_bytes([7; 32])
+}
+
+/// Starts a server with the test key and a user alice whose single message has been encrypted.
+async fn start_with_encrypted_user() -> TestServer {
+    let server = TestServer::start_with(|builder| builder.server_key(test_key())).await;
+    let paths = server.add_user("alice", "secret", &[MESSAGE]).await;
+    let count = server::encrypt_user_maildir(&server.maildirs_dir.join("alice"), &test_key())
+        .await
+        .unwrap();
+    assert_eq!(count, 1);
+
+    for path in paths {
+        let contents = tokio::fs::read(&path).await.unwrap();
+        assert!(!contents.windows(17).any(|window| window == b"The launch codes."));
+    }
+
+    server
+}
+
+#[tokio::test]
+async fn encrypted_messages_are_decrypted_after_login() {
+    let server = start_with_encrypted_user().await;
+
+    let mut client = server.login("alice", "secret").await;
+    let (status, lines) = client.multiline("RETR 1").await;
+    assert!(status.starts_with("+OK"), "response to RETR was {status:?}");
+    assert_eq!(lines, ["Subject: secret", "", "The launch codes."]);
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn changing_the_password_keeps_messages_readable() {
+    let server = start_with_encrypted_user().await;
+    server::create_user_maildir(&server.maildirs_dir, "alice", "new secret")
+        .await
+        .unwrap();
+
+    let mut client = server.login("alice", "new secret").await;
+    let (status, lines) = client.multiline("RETR 1").await;
+    assert!(status.starts_with("+OK"), "response to RETR was {status:?}");
+    assert_eq!(lines.last().map(String::as_str), Some("The launch codes."));
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn login_fails_without_the_server_key() {
+    let keyed_server = start_with_encrypted_user().await;
+
+    // The same maildirs, including alice's password file, served without the key that wraps her message key.
+    let server = TestServer::start().await;
+    tokio::fs::remove_dir_all(&server.maildirs_dir).await.unwrap();
+    tokio::fs::rename(&keyed_server.maildirs_dir, &server.maildirs_dir).await.unwrap();
+    tokio::fs::create_dir(&keyed_server.maildirs_dir).await.unwrap();
+    keyed_server.stop().await;
+
+    let mut client = server.connect().await;
+    assert_eq!(client.command("USER alice").await, "+OK");
+    let response = client.command("PASS secret").await;
+    assert!(response.starts_with("-ERR [SYS/PERM]"), "response to PASS was {response:?}");
+    server.stop().await;
+}
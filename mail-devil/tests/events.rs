@@ -0,0 +1,36 @@
+//! Appending the server's events to an event log.
+
+mod common;
+
+use common::TestServer;
+use mail_devil::hooks::HookSettings;
+use serde_json::Value;
+
+#[tokio::test]
+async fn event_log_holds_a_sessions_events_in_order() {
+    let path = std::env::temp_dir().join(format!("mail-devil-test-{}-events.log", std::process::id()));
+    let _ = tokio::fs::remove_file(&path).await;
+    let settings = HookSettings {
+        commands: Vec::new(),
+        logs: vec![path.clone()],
+    };
+
+    let server = TestServer::start_with(|builder| builder.hooks(settings)).await;
+    server.add_user("alice", "secret", &[]).await;
+    let mut client = server.login("alice", "secret").await;
+    assert_eq!(client.command("QUIT").await, "+OK 0 messages deleted");
+    assert!(client.read_to_end().await.is_empty());
+
+    // Stopping the server waits for the event log to be written and flushed.
+    server.stop().await;
+    let log = tokio::fs::read_to_string(&path).await.unwrap();
+    let events: Vec<Value> = log.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+    let names: Vec<_> = events.iter().map(|event| event["event"].as_str().unwrap()).collect();
+    assert_eq!(
+        names,
+        ["session_opened", "login_succeeded", "session_closed"],
+        "event log was {log:?}"
+    );
+    assert_eq!(events[1]["user"], "alice");
+    tokio::fs::remove_file(&path).await.unwrap();
+}
@@ -0,0 +1,48 @@
+//! Site-specific commands registered by programs embedding the server.
+
+mod common;
+
+use common::TestServer;
+use mail_devil::pop3::extensions::{
+    CommandFuture, CommandHandler, CommandRegistry, CommandRequest, CommandResponse, RegisterCommandError, SessionState,
+};
+
+/// Responds with the same lines every time.
+struct FixedLines(Vec<&'static str>);
+
+impl CommandHandler for FixedLines {
+    fn handle<'a>(&'a self, _: CommandRequest<'a>) -> CommandFuture<'a> {
+        let lines = self.0.iter().map(|line| line.to_string()).collect();
+        Box::pin(async move { CommandResponse::MultiLine(None, lines) })
+    }
+}
+
+#[tokio::test]
+async fn multi_line_responses_keep_empty_lines() {
+    let mut commands = CommandRegistry::new();
+    let handler = FixedLines(vec!["Subject: test", "", "body\r", "split\nin two\n", ".dot", ""]);
+    commands.register("XLINES", &[SessionState::Authorization], None, handler).unwrap();
+
+    let server = TestServer::start_with(|builder| builder.commands(commands)).await;
+    let mut client = server.connect().await;
+    let (status, body) = client.multiline_raw("XLINES").await;
+    assert_eq!(status, "+OK");
+    assert_eq!(
+        body.escape_ascii().to_string(),
+        "Subject: test\\r\\n\\r\\nbody\\r\\nsplit\\r\\nin two\\r\\n..dot\\r\\n\\r\\n.\\r\\n"
+    );
+    server.stop().await;
+}
+
+#[test]
+fn commands_cant_be_registered_for_the_update_state() {
+    let mut commands = CommandRegistry::new();
+    let result = commands.register(
+        "XLINES",
+        &[SessionState::Transaction, SessionState::Update],
+        None,
+        FixedLines(Vec::new()),
+    );
+    assert_eq!(result, Err(RegisterCommandError::UpdateState));
+    assert!(commands.is_empty());
+}
@@ -0,0 +1,69 @@
+//! The limit on the length of command lines, and lines arriving in pieces.
+
+mod common;
+
+use common::TestServer;
+
+#[tokio::test]
+async fn overlong_line_closes_the_connection() {
+    let server = TestServer::start().await;
+    let mut client = server.connect().await;
+
+    client.send(&format!("NOOP {}", "x".repeat(300))).await;
+    assert_eq!(client.read_line().await, "-ERR POP3 lines must be at most 255 characters long");
+    assert!(client.read_to_end().await.is_empty());
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn long_line_within_the_limit_is_accepted() {
+    let server = TestServer::start().await;
+    server.add_user("alice", "secret", &[]).await;
+    let mut client = server.login("alice", "secret").await;
+
+    // The line is rejected for its arguments, not for its length.
+    let line = format!("NOOP {}", "x".repeat(240));
+    assert_eq!(client.command(&line).await, "-ERR This command takes no arguments");
+    assert_eq!(client.command("NOOP").await, "+OK");
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn responses_before_an_overlong_line_are_sent() {
+    let server = TestServer::start().await;
+    let mut client = server.connect().await;
+
+    client.send_raw(format!("CAPA\r\nNOOP\r\n{}\r\n", "x".repeat(300)).as_bytes()).await;
+    let output = String::from_utf8(client.read_to_end().await).unwrap();
+    let lines: Vec<&str> = output.split_terminator("\r\n").collect();
+    assert!(lines[0].starts_with("+OK"));
+    let end = lines.iter().position(|line| *line == ".").unwrap();
+    assert_eq!(
+        lines[end + 1..],
+        [
+            "-ERR Command only allowed in the TRANSACTION state",
+            "-ERR POP3 lines must be at most 255 characters long"
+        ]
+    );
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn lines_split_across_writes() {
+    let server = TestServer::start().await;
+    server.add_user("alice", "secret", &[]).await;
+    let mut client = server.connect().await;
+
+    client.send_raw(b"US").await;
+    tokio::task::yield_now().await;
+    client.send_raw(b"ER alice\r").await;
+    tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    client.send_raw(b"\n").await;
+    assert_eq!(client.read_line().await, "+OK");
+
+    client.send_raw(b"PASS secret").await;
+    tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    client.send_raw(b"\r\n").await;
+    assert_eq!(client.read_line().await, "+OK");
+    server.stop().await;
+}
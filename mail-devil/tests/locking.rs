@@ -0,0 +1,54 @@
+//! A maildrop is locked while a session has it open, so a user can only be logged in once at a time.
+
+mod common;
+
+use common::TestServer;
+
+#[tokio::test]
+async fn second_login_is_rejected_while_the_first_is_active() {
+    let server = TestServer::start().await;
+    server.add_user("alice", "secret", &[b"Subject: lock\n\nBody\n"]).await;
+    let mut first = server.login("alice", "secret").await;
+
+    let mut second = server.connect().await;
+    assert_eq!(second.command("USER alice").await, "+OK");
+    assert_eq!(second.command("PASS secret").await, "-ERR [IN-USE] User is already logged in");
+
+    // The rejected client stays in the AUTHORIZATION state, and the first session is unaffected.
+    assert_eq!(second.command("STAT").await, "-ERR Command only allowed in the TRANSACTION state");
+    assert_eq!(first.command("STAT").await, "+OK 1 23");
+
+    // Once the first session ends, the maildrop can be opened again.
+    assert_eq!(first.command("QUIT").await, "+OK 0 messages deleted");
+    assert!(first.read_to_end().await.is_empty());
+    assert_eq!(second.command("PASS secret").await, "+OK");
+    assert_eq!(second.command("STAT").await, "+OK 1 23");
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn wrong_password_does_not_lock_the_maildrop() {
+    let server = TestServer::start().await;
+    server.add_user("alice", "secret", &[]).await;
+
+    let mut failed = server.connect().await;
+    assert_eq!(failed.command("USER alice").await, "+OK");
+    assert_eq!(failed.command("PASS wrong").await, "-ERR [AUTH] Wrong username or password");
+
+    let mut client = server.login("alice", "secret").await;
+    assert_eq!(client.command("STAT").await, "+OK 0 0");
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn different_users_are_locked_separately() {
+    let server = TestServer::start().await;
+    server.add_user("alice", "secret", &[]).await;
+    server.add_user("bob", "hunter2", &[]).await;
+
+    let mut alice = server.login("alice", "secret").await;
+    let mut bob = server.login("bob", "hunter2").await;
+    assert_eq!(alice.command("NOOP").await, "+OK");
+    assert_eq!(bob.command("NOOP").await, "+OK");
+    server.stop().await;
+}
@@ -0,0 +1,104 @@
+//! Messages are numbered by delivery time, oldest first unless the server is configured to number them newest first.
+
+mod common;
+
+use std::time::{Duration, SystemTime};
+
+use common::TestServer;
+
+/// Names of maildir files, listed out of delivery order. The last one has no timestamp, so its modification time is used.
+const FILE_NAMES: [&str; 4] = [
+    "1700000300.c.localhost",
+    "1700000100.b.localhost",
+    "1700000100.a.localhost",
+    "undated",
+];
+
+async fn write_messages(server: &TestServer) {
+    server.add_user("alice", "secret", &[]).await;
+    for name in FILE_NAMES {
+        tokio::fs::write(server.new_dir("alice").join(name), format!("Subject: {name}\n\nBody\n"))
+            .await
+            .unwrap();
+    }
+
+    let file = std::fs::File::options()
+        .write(true)
+        .open(server.new_dir("alice").join("undated"))
+        .unwrap();
+    file.set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(1700000200)).unwrap();
+}
+
+async fn subjects(server: &TestServer) -> Vec<String> {
+    let mut client = server.login("alice", "secret").await;
+    let mut subjects = Vec::new();
+    for number in 1..=FILE_NAMES.len() {
+        let (status, lines) = client.multiline(&format!("RETR {number}")).await;
+        assert_eq!(status, "+OK");
+        subjects.push(lines[0].trim_start_matches("Subject: ").to_string());
+    }
+
+    subjects
+}
+
+#[tokio::test]
+async fn oldest_first_by_default() {
+    let server = TestServer::start().await;
+    write_messages(&server).await;
+
+    // Messages delivered in the same second are ordered by file name.
+    let expected = [
+        "1700000100.a.localhost",
+        "1700000100.b.localhost",
+        "undated",
+        "1700000300.c.localhost",
+    ];
+    assert_eq!(subjects(&server).await, expected);
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn newest_first() {
+    let server = TestServer::start_with(|builder| builder.newest_first(true)).await;
+    write_messages(&server).await;
+
+    let expected = [
+        "1700000300.c.localhost",
+        "undated",
+        "1700000100.a.localhost",
+        "1700000100.b.localhost",
+    ];
+    assert_eq!(subjects(&server).await, expected);
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn oversized_maildrop_keeps_the_oldest_messages() {
+    let server = TestServer::start().await;
+    server.add_user("alice", "secret", &[]).await;
+
+    // One message more than can be numbered. Which one a directory listing ends with depends on the filesystem, so
+    // truncating before sorting would drop an arbitrary message, often the oldest.
+    let count = u16::MAX as u64 + 1;
+    let new_dir = server.new_dir("alice");
+    for time in 0..count {
+        std::fs::write(new_dir.join(format!("{}.m{time}.localhost", 1700000000 + time)), b"x\n").unwrap();
+    }
+
+    let mut client = server.login("alice", "secret").await;
+    assert!(client.command("STAT").await.starts_with(&format!("+OK {} ", u16::MAX)));
+    assert_eq!(client.command("LIST 65536").await, "-ERR Argument is not a valid number");
+    assert_eq!(client.command("DELE 1").await, "+OK");
+    assert_eq!(client.command(&format!("DELE {}", u16::MAX)).await, "+OK");
+    assert_eq!(client.command("QUIT").await, "+OK 2 messages deleted");
+
+    // The first and last numbered messages were the oldest and second newest, so the newest was the one left out.
+    let retained = common::list_files(&server.retained_dir("alice")).await;
+    assert_eq!(retained.len(), 2);
+    assert!(retained[0].starts_with("1700000000.m0.localhost"), "deleted {retained:?}");
+    assert!(
+        retained[1].starts_with(&format!("{}.m{}.localhost", 1700000000 + count - 2, count - 2)),
+        "deleted {retained:?}"
+    );
+    server.stop().await;
+}
@@ -0,0 +1,98 @@
+//! Giving the files and folders created in a user's maildir to the mailbox's owner, as told by the authenticator.
+//!
+//! Giving files away requires running as root, so these tests do nothing otherwise.
+
+mod common;
+
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+use common::TestServer;
+use mail_devil::auth::{AuthenticateFuture, Authenticator, MaildirOwner, OwnerFuture, PasswordFileAuthenticator};
+use mail_devil::deletion::DeletionPolicy;
+use mail_devil::types::{Pop3ArgString, Pop3Username};
+
+const OWNER: MaildirOwner = MaildirOwner { uid: 12345, gid: 12346 };
+
+/// Checks passwords like the default authenticator, but says every mailbox belongs to [`OWNER`].
+struct FixedOwnerAuthenticator;
+
+impl Authenticator for FixedOwnerAuthenticator {
+    fn authenticate<'a>(
+        &'a self,
+        maildirs_dir: &'a Path,
+        username: &'a Pop3Username,
+        password: &'a Pop3ArgString,
+    ) -> AuthenticateFuture<'a> {
+        PasswordFileAuthenticator.authenticate(maildirs_dir, username, password)
+    }
+
+    fn owner<'a>(&'a self, _: &'a Path, _: &'a Pop3Username) -> OwnerFuture<'a> {
+        Box::pin(async { Some(OWNER) })
+    }
+}
+
+fn running_as_root() -> bool {
+    unsafe { libc::geteuid() == 0 }
+}
+
+fn owner_of(path: &Path) -> MaildirOwner {
+    let metadata = std::fs::metadata(path).unwrap();
+    MaildirOwner {
+        uid: metadata.uid(),
+        gid: metadata.gid(),
+    }
+}
+
+#[tokio::test]
+async fn retained_folder_is_given_to_the_authenticators_owner() {
+    if !running_as_root() {
+        return;
+    }
+
+    let server = TestServer::start_with(|builder| {
+        builder
+            .authenticator(FixedOwnerAuthenticator)
+            .deletion_policy(DeletionPolicy::Retain(None))
+    })
+    .await;
+    server.add_user("alice", "secret", &[b"Subject: one\r\n\r\nHello\r\n"]).await;
+
+    let mut client = server.login("alice", "secret").await;
+    assert_eq!(client.command("DELE 1").await, "+OK");
+    assert_eq!(client.command("QUIT").await, "+OK 1 messages deleted");
+    assert!(client.read_to_end().await.is_empty());
+
+    let retained_folder = server.maildirs_dir.join("alice").join(".Retained");
+    for folder in ["", "new", "tmp", "cur"] {
+        assert_eq!(owner_of(&retained_folder.join(folder)), OWNER, "owner of .Retained/{folder}");
+    }
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn files_follow_the_maildir_folders_owner_by_default() {
+    if !running_as_root() {
+        return;
+    }
+
+    let server = TestServer::start_with(|builder| builder.deletion_policy(DeletionPolicy::Trash)).await;
+    server.add_user("alice", "secret", &[b"Subject: one\r\n\r\nHello\r\n"]).await;
+    let maildir = server.maildirs_dir.join("alice");
+    std::os::unix::fs::chown(&maildir, Some(OWNER.uid), Some(OWNER.gid)).unwrap();
+
+    let mut client = server.login("alice", "secret").await;
+    assert_eq!(client.command("DELE 1").await, "+OK");
+    assert_eq!(client.command("QUIT").await, "+OK 1 messages deleted");
+    assert!(client.read_to_end().await.is_empty());
+    assert_eq!(owner_of(&maildir.join(".Trash").join("cur")), OWNER);
+
+    // Updating the user rewrites their password file, which is also given to the maildir's owner.
+    mail_devil::server::create_user_maildir(&server.maildirs_dir, "alice", "changed")
+        .await
+        .unwrap();
+    assert_eq!(owner_of(&maildir.join("password")), OWNER);
+
+    server.stop().await;
+}
@@ -0,0 +1,92 @@
+//! Sends a large batch of pipelined commands (RFC #2449) in a single write, and checks that every command is answered
+//! in order, with the responses batched into a few writes instead of one per command.
+
+mod common;
+
+use common::{TestClient, TestServer};
+use tokio::net::TcpListener;
+
+const PIPELINED_COMMANDS: usize = 1000;
+const MESSAGE_COUNT: usize = 3;
+
+/// What the response to a pipelined command is expected to look like.
+enum Expected {
+    /// A single line starting with the given text.
+    Line(String),
+    /// A `+OK` line followed by the given lines and a terminating period.
+    MultiLine(Vec<String>),
+}
+
+/// Builds the batch of commands along with the response expected for each of them.
+fn build_commands() -> (String, Vec<Expected>) {
+    let mut commands = String::from("USER alice\r\nPASS secret\r\n");
+    let mut expected = vec![Expected::Line("+OK".into()), Expected::Line("+OK".into())];
+
+    for i in 0..PIPELINED_COMMANDS {
+        let number = i % MESSAGE_COUNT + 1;
+        let (command, response) = match i % 6 {
+            0 => ("NOOP".to_string(), Expected::Line("+OK".into())),
+            1 => ("STAT".to_string(), Expected::Line(format!("+OK {MESSAGE_COUNT} "))),
+            2 => (format!("LIST {number}"), Expected::Line(format!("+OK {number} "))),
+            3 => (
+                format!("RETR {number}"),
+                Expected::MultiLine(vec![
+                    format!("Subject: {number}"),
+                    String::new(),
+                    format!("Message number {number}"),
+                ]),
+            ),
+            4 => ("DELE 99".to_string(), Expected::Line("-ERR".into())),
+            _ => ("BOGUS".to_string(), Expected::Line("-ERR Unknown command".into())),
+        };
+
+        commands.push_str(&command);
+        commands.push_str("\r\n");
+        expected.push(response);
+    }
+
+    (commands, expected)
+}
+
+#[tokio::test]
+async fn pipelined_commands_are_answered_in_order() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let admin_address = listener.local_addr().unwrap();
+    let server = TestServer::start_with(|builder| builder.admin_listener(listener).admin_password("hunter2")).await;
+    let messages: Vec<String> = (1..=MESSAGE_COUNT)
+        .map(|number| format!("Subject: {number}\n\nMessage number {number}\n"))
+        .collect();
+    let messages: Vec<&[u8]> = messages.iter().map(|message| message.as_bytes()).collect();
+    server.add_user("alice", "secret", &messages).await;
+
+    let (commands, expected) = build_commands();
+    let mut client = server.connect().await;
+    client.send_raw(commands.as_bytes()).await;
+
+    for (index, response) in expected.iter().enumerate() {
+        let line = client.read_line().await;
+        match response {
+            Expected::Line(prefix) => assert!(line.starts_with(prefix.as_str()), "response to command {index} was {line:?}"),
+            Expected::MultiLine(body) => {
+                assert_eq!(line, "+OK", "response to command {index}");
+                for body_line in body {
+                    assert_eq!(&client.read_line().await, body_line, "response to command {index}");
+                }
+                assert_eq!(client.read_line().await, ".", "response to command {index}");
+            }
+        }
+    }
+
+    // The responses are only flushed once the server runs out of commands to read or fills its buffer, so it takes far
+    // fewer writes to send them than there were commands. The last column of the session's line is its writes.
+    let mut admin = TestClient::connect(admin_address).await;
+    assert_eq!(admin.command("AUTH hunter2").await, "+OK Authenticated");
+    let (status, sessions) = admin.multiline("SESSIONS").await;
+    assert_eq!((status.as_str(), sessions.len()), ("+OK 1 sessions", 1));
+    let writes: usize = sessions[0].rsplit(' ').next().unwrap().parse().unwrap();
+    assert!(writes < PIPELINED_COMMANDS / 20, "the responses took {writes} writes");
+
+    assert_eq!(client.command("QUIT").await, "+OK 0 messages deleted");
+    assert!(client.read_to_end().await.is_empty());
+    server.stop().await;
+}
@@ -0,0 +1,73 @@
+//! Maildir++ quotas: rejecting logins over a percentage of the quota, and keeping track of the maildir's usage.
+
+mod common;
+
+use std::time::Duration;
+
+use common::TestServer;
+use mail_devil::deletion::{self, DeletionPolicy};
+use mail_devil::quota::{self, Quota};
+
+/// Gives alice a quota of 100 bytes and a maildrop holding 60 of them.
+async fn add_user_at_60_percent(server: &TestServer) {
+    server.add_user("alice", "secret", &[&[b'x'; 60]]).await;
+    let quota: Quota = "100S".parse().ok().unwrap();
+    quota::set_quota(&server.maildirs_dir.join("alice"), quota).await.unwrap();
+}
+
+#[tokio::test]
+async fn login_rejected_over_a_threshold_below_the_quota() {
+    let server = TestServer::start_with(|builder| builder.quota_reject_percent(Some(50))).await;
+    add_user_at_60_percent(&server).await;
+    let mut client = server.connect().await;
+
+    assert_eq!(client.command("USER alice").await, "+OK");
+    let response = client.command("PASS secret").await;
+    assert!(response.starts_with("-ERR [SYS/PERM]"), "response to PASS was {response:?}");
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn login_allowed_under_the_threshold() {
+    let server = TestServer::start_with(|builder| builder.quota_reject_percent(Some(90)).deletion_policy(DeletionPolicy::Unlink)).await;
+    add_user_at_60_percent(&server).await;
+
+    let mut client = server.login("alice", "secret").await;
+    assert_eq!(client.command("DELE 1").await, "+OK");
+    assert_eq!(client.command("QUIT").await, "+OK 1 messages deleted");
+    assert!(client.read_to_end().await.is_empty());
+
+    // A session that deletes nothing doesn't add a line to the maildirsize file.
+    let mut client = server.login("alice", "secret").await;
+    assert_eq!(client.command("QUIT").await, "+OK 0 messages deleted");
+    assert!(client.read_to_end().await.is_empty());
+    let maildirsize = tokio::fs::read_to_string(server.maildirs_dir.join("alice").join("maildirsize"))
+        .await
+        .unwrap();
+    assert_eq!(maildirsize.lines().count(), 3, "maildirsize is {maildirsize:?}");
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn moved_messages_count_until_purged() {
+    let server = TestServer::start_with(|builder| builder.deletion_policy(DeletionPolicy::Retain(Some(30)))).await;
+    add_user_at_60_percent(&server).await;
+    let maildir = server.maildirs_dir.join("alice");
+
+    let mut client = server.login("alice", "secret").await;
+    assert_eq!(client.command("DELE 1").await, "+OK");
+    assert_eq!(client.command("QUIT").await, "+OK 1 messages deleted");
+    assert!(client.read_to_end().await.is_empty());
+
+    // The message was only moved to the `.Retained` folder, where recalculating the usage still finds it.
+    let usage = quota::read_usage(&maildir).await.unwrap();
+    assert_eq!((usage.bytes, usage.messages), (60, 1));
+    let usage = quota::set_quota(&maildir, usage.quota.unwrap()).await.unwrap();
+    assert_eq!((usage.bytes, usage.messages), (60, 1));
+
+    let purged = deletion::purge_retained_messages(&server.maildirs_dir, Duration::ZERO).await;
+    assert_eq!(purged.unwrap(), 1);
+    let usage = quota::read_usage(&maildir).await.unwrap();
+    assert_eq!((usage.bytes, usage.messages), (0, 0));
+    server.stop().await;
+}
@@ -0,0 +1,163 @@
+//! The transformation applied to messages sent with RETR: line endings are converted to CRLF, lines beginning with a
+//! period are byte-stuffed, and the response is terminated with a line holding a single period.
+
+mod common;
+
+use common::TestServer;
+
+/// Retrieves a message with the given contents, returning the raw bytes sent after the status line.
+async fn retrieve(server: &TestServer, contents: &[u8]) -> Vec<u8> {
+    server.add_user("alice", "secret", &[contents]).await;
+    let mut client = server.login("alice", "secret").await;
+    let (status, body) = client.multiline_raw("RETR 1").await;
+    assert_eq!(status, "+OK");
+    assert_eq!(client.command("QUIT").await, "+OK 0 messages deleted");
+    body
+}
+
+/// Converts a message the way the server is expected to, one byte at a time.
+fn expected_transfer(contents: &[u8]) -> Vec<u8> {
+    let mut output = Vec::new();
+    let mut at_line_start = true;
+    for (index, &byte) in contents.iter().enumerate() {
+        if at_line_start && byte == b'.' {
+            output.push(b'.');
+        }
+
+        if byte == b'\n' && (index == 0 || contents[index - 1] != b'\r') {
+            output.push(b'\r');
+        }
+
+        output.push(byte);
+        at_line_start = byte == b'\n';
+    }
+
+    if !output.is_empty() && !output.ends_with(b"\n") {
+        output.extend_from_slice(b"\r\n");
+    }
+
+    output.extend_from_slice(b".\r\n");
+    output
+}
+
+#[tokio::test]
+async fn lf_is_converted_to_crlf() {
+    let server = TestServer::start().await;
+    let body = retrieve(&server, b"Subject: lf\n\nline one\nline two\n").await;
+    assert_eq!(body, b"Subject: lf\r\n\r\nline one\r\nline two\r\n.\r\n");
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn crlf_is_left_alone() {
+    let server = TestServer::start().await;
+    let body = retrieve(&server, b"Subject: crlf\r\n\r\nline one\r\nline two\r\n").await;
+    assert_eq!(body, b"Subject: crlf\r\n\r\nline one\r\nline two\r\n.\r\n");
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn mixed_line_endings() {
+    let server = TestServer::start().await;
+    let body = retrieve(&server, b"a\r\nb\nc\r\n\nd\n").await;
+    assert_eq!(body, b"a\r\nb\r\nc\r\n\r\nd\r\n.\r\n");
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn missing_final_newline_is_added() {
+    let server = TestServer::start().await;
+    let body = retrieve(&server, b"Subject: x\n\nno newline at the end").await;
+    assert_eq!(body, b"Subject: x\r\n\r\nno newline at the end\r\n.\r\n");
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn lines_beginning_with_a_period_are_stuffed() {
+    let server = TestServer::start().await;
+    let body = retrieve(&server, b"Subject: dots\n\n.\n..\n.hidden\r\nnot.a.dot\n.\r\n").await;
+    assert_eq!(body, b"Subject: dots\r\n\r\n..\r\n...\r\n..hidden\r\nnot.a.dot\r\n..\r\n.\r\n");
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn first_line_beginning_with_a_period_is_stuffed() {
+    let server = TestServer::start().await;
+    let body = retrieve(&server, b".first line\nsecond line\n").await;
+    assert_eq!(body, b"..first line\r\nsecond line\r\n.\r\n");
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn message_of_a_single_period() {
+    let server = TestServer::start().await;
+    let body = retrieve(&server, b".").await;
+    assert_eq!(body, b"..\r\n.\r\n");
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn empty_message() {
+    let server = TestServer::start().await;
+    let body = retrieve(&server, b"").await;
+    assert_eq!(body, b".\r\n");
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn transformations_across_buffer_boundaries() {
+    // With a tiny buffer, LFs and periods land at every possible position relative to the buffer's boundaries.
+    let server = TestServer::start_with(|builder| builder.buffer_size(7)).await;
+    let mut contents = Vec::new();
+    for i in 0..200 {
+        contents.extend_from_slice(&b".x\n..\r\n\n.\r\n"[..(i % 11) + 1]);
+        contents.extend_from_slice(format!("line {i}\n").as_bytes());
+    }
+
+    let body = retrieve(&server, &contents).await;
+    assert_eq!(
+        String::from_utf8(body).unwrap(),
+        String::from_utf8(expected_transfer(&contents)).unwrap()
+    );
+    server.stop().await;
+}
+
+/// The listed size is the size of the message with CRLF line endings, which is what's transferred for messages with no
+/// lines beginning with a period and that end with a line ending.
+#[tokio::test]
+async fn listed_size_matches_transferred_size() {
+    let server = TestServer::start().await;
+    let contents = b"Subject: size\n\nsome\r\nlines\nhere\n";
+    server.add_user("alice", "secret", &[contents]).await;
+    let mut client = server.login("alice", "secret").await;
+
+    let (_, body) = client.multiline_raw("RETR 1").await;
+    let transferred = body.len() - b".\r\n".len();
+    assert_eq!(client.command("LIST 1").await, format!("+OK 1 {transferred}"));
+    server.stop().await;
+}
+
+/// Like RFC #1939 defines it, the listed size counts neither the periods added by byte-stuffing nor the line ending
+/// added to terminate a last line that lacks one, so such messages are listed as smaller than what's transferred.
+#[tokio::test]
+async fn listed_size_excludes_byte_stuffing_and_the_added_line_ending() {
+    let server = TestServer::start().await;
+    let contents = b"Subject: size\n\n.one\n..two\nlast";
+    server.add_user("alice", "secret", &[contents]).await;
+    let mut client = server.login("alice", "secret").await;
+
+    let (_, body) = client.multiline_raw("RETR 1").await;
+    assert!(
+        body.ends_with(b"\r\n...two\r\nlast\r\n.\r\n"),
+        "body was {:?}",
+        body.escape_ascii().to_string()
+    );
+    let transferred = body.len() - b".\r\n".len();
+    let stuffed_periods = 2;
+    let added_line_ending = b"\r\n".len();
+    assert_eq!(
+        client.command("LIST 1").await,
+        format!("+OK 1 {}", transferred - stuffed_periods - added_line_ending)
+    );
+    server.stop().await;
+}
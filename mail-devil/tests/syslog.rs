@@ -0,0 +1,64 @@
+//! Sending log messages to a syslog or journald socket without ever blocking the thread that logs them.
+
+#![cfg(unix)]
+
+use std::os::unix::net::UnixDatagram;
+use std::path::PathBuf;
+use std::sync::mpsc;
+use std::time::Duration;
+
+use mail_devil::syslog::{SyslogFacility, SyslogLayer, SyslogTarget};
+use tracing_subscriber::layer::SubscriberExt;
+
+/// Binds a datagram socket standing in for the daemon's, at a path unique to the given test.
+fn bind_daemon_socket(name: &str) -> (UnixDatagram, PathBuf) {
+    let path = std::env::temp_dir().join(format!("mail-devil-test-{}-{name}.sock", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    (UnixDatagram::bind(&path).unwrap(), path)
+}
+
+#[test]
+fn oversized_journald_messages_are_truncated() {
+    let (daemon, path) = bind_daemon_socket("journald-truncate");
+    let layer = SyslogLayer::new(&SyslogTarget::Journald(path.clone()), SyslogFacility::default()).unwrap();
+    tracing::subscriber::with_default(tracing_subscriber::registry().with(layer), || {
+        tracing::info!("{}", "x".repeat(200_000));
+    });
+
+    let mut buf = vec![0u8; 0x40000];
+    daemon.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+    let len = daemon.recv(&mut buf).unwrap();
+    let message = String::from_utf8_lossy(&buf[..len]);
+    assert!(len <= 0x10000, "datagram was {len} bytes long");
+    assert!(message.starts_with("MESSAGE=xxx"), "message was {:?}", &message[..40]);
+    assert!(message.contains("x [truncated]\nPRIORITY=6\n"));
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn full_socket_drops_messages_instead_of_blocking() {
+    let (daemon, path) = bind_daemon_socket("syslog-full");
+    let layer = SyslogLayer::new(&SyslogTarget::Syslog(path.clone()), SyslogFacility::default()).unwrap();
+
+    // The daemon never reads, so its socket's buffer fills up long before all the messages are sent.
+    let (done_sender, done_receiver) = mpsc::channel();
+    std::thread::spawn(move || {
+        tracing::subscriber::with_default(tracing_subscriber::registry().with(layer), || {
+            for i in 0..100_000 {
+                tracing::warn!("message {i}");
+            }
+        });
+        done_sender.send(()).unwrap();
+    });
+
+    done_receiver
+        .recv_timeout(Duration::from_secs(30))
+        .expect("logging blocked on a full socket");
+
+    // The first messages made it through.
+    let mut buf = vec![0u8; 0x10000];
+    let len = daemon.recv(&mut buf).unwrap();
+    assert!(String::from_utf8_lossy(&buf[..len]).ends_with("message 0"));
+    drop(daemon);
+    std::fs::remove_file(path).unwrap();
+}
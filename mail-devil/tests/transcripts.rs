@@ -0,0 +1,110 @@
+//! Recording protocol transcripts of sessions from traced addresses or naming a traced user, with credentials redacted.
+
+mod common;
+
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use common::{TestServer, RESPONSE_TIMEOUT};
+use mail_devil::transcript::TranscriptSettings;
+use mail_devil::types::Pop3Username;
+
+/// Gets a transcripts directory unique to the given test, which doesn't exist yet.
+async fn new_transcripts_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("mail-devil-test-{}-{name}-transcripts", std::process::id()));
+    let _ = tokio::fs::remove_dir_all(&dir).await;
+    dir
+}
+
+/// Waits for the single transcript in the directory to be complete, and returns its contents.
+async fn read_closed_transcript(dir: &Path) -> String {
+    let started = Instant::now();
+    loop {
+        let files = common::list_files(dir).await;
+        if let [file] = files.as_slice() {
+            let contents = tokio::fs::read_to_string(dir.join(file)).await.unwrap();
+            if contents.ends_with("-- Session closed\n") {
+                return contents;
+            }
+        }
+
+        assert!(
+            started.elapsed() < RESPONSE_TIMEOUT,
+            "no complete transcript in {}: {files:?}",
+            dir.display()
+        );
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+}
+
+#[tokio::test]
+async fn oldest_lines_are_dropped_while_waiting_for_a_traced_user() {
+    let transcripts_dir = new_transcripts_dir("pending").await;
+    let settings = TranscriptSettings {
+        users: vec![Pop3Username::try_from("alice").ok().unwrap()],
+        addresses: Vec::new(),
+        dir: transcripts_dir.clone(),
+    };
+
+    let server = TestServer::start_with(|builder| builder.transcripts(settings)).await;
+    server.add_user("alice", "secret", &[]).await;
+    let mut client = server.connect().await;
+
+    // Each command and its response take two lines, many more than are held before the user is named.
+    for i in 0..50 {
+        client.command(&format!("NOOP {i}")).await;
+    }
+
+    assert_eq!(client.command("USER alice").await, "+OK");
+    assert!(client.command("QUIT").await.starts_with("+OK"));
+    assert!(client.read_to_end().await.is_empty());
+
+    let transcript = read_closed_transcript(&transcripts_dir).await;
+    assert!(
+        transcript.contains(" earlier lines not recorded\n"),
+        "transcript was {transcript:?}"
+    );
+    assert!(!transcript.contains("C: NOOP 0\n"));
+    assert!(transcript.contains("C: NOOP 49\n"));
+    assert!(transcript.contains("C: USER alice\n"));
+
+    server.stop().await;
+    tokio::fs::remove_dir_all(&transcripts_dir).await.unwrap();
+}
+
+#[tokio::test]
+async fn credentials_are_redacted_whatever_the_separator() {
+    let transcripts_dir = new_transcripts_dir("redacted").await;
+    let settings = TranscriptSettings {
+        users: Vec::new(),
+        addresses: vec![IpAddr::V4(Ipv4Addr::LOCALHOST)],
+        dir: transcripts_dir.clone(),
+    };
+
+    let server = TestServer::start_with(|builder| builder.transcripts(settings)).await;
+    server.add_user("alice", "secret", &[]).await;
+    let mut client = server.connect().await;
+
+    assert_eq!(client.command("USER alice").await, "+OK");
+    client.send_raw(b"PASS\tsecret\r\n").await;
+    client.read_line().await;
+    client.send_raw(b"APOP alice\tsecret\r\n").await;
+    client.read_line().await;
+    client.send_raw(b" AUTH PLAIN c2VjcmV0\r\n").await;
+    client.read_line().await;
+    assert!(client.command("QUIT").await.starts_with("+OK"));
+    assert!(client.read_to_end().await.is_empty());
+
+    let transcript = read_closed_transcript(&transcripts_dir).await;
+    assert!(
+        !transcript.contains("secret") && !transcript.contains("c2VjcmV0"),
+        "transcript was {transcript:?}"
+    );
+    assert!(transcript.contains("C: PASS [redacted]\n"));
+    assert!(transcript.contains("C: APOP [redacted]\n"));
+    assert!(transcript.contains("C: AUTH [redacted]\n"));
+
+    server.stop().await;
+    tokio::fs::remove_dir_all(&transcripts_dir).await.unwrap();
+}
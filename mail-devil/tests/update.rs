@@ -0,0 +1,167 @@
+//! The UPDATE state: deletions are only committed when the client ends the session with QUIT from the TRANSACTION
+//! state, and never when the connection is dropped or times out.
+
+mod common;
+
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+use common::{list_files, TestServer};
+use mail_devil::deletion::{self, DeletionPolicy};
+
+const MESSAGES: [&[u8]; 3] = [
+    b"Subject: one\n\nFirst\n",
+    b"Subject: two\n\nSecond\n",
+    b"Subject: three\n\nThird\n",
+];
+
+#[tokio::test]
+async fn quit_commits_deletions() {
+    let server = TestServer::start().await;
+    server.add_user("alice", "secret", &MESSAGES).await;
+    let mut client = server.login("alice", "secret").await;
+
+    assert_eq!(client.command("DELE 1").await, "+OK");
+    assert_eq!(client.command("DELE 3").await, "+OK");
+
+    // Nothing is touched until the session enters the UPDATE state.
+    assert_eq!(list_files(&server.new_dir("alice")).await.len(), 3);
+    assert_eq!(client.command("QUIT").await, "+OK 2 messages deleted");
+    assert!(client.read_to_end().await.is_empty());
+
+    // Under the default policy, deleted messages are moved to `.Retained` and the rest are left alone.
+    assert_eq!(list_files(&server.new_dir("alice")).await, ["2.message2.localhost"]);
+    let retained = list_files(&server.retained_dir("alice")).await;
+    assert_eq!(retained.len(), 2);
+    assert!(retained[0].starts_with("1.message1.localhost"));
+    assert!(retained[1].starts_with("3.message3.localhost"));
+
+    // The next session only sees the remaining message.
+    let mut client = server.login("alice", "secret").await;
+    let (_, lines) = client.multiline("RETR 1").await;
+    assert_eq!(lines, ["Subject: two", "", "Second"]);
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn quit_with_unlink_policy_removes_files() {
+    let server = TestServer::start_with(|builder| builder.deletion_policy(DeletionPolicy::Unlink)).await;
+    server.add_user("alice", "secret", &MESSAGES).await;
+    let mut client = server.login("alice", "secret").await;
+
+    assert_eq!(client.command("DELE 2").await, "+OK");
+    assert_eq!(client.command("QUIT").await, "+OK 1 messages deleted");
+    assert!(client.read_to_end().await.is_empty());
+
+    assert_eq!(
+        list_files(&server.new_dir("alice")).await,
+        ["1.message1.localhost", "3.message3.localhost"]
+    );
+    assert!(list_files(&server.retained_dir("alice")).await.is_empty());
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn rset_before_quit_commits_nothing() {
+    let server = TestServer::start().await;
+    server.add_user("alice", "secret", &MESSAGES).await;
+    let mut client = server.login("alice", "secret").await;
+
+    assert_eq!(client.command("DELE 1").await, "+OK");
+    assert_eq!(client.command("RSET").await, "+OK");
+    assert_eq!(client.command("QUIT").await, "+OK 0 messages deleted");
+    assert!(client.read_to_end().await.is_empty());
+
+    assert_eq!(list_files(&server.new_dir("alice")).await.len(), 3);
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn disconnecting_without_quit_commits_nothing() {
+    let server = TestServer::start().await;
+    server.add_user("alice", "secret", &MESSAGES).await;
+    let mut client = server.login("alice", "secret").await;
+
+    assert_eq!(client.command("DELE 1").await, "+OK");
+    assert_eq!(client.command("DELE 2").await, "+OK");
+    drop(client);
+
+    // The lock on the maildrop is released once the server notices the disconnection, so logging in again and
+    // counting the messages also waits for the session to end.
+    let mut client = wait_for_login(&server).await;
+    assert!(client.command("STAT").await.starts_with("+OK 3 "));
+    assert_eq!(list_files(&server.new_dir("alice")).await.len(), 3);
+    assert!(list_files(&server.retained_dir("alice")).await.is_empty());
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn idle_timeout_commits_nothing() {
+    let server = TestServer::start_with(|builder| builder.transaction_timeout(Duration::from_millis(200))).await;
+    server.add_user("alice", "secret", &MESSAGES).await;
+    let mut client = server.login("alice", "secret").await;
+
+    assert_eq!(client.command("DELE 1").await, "+OK");
+
+    // The server closes the connection without a response once the client has been idle for too long.
+    assert!(client.read_to_end().await.is_empty());
+    assert_eq!(list_files(&server.new_dir("alice")).await.len(), 3);
+    assert!(list_files(&server.retained_dir("alice")).await.is_empty());
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn idle_timeout_in_authorization_state() {
+    let server = TestServer::start_with(|builder| builder.auth_timeout(Duration::from_millis(200))).await;
+    let mut client = server.connect().await;
+
+    assert_eq!(client.command("USER alice").await, "+OK");
+    assert!(client.read_to_end().await.is_empty());
+    server.stop().await;
+}
+
+/// Logs in as alice, retrying while the server still considers a previous session of hers active.
+async fn wait_for_login(server: &TestServer) -> common::TestClient {
+    for _ in 0..100 {
+        let mut client = server.connect().await;
+        assert_eq!(client.command("USER alice").await, "+OK");
+        match client.command("PASS secret").await.as_str() {
+            "+OK" => return client,
+            "-ERR [IN-USE] User is already logged in" => tokio::time::sleep(Duration::from_millis(50)).await,
+            response => panic!("unexpected response to PASS: {response:?}"),
+        }
+    }
+
+    panic!("the previous session was never ended");
+}
+
+#[tokio::test]
+async fn purge_only_removes_old_retained_messages() {
+    let server = TestServer::start_with(|builder| builder.deletion_policy(DeletionPolicy::Retain(Some(30)))).await;
+    server.add_user("alice", "secret", &MESSAGES).await;
+    let mut client = server.login("alice", "secret").await;
+    assert_eq!(client.command("DELE 1").await, "+OK");
+    assert_eq!(client.command("DELE 2").await, "+OK");
+    assert_eq!(client.command("QUIT").await, "+OK 2 messages deleted");
+    assert!(client.read_to_end().await.is_empty());
+
+    // Backdate one of the retained messages, as well as a message another program left in the maildir's own `cur`.
+    let month_ago = SystemTime::now() - Duration::from_secs(31 * 24 * 60 * 60);
+    let retained = list_files(&server.retained_dir("alice")).await;
+    set_modified(&server.retained_dir("alice").join(&retained[0]), month_ago);
+    let seen = server.maildirs_dir.join("alice").join("cur").join("4.seen.localhost:2,S");
+    tokio::fs::create_dir_all(seen.parent().unwrap()).await.unwrap();
+    tokio::fs::write(&seen, b"Subject: seen\n\nRead elsewhere\n").await.unwrap();
+    set_modified(&seen, month_ago);
+
+    let purged = deletion::purge_retained_messages(&server.maildirs_dir, Duration::from_secs(30 * 24 * 60 * 60)).await;
+    assert_eq!(purged.unwrap(), 1);
+    assert_eq!(list_files(&server.retained_dir("alice")).await, retained[1..]);
+    assert!(seen.exists());
+    server.stop().await;
+}
+
+fn set_modified(path: &Path, time: SystemTime) {
+    let file = std::fs::File::options().write(true).open(path).unwrap();
+    file.set_modified(time).unwrap();
+}